@@ -26,7 +26,7 @@ fuzz_target!(|data: &[u8]| {
             if_router: None,
         };
 
-        if let Ok(reply) = erbium::dhcp::handle_pkt(&mut pools, &request, serverids, &cfg).await {
+        if let Ok(Some(reply)) = erbium::dhcp::handle_pkt(&mut pools, &request, serverids, &cfg).await {
             let _ = reply.serialise();
         }
     }
@@ -32,9 +32,34 @@ use yaml_rust::yaml;
 #[derive(Debug)]
 pub struct Permission {
     pub allow_dns_recursion: bool,
+    pub allow_dhcp_leasequery: bool,
     pub allow_http: bool,
     pub allow_http_metrics: bool,
     pub allow_http_leases: bool,
+    pub allow_http_forcerenew: bool,
+    pub allow_tftp: bool,
+}
+
+impl Permission {
+    fn check(&self, perm: &PermissionType) -> Result<(), AclError> {
+        fn check_permission(perm: bool, name: &str) -> Result<(), AclError> {
+            if perm {
+                Ok(())
+            } else {
+                Err(AclError::NotAuthorised(name.into()))
+            }
+        }
+        use PermissionType::*;
+        match perm {
+            DnsRecursion => check_permission(self.allow_dns_recursion, "dns-recursion"),
+            DhcpLeaseQuery => check_permission(self.allow_dhcp_leasequery, "dhcp-leasequery"),
+            Http => check_permission(self.allow_http, "http"),
+            HttpLeases => check_permission(self.allow_http_leases, "http-leases"),
+            HttpMetrics => check_permission(self.allow_http_metrics, "http-metrics"),
+            HttpForceRenew => check_permission(self.allow_http_forcerenew, "http-forcerenew"),
+            Tftp => check_permission(self.allow_tftp, "tftp"),
+        }
+    }
 }
 
 pub struct Attributes {
@@ -97,9 +122,12 @@ impl Acl {
 
 pub enum PermissionType {
     DnsRecursion,
+    DhcpLeaseQuery,
     Http,
     HttpLeases,
     HttpMetrics,
+    HttpForceRenew,
+    Tftp,
 }
 
 impl std::fmt::Display for PermissionType {
@@ -107,9 +135,12 @@ impl std::fmt::Display for PermissionType {
         use PermissionType::*;
         match self {
             DnsRecursion => write!(f, "DNS Recursion"),
+            DhcpLeaseQuery => write!(f, "DHCP Leasequery"),
             Http => write!(f, "HTTP"),
             HttpLeases => write!(f, "HTTP Leases"),
             HttpMetrics => write!(f, "HTTP Metrics"),
+            HttpForceRenew => write!(f, "HTTP DHCP Force Renew"),
+            Tftp => write!(f, "TFTP"),
         }
     }
 }
@@ -148,26 +179,22 @@ pub fn require_permission(
     client: &Attributes,
     perm: PermissionType,
 ) -> Result<(), AclError> {
-    fn check_permission(perm: bool, name: &str) -> Result<(), AclError> {
-        if perm {
-            Ok(())
-        } else {
-            Err(AclError::NotAuthorised(name.into()))
-        }
-    }
-    use PermissionType::*;
-    match (check_authenticated(acl, client), perm) {
-        (Ok(perms), DnsRecursion) => check_permission(perms.allow_dns_recursion, "dns-recursion"),
-        (Ok(perms), Http) => check_permission(perms.allow_http, "http"),
-        (Ok(perms), HttpLeases) => check_permission(perms.allow_http_leases, "http-leases"),
-        (Ok(perms), HttpMetrics) => check_permission(perms.allow_http_metrics, "http-metrics"),
-        (Err(err), perm) => {
+    match check_authenticated(acl, client) {
+        Ok(perms) => perms.check(&perm),
+        Err(err) => {
             log::warn!("{}: {}: {}", client, perm, err);
             Err(err)
         }
     }
 }
 
+/* This checks if any ACL at all has a permission, so that services nobody is allowed to use don't
+ * need to listen.
+ */
+pub fn any_has_permission(acl: &[Acl], perm: PermissionType) -> bool {
+    acl.iter().any(|a| a.permission.check(&perm).is_ok())
+}
+
 pub fn default_acls(addresses: &[Prefix]) -> Vec<Acl> {
     vec![
         Acl {
@@ -176,9 +203,12 @@ pub fn default_acls(addresses: &[Prefix]) -> Vec<Acl> {
             unix: None,
             permission: Permission {
                 allow_dns_recursion: true,
+                allow_dhcp_leasequery: false,
                 allow_http_leases: true,
                 allow_http_metrics: true,
                 allow_http: true,
+                allow_http_forcerenew: false,
+                allow_tftp: true,
             },
         },
         Acl {
@@ -196,9 +226,12 @@ pub fn default_acls(addresses: &[Prefix]) -> Vec<Acl> {
             unix: None,
             permission: Permission {
                 allow_dns_recursion: true,
+                allow_dhcp_leasequery: false,
                 allow_http_leases: true,
                 allow_http_metrics: true,
                 allow_http: true,
+                allow_http_forcerenew: false,
+                allow_tftp: true,
             },
         },
         Acl {
@@ -207,9 +240,12 @@ pub fn default_acls(addresses: &[Prefix]) -> Vec<Acl> {
             unix: Some(true),
             permission: Permission {
                 allow_dns_recursion: false,
+                allow_dhcp_leasequery: false,
                 allow_http_leases: true,
                 allow_http_metrics: true,
                 allow_http: true,
+                allow_http_forcerenew: true,
+                allow_tftp: false,
             },
         },
     ]
@@ -247,18 +283,25 @@ pub(crate) fn parse_acl(name: &str, fragment: &yaml::Yaml) -> Result<Option<Acl>
                 }
             }
             let mut allow_dns_recursion = false;
+            let mut allow_dhcp_leasequery = false;
             let mut allow_http = false;
             let mut allow_http_metrics = false;
             let mut allow_http_leases = false;
+            let mut allow_http_forcerenew = false;
+            let mut allow_tftp = false;
             for access in accesses {
                 match access.as_str() {
                     "dhcp-client" => {
                         allow_dns_recursion = true;
+                        allow_tftp = true;
                     }
                     "dns-recursion" => allow_dns_recursion = true,
+                    "dhcp-leasequery" => allow_dhcp_leasequery = true,
                     "http" => allow_http = true,
                     "http-metrics" => allow_http_metrics = true,
                     "http-leases" => allow_http_leases = true,
+                    "http-forcerenew" => allow_http_forcerenew = true,
+                    "tftp" => allow_tftp = true,
                     "http-ro" => {
                         allow_http = true;
                         allow_http_metrics = true;
@@ -272,9 +315,12 @@ pub(crate) fn parse_acl(name: &str, fragment: &yaml::Yaml) -> Result<Option<Acl>
                 unix,
                 permission: Permission {
                     allow_dns_recursion,
+                    allow_dhcp_leasequery,
                     allow_http,
                     allow_http_metrics,
                     allow_http_leases,
+                    allow_http_forcerenew,
+                    allow_tftp,
                 },
             }))
         }
@@ -297,9 +343,12 @@ fn acl_not_authenticated() {
         unix: None,
         permission: Permission {
             allow_dns_recursion: true,
+            allow_dhcp_leasequery: false,
             allow_http: false,
             allow_http_leases: false,
             allow_http_metrics: false,
+            allow_http_forcerenew: false,
+            allow_tftp: false,
         },
     }];
 
@@ -328,9 +377,12 @@ fn acl_not_authorized() {
         unix: None,
         permission: Permission {
             allow_dns_recursion: false,
+            allow_dhcp_leasequery: false,
             allow_http: false,
             allow_http_leases: false,
             allow_http_metrics: false,
+            allow_http_forcerenew: false,
+            allow_tftp: false,
         },
     }];
 
@@ -359,9 +411,12 @@ fn acl_allowed() {
         unix: None,
         permission: Permission {
             allow_dns_recursion: true,
+            allow_dhcp_leasequery: false,
             allow_http: false,
             allow_http_leases: false,
             allow_http_metrics: false,
+            allow_http_forcerenew: false,
+            allow_tftp: false,
         },
     }];
 
@@ -375,6 +430,15 @@ fn acl_allowed() {
         require_permission(&test_acls, &client, PermissionType::DnsRecursion),
         Ok(())
     );
+    assert!(any_has_permission(&test_acls, PermissionType::DnsRecursion));
+    assert!(!any_has_permission(
+        &test_acls,
+        PermissionType::DhcpLeaseQuery
+    ));
+    assert!(!any_has_permission(
+        &default_acls(&[]),
+        PermissionType::DhcpLeaseQuery
+    ));
 }
 
 #[test]
@@ -0,0 +1,55 @@
+/*   Copyright 2023 Perry Lorier
+ *
+ *  Licensed under the Apache License, Version 2.0 (the "License");
+ *  you may not use this file except in compliance with the License.
+ *  You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ *  Unless required by applicable law or agreed to in writing, software
+ *  distributed under the License is distributed on an "AS IS" BASIS,
+ *  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ *  See the License for the specific language governing permissions and
+ *  limitations under the License.
+ *
+ *  SPDX-License-Identifier: Apache-2.0
+ *
+ *  Imports leases from ISC dhcpd or dnsmasq into erbium's lease database.
+ *  This should be run once, before erbium is started for the first time.
+ */
+
+extern crate erbium;
+
+use erbium::dhcp::{import, pool};
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+    let args: Vec<_> = std::env::args_os().collect();
+    if args.len() < 3 {
+        println!(
+            "Usage: {} <configfile> <dhcpd.leases|dnsmasq.leases>...",
+            args[0].to_string_lossy()
+        );
+        return Ok(());
+    }
+    let config_file = std::path::Path::new(&args[1]);
+    let conf = erbium::config::load_config_from_path(config_file).await?;
+    let conf = conf.read().await;
+    let mut pool = pool::Pool::new(&conf)?;
+    for lease_file in &args[2..] {
+        let lease_file = std::path::Path::new(lease_file);
+        let content = std::fs::read_to_string(lease_file)
+            .map_err(|e| format!("Failed to read {}: {}", lease_file.display(), e))?;
+        let leases = import::parse(&content)
+            .map_err(|e| format!("Failed to parse {}: {}", lease_file.display(), e))?;
+        let summary = import::import(&mut pool, &conf, &leases)?;
+        println!(
+            "{}: imported {} leases, skipped {}",
+            lease_file.display(),
+            summary.imported,
+            summary.skipped
+        );
+    }
+    Ok(())
+}
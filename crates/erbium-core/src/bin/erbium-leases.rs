@@ -0,0 +1,65 @@
+/*   Copyright 2023 Perry Lorier
+ *
+ *  Licensed under the Apache License, Version 2.0 (the "License");
+ *  you may not use this file except in compliance with the License.
+ *  You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ *  Unless required by applicable law or agreed to in writing, software
+ *  distributed under the License is distributed on an "AS IS" BASIS,
+ *  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ *  See the License for the specific language governing permissions and
+ *  limitations under the License.
+ *
+ *  SPDX-License-Identifier: Apache-2.0
+ *
+ *  Backs up and restores the DHCP lease database.
+ */
+
+extern crate erbium;
+
+use erbium::dhcp::{backup, pool};
+
+fn usage(argv0: &std::ffi::OsStr) {
+    println!(
+        "Usage: {0} <configfile> export [json|csv]\n       {0} <configfile> restore <backupfile>",
+        argv0.to_string_lossy()
+    );
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args: Vec<_> = std::env::args_os().collect();
+    if args.len() < 3 {
+        usage(&args[0]);
+        return Ok(());
+    }
+    let config_file = std::path::Path::new(&args[1]);
+    let conf = erbium::config::load_config_from_path(config_file).await?;
+    let conf = conf.read().await;
+    match (
+        args[2].to_string_lossy().as_ref(),
+        args.get(3).map(|a| a.to_string_lossy()).as_deref(),
+    ) {
+        ("export", format @ (None | Some("json") | Some("csv"))) => {
+            let format = if format == Some("csv") {
+                backup::Format::Csv
+            } else {
+                backup::Format::Json
+            };
+            let mut pool = pool::Pool::new_read_only(&conf)?;
+            print!("{}", backup::export(&mut pool, format)?);
+        }
+        ("restore", Some(backup_file)) => {
+            let content = std::fs::read_to_string(backup_file)
+                .map_err(|e| format!("Failed to read {}: {}", backup_file, e))?;
+            let backup = backup::parse(&content)?;
+            let mut pool = pool::Pool::new(&conf)?;
+            let count = backup::restore(&mut pool, &backup)?;
+            println!("Restored {} leases", count);
+        }
+        _ => usage(&args[0]),
+    }
+    Ok(())
+}
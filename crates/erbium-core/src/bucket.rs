@@ -0,0 +1,161 @@
+/*   Copyright 2023 Perry Lorier
+ *
+ *  Licensed under the Apache License, Version 2.0 (the "License");
+ *  you may not use this file except in compliance with the License.
+ *  You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ *  Unless required by applicable law or agreed to in writing, software
+ *  distributed under the License is distributed on an "AS IS" BASIS,
+ *  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ *  See the License for the specific language governing permissions and
+ *  limitations under the License.
+ *
+ *  SPDX-License-Identifier: Apache-2.0
+ *
+ *  Token Bucket implementation, used to rate limit clients.
+ */
+
+/* The token bucket holds a timestamp of when the bucket last had 0 tokens remaining in it.
+ * The current contents of the bucket can be calculated by taking how long ago that was, and
+ * calculating how many tokens would have been deposited in the bucket since then.  Updating
+ * the bucket to deplete some tokens is handled similarly.
+ *
+ * Timestamps are in microseconds so that fast rates (eg thousands of packets per second) still
+ * refill smoothly.
+ */
+
+type Timestamp = u64;
+pub type RealTimeClock = std::time::SystemTime;
+
+pub trait Clock: Send + Sync + 'static {
+    /// Microseconds since some fixed point in time.
+    fn now() -> Timestamp;
+}
+
+impl Clock for RealTimeClock {
+    fn now() -> Timestamp {
+        RealTimeClock::now()
+            .duration_since(RealTimeClock::UNIX_EPOCH)
+            .unwrap()
+            .as_micros() as Timestamp
+    }
+}
+
+/// How quickly tokens are deposited in a bucket, and how many it can hold.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Rate {
+    pub tokens_per_second: u32,
+    pub max_tokens: u32,
+}
+
+impl Rate {
+    /// How long it takes for `tokens` to be deposited in the bucket.
+    const fn refill_time(&self, tokens: u32) -> Timestamp {
+        /* Round up, so we never allow slightly more than the configured rate */
+        let per_token = (1_000_000 as Timestamp).div_ceil(self.tokens_per_second as Timestamp);
+        per_token * tokens as Timestamp
+    }
+}
+
+pub struct GenericTokenBucket(Timestamp);
+
+impl GenericTokenBucket {
+    pub const fn new() -> Self {
+        Self(0)
+    }
+
+    // If the bucket is currently "over full", then cap it at the maximum fullness.
+    fn get_tokens_with_time(&self, rate: &Rate, now: Timestamp) -> Timestamp {
+        std::cmp::max(
+            self.0,
+            now.saturating_sub(rate.refill_time(rate.max_tokens)),
+        )
+    }
+
+    pub fn check_at(&self, rate: &Rate, tokens: u32, now: Timestamp) -> bool {
+        let empty_at = self.get_tokens_with_time(rate, now);
+        rate.refill_time(tokens) <= now.saturating_sub(empty_at)
+    }
+
+    pub fn check<T: Clock>(&self, rate: &Rate, tokens: u32) -> bool {
+        self.check_at(rate, tokens, T::now())
+    }
+
+    // Remove some tokens
+    pub fn deplete_at(&mut self, rate: &Rate, tokens: u32, now: Timestamp) {
+        self.0 = self.get_tokens_with_time(rate, now) + rate.refill_time(tokens);
+    }
+
+    pub fn deplete<T: Clock>(&mut self, rate: &Rate, tokens: u32) {
+        self.deplete_at(rate, tokens, T::now())
+    }
+
+    // Remove some tokens if there are enough, returning false if there weren't.
+    pub fn take_at(&mut self, rate: &Rate, tokens: u32, now: Timestamp) -> bool {
+        if self.check_at(rate, tokens, now) {
+            self.deplete_at(rate, tokens, now);
+            true
+        } else {
+            false
+        }
+    }
+
+    // Add some tokens (independent of the passage of time)
+    #[allow(dead_code)]
+    pub fn refill<T: Clock>(&mut self, rate: &Rate, tokens: u32) {
+        self.0 = self
+            .get_tokens_with_time(rate, T::now())
+            .saturating_sub(rate.refill_time(tokens));
+    }
+
+    // Empty a bucket, ie: make the bucket has no available tokens.
+    #[allow(dead_code)]
+    pub fn empty<T: Clock>(&mut self) {
+        self.0 = T::now();
+    }
+}
+
+impl Default for GenericTokenBucket {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[test]
+fn test_tokens() {
+    let rate = Rate {
+        tokens_per_second: 2,
+        max_tokens: 100,
+    };
+    let mut bucket = GenericTokenBucket::new();
+    bucket.empty::<RealTimeClock>();
+    bucket.refill::<RealTimeClock>(&rate, 20);
+    assert!(bucket.check::<RealTimeClock>(&rate, 10));
+    bucket.deplete::<RealTimeClock>(&rate, 40);
+    assert!(!bucket.check::<RealTimeClock>(&rate, 10));
+}
+
+#[test]
+fn test_take() {
+    let rate = Rate {
+        tokens_per_second: 3,
+        max_tokens: 3,
+    };
+    let mut bucket = GenericTokenBucket::new();
+    let now = 1_000_000_000;
+    /* A full bucket allows a burst of max_tokens */
+    for _ in 0..3 {
+        assert!(bucket.take_at(&rate, 1, now));
+    }
+    assert!(!bucket.take_at(&rate, 1, now));
+    /* A token takes a third of a second to come back */
+    assert!(!bucket.take_at(&rate, 1, now + 300_000));
+    assert!(bucket.take_at(&rate, 1, now + 400_000));
+    /* It never holds more than max_tokens */
+    for _ in 0..3 {
+        assert!(bucket.take_at(&rate, 1, now + 60_000_000));
+    }
+    assert!(!bucket.take_at(&rate, 1, now + 60_000_000));
+}
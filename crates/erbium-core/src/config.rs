@@ -773,10 +773,43 @@ impl Default for AddressType {
     }
 }
 
+/// Where DHCP leases are kept.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum LeaseStoreType {
+    #[default]
+    Sqlite,
+    AppendOnly,
+    Memory,
+}
+
+fn parse_lease_store(name: &str, fragment: &yaml::Yaml) -> Result<Option<LeaseStoreType>, Error> {
+    match parse_string(name, fragment)?.as_deref() {
+        None => Ok(None),
+        Some("sqlite") => Ok(Some(LeaseStoreType::Sqlite)),
+        Some("append-only") => Ok(Some(LeaseStoreType::AppendOnly)),
+        Some("memory") => Ok(Some(LeaseStoreType::Memory)),
+        Some(o) => Err(Error::InvalidConfig(format!(
+            "invalid {} {}, expected sqlite, append-only or memory",
+            name, o
+        ))),
+    }
+}
+
+/// systemd (and NixOS) tell us where to keep our state in $STATE_DIRECTORY, which may be a colon
+/// separated list if there are multiple.
+fn default_state_directory() -> std::path::PathBuf {
+    std::env::var_os("STATE_DIRECTORY")
+        .and_then(|dirs| std::env::split_paths(&dirs).next())
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .unwrap_or_else(|| "/var/lib/erbium".into())
+}
+
 #[derive(Debug, Default)]
 pub struct Config {
     #[cfg(feature = "dhcp")]
     pub dhcp: crate::dhcp::config::Config,
+    #[cfg(feature = "dhcp6")]
+    pub dhcp6: crate::dhcp6::config::Config,
     pub ra: crate::radv::config::Config,
     pub dns_servers: Vec<std::net::IpAddr>,
     pub dns_search: Vec<String>,
@@ -785,7 +818,11 @@ pub struct Config {
     pub listeners: Vec<NetAddr>,
     pub dns_listeners: AddressType,
     pub dns_routes: Vec<crate::dns::config::Route>,
+    #[cfg(feature = "tftp")]
+    pub tftp: Option<crate::tftp::config::Config>,
     pub acls: Vec<crate::acl::Acl>,
+    pub state_directory: std::path::PathBuf,
+    pub lease_store: LeaseStoreType,
 }
 
 pub type SharedConfig = std::sync::Arc<tokio::sync::RwLock<Config>>;
@@ -817,6 +854,24 @@ fn load_config_from_string(cfg: &str) -> Result<SharedConfig, Error> {
         let mut ra = None;
         #[cfg(feature = "dhcp")]
         let mut dhcp = None;
+        #[cfg(feature = "dhcp")]
+        let mut dhcp_decline_quarantine = None;
+        #[cfg(feature = "dhcp")]
+        let mut dhcp_conflict_detection = None;
+        #[cfg(feature = "dhcp")]
+        let mut dhcp_lease_retention = None;
+        #[cfg(feature = "dhcp")]
+        let mut dhcp_forcerenew_secret = None;
+        #[cfg(all(feature = "dhcp", feature = "dns"))]
+        let mut dhcp_dns_update = None;
+        #[cfg(feature = "dhcp")]
+        let mut dhcp_lease_hooks = None;
+        #[cfg(feature = "dhcp")]
+        let mut dhcp_failover = None;
+        #[cfg(feature = "dhcp")]
+        let mut dhcp_rate_limit = None;
+        #[cfg(feature = "dhcp6")]
+        let mut dhcp6 = None;
         #[cfg(feature = "dns")]
         let mut dns_servers = vec![INTERFACE4, INTERFACE6];
         #[cfg(not(feature = "dns"))]
@@ -827,8 +882,12 @@ fn load_config_from_string(cfg: &str) -> Result<SharedConfig, Error> {
         let mut listeners = None;
         let mut dns_listeners = None;
         let mut dns_routes = None;
+        #[cfg(feature = "tftp")]
+        let mut tftp = None;
         let mut default_listen_style = DefaultAddressType::Unspecified;
         let mut acls = None;
+        let mut state_directory = None;
+        let mut lease_store = None;
         for (k, v) in fragment {
             match (k.as_str(), v) {
                 (Some("dhcp"), _) => return Err(Error::InvalidConfig("The dhcp section has been replaced with dhcp-policies section, please see the manpage for more details".into())),
@@ -837,6 +896,59 @@ fn load_config_from_string(cfg: &str) -> Result<SharedConfig, Error> {
                     .map_err(|e| e.annotate("while parsing dhcp-policies"))?,
                 #[cfg(not(feature = "dhcp"))]
                 (Some("dhcp-policies"), _) => (),
+                #[cfg(feature = "dhcp")]
+                (Some("dhcp-decline-quarantine"), d) => {
+                    dhcp_decline_quarantine = parse_duration("dhcp-decline-quarantine", d)?;
+                }
+                #[cfg(not(feature = "dhcp"))]
+                (Some("dhcp-decline-quarantine"), _) => (),
+                #[cfg(feature = "dhcp")]
+                (Some("dhcp-conflict-detection"), d) => {
+                    dhcp_conflict_detection = parse_duration("dhcp-conflict-detection", d)?;
+                }
+                #[cfg(not(feature = "dhcp"))]
+                (Some("dhcp-conflict-detection"), _) => (),
+                #[cfg(feature = "dhcp")]
+                (Some("dhcp-lease-retention"), d) => {
+                    dhcp_lease_retention = parse_duration("dhcp-lease-retention", d)?;
+                }
+                #[cfg(not(feature = "dhcp"))]
+                (Some("dhcp-lease-retention"), _) => (),
+                #[cfg(feature = "dhcp")]
+                (Some("dhcp-forcerenew-secret"), d) => {
+                    dhcp_forcerenew_secret = parse_string("dhcp-forcerenew-secret", d)?;
+                }
+                #[cfg(not(feature = "dhcp"))]
+                (Some("dhcp-forcerenew-secret"), _) => (),
+                #[cfg(all(feature = "dhcp", feature = "dns"))]
+                (Some("dhcp-dns-update"), d) => {
+                    dhcp_dns_update = crate::dhcp::ddns::parse("dhcp-dns-update", d)?;
+                }
+                #[cfg(not(all(feature = "dhcp", feature = "dns")))]
+                (Some("dhcp-dns-update"), _) => (),
+                #[cfg(feature = "dhcp")]
+                (Some("dhcp-lease-hooks"), d) => {
+                    dhcp_lease_hooks = crate::dhcp::hooks::parse("dhcp-lease-hooks", d)?;
+                }
+                #[cfg(not(feature = "dhcp"))]
+                (Some("dhcp-lease-hooks"), _) => (),
+                #[cfg(feature = "dhcp")]
+                (Some("dhcp-failover"), d) => {
+                    dhcp_failover = crate::dhcp::failover::parse("dhcp-failover", d)?;
+                }
+                #[cfg(not(feature = "dhcp"))]
+                (Some("dhcp-failover"), _) => (),
+                #[cfg(feature = "dhcp")]
+                (Some("dhcp-rate-limit"), d) => {
+                    dhcp_rate_limit = Some(crate::dhcp::ratelimit::parse("dhcp-rate-limit", d)?);
+                }
+                #[cfg(not(feature = "dhcp"))]
+                (Some("dhcp-rate-limit"), _) => (),
+                #[cfg(feature = "dhcp6")]
+                (Some("dhcp6-policies"), d) => dhcp6 = crate::dhcp6::config::Config::new(d)
+                    .map_err(|e| e.annotate("while parsing dhcp6-policies"))?,
+                #[cfg(not(feature = "dhcp6"))]
+                (Some("dhcp6-policies"), _) => (),
                 (Some("router-advertisements"), r) => ra = crate::radv::config::parse(r)
                     .map_err(|e| e.annotate("while parsing router-advertisements"))?,
                 (Some("dns-servers"), s) => {
@@ -875,9 +987,21 @@ fn load_config_from_string(cfg: &str) -> Result<SharedConfig, Error> {
                 (Some("acls"), s) => {
                     acls = parse_array("acls", s, crate::acl::parse_acl)?;
                 }
+                (Some("state-directory"), s) => {
+                    state_directory = parse_string("state-directory", s)?.map(std::path::PathBuf::from);
+                }
+                (Some("lease-store"), s) => {
+                    lease_store = parse_lease_store("lease-store", s)?;
+                }
                 (Some("dns-routes"), s) => {
                     dns_routes = crate::dns::config::parse_dns_routes("dns-routes", s)?;
                 }
+                #[cfg(feature = "tftp")]
+                (Some("tftp"), t) => {
+                    tftp = crate::tftp::config::parse("tftp", t)?;
+                }
+                #[cfg(not(feature = "tftp"))]
+                (Some("tftp"), _) => (),
                 (Some(x), _) => {
                     return Err(Error::InvalidConfig(format!(
                         "Unknown configuration option {}",
@@ -895,7 +1019,20 @@ fn load_config_from_string(cfg: &str) -> Result<SharedConfig, Error> {
         let addresses = addresses.unwrap_or_default();
         let conf = Config {
             #[cfg(feature = "dhcp")]
-            dhcp: dhcp.unwrap_or_default(),
+            dhcp: crate::dhcp::config::Config {
+                decline_quarantine: dhcp_decline_quarantine,
+                conflict_detection: dhcp_conflict_detection,
+                lease_retention: dhcp_lease_retention,
+                forcerenew_secret: dhcp_forcerenew_secret.map(String::into_bytes),
+                #[cfg(feature = "dns")]
+                dns_update: dhcp_dns_update,
+                lease_hooks: dhcp_lease_hooks.unwrap_or_default(),
+                failover: dhcp_failover,
+                rate_limit: dhcp_rate_limit.unwrap_or_default(),
+                ..dhcp.unwrap_or_default()
+            },
+            #[cfg(feature = "dhcp6")]
+            dhcp6: dhcp6.unwrap_or_default(),
             ra: ra.unwrap_or_default(),
             dns_servers,
             dns_search,
@@ -912,6 +1049,14 @@ fn load_config_from_string(cfg: &str) -> Result<SharedConfig, Error> {
                 DefaultAddressType::Interface => AddressType::BindInterface,
             }),
             dns_routes: dns_routes.unwrap_or_default(),
+            #[cfg(feature = "tftp")]
+            tftp: tftp.map(|tftp| crate::tftp::config::Config {
+                listeners: tftp.listeners.or(match default_listen_style {
+                    DefaultAddressType::Unspecified => None,
+                    DefaultAddressType::Interface => Some(AddressType::BindInterface),
+                }),
+                ..tftp
+            }),
             captive_portal,
             listeners: listeners.unwrap_or_else(|| {
                 vec![UnixAddr::new("/var/lib/erbium/control")
@@ -919,6 +1064,8 @@ fn load_config_from_string(cfg: &str) -> Result<SharedConfig, Error> {
                     .to_net_addr()]
             }),
             acls: acls.unwrap_or_else(|| crate::acl::default_acls(&addresses)),
+            state_directory: state_directory.unwrap_or_else(default_state_directory),
+            lease_store: lease_store.unwrap_or_default(),
             addresses,
         };
         Ok(std::sync::Arc::new(tokio::sync::RwLock::new(conf)))
@@ -1041,6 +1188,56 @@ dns-listeners: [192.0.2.0:53]
     Ok(())
 }
 
+#[cfg(feature = "dhcp")]
+#[tokio::test]
+async fn test_decline_quarantine_parse() -> Result<(), Error> {
+    let conf = load_config_from_string(
+        "---
+dhcp-decline-quarantine: 5m
+dhcp-conflict-detection: 2
+dhcp-lease-retention: 7d
+dhcp-forcerenew-secret: hunter2
+dhcp-policies:
+  - match-subnet: 192.0.2.0/24
+    apply-subnet: 192.0.2.0/24
+",
+    )?;
+    let conf = conf.read().await;
+    assert_eq!(
+        conf.dhcp.decline_quarantine,
+        Some(std::time::Duration::from_secs(300))
+    );
+    assert_eq!(
+        conf.dhcp.conflict_detection,
+        Some(std::time::Duration::from_secs(2))
+    );
+    assert_eq!(
+        conf.dhcp.lease_retention,
+        Some(std::time::Duration::from_secs(7 * 86400))
+    );
+    assert_eq!(conf.dhcp.forcerenew_secret, Some(b"hunter2".to_vec()));
+    assert_eq!(conf.dhcp.policies.len(), 1);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_lease_store_parse() -> Result<(), Error> {
+    let conf = load_config_from_string(
+        "---
+state-directory: /run/erbium-test
+lease-store: append-only
+",
+    )?;
+    let conf = conf.read().await;
+    assert_eq!(
+        conf.state_directory,
+        std::path::PathBuf::from("/run/erbium-test")
+    );
+    assert_eq!(conf.lease_store, LeaseStoreType::AppendOnly);
+    assert!(load_config_from_string("lease-store: flatfile").is_err());
+    Ok(())
+}
+
 #[test]
 fn test_duration() {
     assert_eq!(
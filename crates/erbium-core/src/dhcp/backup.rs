@@ -0,0 +1,486 @@
+/*   Copyright 2023 Perry Lorier
+ *
+ *  Licensed under the Apache License, Version 2.0 (the "License");
+ *  you may not use this file except in compliance with the License.
+ *  You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ *  Unless required by applicable law or agreed to in writing, software
+ *  distributed under the License is distributed on an "AS IS" BASIS,
+ *  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ *  See the License for the specific language governing permissions and
+ *  limitations under the License.
+ *
+ *  SPDX-License-Identifier: Apache-2.0
+ *
+ *  Backing up and restoring the lease database.
+ *
+ *  Backups contain every lease, including the raw options, in JSON or CSV.  Binary fields are
+ *  hex, with "-" for empty, the same as the append only lease store.  Each backup records the
+ *  schema version of the store it was taken from, and we refuse to restore a backup from a newer
+ *  version of erbium.
+ */
+
+use super::pool::{self, LeaseInfo};
+use super::store::{decode_hex, encode_hex};
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Error {
+    Parse(String),
+    /// The backup's schema version, and the newest version we support.
+    UnsupportedVersion(u32, u32),
+    Pool(pool::Error),
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Parse(msg) => write!(f, "Failed to parse backup: {}", msg),
+            Error::UnsupportedVersion(backup, current) => write!(
+                f,
+                "Backup has schema version {} which is newer than {}, the newest supported version",
+                backup, current
+            ),
+            Error::Pool(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    Json,
+    Csv,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct Backup {
+    pub schema_version: u32,
+    pub leases: Vec<LeaseInfo>,
+}
+
+const CSV_HEADER: &str = "ip,client_id,start,expire,options,chaddr";
+/// Backups from before the hardware address was recorded.
+const OLD_CSV_HEADER: &str = "ip,client_id,start,expire,options";
+const CSV_VERSION_PREFIX: &str = "# schema_version=";
+
+/// Dumps every lease in the pool.
+pub fn export(pool: &mut pool::Pool, format: Format) -> Result<String, Error> {
+    let schema_version = pool.schema_version().map_err(Error::Pool)?;
+    let mut leases = pool.get_leases().map_err(Error::Pool)?;
+    leases.sort();
+    Ok(match format {
+        Format::Json => format!(
+            "{{\n \"schema_version\": {},\n \"leases\": [\n{}\n ]\n}}\n",
+            schema_version,
+            leases
+                .iter()
+                .map(|li| format!(
+                    "  {{ \"ip\": \"{}\", \"client_id\": \"{}\", \"start\": {}, \"expire\": {}, \"options\": \"{}\", \"chaddr\": \"{}\" }}",
+                    li.ip,
+                    encode_hex(&li.client_id),
+                    li.start,
+                    li.expire,
+                    encode_hex(&li.options),
+                    encode_hex(&li.chaddr)
+                ))
+                .collect::<Vec<_>>()
+                .join(",\n")
+        ),
+        Format::Csv => {
+            let mut ret = format!("{}{}\n{}\n", CSV_VERSION_PREFIX, schema_version, CSV_HEADER);
+            for li in &leases {
+                ret.push_str(&format!(
+                    "{},{},{},{},{},{}\n",
+                    li.ip,
+                    encode_hex(&li.client_id),
+                    li.start,
+                    li.expire,
+                    encode_hex(&li.options),
+                    encode_hex(&li.chaddr)
+                ));
+            }
+            ret
+        }
+    })
+}
+
+/// Adds every lease in the backup to the pool, replacing any existing lease for the same
+/// address.  Returns how many leases were restored.
+pub fn restore(pool: &mut pool::Pool, backup: &Backup) -> Result<usize, Error> {
+    let current = pool.schema_version().map_err(Error::Pool)?;
+    if backup.schema_version > current {
+        return Err(Error::UnsupportedVersion(backup.schema_version, current));
+    }
+    for lease in &backup.leases {
+        pool.restore_lease(lease.clone()).map_err(Error::Pool)?;
+    }
+    Ok(backup.leases.len())
+}
+
+/// Parses a backup made by [`export`], in either format.
+pub fn parse(content: &str) -> Result<Backup, Error> {
+    if content.trim_start().starts_with('{') {
+        parse_json(content)
+    } else {
+        parse_csv(content)
+    }
+}
+
+fn parse_field<T: std::str::FromStr>(name: &str, value: &str) -> Result<T, Error> {
+    value
+        .parse()
+        .map_err(|_| Error::Parse(format!("Invalid {} '{}'", name, value)))
+}
+
+fn parse_hex_field(name: &str, value: &str) -> Result<Vec<u8>, Error> {
+    decode_hex(value).ok_or_else(|| Error::Parse(format!("Invalid {} '{}'", name, value)))
+}
+
+/* ---- CSV ---- */
+
+fn parse_csv(content: &str) -> Result<Backup, Error> {
+    let mut schema_version = None;
+    let mut leases = vec![];
+    for (lineno, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if let Some(version) = line.strip_prefix(CSV_VERSION_PREFIX) {
+            schema_version = Some(parse_field("schema_version", version)?);
+            continue;
+        }
+        if line.is_empty() || line.starts_with('#') || line == CSV_HEADER || line == OLD_CSV_HEADER
+        {
+            continue;
+        }
+        let fields = line.split(',').collect::<Vec<_>>();
+        match fields.as_slice() {
+            [ip, client_id, start, expire, options, chaddr @ ..] if chaddr.len() <= 1 => leases
+                .push(LeaseInfo {
+                    ip: parse_field("ip", ip)?,
+                    client_id: parse_hex_field("client_id", client_id)?,
+                    chaddr: chaddr
+                        .first()
+                        .map(|chaddr| parse_hex_field("chaddr", chaddr))
+                        .transpose()?
+                        .unwrap_or_default(),
+                    start: parse_field("start", start)?,
+                    expire: parse_field("expire", expire)?,
+                    options: parse_hex_field("options", options)?,
+                }),
+            _ => {
+                return Err(Error::Parse(format!(
+                    "line {}: Expected 6 fields, got {}",
+                    lineno + 1,
+                    fields.len()
+                )))
+            }
+        }
+    }
+    Ok(Backup {
+        schema_version: schema_version
+            .ok_or_else(|| Error::Parse("Missing schema_version".into()))?,
+        leases,
+    })
+}
+
+/* ---- JSON ---- */
+
+/// Just enough JSON to read back what [`export`] writes.
+#[derive(Debug, PartialEq)]
+enum Json {
+    Null,
+    Bool(bool),
+    Number(String),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+struct JsonParser<'a> {
+    chars: std::iter::Peekable<std::str::Chars<'a>>,
+}
+
+impl<'a> JsonParser<'a> {
+    fn skip_whitespace(&mut self) {
+        while self.chars.next_if(|c| c.is_whitespace()).is_some() {}
+    }
+
+    fn expect(&mut self, expected: char) -> Result<(), Error> {
+        self.skip_whitespace();
+        match self.chars.next() {
+            Some(c) if c == expected => Ok(()),
+            Some(c) => Err(Error::Parse(format!(
+                "Expected '{}', found '{}'",
+                expected, c
+            ))),
+            None => Err(Error::Parse(format!(
+                "Expected '{}', found end of file",
+                expected
+            ))),
+        }
+    }
+
+    fn string(&mut self) -> Result<String, Error> {
+        self.expect('"')?;
+        let mut ret = String::new();
+        loop {
+            match self.chars.next() {
+                Some('"') => return Ok(ret),
+                Some('\\') => match self.chars.next() {
+                    Some('n') => ret.push('\n'),
+                    Some('r') => ret.push('\r'),
+                    Some('t') => ret.push('\t'),
+                    Some('b') => ret.push('\u{8}'),
+                    Some('f') => ret.push('\u{c}'),
+                    Some('u') => {
+                        let hex = (0..4).filter_map(|_| self.chars.next()).collect::<String>();
+                        ret.push(
+                            u32::from_str_radix(&hex, 16)
+                                .ok()
+                                .and_then(char::from_u32)
+                                .ok_or_else(|| {
+                                    Error::Parse(format!("Invalid escape '\\u{}'", hex))
+                                })?,
+                        );
+                    }
+                    Some(c) => ret.push(c),
+                    None => return Err(Error::Parse("Unterminated string".into())),
+                },
+                Some(c) => ret.push(c),
+                None => return Err(Error::Parse("Unterminated string".into())),
+            }
+        }
+    }
+
+    fn value(&mut self) -> Result<Json, Error> {
+        self.skip_whitespace();
+        match self.chars.peek() {
+            Some('{') => {
+                self.chars.next();
+                let mut members = vec![];
+                self.skip_whitespace();
+                if self.chars.next_if_eq(&'}').is_some() {
+                    return Ok(Json::Object(members));
+                }
+                loop {
+                    let key = self.string()?;
+                    self.expect(':')?;
+                    members.push((key, self.value()?));
+                    self.skip_whitespace();
+                    match self.chars.next() {
+                        Some(',') => (),
+                        Some('}') => return Ok(Json::Object(members)),
+                        _ => return Err(Error::Parse("Expected ',' or '}'".into())),
+                    }
+                }
+            }
+            Some('[') => {
+                self.chars.next();
+                let mut elements = vec![];
+                self.skip_whitespace();
+                if self.chars.next_if_eq(&']').is_some() {
+                    return Ok(Json::Array(elements));
+                }
+                loop {
+                    elements.push(self.value()?);
+                    self.skip_whitespace();
+                    match self.chars.next() {
+                        Some(',') => (),
+                        Some(']') => return Ok(Json::Array(elements)),
+                        _ => return Err(Error::Parse("Expected ',' or ']'".into())),
+                    }
+                }
+            }
+            Some('"') => Ok(Json::String(self.string()?)),
+            Some(_) => {
+                let mut word = String::new();
+                while let Some(c) = self
+                    .chars
+                    .next_if(|c| c.is_ascii_alphanumeric() || "+-.".contains(*c))
+                {
+                    word.push(c);
+                }
+                match word.as_str() {
+                    "null" => Ok(Json::Null),
+                    "true" => Ok(Json::Bool(true)),
+                    "false" => Ok(Json::Bool(false)),
+                    n if n.starts_with(|c: char| c == '-' || c.is_ascii_digit()) => {
+                        Ok(Json::Number(word))
+                    }
+                    _ => Err(Error::Parse(format!("Unexpected '{}'", word))),
+                }
+            }
+            None => Err(Error::Parse("Unexpected end of file".into())),
+        }
+    }
+}
+
+impl Json {
+    fn get(&self, key: &str) -> Result<&Json, Error> {
+        match self {
+            Json::Object(members) => members
+                .iter()
+                .find(|(k, _)| k == key)
+                .map(|(_, v)| v)
+                .ok_or_else(|| Error::Parse(format!("Missing {}", key))),
+            _ => Err(Error::Parse(format!(
+                "Expected an object containing {}",
+                key
+            ))),
+        }
+    }
+
+    fn get_number<T: std::str::FromStr>(&self, key: &str) -> Result<T, Error> {
+        match self.get(key)? {
+            Json::Number(n) => parse_field(key, n),
+            _ => Err(Error::Parse(format!("Expected {} to be a number", key))),
+        }
+    }
+
+    fn get_string(&self, key: &str) -> Result<&str, Error> {
+        match self.get(key)? {
+            Json::String(s) => Ok(s),
+            _ => Err(Error::Parse(format!("Expected {} to be a string", key))),
+        }
+    }
+}
+
+fn parse_json(content: &str) -> Result<Backup, Error> {
+    let mut parser = JsonParser {
+        chars: content.chars().peekable(),
+    };
+    let json = parser.value()?;
+    parser.skip_whitespace();
+    if parser.chars.next().is_some() {
+        return Err(Error::Parse("Trailing data after backup".into()));
+    }
+    let leases = match json.get("leases")? {
+        Json::Array(leases) => leases
+            .iter()
+            .map(|lease| {
+                Ok(LeaseInfo {
+                    ip: parse_field("ip", lease.get_string("ip")?)?,
+                    client_id: parse_hex_field("client_id", lease.get_string("client_id")?)?,
+                    /* Not in backups from older versions */
+                    chaddr: match lease.get("chaddr") {
+                        Ok(_) => parse_hex_field("chaddr", lease.get_string("chaddr")?)?,
+                        Err(_) => vec![],
+                    },
+                    start: lease.get_number("start")?,
+                    expire: lease.get_number("expire")?,
+                    options: parse_hex_field("options", lease.get_string("options")?)?,
+                })
+            })
+            .collect::<Result<Vec<_>, Error>>()?,
+        _ => return Err(Error::Parse("Expected leases to be an array".into())),
+    };
+    Ok(Backup {
+        schema_version: json.get_number("schema_version")?,
+        leases,
+    })
+}
+
+#[cfg(test)]
+fn mk_pool() -> pool::Pool {
+    let mut pool = pool::Pool::new_with_store(Box::new(
+        super::store::SqliteStore::new_in_memory().unwrap(),
+    ));
+    for (ip, client_id, chaddr, options) in [
+        (
+            "192.0.2.1",
+            &b"one"[..],
+            &[0x00, 0x00, 0x5E, 0x00, 0x53, 0x00][..],
+            &[12, 1, b'a', 255][..],
+        ),
+        /* Quarantined */
+        ("192.0.2.2", &b""[..], &b""[..], &b""[..]),
+    ] {
+        pool.restore_lease(LeaseInfo {
+            ip: ip.parse().unwrap(),
+            client_id: client_id.to_vec(),
+            chaddr: chaddr.to_vec(),
+            start: 100,
+            expire: 200,
+            options: options.to_vec(),
+        })
+        .unwrap();
+    }
+    pool
+}
+
+#[test]
+fn test_round_trip() {
+    for format in [Format::Json, Format::Csv] {
+        let mut pool = mk_pool();
+        let backup = parse(&export(&mut pool, format).unwrap()).unwrap();
+        assert_eq!(backup.schema_version, super::store::SCHEMA_VERSION);
+        assert_eq!(backup.leases, pool.get_leases().unwrap());
+
+        let mut restored = pool::Pool::new_in_memory().unwrap();
+        assert_eq!(restore(&mut restored, &backup).unwrap(), 2);
+        let mut leases = restored.get_leases().unwrap();
+        leases.sort();
+        assert_eq!(leases, backup.leases);
+    }
+}
+
+#[test]
+fn test_parse_json() {
+    let backup = parse(
+        r#"{"leases": [{"ip": "192.0.2.1", "client_id": "6f6e65", "start": 100,
+            "expire": 200, "options": "-", "host-name": "a \"b\"!"}],
+            "schema_version": 1}"#,
+    )
+    .unwrap();
+    assert_eq!(backup.schema_version, 1);
+    assert_eq!(backup.leases[0].client_id, b"one");
+    assert_eq!(backup.leases[0].options, b"");
+    /* Backups from before the hardware address was recorded */
+    assert_eq!(backup.leases[0].chaddr, b"");
+    assert!(matches!(parse("{\"leases\": []}"), Err(Error::Parse(_))));
+    assert!(matches!(
+        parse("{\"schema_version\": 1, \"leases\": [{\"ip\": \"192.0.2.1\"}]}"),
+        Err(Error::Parse(_))
+    ));
+    assert!(matches!(
+        parse("{\"schema_version\": 1, \"leases\": []} x"),
+        Err(Error::Parse(_))
+    ));
+}
+
+#[test]
+fn test_parse_csv() {
+    /* Backups from before the hardware address was recorded */
+    let backup = parse(
+        "# schema_version=1\nip,client_id,start,expire,options\n192.0.2.1,6f6e65,100,200,-\n",
+    )
+    .unwrap();
+    assert_eq!(backup.leases[0].client_id, b"one");
+    assert_eq!(backup.leases[0].chaddr, b"");
+    assert!(matches!(
+        parse("ip,client_id,start,expire,options\n"),
+        Err(Error::Parse(_))
+    ));
+    assert!(matches!(
+        parse("# schema_version=1\n192.0.2.1,zz,100,200,-\n"),
+        Err(Error::Parse(_))
+    ));
+}
+
+#[test]
+fn test_restore_newer_version() {
+    let mut pool = mk_pool();
+    let backup = Backup {
+        schema_version: super::store::SCHEMA_VERSION + 1,
+        leases: vec![],
+    };
+    assert_eq!(
+        restore(&mut pool, &backup),
+        Err(Error::UnsupportedVersion(
+            super::store::SCHEMA_VERSION + 1,
+            super::store::SCHEMA_VERSION
+        ))
+    );
+}
@@ -23,17 +23,55 @@ use yaml_rust::yaml;
 
 pub use crate::config::*;
 
+/// How a match-* value is compared against what the client sent.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StringMatch {
+    Exact(Vec<u8>),
+    Prefix(Vec<u8>),
+    Substring(Vec<u8>),
+}
+
+impl StringMatch {
+    pub fn matches(&self, value: &[u8]) -> bool {
+        match self {
+            StringMatch::Exact(s) => value == s.as_slice(),
+            StringMatch::Prefix(s) => value.starts_with(s),
+            StringMatch::Substring(s) => {
+                s.is_empty() || value.windows(s.len()).any(|w| w == s.as_slice())
+            }
+        }
+    }
+}
+
 #[derive(Debug, Default)]
 pub struct Policy {
     pub match_all: bool,
     pub match_interface: Option<Option<String>>,
     pub match_chaddr: Option<Vec<u8>>,
     pub match_subnet: Option<erbium_net::Ipv4Subnet>,
+    pub match_circuit_id: Option<Vec<u8>>,
+    pub match_remote_id: Option<Vec<u8>>,
+    pub match_vendor_class: Option<StringMatch>,
+    pub match_user_class: Option<StringMatch>,
+    /// Matched against the parameter request list fingerprint, with a comma either side.
+    pub match_parameter_list: Option<StringMatch>,
     pub match_other:
         std::collections::HashMap<dhcppkt::DhcpOption, Option<dhcppkt::DhcpOptionTypeValue>>,
     pub apply_address: Option<super::pool::PoolAddresses>,
+    /// The apply-range and apply-subnet blocks apply_address was built from, for reporting.
+    pub apply_ranges: Vec<super::pool::AddressRange>,
     pub apply_default_lease: Option<std::time::Duration>,
     pub apply_max_lease: Option<std::time::Duration>,
+    pub apply_next_server: Option<std::net::Ipv4Addr>,
+    pub apply_boot_file: Option<String>,
+    /// Ignore the client completely.  Subpolicies can set this back to false.
+    pub apply_deny: Option<bool>,
+    /// NAK any request from the client, and don't make offers to it.
+    pub apply_nak: Option<bool>,
+    /// Answer a DHCPDISCOVER with the Rapid Commit option (RFC4039) with an immediate DHCPACK.
+    pub apply_rapid_commit: Option<bool>,
+    /// Tell clients that ask for it that they can go without IPv4 (RFC8925), and for how long.
+    pub apply_ipv6_only_preferred: Option<std::time::Duration>,
     pub apply_other:
         std::collections::HashMap<dhcppkt::DhcpOption, Option<dhcppkt::DhcpOptionTypeValue>>,
     pub policies: Vec<Policy>,
@@ -47,8 +85,15 @@ impl Clone for Policy {
             address_cache: Default::default(),
             match_interface: self.match_interface.clone(),
             match_chaddr: self.match_chaddr.clone(),
+            match_circuit_id: self.match_circuit_id.clone(),
+            match_remote_id: self.match_remote_id.clone(),
+            match_vendor_class: self.match_vendor_class.clone(),
+            match_user_class: self.match_user_class.clone(),
+            match_parameter_list: self.match_parameter_list.clone(),
             match_other: self.match_other.clone(),
             apply_address: self.apply_address.clone(),
+            apply_ranges: self.apply_ranges.clone(),
+            apply_boot_file: self.apply_boot_file.clone(),
             apply_other: self.apply_other.clone(),
             policies: self.policies.clone(),
             ..*self
@@ -78,6 +123,25 @@ impl Policy {
 #[derive(Debug, Default)]
 pub struct Config {
     pub policies: Vec<Policy>,
+    /// How long an address is withheld from allocation after a client DHCPDECLINEs it.
+    pub decline_quarantine: Option<std::time::Duration>,
+    /// How long to wait for a reply to an ARP probe before offering an address that hasn't been
+    /// leased recently.  Probing is disabled if this is unset or zero.
+    pub conflict_detection: Option<std::time::Duration>,
+    /// How long to remember a lease after it has expired.
+    pub lease_retention: Option<std::time::Duration>,
+    /// The secret that the nonces used to authenticate DHCPFORCERENEW messages are derived from.
+    /// DHCPFORCERENEW is disabled without one.
+    pub forcerenew_secret: Option<Vec<u8>>,
+    /// Where to send dynamic DNS updates for leases, if anywhere.
+    #[cfg(feature = "dns")]
+    pub dns_update: Option<super::ddns::Config>,
+    /// Programs to run or URLs to notify when leases change.
+    pub lease_hooks: Vec<super::hooks::Hook>,
+    /// The other server to share leases with, if any.
+    pub failover: Option<super::failover::Config>,
+    /// How many packets each client, and each interface, may send us.
+    pub rate_limit: super::ratelimit::Config,
 }
 
 impl Config {
@@ -271,6 +335,23 @@ impl Config {
                         })
                         .transpose()?
                         .map(DhcpOptionTypeValue::U16),
+                    /* A single number is accepted as a list of one */
+                    Some(DhcpOptionType::U16List) => {
+                        let parse_u16 = |_: &str, value: &yaml::Yaml| {
+                            Config::parse_number(value)?
+                                .map(|i| {
+                                    u16::try_from(i).map_err(|_| {
+                                        Error::InvalidConfig(format!("Integer {} out of range", i))
+                                    })
+                                })
+                                .transpose()
+                        };
+                        match value {
+                            yaml::Yaml::Array(_) => parse_array(name, value, parse_u16)?,
+                            _ => parse_u16(name, value)?.map(|i| vec![i]),
+                        }
+                        .map(DhcpOptionTypeValue::U16List)
+                    }
                     Some(DhcpOptionType::U32) => Config::parse_number(value)?
                         .map(|i| {
                             u32::try_from(i).map_err(|_| {
@@ -330,6 +411,50 @@ impl Config {
         }
     }
 
+    /// Parses match-foo, match-foo-prefix and match-foo-substring, where `base` is match-foo.
+    fn parse_string_match(
+        field: &mut Option<StringMatch>,
+        base: &str,
+        key: &str,
+        fragment: &yaml::Yaml,
+    ) -> Result<(), Error> {
+        if field.is_some() {
+            return Err(Error::InvalidConfig(format!("{} specified twice", base)));
+        }
+        let value = parse_string(key, fragment)
+            .map_err(|x| x.annotate(&format!("Failed to parse {}", key)))?
+            .ok_or_else(|| Error::InvalidConfig(format!("{} cannot be nil", key)))?
+            .into_bytes();
+        *field = Some(match &key[base.len()..] {
+            "-prefix" => StringMatch::Prefix(value),
+            "-substring" => StringMatch::Substring(value),
+            _ => StringMatch::Exact(value),
+        });
+        Ok(())
+    }
+
+    fn normalise_fingerprint(key: &str, fp: &[u8]) -> Result<Vec<u8>, Error> {
+        let options = String::from_utf8_lossy(fp)
+            .split(',')
+            .map(|o| o.trim().parse::<u8>())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| {
+                Error::InvalidConfig(format!(
+                    "{} should be a comma separated list of option numbers",
+                    key
+                ))
+            })?;
+        Ok(format!(
+            ",{},",
+            options
+                .iter()
+                .map(|o| o.to_string())
+                .collect::<Vec<_>>()
+                .join(",")
+        )
+        .into_bytes())
+    }
+
     fn parse_policy(fragment: &yaml::Yaml) -> Result<Policy, Error> {
         if let Some(h) = fragment.as_hash() {
             let mut policy: Policy = Default::default();
@@ -365,6 +490,71 @@ impl Config {
                                 })?,
                         );
                     }
+                    Some("match-circuit-id") => {
+                        policy.match_circuit_id = Some(
+                            parse_string("match-circuit-id", v)
+                                .map_err(|x| x.annotate("Failed to parse match-circuit-id"))?
+                                .ok_or_else(|| {
+                                    Error::InvalidConfig("match-circuit-id cannot be nil".into())
+                                })?
+                                .into_bytes(),
+                        );
+                    }
+                    Some("match-remote-id") => {
+                        policy.match_remote_id = Some(
+                            parse_string("match-remote-id", v)
+                                .map_err(|x| x.annotate("Failed to parse match-remote-id"))?
+                                .ok_or_else(|| {
+                                    Error::InvalidConfig("match-remote-id cannot be nil".into())
+                                })?
+                                .into_bytes(),
+                        );
+                    }
+                    Some(
+                        k @ ("match-vendor-class"
+                        | "match-vendor-class-prefix"
+                        | "match-vendor-class-substring"),
+                    ) => {
+                        Self::parse_string_match(
+                            &mut policy.match_vendor_class,
+                            "match-vendor-class",
+                            k,
+                            v,
+                        )?;
+                    }
+                    Some(
+                        k @ ("match-user-class"
+                        | "match-user-class-prefix"
+                        | "match-user-class-substring"),
+                    ) => {
+                        Self::parse_string_match(
+                            &mut policy.match_user_class,
+                            "match-user-class",
+                            k,
+                            v,
+                        )?;
+                    }
+                    Some(
+                        k @ ("match-parameter-list"
+                        | "match-parameter-list-prefix"
+                        | "match-parameter-list-substring"),
+                    ) => {
+                        Self::parse_string_match(
+                            &mut policy.match_parameter_list,
+                            "match-parameter-list",
+                            k,
+                            v,
+                        )?;
+                        /* Normalise to the same format as the fingerprint, wrapped in commas */
+                        if let Some(
+                            StringMatch::Exact(fp)
+                            | StringMatch::Prefix(fp)
+                            | StringMatch::Substring(fp),
+                        ) = &mut policy.match_parameter_list
+                        {
+                            *fp = Self::normalise_fingerprint(k, fp)?;
+                        }
+                    }
                     Some(x) if x.starts_with("match-") => {
                         let name = &x[6..];
                         let (opt, value) = Config::parse_generic(name, v)
@@ -399,6 +589,64 @@ impl Config {
                                 })?,
                         );
                     }
+                    Some("apply-next-server") => {
+                        policy.apply_next_server = Some(
+                            parse_string_ip4("apply-next-server", v)
+                                .map_err(|x| x.annotate("Failed to parse apply-next-server"))?
+                                .ok_or_else(|| {
+                                    Error::InvalidConfig("apply-next-server cannot be nil".into())
+                                })?,
+                        );
+                    }
+                    Some("apply-boot-file") => {
+                        policy.apply_boot_file = Some(
+                            parse_string("apply-boot-file", v)
+                                .map_err(|x| x.annotate("Failed to parse apply-boot-file"))?
+                                .ok_or_else(|| {
+                                    Error::InvalidConfig("apply-boot-file cannot be nil".into())
+                                })?,
+                        );
+                    }
+                    Some("apply-deny") => {
+                        policy.apply_deny = Some(
+                            parse_boolean("apply-deny", v)
+                                .map_err(|x| x.annotate("Failed to parse apply-deny"))?
+                                .ok_or_else(|| {
+                                    Error::InvalidConfig("apply-deny cannot be nil".into())
+                                })?,
+                        );
+                    }
+                    Some("apply-nak") => {
+                        policy.apply_nak = Some(
+                            parse_boolean("apply-nak", v)
+                                .map_err(|x| x.annotate("Failed to parse apply-nak"))?
+                                .ok_or_else(|| {
+                                    Error::InvalidConfig("apply-nak cannot be nil".into())
+                                })?,
+                        );
+                    }
+                    Some("apply-rapid-commit") => {
+                        policy.apply_rapid_commit = Some(
+                            parse_boolean("apply-rapid-commit", v)
+                                .map_err(|x| x.annotate("Failed to parse apply-rapid-commit"))?
+                                .ok_or_else(|| {
+                                    Error::InvalidConfig("apply-rapid-commit cannot be nil".into())
+                                })?,
+                        );
+                    }
+                    Some("apply-ipv6-only-preferred") => {
+                        policy.apply_ipv6_only_preferred = Some(
+                            parse_duration("apply-ipv6-only-preferred", v)
+                                .map_err(|x| {
+                                    x.annotate("Failed to parse apply-ipv6-only-preferred")
+                                })?
+                                .ok_or_else(|| {
+                                    Error::InvalidConfig(
+                                        "apply-ipv6-only-preferred cannot be nil".into(),
+                                    )
+                                })?,
+                        );
+                    }
                     Some("apply-range") => {
                         if let Some(range) = v.as_hash() {
                             let mut start: Option<std::net::Ipv4Addr> = None;
@@ -455,6 +703,11 @@ impl Config {
                             for i in u32::from(start)..=u32::from(end) {
                                 addresses.push(i.into());
                             }
+                            policy.apply_ranges.push(super::pool::AddressRange {
+                                name: format!("{}-{}", start, end),
+                                start,
+                                end,
+                            });
                         } else {
                             return Err(Error::InvalidConfig(format!(
                                 "Range should be a hash, not '{:?}'",
@@ -473,6 +726,9 @@ impl Config {
                         for i in 1..(((1 << (32 - subnet.prefixlen)) - 1) - 1) {
                             addresses.push((base + i).into())
                         }
+                        policy
+                            .apply_ranges
+                            .extend(super::pool::AddressRange::from_subnet(&subnet));
                     }
                     Some(x) if x.starts_with("apply-") => {
                         let name = &x[6..];
@@ -535,6 +791,7 @@ impl Config {
     pub fn new(y: &yaml::Yaml) -> Result<Option<Self>, Error> {
         Ok(Some(Config {
             policies: Config::parse_policies(y)?,
+            ..Default::default()
         }))
     }
 }
@@ -0,0 +1,251 @@
+/*   Copyright 2023 Perry Lorier
+ *
+ *  Licensed under the Apache License, Version 2.0 (the "License");
+ *  you may not use this file except in compliance with the License.
+ *  You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ *  Unless required by applicable law or agreed to in writing, software
+ *  distributed under the License is distributed on an "AS IS" BASIS,
+ *  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ *  See the License for the specific language governing permissions and
+ *  limitations under the License.
+ *
+ *  SPDX-License-Identifier: Apache-2.0
+ *
+ *  Address conflict detection.
+ *
+ *  Before offering an address that hasn't been leased for a while to a client, we ARP for it
+ *  (RFC5227 Section 2.1.1) to make sure nothing else on the link, such as a device with a
+ *  forgotten static address, is already using it.
+ */
+use super::pool;
+use erbium_net::addr::ToNetAddr as _;
+use erbium_net::packet;
+use erbium_net::raw;
+use std::sync::Arc;
+use tokio::sync::broadcast;
+
+/// How many addresses we'll try for a single client before we give up probing.
+pub const MAX_PROBES: usize = 3;
+
+/// Addresses that have been leased more recently than this aren't probed, nothing else should
+/// have started using them.
+pub const PROBE_AFTER: std::time::Duration = std::time::Duration::from_secs(86400);
+
+/// How many ARP packets can be waiting for the probes to look at them.
+const MAX_QUEUED_ARPS: usize = 256;
+
+/// Returns true if the address offered in `offer` should be probed before it is offered: nobody
+/// has had a lease on it, or not for a long while.  An address that was quarantined may still
+/// be in use, so is probed as soon as the quarantine is over.
+pub fn should_probe(offer: &pool::Event, now: u32) -> bool {
+    offer
+        .previous
+        .as_ref()
+        .map(|previous| {
+            previous.client_id.is_empty()
+                || u64::from(previous.expire) + PROBE_AFTER.as_secs() < u64::from(now)
+        })
+        .unwrap_or(true)
+}
+
+/// Remembers the last address the pool offered, and who had it before, so the offer can be
+/// checked with [`should_probe`].
+#[derive(Clone, Default)]
+pub struct OfferTracker(Arc<std::sync::Mutex<Option<pool::Event>>>);
+
+impl OfferTracker {
+    pub fn observer(&self) -> pool::Observer {
+        let last = self.0.clone();
+        Box::new(move |event| {
+            if event.change == pool::Change::Offered {
+                *last.lock().unwrap() = Some(event.clone());
+            }
+        })
+    }
+
+    /// Returns the last offer, and forgets it.
+    pub fn take(&self) -> Option<pool::Event> {
+        self.0.lock().unwrap().take()
+    }
+}
+
+/// An ARP socket shared by every probe, and the ARP packets heard on it, with the interface they
+/// were heard on.
+pub struct Prober {
+    sock: raw::RawSocket,
+    heard: broadcast::Sender<(u32, packet::Arp)>,
+}
+
+impl Prober {
+    pub fn new() -> Result<Arc<Self>, std::io::Error> {
+        let prober = Arc::new(Prober {
+            sock: raw::RawSocket::new(raw::EthProto::ARP)?,
+            heard: broadcast::channel(MAX_QUEUED_ARPS).0,
+        });
+        tokio::spawn(prober.clone().listen());
+        Ok(prober)
+    }
+
+    async fn listen(self: Arc<Self>) {
+        loop {
+            let rm = match self.sock.recv_msg(1500, raw::MsgFlags::empty()).await {
+                Ok(rm) => rm,
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => continue,
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    log::warn!(
+                        "Failed to receive ARP, no longer detecting conflicts: {}",
+                        e
+                    );
+                    return;
+                }
+            };
+            let ifindex = match rm.address.as_ref().and_then(|a| a.as_link_addr()) {
+                Some(ll) => ll.ifindex() as u32,
+                None => continue,
+            };
+            if let Some(arp) = packet::parse_arp(&rm.buffer) {
+                /* It's fine if nobody is waiting for it */
+                let _ = self.heard.send((ifindex, arp));
+            }
+        }
+    }
+
+    /// Sends an ARP probe for `addr` out `ifindex`, and waits up to `timeout` for another host
+    /// to claim it.
+    ///
+    /// Returns the MAC address of the host using `addr` if there is one.
+    pub async fn probe(
+        &self,
+        ifindex: u32,
+        srcmac: &[u8; 6],
+        addr: std::net::Ipv4Addr,
+        timeout: std::time::Duration,
+    ) -> Result<Option<[u8; 6]>, std::io::Error> {
+        /* Start listening before we send the probe, so we can't miss a fast reply. */
+        let mut heard = self.heard.subscribe();
+        self.sock
+            .send_msg(
+                &packet::Fragment::new_arp_probe(srcmac, addr).flatten(),
+                &raw::ControlMessage::new(),
+                raw::MsgFlags::empty(),
+                Some(&erbium_net::addr::linkaddr_for_ifindex(ifindex as usize).to_net_addr()),
+            )
+            .await?;
+
+        let wait = async {
+            loop {
+                match heard.recv().await {
+                    /* The socket sees ARP on every interface, only listen to the one we probed. */
+                    Ok((i, arp)) if i == ifindex && is_conflict(&arp, srcmac, addr) => {
+                        return Some(arp.sender_mac)
+                    }
+                    Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => (),
+                    Err(broadcast::error::RecvError::Closed) => return None,
+                }
+            }
+        };
+
+        Ok(tokio::time::timeout(timeout, wait).await.unwrap_or(None))
+    }
+}
+
+/// RFC5227 Section 2.1.1: Any ARP packet where the sender IP address is the address being probed
+/// means the address is in use.  We also ignore our own probe being looped back to us.
+fn is_conflict(arp: &packet::Arp, srcmac: &[u8; 6], addr: std::net::Ipv4Addr) -> bool {
+    arp.sender_ip == addr && arp.sender_mac != *srcmac
+}
+
+#[test]
+fn test_is_conflict() {
+    let ours = [2, 0, 0, 0, 0, 1];
+    let addr = "192.0.2.5".parse().unwrap();
+    let reply = packet::Arp {
+        op: packet::ARP_REPLY,
+        sender_mac: [2, 0, 0, 0, 0, 2],
+        sender_ip: addr,
+        target_mac: ours,
+        target_ip: std::net::Ipv4Addr::UNSPECIFIED,
+    };
+    assert!(is_conflict(&reply, &ours, addr));
+    /* A gratuitous ARP from the squatter counts too */
+    assert!(is_conflict(
+        &packet::Arp {
+            op: packet::ARP_REQUEST,
+            target_mac: [0; 6],
+            target_ip: addr,
+            ..reply
+        },
+        &ours,
+        addr
+    ));
+    /* Replies about other addresses don't */
+    assert!(!is_conflict(
+        &packet::Arp {
+            sender_ip: "192.0.2.6".parse().unwrap(),
+            ..reply
+        },
+        &ours,
+        addr
+    ));
+    /* Nor does hearing our own probe */
+    assert!(!is_conflict(
+        &packet::Arp {
+            sender_mac: ours,
+            ..reply
+        },
+        &ours,
+        addr
+    ));
+}
+
+#[test]
+fn test_should_probe() {
+    let now = 1_000_000;
+    let lease = pool::LeaseInfo {
+        ip: "192.0.2.5".parse().unwrap(),
+        client_id: b"client".to_vec(),
+        chaddr: vec![],
+        start: now,
+        expire: now + 300,
+        options: vec![],
+    };
+    let offer = |previous: Option<pool::LeaseInfo>| pool::Event {
+        change: pool::Change::Offered,
+        lease: lease.clone(),
+        previous,
+    };
+    /* Never leased */
+    assert!(should_probe(&offer(None), now));
+    /* Expired (or released) recently, eg being handed back to the client that had it */
+    assert!(!should_probe(
+        &offer(Some(pool::LeaseInfo {
+            start: now - 600,
+            expire: now - 300,
+            ..lease.clone()
+        })),
+        now
+    ));
+    /* Expired long ago */
+    assert!(should_probe(
+        &offer(Some(pool::LeaseInfo {
+            start: 0,
+            expire: 100,
+            ..lease.clone()
+        })),
+        now
+    ));
+    /* Quarantined because something else was using it */
+    assert!(should_probe(
+        &offer(Some(pool::LeaseInfo {
+            client_id: vec![],
+            start: now - 600,
+            expire: now - 300,
+            ..lease.clone()
+        })),
+        now
+    ));
+}
@@ -0,0 +1,908 @@
+/*   Copyright 2023 Perry Lorier
+ *
+ *  Licensed under the Apache License, Version 2.0 (the "License");
+ *  you may not use this file except in compliance with the License.
+ *  You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ *  Unless required by applicable law or agreed to in writing, software
+ *  distributed under the License is distributed on an "AS IS" BASIS,
+ *  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ *  See the License for the specific language governing permissions and
+ *  limitations under the License.
+ *
+ *  SPDX-License-Identifier: Apache-2.0
+ *
+ *  Dynamic DNS updates (RFC2136) of A/PTR records for DHCP leases.
+ *
+ *  Handles the Client FQDN option (RFC4702) to agree with the client who updates what, and then
+ *  pushes updates, optionally signed with TSIG (RFC8945), to an external authoritative server.
+ */
+use std::net::Ipv4Addr;
+
+use crate::config::{parse_boolean, parse_duration, parse_string, Error};
+use crate::dhcp::dhcppkt::{self, ClientFqdn, FQDN_FLAG_E, FQDN_FLAG_N, FQDN_FLAG_O, FQDN_FLAG_S};
+use crate::dhcp::pool::{self, LeaseInfo};
+use crate::dns::dnspkt;
+use yaml_rust::yaml;
+
+lazy_static::lazy_static! {
+    static ref DHCP_DNS_UPDATES: prometheus::IntCounterVec = prometheus::register_int_counter_vec!(
+        "dhcp_dns_updates",
+        "Counts of dynamic DNS updates sent, by result",
+        &["result"]
+    )
+    .unwrap();
+}
+
+const DEFAULT_TTL: u32 = 300;
+const UPDATE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+/* RFC8945 Section 10: 300 seconds is the recommended fudge */
+const TSIG_FUDGE: u16 = 300;
+const RR_TSIG: dnspkt::Type = dnspkt::Type(250);
+const CLASS_NONE: dnspkt::Class = dnspkt::Class(254);
+const CLASS_ANY: dnspkt::Class = dnspkt::Class(255);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TsigAlgorithm {
+    HmacMd5,
+    HmacSha1,
+    HmacSha256,
+    HmacSha512,
+}
+
+impl TsigAlgorithm {
+    const fn name(&self) -> &'static str {
+        match self {
+            TsigAlgorithm::HmacMd5 => "hmac-md5.sig-alg.reg.int",
+            TsigAlgorithm::HmacSha1 => "hmac-sha1",
+            TsigAlgorithm::HmacSha256 => "hmac-sha256",
+            TsigAlgorithm::HmacSha512 => "hmac-sha512",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().trim_end_matches('.') {
+            "hmac-md5" | "hmac-md5.sig-alg.reg.int" => Some(TsigAlgorithm::HmacMd5),
+            "hmac-sha1" => Some(TsigAlgorithm::HmacSha1),
+            "hmac-sha256" => Some(TsigAlgorithm::HmacSha256),
+            "hmac-sha512" => Some(TsigAlgorithm::HmacSha512),
+            _ => None,
+        }
+    }
+
+    fn mac(&self, key: &[u8], data: &[u8]) -> Vec<u8> {
+        fn hmac<D: crypto::digest::Digest>(digest: D, key: &[u8], data: &[u8]) -> Vec<u8> {
+            use crypto::mac::Mac as _;
+            let mut hasher = crypto::hmac::Hmac::new(digest, key);
+            hasher.input(data);
+            hasher.result().code().to_vec()
+        }
+        match self {
+            TsigAlgorithm::HmacMd5 => hmac(crypto::md5::Md5::new(), key, data),
+            TsigAlgorithm::HmacSha1 => hmac(crypto::sha1::Sha1::new(), key, data),
+            TsigAlgorithm::HmacSha256 => hmac(crypto::sha2::Sha256::new(), key, data),
+            TsigAlgorithm::HmacSha512 => hmac(crypto::sha2::Sha512::new(), key, data),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TsigKey {
+    pub name: String,
+    pub algorithm: TsigAlgorithm,
+    pub secret: Vec<u8>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Config {
+    pub server: std::net::SocketAddr,
+    /// The zone forward (A) records are added to, each lease gets "hostname.zone".
+    pub zone: String,
+    /// The zone PTR records are added to, defaults to the /24 containing the lease.
+    pub reverse_zone: Option<String>,
+    pub ttl: u32,
+    pub tsig: Option<TsigKey>,
+    /// Perform the A update even if the client asked to do it itself.
+    pub override_client_updates: bool,
+}
+
+fn decode_base64(s: &str) -> Option<Vec<u8>> {
+    let mut ret = vec![];
+    let mut acc = 0_u32;
+    let mut bits = 0;
+    for c in s.bytes().filter(|c| !c.is_ascii_whitespace()) {
+        let v = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            b'=' => break,
+            _ => return None,
+        };
+        acc = (acc << 6) | u32::from(v);
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            ret.push((acc >> bits) as u8);
+        }
+    }
+    Some(ret)
+}
+
+fn parse_domain(name: &str, value: String) -> Result<String, Error> {
+    let value = value.trim_end_matches('.').to_ascii_lowercase();
+    value
+        .parse::<dnspkt::Domain>()
+        .map_err(|e| Error::InvalidConfig(format!("{}: {}", name, e)))?;
+    Ok(value)
+}
+
+pub fn parse(name: &str, fragment: &yaml::Yaml) -> Result<Option<Config>, Error> {
+    let h = match fragment {
+        yaml::Yaml::Null => return Ok(None),
+        yaml::Yaml::Hash(h) => h,
+        e => {
+            return Err(Error::InvalidConfig(format!(
+                "{} should be of type Hash, not {}",
+                name,
+                crate::config::type_to_name(e)
+            )))
+        }
+    };
+    let mut server = None;
+    let mut zone = None;
+    let mut reverse_zone = None;
+    let mut ttl = None;
+    let mut tsig_key_name = None;
+    let mut tsig_algorithm = None;
+    let mut tsig_secret = None;
+    let mut override_client_updates = None;
+    for (k, v) in h {
+        match k.as_str() {
+            Some("server") => server = parse_string("server", v)?,
+            Some("zone") => zone = parse_string("zone", v)?,
+            Some("reverse-zone") => reverse_zone = parse_string("reverse-zone", v)?,
+            Some("ttl") => ttl = parse_duration("ttl", v)?,
+            Some("tsig-key-name") => tsig_key_name = parse_string("tsig-key-name", v)?,
+            Some("tsig-algorithm") => tsig_algorithm = parse_string("tsig-algorithm", v)?,
+            Some("tsig-secret") => tsig_secret = parse_string("tsig-secret", v)?,
+            Some("override-client-updates") => {
+                override_client_updates = parse_boolean("override-client-updates", v)?
+            }
+            Some(e) => {
+                return Err(Error::InvalidConfig(format!(
+                    "Unexpected key in {}: {}",
+                    name, e
+                )))
+            }
+            None => {
+                return Err(Error::InvalidConfig(format!(
+                    "{} key is not a string, instead: '{:?}'",
+                    name, k
+                )))
+            }
+        }
+    }
+    let server =
+        server.ok_or_else(|| Error::InvalidConfig(format!("Missing server in {}", name)))?;
+    let server = server
+        .parse::<std::net::SocketAddr>()
+        .or_else(|_| {
+            server
+                .parse::<std::net::IpAddr>()
+                .map(|ip| std::net::SocketAddr::new(ip, 53))
+        })
+        .map_err(|e| Error::InvalidConfig(format!("server {:?}: {}", server, e)))?;
+    let zone = parse_domain(
+        "zone",
+        zone.ok_or_else(|| Error::InvalidConfig(format!("Missing zone in {}", name)))?,
+    )?;
+    let reverse_zone = reverse_zone
+        .map(|z| parse_domain("reverse-zone", z))
+        .transpose()?;
+    let tsig = match (tsig_key_name, tsig_secret) {
+        (None, None) => None,
+        (Some(key_name), Some(secret)) => Some(TsigKey {
+            name: parse_domain("tsig-key-name", key_name)?,
+            algorithm: match tsig_algorithm {
+                None => TsigAlgorithm::HmacSha256,
+                Some(a) => TsigAlgorithm::from_name(&a).ok_or_else(|| {
+                    Error::InvalidConfig(format!(
+                        "Unknown tsig-algorithm {}, expected hmac-md5, hmac-sha1, hmac-sha256 or hmac-sha512",
+                        a
+                    ))
+                })?,
+            },
+            secret: decode_base64(&secret).ok_or_else(|| {
+                Error::InvalidConfig("tsig-secret should be base64 encoded".into())
+            })?,
+        }),
+        _ => {
+            return Err(Error::InvalidConfig(
+                "tsig-key-name and tsig-secret must be specified together".into(),
+            ))
+        }
+    };
+    Ok(Some(Config {
+        server,
+        zone,
+        reverse_zone,
+        ttl: ttl
+            .map(|t| t.as_secs().try_into().unwrap_or(u32::MAX))
+            .unwrap_or(DEFAULT_TTL),
+        tsig,
+        override_client_updates: override_client_updates.unwrap_or(false),
+    }))
+}
+
+/// What DNS records a lease should have.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Registration {
+    pub name: String,
+    pub forward: bool,
+    pub reverse: bool,
+}
+
+/// Decides which updates the server performs for a client, and what Client FQDN option to reply
+/// with (RFC4702 Section 3.1 and RFC4702 Section 4).
+///
+/// A reply option is only returned if the client sent one.  A registration is only returned if we
+/// should be updating DNS on behalf of this client.
+pub fn plan(
+    conf: Option<&Config>,
+    fqdn: Option<&ClientFqdn>,
+    hostname: Option<&str>,
+) -> (Option<ClientFqdn>, Option<Registration>) {
+    /* Reply using the same encoding the client used */
+    let encoding = fqdn.map(|f| f.flags & FQDN_FLAG_E).unwrap_or(FQDN_FLAG_E);
+    /* We only use the first label of whatever the client gives us, and put it in our zone */
+    let name = conf.and_then(|c| {
+        fqdn.map(|f| f.name.as_str())
+            .filter(|n| !n.is_empty())
+            .or(hostname)
+            .and_then(crate::dns::leases::sanitise_hostname)
+            .map(|label| format!("{}.{}", label, c.zone))
+    });
+    let (conf, name) = match (conf, name) {
+        (Some(conf), Some(name)) => (conf, name),
+        /* We're not going to do any updates, so tell the client that */
+        _ => {
+            return (
+                fqdn.map(|f| ClientFqdn {
+                    flags: encoding | FQDN_FLAG_N,
+                    name: f.name.clone(),
+                }),
+                None,
+            )
+        }
+    };
+    let (flags, forward) = match fqdn {
+        /* Clients that don't know about the FQDN option get both records registered */
+        None => (0, true),
+        Some(f) if f.flags & FQDN_FLAG_S == 0 && conf.override_client_updates => {
+            (FQDN_FLAG_S | FQDN_FLAG_O, true)
+        }
+        Some(f) if f.flags & FQDN_FLAG_N != 0 => {
+            return (
+                Some(ClientFqdn {
+                    flags: encoding | FQDN_FLAG_N,
+                    name,
+                }),
+                None,
+            )
+        }
+        Some(f) if f.flags & FQDN_FLAG_S != 0 => (FQDN_FLAG_S, true),
+        /* The client will update the A record itself, we only do the PTR */
+        Some(_) => (0, false),
+    };
+    (
+        fqdn.map(|_| ClientFqdn {
+            flags: encoding | flags,
+            name: name.clone(),
+        }),
+        Some(Registration {
+            name,
+            forward,
+            reverse: true,
+        }),
+    )
+}
+
+fn reverse_name(ip: Ipv4Addr) -> String {
+    let o = ip.octets();
+    format!("{}.{}.{}.{}.in-addr.arpa", o[3], o[2], o[1], o[0])
+}
+
+fn reverse_zone(conf: &Config, ip: Ipv4Addr) -> String {
+    conf.reverse_zone.clone().unwrap_or_else(|| {
+        let o = ip.octets();
+        format!("{}.{}.{}.in-addr.arpa", o[2], o[1], o[0])
+    })
+}
+
+fn to_domain(name: &str) -> dnspkt::Domain {
+    /* Everything here has been validated by the config parser or sanitise_hostname */
+    name.parse().expect("invalid domain name")
+}
+
+fn build_update(zone: &str, updates: Vec<dnspkt::RR>) -> dnspkt::DNSPkt {
+    /* RFC2136 Section 2: The question section is the zone section, and the nameserver section is
+     * the update section.  We don't use prerequisites.
+     */
+    dnspkt::DNSPkt {
+        qid: rand::random(),
+        rd: false,
+        tc: false,
+        aa: false,
+        qr: false,
+        opcode: dnspkt::OPCODE_UPDATE,
+        cd: false,
+        ad: false,
+        ra: false,
+        rcode: dnspkt::NOERROR,
+        bufsize: 512,
+        edns_ver: None,
+        edns_do: false,
+        question: dnspkt::Question {
+            qdomain: to_domain(zone),
+            qclass: dnspkt::CLASS_IN,
+            qtype: dnspkt::RR_SOA,
+        },
+        answer: vec![],
+        nameserver: updates,
+        additional: vec![],
+        edns: None,
+    }
+}
+
+fn rr(
+    name: &str,
+    class: dnspkt::Class,
+    rrtype: dnspkt::Type,
+    ttl: u32,
+    rdata: dnspkt::RData,
+) -> dnspkt::RR {
+    dnspkt::RR {
+        domain: to_domain(name),
+        class,
+        rrtype,
+        ttl,
+        rdata,
+    }
+}
+
+/// Builds the updates that add the records for a registration.
+fn add_updates(conf: &Config, ip: Ipv4Addr, reg: &Registration) -> Vec<dnspkt::DNSPkt> {
+    let mut ret = vec![];
+    if reg.forward {
+        /* Replace whatever address the name previously had */
+        ret.push(build_update(
+            &conf.zone,
+            vec![
+                rr(
+                    &reg.name,
+                    CLASS_ANY,
+                    dnspkt::RR_A,
+                    0,
+                    dnspkt::RData::Other(vec![]),
+                ),
+                rr(
+                    &reg.name,
+                    dnspkt::CLASS_IN,
+                    dnspkt::RR_A,
+                    conf.ttl,
+                    dnspkt::RData::Other(ip.octets().to_vec()),
+                ),
+            ],
+        ));
+    }
+    if reg.reverse {
+        let ptr = reverse_name(ip);
+        ret.push(build_update(
+            &reverse_zone(conf, ip),
+            vec![
+                rr(
+                    &ptr,
+                    CLASS_ANY,
+                    dnspkt::RR_PTR,
+                    0,
+                    dnspkt::RData::Other(vec![]),
+                ),
+                rr(
+                    &ptr,
+                    dnspkt::CLASS_IN,
+                    dnspkt::RR_PTR,
+                    conf.ttl,
+                    dnspkt::RData::Ptr(to_domain(&reg.name)),
+                ),
+            ],
+        ));
+    }
+    ret
+}
+
+/// Builds the updates that remove the records for a registration.
+fn remove_updates(conf: &Config, ip: Ipv4Addr, reg: &Registration) -> Vec<dnspkt::DNSPkt> {
+    let mut ret = vec![];
+    if reg.forward {
+        /* Only remove our address, in case the name has since moved to another lease */
+        ret.push(build_update(
+            &conf.zone,
+            vec![rr(
+                &reg.name,
+                CLASS_NONE,
+                dnspkt::RR_A,
+                0,
+                dnspkt::RData::Other(ip.octets().to_vec()),
+            )],
+        ));
+    }
+    if reg.reverse {
+        ret.push(build_update(
+            &reverse_zone(conf, ip),
+            vec![rr(
+                &reverse_name(ip),
+                CLASS_ANY,
+                dnspkt::RR_PTR,
+                0,
+                dnspkt::RData::Other(vec![]),
+            )],
+        ));
+    }
+    ret
+}
+
+/* Names in TSIG are always uncompressed and in canonical (lowercase) form */
+fn push_name(v: &mut Vec<u8>, name: &str) {
+    for label in name.split('.').filter(|l| !l.is_empty()) {
+        v.push(label.len() as u8);
+        v.extend(label.to_ascii_lowercase().as_bytes());
+    }
+    v.push(0);
+}
+
+/// Appends a TSIG record to a serialised message (RFC8945 Section 4).
+fn sign(key: &TsigKey, msg: &mut Vec<u8>, time_signed: u64) {
+    let time = &time_signed.to_be_bytes()[2..];
+    /* RFC8945 Section 4.3.3: The MAC covers the message, followed by the TSIG variables */
+    let mut variables = vec![];
+    push_name(&mut variables, &key.name);
+    variables.extend(CLASS_ANY.0.to_be_bytes());
+    variables.extend(0_u32.to_be_bytes()); /* TTL */
+    push_name(&mut variables, key.algorithm.name());
+    variables.extend(time);
+    variables.extend(TSIG_FUDGE.to_be_bytes());
+    variables.extend(0_u16.to_be_bytes()); /* Error */
+    variables.extend(0_u16.to_be_bytes()); /* Other Len */
+    let mut signed = msg.clone();
+    signed.extend(&variables);
+    let mac = key.algorithm.mac(&key.secret, &signed);
+
+    let mut rdata = vec![];
+    push_name(&mut rdata, key.algorithm.name());
+    rdata.extend(time);
+    rdata.extend(TSIG_FUDGE.to_be_bytes());
+    rdata.extend((mac.len() as u16).to_be_bytes());
+    rdata.extend(&mac);
+    rdata.extend(&msg[0..2]); /* Original ID */
+    rdata.extend(0_u16.to_be_bytes()); /* Error */
+    rdata.extend(0_u16.to_be_bytes()); /* Other Len */
+
+    push_name(msg, &key.name);
+    msg.extend(RR_TSIG.0.to_be_bytes());
+    msg.extend(CLASS_ANY.0.to_be_bytes());
+    msg.extend(0_u32.to_be_bytes());
+    msg.extend((rdata.len() as u16).to_be_bytes());
+    msg.extend(rdata);
+    /* Bump ARCOUNT */
+    let arcount = u16::from_be_bytes([msg[10], msg[11]]) + 1;
+    msg[10..12].copy_from_slice(&arcount.to_be_bytes());
+}
+
+fn now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+async fn send_update(conf: &Config, update: &dnspkt::DNSPkt) -> Result<(), String> {
+    let mut msg = update.serialise();
+    if let Some(key) = &conf.tsig {
+        sign(key, &mut msg, now());
+    }
+    let local: std::net::SocketAddr = match conf.server {
+        std::net::SocketAddr::V4(_) => (std::net::Ipv4Addr::UNSPECIFIED, 0).into(),
+        std::net::SocketAddr::V6(_) => (std::net::Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+    let sock = tokio::net::UdpSocket::bind(local)
+        .await
+        .map_err(|e| e.to_string())?;
+    sock.connect(conf.server).await.map_err(|e| e.to_string())?;
+    sock.send(&msg).await.map_err(|e| e.to_string())?;
+    let mut buf = [0_u8; 4096];
+    loop {
+        let len = tokio::time::timeout(UPDATE_TIMEOUT, sock.recv(&mut buf))
+            .await
+            .map_err(|_| "Timed out waiting for reply".to_string())?
+            .map_err(|e| e.to_string())?;
+        /* Ignore anything that isn't a reply to our update */
+        if len < 12 || buf[0..2] != msg[0..2] || buf[2] & 0x80 == 0 {
+            continue;
+        }
+        return match dnspkt::RCode(u16::from(buf[3] & 0x0F)) {
+            dnspkt::NOERROR => Ok(()),
+            rcode => Err(format!("Server replied {}", rcode)),
+        };
+    }
+}
+
+fn spawn_updates(conf: &Config, updates: Vec<dnspkt::DNSPkt>) {
+    let conf = conf.clone();
+    tokio::spawn(async move {
+        for update in updates {
+            match send_update(&conf, &update).await {
+                Ok(()) => {
+                    log::info!(
+                        "Updated {} in {}",
+                        update
+                            .nameserver
+                            .last()
+                            .map(|rr| rr.to_string())
+                            .unwrap_or_default(),
+                        update.question.qdomain
+                    );
+                    DHCP_DNS_UPDATES.with_label_values(&["SUCCESS"]).inc();
+                }
+                Err(e) => {
+                    log::warn!(
+                        "Failed to update {} on {}: {}",
+                        update.question.qdomain,
+                        conf.server,
+                        e
+                    );
+                    DHCP_DNS_UPDATES.with_label_values(&["FAILED"]).inc();
+                }
+            }
+        }
+    });
+}
+
+/// What `lease` should have registered in DNS.  Offers, quarantines and released leases don't
+/// have anything registered.
+fn get_registration(conf: &Config, lease: &LeaseInfo) -> Option<Registration> {
+    if lease.client_id.is_empty() || super::get_binding(lease) != super::Binding::Bound {
+        return None;
+    }
+    let options = dhcppkt::parse_options(crate::pktparser::Buffer::new(&lease.options)).ok()?;
+    plan(
+        Some(conf),
+        options.get_client_fqdn().as_ref(),
+        options.get_hostname().as_deref(),
+    )
+    .1
+}
+
+/// Works out the updates for a change to the pool: records are added when a lease is committed,
+/// replaced if a renewal changes them, and removed when the lease is released, declined or
+/// expires.
+fn get_updates(conf: &Config, change: &pool::Event) -> Vec<dnspkt::DNSPkt> {
+    let lease = &change.lease;
+    /* What the address had registered, if it was still held when this change happened */
+    let previous = change
+        .previous
+        .as_ref()
+        .filter(|previous| previous.expire >= lease.start)
+        .and_then(|previous| get_registration(conf, previous));
+    let (remove, add) = match change.change {
+        pool::Change::Committed(_) => match (previous, get_registration(conf, lease)) {
+            /* A renewal, nothing has changed */
+            (previous, reg) if previous == reg => return vec![],
+            (previous, reg) => (previous, reg),
+        },
+        pool::Change::Released | pool::Change::Quarantined => (previous, None),
+        pool::Change::Expired => (get_registration(conf, lease), None),
+        pool::Change::Offered | pool::Change::Imported => return vec![],
+    };
+    let mut updates = vec![];
+    if let Some(reg) = remove {
+        updates.extend(remove_updates(conf, lease.ip, &reg));
+    }
+    if let Some(reg) = add {
+        updates.extend(add_updates(conf, lease.ip, &reg));
+    }
+    updates
+}
+
+/// Updates DNS in the background for a lease that was granted, released, declined or expired.
+pub fn handle(conf: &Config, change: &pool::Event) {
+    let updates = get_updates(conf, change);
+    if !updates.is_empty() {
+        spawn_updates(conf, updates);
+    }
+}
+
+#[cfg(test)]
+fn test_config() -> Config {
+    Config {
+        server: "192.0.2.53:53".parse().unwrap(),
+        zone: "example.com".into(),
+        reverse_zone: None,
+        ttl: 300,
+        tsig: None,
+        override_client_updates: false,
+    }
+}
+
+#[test]
+fn test_parse_config() {
+    let y = yaml_rust::YamlLoader::load_from_str(
+        "
+server: 192.0.2.53
+zone: Example.COM.
+reverse-zone: 2.0.192.in-addr.arpa
+ttl: 1h
+tsig-key-name: dhcp-key
+tsig-algorithm: hmac-sha512
+tsig-secret: c2VjcmV0
+override-client-updates: true
+",
+    )
+    .unwrap();
+    let conf = parse("dhcp-dns-update", &y[0]).unwrap().unwrap();
+    assert_eq!(
+        conf,
+        Config {
+            server: "192.0.2.53:53".parse().unwrap(),
+            zone: "example.com".into(),
+            reverse_zone: Some("2.0.192.in-addr.arpa".into()),
+            ttl: 3600,
+            tsig: Some(TsigKey {
+                name: "dhcp-key".into(),
+                algorithm: TsigAlgorithm::HmacSha512,
+                secret: b"secret".to_vec(),
+            }),
+            override_client_updates: true,
+        }
+    );
+    let y = yaml_rust::YamlLoader::load_from_str("{server: 192.0.2.53, tsig-key-name: k}").unwrap();
+    assert!(parse("dhcp-dns-update", &y[0]).is_err());
+}
+
+#[test]
+fn test_decode_base64() {
+    assert_eq!(decode_base64("").unwrap(), b"");
+    assert_eq!(decode_base64("Zg==").unwrap(), b"f");
+    assert_eq!(decode_base64("Zm8=").unwrap(), b"fo");
+    assert_eq!(decode_base64("Zm9v").unwrap(), b"foo");
+    assert_eq!(decode_base64("Zm9v YmFy").unwrap(), b"foobar");
+    assert_eq!(decode_base64("Zm9v!"), None);
+}
+
+#[test]
+fn test_plan() {
+    let conf = test_config();
+    let fqdn = |flags, name: &str| ClientFqdn {
+        flags,
+        name: name.into(),
+    };
+    let reg = |forward| {
+        Some(Registration {
+            name: "laptop.example.com".into(),
+            forward,
+            reverse: true,
+        })
+    };
+    /* Not configured, so we tell the client we won't do anything */
+    assert_eq!(
+        plan(None, Some(&fqdn(FQDN_FLAG_E | FQDN_FLAG_S, "laptop")), None),
+        (Some(fqdn(FQDN_FLAG_E | FQDN_FLAG_N, "laptop")), None)
+    );
+    /* No option, but a hostname */
+    assert_eq!(plan(Some(&conf), None, Some("Laptop")), (None, reg(true)));
+    /* Client asks us to do the A record */
+    assert_eq!(
+        plan(
+            Some(&conf),
+            Some(&fqdn(FQDN_FLAG_E | FQDN_FLAG_S, "laptop.elsewhere.net")),
+            None
+        ),
+        (
+            Some(fqdn(FQDN_FLAG_E | FQDN_FLAG_S, "laptop.example.com")),
+            reg(true)
+        )
+    );
+    /* Client wants to do the A record itself, in the ASCII encoding */
+    assert_eq!(
+        plan(Some(&conf), Some(&fqdn(0, "laptop")), None),
+        (Some(fqdn(0, "laptop.example.com")), reg(false))
+    );
+    /* Client doesn't want any updates */
+    assert_eq!(
+        plan(Some(&conf), Some(&fqdn(FQDN_FLAG_N, "laptop")), None),
+        (Some(fqdn(FQDN_FLAG_N, "laptop.example.com")), None)
+    );
+    /* ... unless we're configured to override it */
+    let conf = Config {
+        override_client_updates: true,
+        ..conf
+    };
+    assert_eq!(
+        plan(Some(&conf), Some(&fqdn(FQDN_FLAG_N, "laptop")), None),
+        (
+            Some(fqdn(FQDN_FLAG_S | FQDN_FLAG_O, "laptop.example.com")),
+            reg(true)
+        )
+    );
+    /* An empty name falls back to the hostname option */
+    assert_eq!(
+        plan(
+            Some(&conf),
+            Some(&fqdn(FQDN_FLAG_E | FQDN_FLAG_S, "")),
+            Some("laptop")
+        ),
+        (
+            Some(fqdn(FQDN_FLAG_E | FQDN_FLAG_S, "laptop.example.com")),
+            reg(true)
+        )
+    );
+}
+
+#[test]
+fn test_updates() {
+    let conf = test_config();
+    let ip = "192.0.2.7".parse().unwrap();
+    let reg = Registration {
+        name: "laptop.example.com".into(),
+        forward: true,
+        reverse: true,
+    };
+    let add = add_updates(&conf, ip, &reg);
+    assert_eq!(add.len(), 2);
+    assert_eq!(add[0].opcode, dnspkt::OPCODE_UPDATE);
+    assert_eq!(add[0].question.qdomain.to_string(), "example.com");
+    assert_eq!(add[0].question.qtype, dnspkt::RR_SOA);
+    assert_eq!(add[0].nameserver[0].class, CLASS_ANY);
+    assert_eq!(
+        add[0].nameserver[1].rdata,
+        dnspkt::RData::Other(vec![192, 0, 2, 7])
+    );
+    assert_eq!(add[1].question.qdomain.to_string(), "2.0.192.in-addr.arpa");
+    assert_eq!(
+        add[1].nameserver[1].domain.to_string(),
+        "7.2.0.192.in-addr.arpa"
+    );
+    assert_eq!(
+        add[1].nameserver[1].rdata,
+        dnspkt::RData::Ptr("laptop.example.com".parse().unwrap())
+    );
+    let remove = remove_updates(
+        &conf,
+        ip,
+        &Registration {
+            forward: false,
+            ..reg
+        },
+    );
+    assert_eq!(remove.len(), 1);
+    assert_eq!(remove[0].nameserver[0].class, CLASS_ANY);
+    assert_eq!(remove[0].nameserver[0].rrtype, dnspkt::RR_PTR);
+}
+
+#[test]
+fn test_get_updates() {
+    let conf = test_config();
+    let options = |msgtype: dhcppkt::MessageType, hostname: &str| {
+        use dhcppkt::Serialise as _;
+        let mut raw = vec![];
+        dhcppkt::DhcpOptions::default()
+            .set_option(&dhcppkt::OPTION_MSGTYPE, &msgtype)
+            .set_option(&dhcppkt::OPTION_HOSTNAME, &hostname.to_string())
+            .serialise(&mut raw);
+        raw
+    };
+    let lease = LeaseInfo {
+        ip: "192.0.2.7".parse().unwrap(),
+        client_id: b"client".to_vec(),
+        chaddr: vec![],
+        start: 1000,
+        expire: 2000,
+        options: options(dhcppkt::DHCPREQUEST, "laptop"),
+    };
+    let offer = LeaseInfo {
+        options: options(dhcppkt::DHCPDISCOVER, "laptop"),
+        ..lease.clone()
+    };
+    let renewed = LeaseInfo {
+        start: 1500,
+        expire: 2500,
+        ..lease.clone()
+    };
+    let renamed = LeaseInfo {
+        options: options(dhcppkt::DHCPREQUEST, "desktop"),
+        ..renewed.clone()
+    };
+    let released = LeaseInfo {
+        expire: 1499,
+        options: options(dhcppkt::DHCPRELEASE, "laptop"),
+        ..lease.clone()
+    };
+    let updates = |change, lease: &LeaseInfo, previous: Option<&LeaseInfo>| {
+        get_updates(
+            &conf,
+            &pool::Event {
+                change,
+                lease: lease.clone(),
+                previous: previous.cloned(),
+            },
+        )
+        .iter()
+        .map(|update| {
+            (
+                update.question.qdomain.to_string(),
+                update.nameserver.last().unwrap().class != dnspkt::CLASS_IN,
+            )
+        })
+        .collect::<Vec<_>>()
+    };
+    let reusing = pool::Change::Committed(pool::LeaseType::ReusingLease);
+    let added = vec![
+        ("example.com".to_string(), false),
+        ("2.0.192.in-addr.arpa".to_string(), false),
+    ];
+    let removed = vec![
+        ("example.com".to_string(), true),
+        ("2.0.192.in-addr.arpa".to_string(), true),
+    ];
+
+    assert_eq!(updates(pool::Change::Offered, &offer, None), vec![]);
+    assert_eq!(updates(reusing, &lease, Some(&offer)), added);
+    /* Renewing with the same name doesn't change anything, with a new name replaces it */
+    assert_eq!(updates(reusing, &renewed, Some(&lease)), vec![]);
+    assert_eq!(
+        updates(reusing, &renamed, Some(&renewed)),
+        [removed.clone(), added].concat()
+    );
+    assert_eq!(
+        updates(pool::Change::Released, &released, Some(&lease)),
+        removed
+    );
+    assert_eq!(updates(pool::Change::Expired, &lease, None), removed);
+    /* Released leases were already removed */
+    assert_eq!(updates(pool::Change::Expired, &released, None), vec![]);
+}
+
+#[test]
+fn test_tsig_sign() {
+    let key = TsigKey {
+        name: "Key.Example".into(),
+        algorithm: TsigAlgorithm::HmacSha256,
+        secret: b"secret".to_vec(),
+    };
+    /* A minimal header with ID 0x1234 and no records */
+    let mut msg = vec![0x12, 0x34, 0x28, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+    sign(&key, &mut msg, 1_700_000_000);
+    assert_eq!(msg[10..12], [0, 1]);
+    let tsig = &msg[12..];
+    assert_eq!(tsig[0..13], *b"\x03key\x07example\x00");
+    assert_eq!(tsig[13..23], [0, 250, 0, 255, 0, 0, 0, 0, 0, 61]);
+    let rdata = &tsig[23..];
+    assert_eq!(rdata[0..13], *b"\x0bhmac-sha256\x00");
+    assert_eq!(rdata[13..19], [0, 0, 0x65, 0x53, 0xf1, 0x00]);
+    assert_eq!(rdata[19..21], TSIG_FUDGE.to_be_bytes());
+    assert_eq!(rdata[21..23], [0, 32]);
+    /* Independently calculated HMAC-SHA256 over the message and TSIG variables */
+    assert_eq!(
+        rdata[23..55],
+        [
+            0xa6, 0x06, 0x40, 0x47, 0x50, 0xdf, 0x8d, 0xeb, 0x57, 0xf8, 0x9b, 0xef, 0xb3, 0x40,
+            0x23, 0xd5, 0x2b, 0x41, 0xe1, 0xaf, 0x92, 0xa4, 0x3b, 0xb8, 0x62, 0x4f, 0x90, 0x2b,
+            0xd2, 0x38, 0x97, 0x5e,
+        ]
+    );
+    assert_eq!(rdata[55..61], [0x12, 0x34, 0, 0, 0, 0]);
+    assert_eq!(rdata.len(), 61);
+}
@@ -79,8 +79,19 @@ impl fmt::Debug for DhcpOp {
 
 #[derive(PartialEq, Eq)]
 pub struct HwType(u8);
+/// Used when there is no hardware address, eg in a leasequery reply for a client we don't know
+/// the hardware address of.
+pub const HWTYPE_NONE: HwType = HwType(0);
 pub const HWTYPE_ETHERNET: HwType = HwType(1);
 
+/// RFC2131 Section 2: The leftmost bit of 'flags' asks for replies to be broadcast.
+pub const FLAG_BROADCAST: u16 = 0x8000;
+
+/* Relay Agent Information sub-options, RFC3046 */
+pub const RELAY_CIRCUIT_ID: u8 = 1;
+pub const RELAY_REMOTE_ID: u8 = 2;
+pub const RELAY_RELAY_ID: u8 = 12; /* RFC6925 */
+
 impl ToString for HwType {
     fn to_string(&self) -> String {
         match self {
@@ -107,6 +118,18 @@ pub const DHCPNAK: MessageType = MessageType(6);
 pub const DHCPRELEASE: MessageType = MessageType(7);
 pub const DHCPINFORM: MessageType = MessageType(8);
 pub const DHCPFORCERENEW: MessageType = MessageType(9);
+/* RFC4388 */
+pub const DHCPLEASEQUERY: MessageType = MessageType(10);
+pub const DHCPLEASEUNASSIGNED: MessageType = MessageType(11);
+pub const DHCPLEASEUNKNOWN: MessageType = MessageType(12);
+pub const DHCPLEASEACTIVE: MessageType = MessageType(13);
+/* RFC6926 */
+pub const DHCPBULKLEASEQUERY: MessageType = MessageType(14);
+pub const DHCPLEASEQUERYDONE: MessageType = MessageType(15);
+/* RFC7724 */
+pub const DHCPACTIVELEASEQUERY: MessageType = MessageType(16);
+pub const DHCPLEASEQUERYSTATUS: MessageType = MessageType(17);
+pub const DHCPTLS: MessageType = MessageType(18);
 
 impl ToString for MessageType {
     fn to_string(&self) -> String {
@@ -120,6 +143,15 @@ impl ToString for MessageType {
             &DHCPRELEASE => String::from("DHCPRELEASE"),
             &DHCPINFORM => String::from("DHCPINFORM"),
             &DHCPFORCERENEW => String::from("DHCPFORCERENEW"),
+            &DHCPLEASEQUERY => String::from("DHCPLEASEQUERY"),
+            &DHCPLEASEUNASSIGNED => String::from("DHCPLEASEUNASSIGNED"),
+            &DHCPLEASEUNKNOWN => String::from("DHCPLEASEUNKNOWN"),
+            &DHCPLEASEACTIVE => String::from("DHCPLEASEACTIVE"),
+            &DHCPBULKLEASEQUERY => String::from("DHCPBULKLEASEQUERY"),
+            &DHCPLEASEQUERYDONE => String::from("DHCPLEASEQUERYDONE"),
+            &DHCPACTIVELEASEQUERY => String::from("DHCPACTIVELEASEQUERY"),
+            &DHCPLEASEQUERYSTATUS => String::from("DHCPLEASEQUERYSTATUS"),
+            &DHCPTLS => String::from("DHCPTLS"),
             MessageType(x) => format!("#{}", x),
         }
     }
@@ -195,6 +227,8 @@ pub const OPTION_VENDOR_CLASS: DhcpOption = DhcpOption(60);
 pub const OPTION_CLIENTID: DhcpOption = DhcpOption(61);
 pub const OPTION_NIS3DOMAIN: DhcpOption = DhcpOption(64);
 pub const OPTION_NIS3SERVERS: DhcpOption = DhcpOption(65);
+pub const OPTION_TFTPSERVER: DhcpOption = DhcpOption(66);
+pub const OPTION_BOOTFILE: DhcpOption = DhcpOption(67);
 pub const OPTION_HOMEAGENT: DhcpOption = DhcpOption(68);
 pub const OPTION_SMTP: DhcpOption = DhcpOption(69);
 pub const OPTION_POP3: DhcpOption = DhcpOption(70);
@@ -205,15 +239,29 @@ pub const OPTION_IRC: DhcpOption = DhcpOption(74);
 pub const OPTION_STREETTALK: DhcpOption = DhcpOption(75);
 pub const OPTION_STDA: DhcpOption = DhcpOption(76);
 pub const OPTION_USERCLASS: DhcpOption = DhcpOption(77); /* RFC3004 */
+pub const OPTION_RAPID_COMMIT: DhcpOption = DhcpOption(80); /* RFC4039 */
 pub const OPTION_FQDN: DhcpOption = DhcpOption(81); /* RFC4702 */
+pub const OPTION_RELAYAGENTINFO: DhcpOption = DhcpOption(82); /* RFC3046 */
+pub const OPTION_AUTHENTICATION: DhcpOption = DhcpOption(90); /* RFC3118 */
+pub const OPTION_CLIENT_LAST_TRANSACTION_TIME: DhcpOption = DhcpOption(91); /* RFC4388 */
+pub const OPTION_ASSOCIATED_IP: DhcpOption = DhcpOption(92); /* RFC4388 */
+pub const OPTION_CLIENTARCH: DhcpOption = DhcpOption(93); /* RFC4578 */
 pub const OPTION_UUID: DhcpOption = DhcpOption(97); /* RFC4578 */
 pub const OPTION_PCODE: DhcpOption = DhcpOption(100); /* RFC4833 */
 pub const OPTION_TCODE: DhcpOption = DhcpOption(101); /* RFC4833 */
 pub const OPTION_AUTOCONF: DhcpOption = DhcpOption(103);
-pub const OPTION_SUBNETSELECT: DhcpOption = DhcpOption(104);
+pub const OPTION_IPV6_ONLY_PREFERRED: DhcpOption = DhcpOption(108); /* RFC8925 */
+pub const OPTION_SUBNETSELECT: DhcpOption = DhcpOption(118); /* RFC3011 */
 pub const OPTION_DOMAINSEARCH: DhcpOption = DhcpOption(119);
 pub const OPTION_SIPSERVERS: DhcpOption = DhcpOption(120);
 pub const OPTION_CIDRROUTE: DhcpOption = DhcpOption(121);
+pub const OPTION_FORCERENEW_NONCE_CAPABLE: DhcpOption = DhcpOption(145); /* RFC6704 */
+pub const OPTION_STATUS_CODE: DhcpOption = DhcpOption(151); /* RFC6926 */
+pub const OPTION_BASE_TIME: DhcpOption = DhcpOption(152); /* RFC6926 */
+pub const OPTION_START_TIME_OF_STATE: DhcpOption = DhcpOption(153); /* RFC6926 */
+pub const OPTION_QUERY_START_TIME: DhcpOption = DhcpOption(154); /* RFC6926 */
+pub const OPTION_QUERY_END_TIME: DhcpOption = DhcpOption(155); /* RFC6926 */
+pub const OPTION_DHCP_STATE: DhcpOption = DhcpOption(156); /* RFC6926 */
 pub const OPTION_CAPTIVEPORTAL: DhcpOption = DhcpOption(160);
 pub const OPTION_WPAD: DhcpOption = DhcpOption(252);
 
@@ -359,7 +407,7 @@ const OPT_INFO: &[(&str, DhcpOption, DhcpOptionType)] = &[
     // 80
     //("rapid-commit", OPTION_RAPID_COMMIT
     ("fqdn", OPTION_FQDN, DhcpOptionType::String),
-    // option 82 (relay agent information) needs special handling.
+    // option 82 (relay agent information) needs special handling, see get_relay_agent_suboption.
     // iSNS
     // NDS Servers
     // NDS Tree
@@ -370,7 +418,11 @@ const OPT_INFO: &[(&str, DhcpOption, DhcpOptionType)] = &[
     // Authentication, needs special handling
     // client-last-transaction-time, RFC4388
     // associated-ip, RFC4388
-    // client-system, RFC4578
+    (
+        "client-architecture",
+        OPTION_CLIENTARCH,
+        DhcpOptionType::U16List,
+    ), // RFC4578
     // client-ndi, RFC4578
     // ldap, RFC3679
     //
@@ -382,7 +434,8 @@ const OPT_INFO: &[(&str, DhcpOption, DhcpOptionType)] = &[
     ("tz-name", OPTION_TCODE, DhcpOptionType::String),
     // uuid/guid
     ("autoconfig", OPTION_AUTOCONF, DhcpOptionType::Bool),
-    ("subnet-selection", OPTION_SUBNETSELECT, DhcpOptionType::Ip), // RFC3011 -- needs better support
+    // ipv6-only-preferred, handled specially.
+    ("subnet-selection", OPTION_SUBNETSELECT, DhcpOptionType::Ip), // RFC3011
     (
         "dns-searches",
         OPTION_DOMAINSEARCH,
@@ -416,6 +469,7 @@ pub enum DhcpOptionType {
     I32,
     U8,
     U16,
+    U16List,
     U32,
     Bool,
     Seconds16,
@@ -439,6 +493,7 @@ impl DhcpOptionType {
             DhcpOptionType::I32 => i32::parse_into(v).map(DhcpOptionTypeValue::I32),
             DhcpOptionType::U8 => u8::parse_into(v).map(DhcpOptionTypeValue::U8),
             DhcpOptionType::U16 => u16::parse_into(v).map(DhcpOptionTypeValue::U16),
+            DhcpOptionType::U16List => Vec::<u16>::parse_into(v).map(DhcpOptionTypeValue::U16List),
             DhcpOptionType::U32 => u32::parse_into(v).map(DhcpOptionTypeValue::U32),
             DhcpOptionType::Bool => u8::parse_into(v).map(DhcpOptionTypeValue::U8), // ?
             DhcpOptionType::Seconds16 => u16::parse_into(v).map(DhcpOptionTypeValue::U16), // ?
@@ -461,6 +516,7 @@ pub enum DhcpOptionTypeValue {
     I32(i32),
     U8(u8),
     U16(u16),
+    U16List(Vec<u16>),
     U32(u32),
     HwAddr(Vec<u8>),
     Routes(Vec<Route>),
@@ -482,6 +538,7 @@ impl DhcpOptionTypeValue {
             DhcpOptionTypeValue::I32(x) => x.to_be_bytes().to_vec(),
             DhcpOptionTypeValue::U8(x) => x.to_be_bytes().to_vec(),
             DhcpOptionTypeValue::U16(x) => x.to_be_bytes().to_vec(),
+            DhcpOptionTypeValue::U16List(v) => v.iter().flat_map(|x| x.to_be_bytes()).collect(),
             DhcpOptionTypeValue::U32(x) => x.to_be_bytes().to_vec(),
             DhcpOptionTypeValue::HwAddr(x) => x.clone(),
             DhcpOptionTypeValue::Routes(v) => {
@@ -527,6 +584,14 @@ impl std::fmt::Display for DhcpOptionTypeValue {
             DhcpOptionTypeValue::Ip(i) => i.fmt(f),
             DhcpOptionTypeValue::U8(i) => i.fmt(f),
             DhcpOptionTypeValue::U16(i) => i.fmt(f),
+            DhcpOptionTypeValue::U16List(l) => write!(
+                f,
+                "{}",
+                l.iter()
+                    .map(|i| format!("{}", i))
+                    .collect::<Vec<String>>()
+                    .join(",")
+            ),
             DhcpOptionTypeValue::U32(i) => i.fmt(f),
             DhcpOptionTypeValue::I32(i) => i.fmt(f),
             DhcpOptionTypeValue::IpList(l) => write!(
@@ -702,6 +767,17 @@ impl DhcpParse for u16 {
     }
 }
 
+impl DhcpParse for Vec<u16> {
+    type Item = Self;
+    fn parse_into(v: &[u8]) -> Option<Self> {
+        let chunks = v.chunks_exact(2);
+        if !chunks.remainder().is_empty() {
+            return None;
+        }
+        Some(chunks.map(|x| u16::from_be_bytes([x[0], x[1]])).collect())
+    }
+}
+
 impl DhcpParse for i32 {
     type Item = Self;
     fn parse_into(v: &[u8]) -> Option<Self> {
@@ -753,6 +829,46 @@ impl DhcpParse for String {
     }
 }
 
+/* RFC4702 Section 2.1: Flags in the Client FQDN option */
+pub const FQDN_FLAG_S: u8 = 0x01; /* Server should perform the A update */
+pub const FQDN_FLAG_O: u8 = 0x02; /* Server has overridden the client's S bit */
+pub const FQDN_FLAG_E: u8 = 0x04; /* Domain name is in canonical wire format */
+pub const FQDN_FLAG_N: u8 = 0x08; /* Server should not perform any updates */
+
+/// The Client Fully Qualified Domain Name option (RFC4702).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ClientFqdn {
+    pub flags: u8,
+    /// The name, without a trailing '.'.  May be a partial name (eg just the hostname).
+    pub name: String,
+}
+
+impl DhcpParse for ClientFqdn {
+    type Item = Self;
+    fn parse_into(v: &[u8]) -> Option<Self> {
+        /* The two RCODE bytes are deprecated and ignored by servers (RFC4702 Section 2.2) */
+        let (&flags, rest) = v.split_first()?;
+        let name = rest.get(2..)?;
+        let name = if flags & FQDN_FLAG_E != 0 {
+            /* A partial name isn't terminated by an empty label, so parse labels by hand */
+            let mut buf = pktparser::Buffer::new(name);
+            let mut labels = vec![];
+            while let Some(len) = buf.get_u8() {
+                if len == 0 {
+                    break;
+                }
+                labels.push(String::from_utf8_lossy(buf.get_bytes(len as usize)?).to_string());
+            }
+            labels.join(".")
+        } else {
+            String::from_utf8_lossy(name)
+                .trim_end_matches('.')
+                .to_string()
+        };
+        Some(ClientFqdn { flags, name })
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Default, Eq)]
 pub struct DhcpOptions {
     pub other: collections::HashMap<DhcpOption, Vec<u8>>,
@@ -787,6 +903,65 @@ impl DhcpOptions {
         self.get_option::<String>(&OPTION_HOSTNAME)
     }
 
+    pub fn get_client_fqdn(&self) -> Option<ClientFqdn> {
+        self.get_option::<ClientFqdn>(&OPTION_FQDN)
+    }
+
+    /// The client will accept a DHCPACK in reply to its DHCPDISCOVER (RFC4039).
+    pub fn has_rapid_commit(&self) -> bool {
+        self.other.contains_key(&OPTION_RAPID_COMMIT)
+    }
+
+    /// Returns the classes from the User Class option (RFC3004).
+    ///
+    /// Some clients (notably iPXE) send a bare string rather than a list of length prefixed
+    /// classes, so if the option doesn't parse as a list, the whole option is returned as the only
+    /// class.
+    pub fn get_user_classes(&self) -> Option<Vec<Vec<u8>>> {
+        let raw = self.other.get(&OPTION_USERCLASS)?;
+        let mut buf = pktparser::Buffer::new(raw);
+        let mut classes = vec![];
+        while let Some(len) = buf.get_u8() {
+            match buf.get_bytes(len as usize) {
+                Some(class) if len > 0 => classes.push(class.to_vec()),
+                _ => return Some(vec![raw.clone()]),
+            }
+        }
+        if classes.is_empty() {
+            Some(vec![raw.clone()])
+        } else {
+            Some(classes)
+        }
+    }
+
+    /// Returns the Parameter Request List as a comma separated list of option numbers, in the
+    /// order the client sent them, eg "1,3,6,15".
+    ///
+    /// Clients of the same type tend to send the same list, so this makes a reasonable
+    /// fingerprint of the client's operating system.
+    pub fn get_parameter_list_fingerprint(&self) -> Option<String> {
+        Some(
+            self.get_raw_option(&OPTION_PARAMLIST)?
+                .iter()
+                .map(|o| o.to_string())
+                .collect::<Vec<_>>()
+                .join(","),
+        )
+    }
+
+    /// Returns a sub-option from the Relay Agent Information option (RFC3046).
+    pub fn get_relay_agent_suboption(&self, suboption: u8) -> Option<Vec<u8>> {
+        let mut buf = pktparser::Buffer::new(self.other.get(&OPTION_RELAYAGENTINFO)?);
+        while let Some(code) = buf.get_u8() {
+            let len = buf.get_u8()?;
+            let value = buf.get_bytes(len as usize)?;
+            if code == suboption {
+                return Some(value.to_vec());
+            }
+        }
+        None
+    }
+
     #[must_use]
     pub fn set_raw_option(mut self, option: &DhcpOption, value: &[u8]) -> Self {
         self.other.insert(*option, value.to_vec());
@@ -1031,6 +1206,24 @@ impl Serialise for String {
     }
 }
 
+impl Serialise for ClientFqdn {
+    fn serialise(&self, v: &mut Vec<u8>) {
+        v.push(self.flags);
+        /* RFC4702 Section 2.2: Servers set both RCODEs to 255 */
+        v.push(255);
+        v.push(255);
+        if self.flags & FQDN_FLAG_E != 0 {
+            for label in self.name.split('.').filter(|l| !l.is_empty()) {
+                v.push(label.len() as u8);
+                v.extend(label.as_bytes());
+            }
+            v.push(0);
+        } else {
+            v.extend(self.name.as_bytes());
+        }
+    }
+}
+
 impl Serialise for i32 {
     fn serialise(&self, v: &mut Vec<u8>) {
         for i in self.to_be_bytes().iter() {
@@ -1139,6 +1332,10 @@ fn test_type_serialisation() {
         serialise_one_for_test(DhcpOptionTypeValue::U16(258)),
         vec![1, 2],
     );
+    assert_eq!(
+        serialise_one_for_test(DhcpOptionTypeValue::U16List(vec![7, 258])),
+        vec![0, 7, 1, 2],
+    );
     assert_eq!(
         serialise_one_for_test(DhcpOptionTypeValue::U32(16909060)),
         vec![1, 2, 3, 4]
@@ -1203,6 +1400,14 @@ fn test_parse() {
         format!("{}", DhcpOptionType::U16.decode(&vec![1, 2]).unwrap()),
         "258",
     );
+    assert_eq!(
+        format!(
+            "{}",
+            DhcpOptionType::U16List.decode(&vec![0, 7, 1, 2]).unwrap()
+        ),
+        "7,258",
+    );
+    assert!(DhcpOptionType::U16List.decode(&vec![0, 7, 1]).is_none());
     assert_eq!(
         format!("{}", DhcpOptionType::U32.decode(&vec![1, 2, 3, 4]).unwrap()),
         "16909060",
@@ -1252,3 +1457,76 @@ fn test_parse() {
         "192.0.2.0/24->192.0.2.254,198.51.100.0/24->192.0.2.254"
     );
 }
+
+#[test]
+fn test_client_fqdn() {
+    /* Canonical wire format, as sent by most modern clients */
+    let wire = [
+        FQDN_FLAG_E | FQDN_FLAG_S,
+        0,
+        0,
+        4,
+        b'h',
+        b'o',
+        b's',
+        b't',
+        7,
+        b'e',
+        b'x',
+        b'a',
+        b'm',
+        b'p',
+        b'l',
+        b'e',
+        0,
+    ];
+    let fqdn = ClientFqdn::parse_into(&wire).unwrap();
+    assert_eq!(
+        fqdn,
+        ClientFqdn {
+            flags: FQDN_FLAG_E | FQDN_FLAG_S,
+            name: "host.example".into()
+        }
+    );
+    let mut v = vec![];
+    fqdn.serialise(&mut v);
+    assert_eq!(v[3..], wire[3..]);
+    assert_eq!(v[1..3], [255, 255]);
+    /* Deprecated ASCII encoding, with a partial name */
+    assert_eq!(
+        ClientFqdn::parse_into(b"\x00\x00\x00host").unwrap(),
+        ClientFqdn {
+            flags: 0,
+            name: "host".into()
+        }
+    );
+    /* Truncated */
+    assert_eq!(ClientFqdn::parse_into(&[FQDN_FLAG_E, 0]), None);
+    assert_eq!(ClientFqdn::parse_into(&[FQDN_FLAG_E, 0, 0, 4, b'h']), None);
+}
+
+#[test]
+fn test_user_classes() {
+    let opts = DhcpOptions::default();
+    assert_eq!(opts.get_user_classes(), None);
+    /* RFC3004 formatted */
+    let opts = DhcpOptions::default().set_raw_option(&OPTION_USERCLASS, b"\x04iPXE\x03lab");
+    assert_eq!(
+        opts.get_user_classes(),
+        Some(vec![b"iPXE".to_vec(), b"lab".to_vec()])
+    );
+    /* A bare string, as sent by iPXE */
+    let opts = DhcpOptions::default().set_raw_option(&OPTION_USERCLASS, b"iPXE");
+    assert_eq!(opts.get_user_classes(), Some(vec![b"iPXE".to_vec()]));
+}
+
+#[test]
+fn test_parameter_list_fingerprint() {
+    let opts = DhcpOptions::default();
+    assert_eq!(opts.get_parameter_list_fingerprint(), None);
+    let opts = DhcpOptions::default().set_raw_option(&OPTION_PARAMLIST, &[1, 121, 3, 6, 15]);
+    assert_eq!(
+        opts.get_parameter_list_fingerprint(),
+        Some("1,121,3,6,15".into())
+    );
+}
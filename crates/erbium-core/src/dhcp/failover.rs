@@ -0,0 +1,967 @@
+/*   Copyright 2023 Perry Lorier
+ *
+ *  Licensed under the Apache License, Version 2.0 (the "License");
+ *  you may not use this file except in compliance with the License.
+ *  You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ *  Unless required by applicable law or agreed to in writing, software
+ *  distributed under the License is distributed on an "AS IS" BASIS,
+ *  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ *  See the License for the specific language governing permissions and
+ *  limitations under the License.
+ *
+ *  SPDX-License-Identifier: Apache-2.0
+ *
+ *  DHCP failover between two erbium instances.
+ *
+ *  This is a much simplified version of the ISC failover protocol (draft-ietf-dhc-failover-12).
+ *  Each server connects to the other over TCP and sends it every lease it changes, one per line,
+ *  in the same format as the append only lease store.  The receiving server stores the lease and
+ *  acknowledges it.  On connecting, a server sends every lease it knows about, and the receiver
+ *  keeps whichever of its own lease and the peer's lease started most recently.
+ *
+ *  Both servers are configured with a shared secret.  The receiving server starts each connection
+ *  by sending a random challenge, and every line after that starts with an HMAC-SHA256 of the
+ *  challenge, the line's sequence number and the line itself.  Without the secret, the peer can't
+ *  be impersonated, and lines can't be altered, reordered or replayed.
+ *
+ *  The Maximum Client Lead Time (MCLT) keeps leases safe if the servers can't talk to each other:
+ *  a client is never given a lease that lasts more than MCLT past what the peer has acknowledged.
+ *  A new client gets a lease of MCLT at first, which is extended to the full lease when it renews
+ *  (by which time the peer has acknowledged the full lease).  The pool applies this limit before
+ *  it stores the lease, so the lease we store and send to the peer is the one the client got.
+ */
+
+use super::pool::{self, LeaseInfo, PoolAddresses};
+use super::{ServerIds, SharedServerIds};
+use crate::config::{parse_duration, parse_string, Error};
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use tokio::io::{AsyncBufReadExt as _, AsyncWriteExt as _};
+use tokio::sync::{mpsc, Mutex};
+use yaml_rust::yaml;
+
+/// The IANA assigned port for DHCP failover.
+pub const DEFAULT_PORT: u16 = 647;
+pub const DEFAULT_MCLT: std::time::Duration = std::time::Duration::from_secs(3600);
+/// How often we tell the peer we're still alive.
+const HEARTBEAT_INTERVAL: std::time::Duration = std::time::Duration::from_secs(10);
+/// How long without hearing from the peer before we decide it's down.
+const PEER_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(30);
+const RECONNECT_INTERVAL: std::time::Duration = std::time::Duration::from_secs(5);
+/// How many lease updates can be waiting to be sent, before we give up and resync everything.
+const MAX_QUEUED_UPDATES: usize = 4096;
+const CHALLENGE_LEN: usize = 16;
+
+lazy_static::lazy_static! {
+    static ref DHCP_FAILOVER_PEER_UP: prometheus::IntGauge = prometheus::register_int_gauge!(
+        "dhcp_failover_peer_up",
+        "1 if we can currently talk to the DHCP failover peer"
+    )
+    .unwrap();
+    static ref DHCP_FAILOVER_UPDATES: prometheus::IntCounterVec =
+        prometheus::register_int_counter_vec!(
+            "dhcp_failover_updates",
+            "Number of lease updates exchanged with the DHCP failover peer",
+            &["direction"]
+        )
+        .unwrap();
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Role {
+    Primary,
+    Secondary,
+}
+
+impl Role {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Role::Primary => "primary",
+            Role::Secondary => "secondary",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "primary" => Some(Role::Primary),
+            "secondary" => Some(Role::Secondary),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Mode {
+    /// The primary answers every client, the secondary only answers when the primary is down.
+    ActiveStandby,
+    /// Both servers answer every client, but each only hands out new addresses from its own half
+    /// of each pool.
+    Split,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Config {
+    pub role: Role,
+    pub mode: Mode,
+    pub peer: SocketAddr,
+    pub listen: SocketAddr,
+    pub mclt: std::time::Duration,
+    /// Shared with the peer, to authenticate the connection.
+    pub secret: Vec<u8>,
+}
+
+impl Config {
+    /// Returns true if we can hand out `ip` to a new client.  In split mode the primary owns the
+    /// even addresses and the secondary owns the odd addresses.
+    pub fn owns_address(&self, ip: Ipv4Addr) -> bool {
+        match self.mode {
+            Mode::ActiveStandby => true,
+            Mode::Split => (u32::from(ip) % 2 == 0) == (self.role == Role::Primary),
+        }
+    }
+}
+
+/// The addresses a client can be offered: our share of the pool, plus any address the client
+/// already holds (which the peer may have given it).
+pub fn filter_addresses<'a>(
+    conf: Option<&Config>,
+    addresses: &'a PoolAddresses,
+    held: &[Ipv4Addr],
+) -> std::borrow::Cow<'a, PoolAddresses> {
+    match conf {
+        Some(conf) if conf.mode == Mode::Split => std::borrow::Cow::Owned(
+            addresses
+                .iter()
+                .filter(|ip| conf.owns_address(**ip) || held.contains(ip))
+                .copied()
+                .collect(),
+        ),
+        _ => std::borrow::Cow::Borrowed(addresses),
+    }
+}
+
+fn parse_addr(name: &str, addr: String) -> Result<SocketAddr, Error> {
+    addr.parse::<SocketAddr>()
+        .or_else(|_| {
+            addr.parse::<std::net::IpAddr>()
+                .map(|ip| SocketAddr::new(ip, DEFAULT_PORT))
+        })
+        .map_err(|e| Error::InvalidConfig(format!("{} {:?}: {}", name, addr, e)))
+}
+
+pub fn parse(name: &str, fragment: &yaml::Yaml) -> Result<Option<Config>, Error> {
+    let h = match fragment {
+        yaml::Yaml::Null => return Ok(None),
+        yaml::Yaml::Hash(h) => h,
+        e => {
+            return Err(Error::InvalidConfig(format!(
+                "{} should be of type Hash, not {}",
+                name,
+                crate::config::type_to_name(e)
+            )))
+        }
+    };
+    let mut role = None;
+    let mut mode = None;
+    let mut peer = None;
+    let mut listen = None;
+    let mut mclt = None;
+    let mut secret = None;
+    for (k, v) in h {
+        match k.as_str() {
+            Some("role") => role = parse_string("role", v)?,
+            Some("mode") => mode = parse_string("mode", v)?,
+            Some("peer") => peer = parse_string("peer", v)?,
+            Some("listen") => listen = parse_string("listen", v)?,
+            Some("mclt") => mclt = parse_duration("mclt", v)?,
+            Some("secret") => secret = parse_string("secret", v)?,
+            Some(e) => {
+                return Err(Error::InvalidConfig(format!(
+                    "Unexpected key in {}: {}",
+                    name, e
+                )))
+            }
+            None => {
+                return Err(Error::InvalidConfig(format!(
+                    "{} key is not a string, instead: '{:?}'",
+                    name, k
+                )))
+            }
+        }
+    }
+    let role = role.ok_or_else(|| Error::InvalidConfig(format!("Missing role in {}", name)))?;
+    let role = Role::from_name(&role).ok_or_else(|| {
+        Error::InvalidConfig(format!(
+            "Unknown role {:?}, expected primary or secondary",
+            role
+        ))
+    })?;
+    let mode = match mode.as_deref() {
+        None | Some("active-standby") => Mode::ActiveStandby,
+        Some("split") => Mode::Split,
+        Some(m) => {
+            return Err(Error::InvalidConfig(format!(
+                "Unknown mode {:?}, expected active-standby or split",
+                m
+            )))
+        }
+    };
+    let peer = parse_addr(
+        "peer",
+        peer.ok_or_else(|| Error::InvalidConfig(format!("Missing peer in {}", name)))?,
+    )?;
+    let listen = listen
+        .map(|l| parse_addr("listen", l))
+        .transpose()?
+        .unwrap_or_else(|| SocketAddr::new(std::net::Ipv6Addr::UNSPECIFIED.into(), DEFAULT_PORT));
+    let secret = secret
+        .filter(|secret| !secret.is_empty())
+        .ok_or_else(|| Error::InvalidConfig(format!("Missing secret in {}", name)))?;
+    Ok(Some(Config {
+        role,
+        mode,
+        peer,
+        listen,
+        mclt: mclt.unwrap_or(DEFAULT_MCLT),
+        secret: secret.into_bytes(),
+    }))
+}
+
+/* ---- Protocol ---- */
+
+#[derive(Debug, PartialEq, Eq)]
+enum Message {
+    /// Sent unsigned by the receiver when the connection starts, to be included in every HMAC.
+    Challenge(Vec<u8>),
+    /// Sent when connecting and as a heartbeat, with the sender's server identifiers.
+    Hello(Role, Vec<Ipv4Addr>),
+    /// A lease sent when connecting, the newest lease wins.
+    Sync(LeaseInfo),
+    /// A lease that has just changed.
+    Lease(LeaseInfo),
+    /// The lease the receiver now has for an address.
+    Ack(Ipv4Addr, Vec<u8>, u32),
+}
+
+impl Message {
+    fn format(&self) -> String {
+        use super::store::{encode_hex, format_line};
+        match self {
+            Message::Challenge(challenge) => format!("CHALLENGE {}\n", encode_hex(challenge)),
+            Message::Hello(role, serverids) => format!(
+                "HELLO {}{}\n",
+                role.as_str(),
+                serverids
+                    .iter()
+                    .map(|si| format!(" {}", si))
+                    .collect::<String>()
+            ),
+            Message::Sync(lease) => format!("SYNC {}", format_line(lease)),
+            Message::Lease(lease) => format!("LEASE {}", format_line(lease)),
+            Message::Ack(ip, client_id, expire) => {
+                format!("ACK {} {} {}\n", ip, encode_hex(client_id), expire)
+            }
+        }
+    }
+
+    fn parse(line: &str) -> Option<Self> {
+        use super::store::{decode_hex, parse_line};
+        let (kind, rest) = line.split_once(' ')?;
+        match kind {
+            "CHALLENGE" => decode_hex(rest).map(Message::Challenge),
+            "HELLO" => {
+                let mut words = rest.split(' ');
+                let role = Role::from_name(words.next()?)?;
+                Some(Message::Hello(
+                    role,
+                    words.map(|w| w.parse().ok()).collect::<Option<Vec<_>>>()?,
+                ))
+            }
+            "SYNC" => parse_line(rest).map(Message::Sync),
+            "LEASE" => parse_line(rest).map(Message::Lease),
+            "ACK" => match rest.split(' ').collect::<Vec<_>>().as_slice() {
+                [ip, client_id, expire] => Some(Message::Ack(
+                    ip.parse().ok()?,
+                    decode_hex(client_id)?,
+                    expire.parse().ok()?,
+                )),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+}
+
+/// Signs the lines we send over a connection, and checks the lines we receive, using the
+/// connection's challenge.  Each direction has its own sequence numbers.
+struct Signer<'a> {
+    secret: &'a [u8],
+    challenge: Vec<u8>,
+    sent: u64,
+    received: u64,
+}
+
+impl<'a> Signer<'a> {
+    fn new(secret: &'a [u8], challenge: Vec<u8>) -> Self {
+        Self {
+            secret,
+            challenge,
+            sent: 0,
+            received: 0,
+        }
+    }
+
+    fn hmac(&self, seq: u64, line: &str) -> Vec<u8> {
+        use crypto::mac::Mac as _;
+        let mut hmac = crypto::hmac::Hmac::new(crypto::sha2::Sha256::new(), self.secret);
+        hmac.input(&self.challenge);
+        hmac.input(&seq.to_be_bytes());
+        hmac.input(line.as_bytes());
+        hmac.result().code().to_vec()
+    }
+
+    fn sign(&mut self, msg: &Message) -> String {
+        let line = msg.format();
+        let line = line.trim_end();
+        let hmac = self.hmac(self.sent, line);
+        self.sent += 1;
+        format!("{} {}\n", super::store::encode_hex(&hmac), line)
+    }
+
+    /// Returns a line without its HMAC, or None if it isn't signed correctly.
+    fn verify<'l>(&mut self, line: &'l str) -> Option<&'l str> {
+        let (hmac, line) = line.split_once(' ')?;
+        let hmac = super::store::decode_hex(hmac)?;
+        if !crypto::util::fixed_time_eq(&hmac, &self.hmac(self.received, line)) {
+            return None;
+        }
+        self.received += 1;
+        Some(line)
+    }
+}
+
+fn bad_signature(line: &str) -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        format!("Bad signature on {:?}, check the secret", line),
+    )
+}
+
+/// Decides which of two leases for the same address to keep when syncing.
+fn is_newer(lease: &LeaseInfo, than: &LeaseInfo) -> bool {
+    (lease.start, lease.expire) > (than.start, than.expire)
+}
+
+/// The longest lease (in seconds) we can give a client, given the expiry the peer has
+/// acknowledged for it.
+fn max_lease_time(acked_expire: Option<u32>, now: u32, mclt: std::time::Duration) -> u32 {
+    acked_expire
+        .unwrap_or(0)
+        .saturating_sub(now)
+        .saturating_add(u32::try_from(mclt.as_secs()).unwrap_or(u32::MAX))
+}
+
+fn now() -> u32 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs() as u32)
+        .unwrap_or(0)
+}
+
+fn is_from(addr: SocketAddr, peer: SocketAddr) -> bool {
+    let ip = match addr.ip() {
+        std::net::IpAddr::V6(v6) => v6
+            .to_ipv4_mapped()
+            .map(std::net::IpAddr::V4)
+            .unwrap_or(std::net::IpAddr::V6(v6)),
+        ip => ip,
+    };
+    ip == peer.ip()
+}
+
+/// The client and expiry the peer has acknowledged for each address.  This is consulted by the
+/// pool while it's locked, so it can't be behind an async lock.
+type Acked = Arc<std::sync::Mutex<HashMap<Ipv4Addr, (Vec<u8>, u32)>>>;
+
+#[derive(Default)]
+struct State {
+    last_heard: Option<std::time::Instant>,
+    peer_serverids: ServerIds,
+}
+
+pub struct Failover {
+    conf: Config,
+    pool: Arc<Mutex<pool::Pool>>,
+    serverids: SharedServerIds,
+    state: Mutex<State>,
+    acked: Acked,
+    updates: Mutex<mpsc::Receiver<LeaseInfo>>,
+    /// Set if we've dropped updates, and need to send everything again.
+    resync: Arc<AtomicBool>,
+}
+
+impl Failover {
+    /// Sets up failover, starts watching the pool for leases to send to the peer, and limits the
+    /// leases the pool hands out to MCLT past what the peer has acknowledged.
+    pub async fn new(
+        conf: Config,
+        pool: Arc<Mutex<pool::Pool>>,
+        serverids: SharedServerIds,
+    ) -> Arc<Self> {
+        let (tx, rx) = mpsc::channel(MAX_QUEUED_UPDATES);
+        let resync = Arc::new(AtomicBool::new(false));
+        let overflow = resync.clone();
+        let acked: Acked = Default::default();
+        let limit_acked = acked.clone();
+        let mclt = conf.mclt;
+        {
+            let mut pool = pool.lock().await;
+            pool.add_observer(Box::new(move |event: &pool::Event| {
+                /* Never wait here, the pool is locked */
+                if tx.try_send(event.lease.clone()).is_err() {
+                    overflow.store(true, Ordering::Relaxed);
+                }
+            }));
+            pool.set_lease_limit(Box::new(move |ip, client_id| {
+                let acked = limit_acked
+                    .lock()
+                    .unwrap()
+                    .get(&ip)
+                    .filter(|(acked_client, _)| acked_client == client_id)
+                    .map(|(_, expire)| *expire);
+                std::time::Duration::from_secs(max_lease_time(acked, now(), mclt).into())
+            }));
+        }
+        Arc::new(Self {
+            conf,
+            pool,
+            serverids,
+            state: Default::default(),
+            acked,
+            updates: Mutex::new(rx),
+            resync,
+        })
+    }
+
+    pub async fn is_peer_up(&self) -> bool {
+        self.state
+            .lock()
+            .await
+            .last_heard
+            .map(|t| t.elapsed() < PEER_TIMEOUT)
+            .unwrap_or(false)
+    }
+
+    /// Returns false if the peer is handling DHCP at the moment, and we should stay quiet.
+    pub async fn should_serve(&self) -> bool {
+        match (self.conf.mode, self.conf.role) {
+            (Mode::Split, _) | (Mode::ActiveStandby, Role::Primary) => true,
+            (Mode::ActiveStandby, Role::Secondary) => !self.is_peer_up().await,
+        }
+    }
+
+    pub async fn is_peer_serverid(&self, serverid: Ipv4Addr) -> bool {
+        self.state.lock().await.peer_serverids.contains(&serverid)
+    }
+
+    /// The server identifiers we answer to.  While the peer is down we take over its identifiers
+    /// too, so that clients that were talking to it can still get an answer.
+    pub async fn get_serverids(&self, mut serverids: ServerIds) -> ServerIds {
+        if !self.is_peer_up().await {
+            serverids.extend(self.state.lock().await.peer_serverids.iter());
+        }
+        serverids
+    }
+
+    async fn hello(&self) -> Message {
+        let mut serverids = self
+            .serverids
+            .lock()
+            .await
+            .iter()
+            .copied()
+            .collect::<Vec<_>>();
+        serverids.sort();
+        Message::Hello(self.conf.role, serverids)
+    }
+
+    /// Stores a lease from the peer, and returns the acknowledgement to send back.
+    async fn apply(&self, lease: LeaseInfo, sync: bool) -> Result<Message, pool::Error> {
+        DHCP_FAILOVER_UPDATES.with_label_values(&["received"]).inc();
+        let mut pool = self.pool.lock().await;
+        let lease = match pool.get_lease(lease.ip)? {
+            Some(current) if sync && is_newer(&current, &lease) => current,
+            _ => {
+                pool.restore_lease(lease.clone())?;
+                lease
+            }
+        };
+        Ok(Message::Ack(lease.ip, lease.client_id, lease.expire))
+    }
+
+    async fn send_all(
+        &self,
+        writer: &mut (impl tokio::io::AsyncWrite + Unpin),
+        signer: &mut Signer<'_>,
+    ) -> Result<(), std::io::Error> {
+        let leases = self
+            .pool
+            .lock()
+            .await
+            .get_leases()
+            .map_err(|e| std::io::Error::other(e.to_string()))?;
+        for lease in leases {
+            DHCP_FAILOVER_UPDATES.with_label_values(&["sent"]).inc();
+            writer
+                .write_all(signer.sign(&Message::Sync(lease)).as_bytes())
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Sends our leases to the peer, for as long as the connection lasts.
+    async fn send_to_peer(&self, stream: tokio::net::TcpStream) -> Result<(), std::io::Error> {
+        let (reader, mut writer) = stream.into_split();
+        let mut lines = tokio::io::BufReader::new(reader).lines();
+        let challenge = match tokio::time::timeout(PEER_TIMEOUT, lines.next_line())
+            .await
+            .map_err(|_| std::io::Error::from(std::io::ErrorKind::TimedOut))??
+            .as_deref()
+            .and_then(Message::parse)
+        {
+            Some(Message::Challenge(challenge)) => challenge,
+            _ => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    "Expected a challenge",
+                ))
+            }
+        };
+        let mut signer = Signer::new(&self.conf.secret, challenge);
+        let mut updates = self.updates.lock().await;
+        /* Anything already queued is covered by sending everything */
+        while updates.try_recv().is_ok() {}
+        self.resync.store(false, Ordering::Relaxed);
+        writer
+            .write_all(signer.sign(&self.hello().await).as_bytes())
+            .await?;
+        self.send_all(&mut writer, &mut signer).await?;
+        let mut heartbeat = tokio::time::interval(HEARTBEAT_INTERVAL);
+        loop {
+            tokio::select! {
+                _ = heartbeat.tick() => {
+                    if self.resync.swap(false, Ordering::Relaxed) {
+                        log::warn!("Too many lease updates queued for failover peer, resending all leases");
+                        self.send_all(&mut writer, &mut signer).await?;
+                    }
+                    writer.write_all(signer.sign(&self.hello().await).as_bytes()).await?;
+                    DHCP_FAILOVER_PEER_UP.set(self.is_peer_up().await.into());
+                }
+                lease = updates.recv() => match lease {
+                    Some(lease) => {
+                        DHCP_FAILOVER_UPDATES.with_label_values(&["sent"]).inc();
+                        writer.write_all(signer.sign(&Message::Lease(lease)).as_bytes()).await?;
+                    }
+                    None => return Ok(()),
+                },
+                line = lines.next_line() => match line? {
+                    Some(line) => match signer.verify(&line).map(Message::parse) {
+                        Some(Some(Message::Ack(ip, client_id, expire))) => {
+                            self.acked.lock().unwrap().insert(ip, (client_id, expire));
+                        }
+                        Some(_) => log::warn!("Unexpected message from failover peer: {:?}", line),
+                        None => return Err(bad_signature(&line)),
+                    },
+                    None => return Err(std::io::ErrorKind::UnexpectedEof.into()),
+                },
+            }
+        }
+    }
+
+    async fn connect_to_peer(self: Arc<Self>) {
+        loop {
+            match tokio::net::TcpStream::connect(self.conf.peer).await {
+                Ok(stream) => {
+                    log::info!("Connected to DHCP failover peer {}", self.conf.peer);
+                    if let Err(e) = self.send_to_peer(stream).await {
+                        log::warn!(
+                            "Lost connection to DHCP failover peer {}: {}",
+                            self.conf.peer,
+                            e
+                        );
+                    }
+                }
+                Err(e) => log::debug!(
+                    "Failed to connect to DHCP failover peer {}: {}",
+                    self.conf.peer,
+                    e
+                ),
+            }
+            DHCP_FAILOVER_PEER_UP.set(self.is_peer_up().await.into());
+            tokio::time::sleep(RECONNECT_INTERVAL).await;
+        }
+    }
+
+    /// Receives leases from the peer, for as long as the connection lasts.
+    async fn receive_from_peer(
+        self: Arc<Self>,
+        stream: tokio::net::TcpStream,
+    ) -> Result<(), std::io::Error> {
+        let (reader, mut writer) = stream.into_split();
+        let mut lines = tokio::io::BufReader::new(reader).lines();
+        let challenge = rand::random::<[u8; CHALLENGE_LEN]>().to_vec();
+        writer
+            .write_all(Message::Challenge(challenge.clone()).format().as_bytes())
+            .await?;
+        let mut signer = Signer::new(&self.conf.secret, challenge);
+        while let Some(line) = tokio::time::timeout(PEER_TIMEOUT, lines.next_line())
+            .await
+            .map_err(|_| std::io::Error::from(std::io::ErrorKind::TimedOut))??
+        {
+            let verified = signer.verify(&line).ok_or_else(|| bad_signature(&line))?;
+            self.state.lock().await.last_heard = Some(std::time::Instant::now());
+            let ack = match Message::parse(verified) {
+                Some(Message::Hello(role, serverids)) => {
+                    if role == self.conf.role {
+                        log::warn!("DHCP failover peer is also configured as {}", role.as_str());
+                    }
+                    self.state.lock().await.peer_serverids = serverids.into_iter().collect();
+                    continue;
+                }
+                Some(Message::Sync(lease)) => self.apply(lease, true).await,
+                Some(Message::Lease(lease)) => self.apply(lease, false).await,
+                _ => {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        format!("Unexpected message {:?}", line),
+                    ))
+                }
+            };
+            match ack {
+                Ok(ack) => writer.write_all(signer.sign(&ack).as_bytes()).await?,
+                Err(e) => log::warn!("Failed to store lease from failover peer: {}", e),
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn run_with_listener(
+        self: Arc<Self>,
+        listener: tokio::net::TcpListener,
+    ) -> Result<(), std::io::Error> {
+        tokio::spawn(self.clone().connect_to_peer());
+        loop {
+            let (stream, addr) = listener.accept().await?;
+            if !is_from(addr, self.conf.peer) {
+                log::warn!(
+                    "Ignoring DHCP failover connection from {}, which isn't our peer",
+                    addr
+                );
+                continue;
+            }
+            let self2 = self.clone();
+            tokio::spawn(async move {
+                if let Err(e) = self2.receive_from_peer(stream).await {
+                    log::warn!("DHCP failover connection from {} failed: {}", addr, e);
+                }
+            });
+        }
+    }
+
+    pub async fn run(self: Arc<Self>) -> Result<(), std::io::Error> {
+        let listener = tokio::net::TcpListener::bind(self.conf.listen).await?;
+        log::info!(
+            "Listening for DHCP failover as {} on {}",
+            self.conf.role.as_str(),
+            self.conf.listen
+        );
+        self.run_with_listener(listener).await
+    }
+}
+
+#[test]
+fn test_parse() {
+    let conf = parse(
+        "dhcp-failover",
+        &yaml::YamlLoader::load_from_str(
+            "{role: secondary, peer: 192.0.2.1, mode: split, secret: hunter2}",
+        )
+        .unwrap()[0],
+    )
+    .unwrap()
+    .unwrap();
+    assert_eq!(conf.role, Role::Secondary);
+    assert_eq!(conf.mode, Mode::Split);
+    assert_eq!(conf.peer, "192.0.2.1:647".parse().unwrap());
+    assert_eq!(conf.listen, "[::]:647".parse().unwrap());
+    assert_eq!(conf.mclt, DEFAULT_MCLT);
+    assert_eq!(conf.secret, b"hunter2");
+
+    for bad in [
+        "{peer: 192.0.2.1}",
+        "{role: tertiary, peer: 192.0.2.1}",
+        "{role: primary}",
+        "{role: primary, peer: 192.0.2.1, mode: both}",
+        "{role: primary, peer: 192.0.2.1, mclt: 1h, colour: blue}",
+        "{role: primary, peer: 192.0.2.1}",
+        "{role: primary, peer: 192.0.2.1, secret: ''}",
+    ] {
+        assert!(
+            parse(
+                "dhcp-failover",
+                &yaml::YamlLoader::load_from_str(bad).unwrap()[0]
+            )
+            .is_err(),
+            "{}",
+            bad
+        );
+    }
+}
+
+#[test]
+fn test_messages() {
+    let lease = LeaseInfo {
+        ip: "192.0.2.10".parse().unwrap(),
+        client_id: b"client".to_vec(),
+        chaddr: vec![0x00, 0x00, 0x5E, 0x00, 0x53, 0x00],
+        start: 100,
+        expire: 200,
+        options: vec![12, 1, b'a', 255],
+    };
+    for msg in [
+        Message::Hello(Role::Primary, vec![]),
+        Message::Hello(
+            Role::Secondary,
+            vec!["192.0.2.1".parse().unwrap(), "192.0.2.2".parse().unwrap()],
+        ),
+        Message::Sync(lease.clone()),
+        Message::Lease(lease.clone()),
+        Message::Ack(lease.ip, lease.client_id.clone(), lease.expire),
+        Message::Challenge(vec![1, 2, 3]),
+    ] {
+        let line = msg.format();
+        assert!(line.ends_with('\n'));
+        assert_eq!(Message::parse(line.trim_end()), Some(msg));
+    }
+    assert_eq!(Message::parse("HELLO tertiary"), None);
+    assert_eq!(Message::parse("LEASE garbage"), None);
+}
+
+#[test]
+fn test_signer() {
+    let msg = Message::Hello(Role::Primary, vec![]);
+    let mut sender = Signer::new(b"secret", vec![1, 2, 3]);
+    let mut receiver = Signer::new(b"secret", vec![1, 2, 3]);
+    let first = sender.sign(&msg);
+    let second = sender.sign(&msg);
+    /* Each line is signed differently, even if it says the same thing */
+    assert_ne!(first, second);
+    assert_eq!(receiver.verify(first.trim_end()), Some("HELLO primary"));
+    /* Lines can't be replayed, or signed with the wrong secret or for another connection */
+    assert_eq!(receiver.verify(first.trim_end()), None);
+    assert_eq!(
+        receiver.verify(Signer::new(b"wrong", vec![1, 2, 3]).sign(&msg).trim_end()),
+        None
+    );
+    assert_eq!(
+        receiver.verify(Signer::new(b"secret", vec![4, 5, 6]).sign(&msg).trim_end()),
+        None
+    );
+    assert_eq!(receiver.verify("HELLO primary"), None);
+    /* Rejected lines don't use up a sequence number */
+    assert_eq!(receiver.verify(second.trim_end()), Some("HELLO primary"));
+}
+
+#[test]
+fn test_max_lease_time() {
+    let mclt = std::time::Duration::from_secs(3600);
+    /* The peer doesn't know about the lease */
+    assert_eq!(max_lease_time(None, 1000, mclt), 3600);
+    /* The peer knows about an expired lease */
+    assert_eq!(max_lease_time(Some(500), 1000, mclt), 3600);
+    /* The peer knows the lease lasts another day */
+    assert_eq!(max_lease_time(Some(1000 + 86400), 1000, mclt), 86400 + 3600);
+}
+
+#[test]
+fn test_filter_addresses() {
+    let mut conf = parse(
+        "dhcp-failover",
+        &yaml::YamlLoader::load_from_str(
+            "{role: primary, peer: 192.0.2.2, mode: split, secret: hunter2}",
+        )
+        .unwrap()[0],
+    )
+    .unwrap()
+    .unwrap();
+    let addresses: PoolAddresses = (10..20)
+        .map(|i| Ipv4Addr::new(192, 0, 2, i))
+        .collect::<std::collections::HashSet<_>>();
+    let held = [Ipv4Addr::new(192, 0, 2, 11)];
+    let filtered = filter_addresses(Some(&conf), &addresses, &held);
+    assert_eq!(filtered.len(), 6);
+    assert!(filtered.contains(&Ipv4Addr::new(192, 0, 2, 10)));
+    assert!(filtered.contains(&Ipv4Addr::new(192, 0, 2, 11)));
+    assert!(!filtered.contains(&Ipv4Addr::new(192, 0, 2, 13)));
+
+    conf.mode = Mode::ActiveStandby;
+    assert_eq!(filter_addresses(Some(&conf), &addresses, &held).len(), 10);
+    assert_eq!(filter_addresses(None, &addresses, &held).len(), 10);
+}
+
+#[test]
+fn test_is_newer() {
+    let lease = |start, expire| LeaseInfo {
+        ip: "192.0.2.10".parse().unwrap(),
+        client_id: b"client".to_vec(),
+        chaddr: vec![],
+        start,
+        expire,
+        options: vec![],
+    };
+    assert!(is_newer(&lease(200, 300), &lease(100, 400)));
+    assert!(is_newer(&lease(100, 400), &lease(100, 300)));
+    assert!(!is_newer(&lease(100, 300), &lease(100, 300)));
+}
+
+#[tokio::test]
+async fn test_replication() {
+    async fn mk_server(
+        role: Role,
+        listener: &tokio::net::TcpListener,
+        peer: &tokio::net::TcpListener,
+    ) -> (Arc<Failover>, Arc<Mutex<pool::Pool>>) {
+        let pool = Arc::new(Mutex::new(pool::Pool::new_in_memory().unwrap()));
+        let conf = Config {
+            role,
+            mode: Mode::ActiveStandby,
+            peer: peer.local_addr().unwrap(),
+            listen: listener.local_addr().unwrap(),
+            mclt: DEFAULT_MCLT,
+            secret: b"secret".to_vec(),
+        };
+        let serverids = Arc::new(Mutex::new(
+            [Ipv4Addr::new(192, 0, 2, role as u8 + 1)]
+                .into_iter()
+                .collect(),
+        ));
+        (Failover::new(conf, pool.clone(), serverids).await, pool)
+    }
+    let primary_listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let secondary_listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let (primary, primary_pool) =
+        mk_server(Role::Primary, &primary_listener, &secondary_listener).await;
+    let (secondary, secondary_pool) =
+        mk_server(Role::Secondary, &secondary_listener, &primary_listener).await;
+
+    /* A lease from before the peers connect is sent when they do.  The peer doesn't know about
+     * it yet, so it only lasts MCLT.
+     */
+    let addresses: PoolAddresses = [Ipv4Addr::new(192, 0, 2, 10), Ipv4Addr::new(192, 0, 2, 11)]
+        .into_iter()
+        .collect();
+    async fn allocate_long(pool: &Mutex<pool::Pool>, addresses: &PoolAddresses) -> pool::Lease {
+        pool.lock()
+            .await
+            .allocate_address(
+                b"one",
+                b"",
+                None,
+                addresses,
+                2 * DEFAULT_MCLT,
+                pool::DEFAULT_MAX_LEASE,
+                b"",
+            )
+            .unwrap()
+    }
+    let first = allocate_long(&primary_pool, &addresses).await;
+    assert_eq!(first.expire, DEFAULT_MCLT);
+    assert!(secondary.should_serve().await);
+
+    tokio::spawn(primary.clone().run_with_listener(primary_listener));
+    tokio::spawn(secondary.clone().run_with_listener(secondary_listener));
+
+    let wait_for = |pool: Arc<Mutex<pool::Pool>>, count: usize| async move {
+        for _ in 0..100 {
+            if pool.lock().await.get_leases().unwrap().len() == count {
+                return;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        }
+        panic!("Lease was not replicated");
+    };
+    wait_for(secondary_pool.clone(), 1).await;
+    assert_eq!(
+        secondary_pool
+            .lock()
+            .await
+            .get_lease(first.ip)
+            .unwrap()
+            .unwrap()
+            .client_id,
+        b"one"
+    );
+
+    /* Changes are sent as they happen, in both directions */
+    secondary_pool
+        .lock()
+        .await
+        .allocate_address(
+            b"two",
+            b"",
+            None,
+            &addresses,
+            pool::DEFAULT_MIN_LEASE,
+            pool::DEFAULT_MAX_LEASE,
+            b"",
+        )
+        .unwrap();
+    wait_for(primary_pool.clone(), 2).await;
+
+    /* The secondary stands by while the primary is up, and knows the primary's server id */
+    assert!(!secondary.should_serve().await);
+    assert!(primary.should_serve().await);
+    assert!(
+        secondary
+            .is_peer_serverid(Ipv4Addr::new(192, 0, 2, 1))
+            .await
+    );
+    assert!(!secondary
+        .get_serverids(ServerIds::new())
+        .await
+        .contains(&Ipv4Addr::new(192, 0, 2, 1)));
+
+    /* Once the secondary has acknowledged the lease, the primary can extend it to MCLT past
+     * that, and the secondary is sent the lease the client was given.
+     */
+    for _ in 0..100 {
+        if primary.acked.lock().unwrap().contains_key(&first.ip) {
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+    }
+    let renewed = allocate_long(&primary_pool, &addresses).await;
+    assert!(renewed.expire > DEFAULT_MCLT);
+    let stored = primary_pool
+        .lock()
+        .await
+        .get_lease(renewed.ip)
+        .unwrap()
+        .unwrap();
+    assert_eq!(
+        u64::from(stored.expire - stored.start),
+        renewed.expire.as_secs()
+    );
+    for _ in 0..100 {
+        if secondary_pool.lock().await.get_lease(renewed.ip).unwrap() == Some(stored.clone()) {
+            return;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+    }
+    panic!("Renewed lease was not replicated");
+}
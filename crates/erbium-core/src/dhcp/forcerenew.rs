@@ -0,0 +1,181 @@
+/*   Copyright 2023 Perry Lorier
+ *
+ *  Licensed under the Apache License, Version 2.0 (the "License");
+ *  you may not use this file except in compliance with the License.
+ *  You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ *  Unless required by applicable law or agreed to in writing, software
+ *  distributed under the License is distributed on an "AS IS" BASIS,
+ *  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ *  See the License for the specific language governing permissions and
+ *  limitations under the License.
+ *
+ *  SPDX-License-Identifier: Apache-2.0
+ *
+ *  Authenticated DHCPFORCERENEW (RFC3203, RFC6704).
+ *
+ *  A client that can authenticate a DHCPFORCERENEW says so with the Forcerenew Nonce Capable
+ *  option, and is given a nonce in the Authentication option (RFC3118) of each DHCPACK.  A
+ *  DHCPFORCERENEW is then signed with HMAC-MD5, keyed by that nonce.
+ *
+ *  Rather than remember every nonce, we derive it from a configured secret and the lease the
+ *  client was acknowledged, so it's the same after a restart, and on a failover peer configured
+ *  with the same secret.
+ */
+
+use super::dhcppkt::{self, OPTION_AUTHENTICATION, OPTION_FORCERENEW_NONCE_CAPABLE};
+use super::pool::LeaseInfo;
+
+const PROTOCOL_FORCERENEW_NONCE: u8 = 3;
+const ALGORITHM_HMAC_MD5: u8 = 1;
+/// The replay detection field is a monotonically increasing counter.
+const RDM_MONOTONIC: u8 = 0;
+const AUTH_TYPE_NONCE: u8 = 1;
+const AUTH_TYPE_HMAC_MD5: u8 = 2;
+const NONCE_LEN: usize = 16;
+/// Protocol, algorithm, RDM, replay detection and type, before the nonce or HMAC.
+const AUTH_HEADER_LEN: usize = 1 + 1 + 1 + 8 + 1;
+
+fn hmac<D: crypto::digest::Digest>(digest: D, key: &[u8], data: &[u8]) -> Vec<u8> {
+    use crypto::mac::Mac as _;
+    let mut hasher = crypto::hmac::Hmac::new(digest, key);
+    hasher.input(data);
+    hasher.result().code().to_vec()
+}
+
+/// Returns true if the client can authenticate a DHCPFORCERENEW signed with HMAC-MD5.
+pub fn is_capable(options: &dhcppkt::DhcpOptions) -> bool {
+    options
+        .get_raw_option(&OPTION_FORCERENEW_NONCE_CAPABLE)
+        .map(|algorithms| algorithms.contains(&ALGORITHM_HMAC_MD5))
+        .unwrap_or(false)
+}
+
+/// The nonce the client holding `lease` was given when it was acknowledged.
+fn get_nonce(secret: &[u8], lease: &LeaseInfo) -> Vec<u8> {
+    let mut data = lease.ip.octets().to_vec();
+    data.extend(lease.start.to_be_bytes());
+    data.extend(&lease.client_id);
+    let mut nonce = hmac(crypto::sha2::Sha256::new(), secret, &data);
+    nonce.truncate(NONCE_LEN);
+    nonce
+}
+
+/// RFC3118 Section 2: The replay detection field only has to increase, so use the time.
+fn get_replay_counter() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or(0)
+}
+
+fn build_auth(auth_type: u8, value: &[u8]) -> Vec<u8> {
+    let mut auth = vec![PROTOCOL_FORCERENEW_NONCE, ALGORITHM_HMAC_MD5, RDM_MONOTONIC];
+    auth.extend(get_replay_counter().to_be_bytes());
+    auth.push(auth_type);
+    auth.extend(value);
+    auth
+}
+
+/// RFC6704 Section 4: The Authentication option to put in a DHCPACK for `lease`, giving the
+/// client the nonce.
+pub fn build_nonce_auth(secret: &[u8], lease: &LeaseInfo) -> Vec<u8> {
+    build_auth(AUTH_TYPE_NONCE, &get_nonce(secret, lease))
+}
+
+/// Finds where the value of `option` starts in a serialised packet.
+fn find_option(buf: &[u8], option: dhcppkt::DhcpOption) -> Option<usize> {
+    /* The fixed header, followed by the magic cookie */
+    let mut i = 240;
+    while i + 1 < buf.len() {
+        match buf[i] {
+            0 => i += 1,
+            255 => return None,
+            o if dhcppkt::DhcpOption::from(o) == option => return Some(i + 2),
+            _ => i += 2 + usize::from(buf[i + 1]),
+        }
+    }
+    None
+}
+
+/// RFC6704 Section 4: Serialises a DHCPFORCERENEW for `lease`, with an Authentication option
+/// holding the HMAC-MD5 of the whole message, keyed by the client's nonce.  The HMAC is
+/// calculated with the HMAC itself set to zero.
+pub fn serialise_signed(mut pkt: dhcppkt::Dhcp, secret: &[u8], lease: &LeaseInfo) -> Vec<u8> {
+    pkt.options = pkt.options.set_raw_option(
+        &OPTION_AUTHENTICATION,
+        &build_auth(AUTH_TYPE_HMAC_MD5, &[0; NONCE_LEN]),
+    );
+    let mut buf = pkt.serialise();
+    let mac = hmac(crypto::md5::Md5::new(), &get_nonce(secret, lease), &buf);
+    let offset = find_option(&buf, OPTION_AUTHENTICATION)
+        .expect("Authentication option missing from serialised packet")
+        + AUTH_HEADER_LEN;
+    buf[offset..offset + NONCE_LEN].copy_from_slice(&mac);
+    buf
+}
+
+#[test]
+fn test_is_capable() {
+    let options = dhcppkt::DhcpOptions::default();
+    assert!(!is_capable(&options));
+    assert!(!is_capable(
+        &options
+            .clone()
+            .set_raw_option(&OPTION_FORCERENEW_NONCE_CAPABLE, &[2])
+    ));
+    assert!(is_capable(&options.set_raw_option(
+        &OPTION_FORCERENEW_NONCE_CAPABLE,
+        &[2, ALGORITHM_HMAC_MD5]
+    )));
+}
+
+#[test]
+fn test_sign() {
+    let lease = LeaseInfo {
+        ip: "192.0.2.10".parse().unwrap(),
+        client_id: vec![1, 0, 0, 0x5e, 0, 0x53, 1],
+        chaddr: vec![0, 0, 0x5e, 0, 0x53, 1],
+        start: 1000,
+        expire: 2000,
+        options: vec![],
+    };
+    let secret = b"secret";
+
+    /* The nonce the client is given depends on the lease */
+    let auth = build_nonce_auth(secret, &lease);
+    assert_eq!(auth.len(), AUTH_HEADER_LEN + NONCE_LEN);
+    assert_eq!(auth[..3], [3, 1, 0]);
+    assert_eq!(auth[AUTH_HEADER_LEN - 1], AUTH_TYPE_NONCE);
+    let nonce = auth[AUTH_HEADER_LEN..].to_vec();
+    assert_eq!(nonce, get_nonce(secret, &lease));
+    assert_ne!(
+        nonce,
+        get_nonce(
+            secret,
+            &LeaseInfo {
+                start: 1001,
+                ..lease.clone()
+            }
+        )
+    );
+    assert_ne!(nonce, get_nonce(b"other secret", &lease));
+
+    let pkt = crate::dhcp::test::mk_dhcp_request().pkt;
+    let buf = serialise_signed(pkt, secret, &lease);
+    let parsed = dhcppkt::parse(&buf).expect("Failed to parse signed packet");
+    let auth = parsed
+        .options
+        .get_raw_option(&OPTION_AUTHENTICATION)
+        .expect("Missing authentication option");
+    assert_eq!(auth[AUTH_HEADER_LEN - 1], AUTH_TYPE_HMAC_MD5);
+
+    /* What the client does to check the HMAC */
+    let mac = auth[AUTH_HEADER_LEN..].to_vec();
+    let offset = find_option(&buf, OPTION_AUTHENTICATION).unwrap() + AUTH_HEADER_LEN;
+    let mut zeroed = buf.clone();
+    zeroed[offset..offset + NONCE_LEN].fill(0);
+    assert_eq!(mac, hmac(crypto::md5::Md5::new(), &nonce, &zeroed));
+}
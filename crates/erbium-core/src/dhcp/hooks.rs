@@ -0,0 +1,622 @@
+/*   Copyright 2023 Perry Lorier
+ *
+ *  Licensed under the Apache License, Version 2.0 (the "License");
+ *  you may not use this file except in compliance with the License.
+ *  You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ *  Unless required by applicable law or agreed to in writing, software
+ *  distributed under the License is distributed on an "AS IS" BASIS,
+ *  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ *  See the License for the specific language governing permissions and
+ *  limitations under the License.
+ *
+ *  SPDX-License-Identifier: Apache-2.0
+ *
+ *  Lease event hooks.
+ *
+ *  When a lease is committed, renewed, released or expires, run a program or POST JSON to a URL so
+ *  other systems (firewalls, home automation etc) can react.  Hooks are queued and run in the
+ *  background with limited concurrency, so a slow hook can never hold up replying to clients.
+ */
+use std::net::Ipv4Addr;
+
+use crate::config::{parse_array, parse_duration, parse_string, type_to_name, Error};
+use crate::dhcp::dhcppkt;
+use crate::dhcp::pool::{self, LeaseInfo};
+use yaml_rust::yaml;
+
+lazy_static::lazy_static! {
+    static ref DHCP_LEASE_HOOKS: prometheus::IntCounterVec = prometheus::register_int_counter_vec!(
+        "dhcp_lease_hooks",
+        "Counts of lease hooks run, by result",
+        &["result"]
+    )
+    .unwrap();
+}
+
+const DEFAULT_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+/// How many hooks can be running at once.
+const MAX_CONCURRENT_HOOKS: usize = 4;
+/// How many hooks can be waiting to run before we start dropping them.
+const MAX_QUEUED_HOOKS: usize = 256;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Event {
+    /// A client has been given a new lease.
+    Commit,
+    /// A client has extended the lease it already had.
+    Renew,
+    /// A client has given its lease back (or declined it).
+    Release,
+    /// A lease ran out without being renewed.
+    Expire,
+}
+
+impl Event {
+    const ALL: [Event; 4] = [Event::Commit, Event::Renew, Event::Release, Event::Expire];
+
+    pub const fn as_str(&self) -> &'static str {
+        match self {
+            Event::Commit => "commit",
+            Event::Renew => "renew",
+            Event::Release => "release",
+            Event::Expire => "expire",
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Action {
+    /// Run this program, with the lease details in the environment.
+    Exec(std::path::PathBuf),
+    /// POST the lease details as JSON to this http:// URL.
+    Post(String),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Hook {
+    pub action: Action,
+    pub events: Vec<Event>,
+    pub timeout: std::time::Duration,
+}
+
+fn parse_event(name: &str, fragment: &yaml::Yaml) -> Result<Option<Event>, Error> {
+    match parse_string(name, fragment)? {
+        None => Ok(None),
+        Some(e) => Event::ALL
+            .iter()
+            .find(|ev| ev.as_str() == e)
+            .map(|ev| Some(*ev))
+            .ok_or_else(|| {
+                Error::InvalidConfig(format!(
+                    "Unknown {} {}, expected commit, renew, release or expire",
+                    name, e
+                ))
+            }),
+    }
+}
+
+fn parse_hook(name: &str, fragment: &yaml::Yaml) -> Result<Option<Hook>, Error> {
+    match fragment {
+        yaml::Yaml::Hash(h) => {
+            let mut action = None;
+            let mut events = None;
+            let mut timeout = None;
+            for (k, v) in h {
+                match (k.as_str(), v) {
+                    (Some("exec"), e) => {
+                        if action.is_some() {
+                            return Err(Error::InvalidConfig(format!(
+                                "{} can only have one of exec or url",
+                                name
+                            )));
+                        }
+                        action = parse_string("exec", e)?
+                            .map(|e| Action::Exec(std::path::PathBuf::from(e)));
+                    }
+                    (Some("url"), u) => {
+                        if action.is_some() {
+                            return Err(Error::InvalidConfig(format!(
+                                "{} can only have one of exec or url",
+                                name
+                            )));
+                        }
+                        action = match parse_string("url", u)? {
+                            Some(u) if split_url(&u).is_some() => Some(Action::Post(u)),
+                            Some(u) => {
+                                return Err(Error::InvalidConfig(format!(
+                                    "Unsupported url {}, only http:// urls are supported",
+                                    u
+                                )))
+                            }
+                            None => None,
+                        };
+                    }
+                    (Some("events"), e) => events = parse_array("events", e, parse_event)?,
+                    (Some("timeout"), t) => timeout = parse_duration("timeout", t)?,
+                    (Some(m), _) => {
+                        return Err(Error::InvalidConfig(format!("Unknown {} key {}", name, m)))
+                    }
+                    (None, _) => {
+                        return Err(Error::InvalidConfig(format!(
+                            "{} keys are expected to be strings",
+                            name
+                        )))
+                    }
+                }
+            }
+            Ok(Some(Hook {
+                action: action.ok_or_else(|| {
+                    Error::InvalidConfig(format!("{} requires one of exec or url", name))
+                })?,
+                events: events.unwrap_or_else(|| Event::ALL.to_vec()),
+                timeout: timeout.unwrap_or(DEFAULT_TIMEOUT),
+            }))
+        }
+        e => Err(Error::InvalidConfig(format!(
+            "Expected hash for {}, got {}",
+            name,
+            type_to_name(e)
+        ))),
+    }
+}
+
+pub fn parse(name: &str, fragment: &yaml::Yaml) -> Result<Option<Vec<Hook>>, Error> {
+    parse_array(name, fragment, parse_hook)
+}
+
+/// The details of a lease passed to hooks.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LeaseEvent {
+    pub event: Event,
+    pub ip: Ipv4Addr,
+    pub mac: Vec<u8>,
+    pub client_id: Vec<u8>,
+    pub hostname: Option<String>,
+    pub interface: String,
+    /// When the lease expires, in seconds since the unix epoch.
+    pub expire: u64,
+}
+
+fn json_string(s: &str) -> String {
+    let mut ret = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => ret.push_str("\\\""),
+            '\\' => ret.push_str("\\\\"),
+            c if (c as u32) < 0x20 => ret.push_str(&format!("\\u{:04x}", c as u32)),
+            c => ret.push(c),
+        }
+    }
+    ret.push('"');
+    ret
+}
+
+impl LeaseEvent {
+    fn env(&self) -> Vec<(&'static str, String)> {
+        vec![
+            ("ERBIUM_EVENT", self.event.as_str().into()),
+            ("ERBIUM_IP", self.ip.to_string()),
+            ("ERBIUM_MAC", super::format_mac(&self.mac)),
+            ("ERBIUM_CLIENT_ID", super::format_mac(&self.client_id)),
+            ("ERBIUM_HOSTNAME", self.hostname.clone().unwrap_or_default()),
+            ("ERBIUM_INTERFACE", self.interface.clone()),
+            ("ERBIUM_EXPIRE", self.expire.to_string()),
+        ]
+    }
+
+    fn to_json(&self) -> String {
+        format!(
+            "{{ \"event\": \"{}\", \"ip\": \"{}\", \"mac\": \"{}\", \"client_id\": \"{}\", {}\"interface\": {}, \"expire\": {} }}",
+            self.event.as_str(),
+            self.ip,
+            super::format_mac(&self.mac),
+            super::format_mac(&self.client_id),
+            self.hostname
+                .as_ref()
+                .map(|h| format!("\"host-name\": {}, ", json_string(h)))
+                .unwrap_or_default(),
+            json_string(&self.interface),
+            self.expire,
+        )
+    }
+}
+
+/// Splits an http:// url into the host:port to connect to, and the path to request.
+fn split_url(url: &str) -> Option<(String, String)> {
+    let rest = url.strip_prefix("http://")?;
+    let (host, path) = match rest.find('/') {
+        Some(i) => (&rest[..i], &rest[i..]),
+        None => (rest, "/"),
+    };
+    if host.is_empty() {
+        return None;
+    }
+    /* Add the default port, taking care with IPv6 literals like [::1] */
+    let host = match host.rfind(':') {
+        Some(i) if !host[i..].contains(']') => host.to_string(),
+        _ => format!("{}:80", host),
+    };
+    Some((host, path.into()))
+}
+
+async fn post(url: &str, body: &str) -> Result<(), std::io::Error> {
+    use tokio::io::{AsyncReadExt as _, AsyncWriteExt as _};
+    let (host, path) = split_url(url)
+        .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidInput, "Invalid url"))?;
+    let mut stream = tokio::net::TcpStream::connect(&host).await?;
+    stream
+        .write_all(
+            format!(
+                "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                path,
+                host,
+                body.len(),
+                body
+            )
+            .as_bytes(),
+        )
+        .await?;
+    let mut response = vec![];
+    stream.read_to_end(&mut response).await?;
+    /* We only care about the status code: "HTTP/1.1 200 OK" */
+    let status = String::from_utf8_lossy(&response)
+        .split_whitespace()
+        .nth(1)
+        .and_then(|s| s.parse::<u16>().ok());
+    match status {
+        Some(200..=299) => Ok(()),
+        Some(s) => Err(std::io::Error::other(format!("HTTP status {}", s))),
+        None => Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "Invalid HTTP response",
+        )),
+    }
+}
+
+async fn run_hook(hook: &Hook, event: &LeaseEvent) -> Result<(), std::io::Error> {
+    match &hook.action {
+        Action::Exec(path) => {
+            let status = tokio::process::Command::new(path)
+                .envs(event.env())
+                .stdin(std::process::Stdio::null())
+                .kill_on_drop(true)
+                .status()
+                .await?;
+            if status.success() {
+                Ok(())
+            } else {
+                Err(std::io::Error::other(format!(
+                    "{} exited with {}",
+                    path.display(),
+                    status
+                )))
+            }
+        }
+        Action::Post(url) => post(url, &event.to_json()).await,
+    }
+}
+
+async fn run_queue(mut queue: tokio::sync::mpsc::Receiver<(Hook, LeaseEvent)>) {
+    let running = std::sync::Arc::new(tokio::sync::Semaphore::new(MAX_CONCURRENT_HOOKS));
+    while let Some((hook, event)) = queue.recv().await {
+        let permit = running.clone().acquire_owned().await.unwrap();
+        tokio::spawn(async move {
+            let result = match tokio::time::timeout(hook.timeout, run_hook(&hook, &event)).await {
+                Ok(Ok(())) => "OK",
+                Ok(Err(e)) => {
+                    log::warn!(
+                        "Lease hook {:?} for {} failed: {}",
+                        hook.action,
+                        event.ip,
+                        e
+                    );
+                    "FAILED"
+                }
+                Err(_) => {
+                    log::warn!("Lease hook {:?} for {} timed out", hook.action, event.ip);
+                    "TIMEOUT"
+                }
+            };
+            DHCP_LEASE_HOOKS.with_label_values(&[result]).inc();
+            drop(permit);
+        });
+    }
+}
+
+fn now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Returns true if `lease` is a client's lease, rather than an offer, a quarantine or a lease
+/// that has been released.
+fn is_bound(lease: &LeaseInfo) -> bool {
+    !lease.client_id.is_empty() && super::get_binding(lease) == super::Binding::Bound
+}
+
+impl LeaseEvent {
+    /// Works out which hook event, if any, a change to the pool is.  Offers aren't reported, so
+    /// a client that was only offered its address commits it when it asks for it.
+    pub fn from_change(change: &pool::Event, interface: String) -> Option<Self> {
+        let is_held = |previous: &&LeaseInfo| {
+            is_bound(previous)
+                && previous.client_id == change.lease.client_id
+                && previous.expire >= change.lease.start
+        };
+        let (event, lease, expire) = match change.change {
+            pool::Change::Committed(lease_type) => (
+                if lease_type == pool::LeaseType::ReusingLease
+                    && change.previous.as_ref().filter(is_held).is_some()
+                {
+                    Event::Renew
+                } else {
+                    Event::Commit
+                },
+                &change.lease,
+                change.lease.expire.into(),
+            ),
+            /* A declined lease is replaced by a quarantine, which has no client */
+            pool::Change::Released | pool::Change::Quarantined => (
+                Event::Release,
+                change.previous.as_ref().filter(|previous| {
+                    is_bound(previous) && previous.expire >= change.lease.start
+                })?,
+                now(),
+            ),
+            pool::Change::Expired if is_bound(&change.lease) => {
+                (Event::Expire, &change.lease, change.lease.expire.into())
+            }
+            _ => return None,
+        };
+        let options = dhcppkt::parse_options(crate::pktparser::Buffer::new(&lease.options)).ok();
+        Some(LeaseEvent {
+            event,
+            ip: lease.ip,
+            mac: lease.chaddr.clone(),
+            client_id: lease.client_id.clone(),
+            hostname: options.and_then(|o| o.get_hostname()),
+            interface,
+            expire,
+        })
+    }
+}
+
+/// Queues the hooks for lease events.
+pub struct Dispatcher {
+    queue: tokio::sync::mpsc::Sender<(Hook, LeaseEvent)>,
+}
+
+impl Default for Dispatcher {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Dispatcher {
+    /// Creates a dispatcher, and starts the task that runs the hooks.
+    pub fn new() -> Self {
+        let (queue, rx) = tokio::sync::mpsc::channel(MAX_QUEUED_HOOKS);
+        tokio::spawn(run_queue(rx));
+        Self { queue }
+    }
+
+    pub fn dispatch(&self, hooks: &[Hook], event: &LeaseEvent) {
+        log::info!(
+            "Lease {} for {} {}",
+            event.ip,
+            super::format_mac(&event.client_id),
+            event.event.as_str()
+        );
+        for hook in hooks.iter().filter(|h| h.events.contains(&event.event)) {
+            /* Never wait here, hooks can be slow */
+            if self.queue.try_send((hook.clone(), event.clone())).is_err() {
+                log::warn!(
+                    "Too many lease hooks queued, dropping hook for {}",
+                    event.ip
+                );
+                DHCP_LEASE_HOOKS.with_label_values(&["DROPPED"]).inc();
+            }
+        }
+    }
+}
+
+#[test]
+fn test_parse() {
+    let y = yaml_rust::YamlLoader::load_from_str(
+        "
+- exec: /usr/local/bin/lease-changed
+- url: http://127.0.0.1:8123/api/webhook/dhcp
+  events: [commit, expire]
+  timeout: 30s
+",
+    )
+    .unwrap();
+    assert_eq!(
+        parse("dhcp-lease-hooks", &y[0]).unwrap().unwrap(),
+        vec![
+            Hook {
+                action: Action::Exec("/usr/local/bin/lease-changed".into()),
+                events: Event::ALL.to_vec(),
+                timeout: DEFAULT_TIMEOUT,
+            },
+            Hook {
+                action: Action::Post("http://127.0.0.1:8123/api/webhook/dhcp".into()),
+                events: vec![Event::Commit, Event::Expire],
+                timeout: std::time::Duration::from_secs(30),
+            },
+        ]
+    );
+    for bad in [
+        "[{exec: /bin/true, url: 'http://localhost/'}]",
+        "[{url: 'https://localhost/'}]",
+        "[{exec: /bin/true, events: [bogus]}]",
+        "[{events: [commit]}]",
+    ] {
+        let y = yaml_rust::YamlLoader::load_from_str(bad).unwrap();
+        assert!(parse("dhcp-lease-hooks", &y[0]).is_err(), "{}", bad);
+    }
+}
+
+#[test]
+fn test_split_url() {
+    assert_eq!(
+        split_url("http://localhost/hook"),
+        Some(("localhost:80".into(), "/hook".into()))
+    );
+    assert_eq!(
+        split_url("http://[::1]:8080"),
+        Some(("[::1]:8080".into(), "/".into()))
+    );
+    assert_eq!(
+        split_url("http://[::1]/x"),
+        Some(("[::1]:80".into(), "/x".into()))
+    );
+    assert_eq!(split_url("https://localhost/"), None);
+}
+
+#[test]
+fn test_to_json() {
+    let event = LeaseEvent {
+        event: Event::Commit,
+        ip: "192.0.2.10".parse().unwrap(),
+        mac: vec![0x00, 0x00, 0x5e, 0x00, 0x53, 0x01],
+        client_id: vec![0x01, 0x00, 0x00, 0x5e, 0x00, 0x53, 0x01],
+        hostname: Some("my\"host".into()),
+        interface: "eth0".into(),
+        expire: 1234,
+    };
+    assert_eq!(
+        event.to_json(),
+        "{ \"event\": \"commit\", \"ip\": \"192.0.2.10\", \"mac\": \"00:00:5e:00:53:01\", \
+         \"client_id\": \"01:00:00:5e:00:53:01\", \"host-name\": \"my\\\"host\", \
+         \"interface\": \"eth0\", \"expire\": 1234 }"
+    );
+}
+
+#[test]
+fn test_from_change() {
+    let options = |msgtype: dhcppkt::MessageType| {
+        use dhcppkt::Serialise as _;
+        let mut raw = vec![];
+        dhcppkt::DhcpOptions::default()
+            .set_option(&dhcppkt::OPTION_MSGTYPE, &msgtype)
+            .set_option(&dhcppkt::OPTION_HOSTNAME, &"laptop".to_string())
+            .serialise(&mut raw);
+        raw
+    };
+    let lease = LeaseInfo {
+        ip: "192.0.2.10".parse().unwrap(),
+        client_id: vec![0x01, 0x00, 0x00, 0x5e, 0x00, 0x53, 0x01],
+        chaddr: vec![0x00, 0x00, 0x5e, 0x00, 0x53, 0x01],
+        start: 1000,
+        expire: 2000,
+        options: options(dhcppkt::DHCPREQUEST),
+    };
+    let offer = LeaseInfo {
+        start: 900,
+        expire: 1200,
+        options: options(dhcppkt::DHCPDISCOVER),
+        ..lease.clone()
+    };
+    let renewed = LeaseInfo {
+        start: 1500,
+        expire: 2500,
+        ..lease.clone()
+    };
+    let released = LeaseInfo {
+        start: 1000,
+        expire: 1499,
+        options: options(dhcppkt::DHCPRELEASE),
+        ..lease.clone()
+    };
+    let event = |change, lease: &LeaseInfo, previous: Option<&LeaseInfo>| {
+        LeaseEvent::from_change(
+            &pool::Event {
+                change,
+                lease: lease.clone(),
+                previous: previous.cloned(),
+            },
+            "eth0".into(),
+        )
+        .map(|e| e.event)
+    };
+    let reusing = pool::Change::Committed(pool::LeaseType::ReusingLease);
+
+    /* Offers aren't reported, and asking for the offered address commits it */
+    assert_eq!(event(pool::Change::Offered, &offer, None), None);
+    assert_eq!(event(reusing, &lease, Some(&offer)), Some(Event::Commit));
+    assert_eq!(
+        event(
+            pool::Change::Committed(pool::LeaseType::NewAddress),
+            &lease,
+            None
+        ),
+        Some(Event::Commit)
+    );
+    assert_eq!(event(reusing, &renewed, Some(&lease)), Some(Event::Renew));
+    assert_eq!(
+        event(pool::Change::Released, &released, Some(&renewed)),
+        Some(Event::Release)
+    );
+
+    /* Declining an offer isn't a release, declining a lease is */
+    let quarantine = LeaseInfo {
+        client_id: vec![],
+        options: vec![],
+        ..renewed.clone()
+    };
+    assert_eq!(
+        event(pool::Change::Quarantined, &quarantine, Some(&offer)),
+        None
+    );
+    assert_eq!(
+        event(pool::Change::Quarantined, &quarantine, Some(&lease)),
+        Some(Event::Release)
+    );
+
+    /* Only leases that were still held expire */
+    assert_eq!(
+        event(pool::Change::Expired, &lease, None),
+        Some(Event::Expire)
+    );
+    assert_eq!(event(pool::Change::Expired, &released, None), None);
+    assert_eq!(event(pool::Change::Expired, &offer, None), None);
+    assert_eq!(event(pool::Change::Expired, &quarantine, None), None);
+
+    let details = LeaseEvent::from_change(
+        &pool::Event {
+            change: pool::Change::Released,
+            lease: released.clone(),
+            previous: Some(lease.clone()),
+        },
+        "eth0".into(),
+    )
+    .unwrap();
+    assert_eq!(details.mac, vec![0x00, 0x00, 0x5e, 0x00, 0x53, 0x01]);
+    assert_eq!(details.hostname.as_deref(), Some("laptop"));
+    assert_eq!(details.interface, "eth0");
+
+    /* Clients identified by a DUID (RFC4361) still have their hardware address reported */
+    let duid = LeaseInfo {
+        client_id: vec![
+            0xff, 0x00, 0x00, 0x00, 0x01, 0x00, 0x04, 0x12, 0x34, 0x56, 0x78,
+        ],
+        chaddr: vec![0x00, 0x00, 0x5e, 0x00, 0x53, 0x02],
+        ..lease.clone()
+    };
+    let details = LeaseEvent::from_change(
+        &pool::Event {
+            change: pool::Change::Committed(pool::LeaseType::NewAddress),
+            lease: duid,
+            previous: None,
+        },
+        "eth0".into(),
+    )
+    .unwrap();
+    assert_eq!(details.mac, vec![0x00, 0x00, 0x5e, 0x00, 0x53, 0x02]);
+}
@@ -0,0 +1,620 @@
+/*   Copyright 2023 Perry Lorier
+ *
+ *  Licensed under the Apache License, Version 2.0 (the "License");
+ *  you may not use this file except in compliance with the License.
+ *  You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ *  Unless required by applicable law or agreed to in writing, software
+ *  distributed under the License is distributed on an "AS IS" BASIS,
+ *  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ *  See the License for the specific language governing permissions and
+ *  limitations under the License.
+ *
+ *  SPDX-License-Identifier: Apache-2.0
+ *
+ *  Imports leases from the lease files of other DHCP servers (ISC dhcpd and dnsmasq), so that
+ *  clients keep their addresses when migrating to erbium.
+ */
+
+use super::dhcppkt::{self, Serialise as _};
+use super::pool::{self, LeaseInfo};
+use std::collections::BTreeMap;
+use std::net::Ipv4Addr;
+
+/// Used for leases that never expire.
+const NEVER: u32 = u32::MAX;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Error {
+    /// A syntax error on a line of the lease file.
+    Parse(usize, String),
+    Pool(pool::Error),
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Parse(line, msg) => write!(f, "line {}: {}", line, msg),
+            Error::Pool(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// A lease read from another server's lease file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ImportedLease {
+    pub ip: Ipv4Addr,
+    /// The client identifier if the client sent one, otherwise its hardware address, the same as
+    /// erbium uses.
+    pub client_id: Vec<u8>,
+    /// The client's hardware address, empty if the lease file doesn't say.
+    pub chaddr: Vec<u8>,
+    pub start: u32,
+    pub expire: u32,
+    pub hostname: Option<String>,
+}
+
+impl ImportedLease {
+    fn to_lease_info(&self) -> LeaseInfo {
+        /* We normally remember the options from the client's request, all we know is its name */
+        let mut options = vec![];
+        if let Some(hostname) = &self.hostname {
+            dhcppkt::DhcpOptions::default()
+                .set_option(&dhcppkt::OPTION_HOSTNAME, hostname)
+                .serialise(&mut options);
+        }
+        LeaseInfo {
+            ip: self.ip,
+            client_id: self.client_id.clone(),
+            chaddr: self.chaddr.clone(),
+            start: self.start,
+            expire: self.expire,
+            options,
+        }
+    }
+}
+
+fn now() -> u32 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs() as u32)
+        .unwrap_or(0)
+}
+
+fn parse_hex(s: &str) -> Option<Vec<u8>> {
+    s.split(':')
+        .map(|b| {
+            if b.is_empty() || b.len() > 2 {
+                None
+            } else {
+                u8::from_str_radix(b, 16).ok()
+            }
+        })
+        .collect()
+}
+
+/* ---- ISC dhcpd ---- */
+
+#[derive(Debug, PartialEq, Eq)]
+enum Token {
+    Word(String),
+    Str(Vec<u8>),
+    Open,
+    Close,
+    Semi,
+}
+
+fn tokenise(content: &str) -> Result<Vec<(usize, Token)>, Error> {
+    let mut tokens = vec![];
+    let mut line = 1;
+    let mut chars = content.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\n' => line += 1,
+            c if c.is_whitespace() => (),
+            '#' => while chars.next_if(|c| *c != '\n').is_some() {},
+            '{' => tokens.push((line, Token::Open)),
+            '}' => tokens.push((line, Token::Close)),
+            ';' => tokens.push((line, Token::Semi)),
+            '"' => {
+                let mut s = vec![];
+                loop {
+                    match chars.next() {
+                        None | Some('\n') => {
+                            return Err(Error::Parse(line, "Unterminated string".into()))
+                        }
+                        Some('"') => break,
+                        Some('\\') => match chars.next() {
+                            Some(d @ '0'..='7') => {
+                                /* Octal escapes, used for non-printable bytes */
+                                let mut b = d as u32 - '0' as u32;
+                                for _ in 0..2 {
+                                    if let Some(d) = chars.next_if(|c| ('0'..='7').contains(c)) {
+                                        b = b * 8 + (d as u32 - '0' as u32);
+                                    }
+                                }
+                                s.push(b as u8);
+                            }
+                            Some('n') => s.push(b'\n'),
+                            Some('r') => s.push(b'\r'),
+                            Some('t') => s.push(b'\t'),
+                            Some(c) => s.extend(c.to_string().as_bytes()),
+                            None => return Err(Error::Parse(line, "Unterminated string".into())),
+                        },
+                        Some(c) => s.extend(c.to_string().as_bytes()),
+                    }
+                }
+                tokens.push((line, Token::Str(s)));
+            }
+            c => {
+                let mut word = c.to_string();
+                while let Some(c) = chars.next_if(|c| !c.is_whitespace() && !"{};\"#".contains(*c))
+                {
+                    word.push(c);
+                }
+                tokens.push((line, Token::Word(word)));
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+/// Days since 1970-01-01 of a date in the proleptic Gregorian calendar.
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = if year >= 0 { year } else { year - 399 } / 400;
+    let yoe = year - era * 400;
+    let doy = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+/// Parses the time in "starts" and "ends" statements, which are one of:
+///  * `never`
+///  * `epoch <seconds>`
+///  * `<weekday> <yyyy>/<mm>/<dd> <hh>:<mm>:<ss>` in UTC.
+fn parse_time(args: &[Token]) -> Option<u32> {
+    let words = args
+        .iter()
+        .map(|t| match t {
+            Token::Word(w) => Some(w.as_str()),
+            _ => None,
+        })
+        .collect::<Option<Vec<_>>>()?;
+    match words.as_slice() {
+        ["never"] => Some(NEVER),
+        ["epoch", secs] => secs.parse().ok(),
+        [_weekday, date, time] => {
+            let date = date
+                .split('/')
+                .map(|n| n.parse::<i64>().ok())
+                .collect::<Option<Vec<_>>>()?;
+            let time = time
+                .split(':')
+                .map(|n| n.parse::<i64>().ok())
+                .collect::<Option<Vec<_>>>()?;
+            match (date.as_slice(), time.as_slice()) {
+                ([year, month, day], [hour, min, sec]) => u32::try_from(
+                    days_from_civil(*year, *month, *day) * 86400 + hour * 3600 + min * 60 + sec,
+                )
+                .ok(),
+                _ => None,
+            }
+        }
+        _ => None,
+    }
+}
+
+/// Skips over a statement that we don't care about, including any block it contains.
+fn skip_statement(
+    first: Token,
+    line: usize,
+    tokens: &mut impl Iterator<Item = (usize, Token)>,
+) -> Result<(), Error> {
+    let mut depth = 0;
+    let mut next = Some((line, first));
+    while let Some((line, token)) = next {
+        match token {
+            Token::Semi if depth == 0 => return Ok(()),
+            Token::Open => depth += 1,
+            Token::Close if depth == 0 => return Err(Error::Parse(line, "Unexpected '}'".into())),
+            Token::Close if depth == 1 => return Ok(()),
+            Token::Close => depth -= 1,
+            _ => (),
+        }
+        next = tokens.next();
+    }
+    Err(Error::Parse(line, "Unterminated statement".into()))
+}
+
+/// Reads the statements in a block up to the closing brace, ignoring any nested blocks.
+fn read_block(
+    line: usize,
+    tokens: &mut impl Iterator<Item = (usize, Token)>,
+) -> Result<Vec<Vec<Token>>, Error> {
+    let mut statements = vec![];
+    let mut current = vec![];
+    while let Some((line, token)) = tokens.next() {
+        match token {
+            Token::Semi => statements.push(std::mem::take(&mut current)),
+            Token::Close if current.is_empty() => return Ok(statements),
+            Token::Close => return Err(Error::Parse(line, "Expected ';'".into())),
+            Token::Open => {
+                /* eg "on expiry { ... }" */
+                current.clear();
+                skip_statement(Token::Open, line, tokens)?;
+            }
+            t => current.push(t),
+        }
+    }
+    Err(Error::Parse(line, "Unterminated lease".into()))
+}
+
+/// Returns None for leases that aren't active.
+fn lease_from_statements(
+    line: usize,
+    ip: Ipv4Addr,
+    statements: &[Vec<Token>],
+) -> Result<Option<ImportedLease>, Error> {
+    let mut start = 0;
+    let mut expire = NEVER;
+    let mut mac = None;
+    let mut uid = None;
+    let mut hostname = None;
+    for statement in statements {
+        let invalid = |what: &str| Error::Parse(line, format!("Invalid {} for {}", what, ip));
+        match statement.as_slice() {
+            [Token::Word(w), args @ ..] if w == "starts" => {
+                start = parse_time(args).ok_or_else(|| invalid("starts"))?;
+            }
+            [Token::Word(w), args @ ..] if w == "ends" => {
+                expire = parse_time(args).ok_or_else(|| invalid("ends"))?;
+            }
+            [Token::Word(w), Token::Word(s), Token::Word(state)]
+                if w == "binding" && s == "state" && state != "active" =>
+            {
+                return Ok(None);
+            }
+            [Token::Word(w), Token::Word(_), Token::Word(hw)] if w == "hardware" => {
+                mac = Some(parse_hex(hw).ok_or_else(|| invalid("hardware address"))?);
+            }
+            [Token::Word(w), Token::Str(s)] if w == "uid" => uid = Some(s.clone()),
+            [Token::Word(w), Token::Word(s)] if w == "uid" => {
+                uid = Some(parse_hex(s).ok_or_else(|| invalid("uid"))?);
+            }
+            [Token::Word(w), Token::Str(s)] if w == "client-hostname" => {
+                hostname = Some(String::from_utf8_lossy(s).into_owned());
+            }
+            _ => (),
+        }
+    }
+    let chaddr = mac.clone().unwrap_or_default();
+    match uid.or(mac) {
+        Some(client_id) if !client_id.is_empty() => Ok(Some(ImportedLease {
+            ip,
+            client_id,
+            chaddr,
+            start,
+            expire,
+            hostname,
+        })),
+        _ => Err(Error::Parse(
+            line,
+            format!("Lease for {} has no client identifier", ip),
+        )),
+    }
+}
+
+/// Parses an ISC dhcpd `dhcpd.leases` file.  This file is a log, so later entries for an address
+/// replace earlier ones.
+pub fn parse_dhcpd_leases(content: &str) -> Result<Vec<ImportedLease>, Error> {
+    let mut leases = BTreeMap::new();
+    let mut tokens = tokenise(content)?.into_iter();
+    while let Some((line, token)) = tokens.next() {
+        match token {
+            Token::Word(w) if w == "lease" => {
+                let ip = match tokens.next() {
+                    Some((_, Token::Word(ip))) => ip.parse::<Ipv4Addr>().map_err(|e| {
+                        Error::Parse(line, format!("Invalid address {}: {}", ip, e))
+                    })?,
+                    _ => return Err(Error::Parse(line, "Expected lease address".into())),
+                };
+                if !matches!(tokens.next(), Some((_, Token::Open))) {
+                    return Err(Error::Parse(line, "Expected '{'".into()));
+                }
+                let statements = read_block(line, &mut tokens)?;
+                match lease_from_statements(line, ip, &statements)? {
+                    Some(lease) => leases.insert(ip, lease),
+                    None => leases.remove(&ip),
+                };
+            }
+            /* Everything else, including IPv6 leases, is ignored */
+            t => skip_statement(t, line, &mut tokens)?,
+        }
+    }
+    Ok(leases.into_values().collect())
+}
+
+/* ---- dnsmasq ---- */
+
+/// Parses a dnsmasq `dnsmasq.leases` file, which has one lease per line:
+///
+/// `<expiry> <mac> <address> <hostname> <client-id>`
+///
+/// with "*" for a missing hostname or client id, and an expiry of 0 for infinite leases.  dnsmasq
+/// doesn't record when a lease started, so we pretend it started now.
+pub fn parse_dnsmasq_leases(content: &str) -> Result<Vec<ImportedLease>, Error> {
+    let ts = now();
+    let mut leases = vec![];
+    for (lineno, line) in content.lines().enumerate() {
+        let lineno = lineno + 1;
+        let fields = line.split_whitespace().collect::<Vec<_>>();
+        let (expiry, mac, ip, hostname, client_id) = match fields.as_slice() {
+            [] => continue,
+            /* The server's DUID, for the IPv6 leases that follow */
+            ["duid", ..] => continue,
+            [expiry, mac, ip, hostname] => (expiry, mac, ip, hostname, &"*"),
+            [expiry, mac, ip, hostname, client_id, ..] => (expiry, mac, ip, hostname, client_id),
+            _ => return Err(Error::Parse(lineno, "Too few fields".into())),
+        };
+        let ip = match ip.parse::<Ipv4Addr>() {
+            Ok(ip) => ip,
+            Err(_) if ip.contains(':') => continue, /* IPv6 */
+            Err(e) => {
+                return Err(Error::Parse(
+                    lineno,
+                    format!("Invalid address {}: {}", ip, e),
+                ))
+            }
+        };
+        let expire = match expiry.parse::<u32>() {
+            Ok(0) => NEVER,
+            Ok(expire) => expire,
+            Err(e) => return Err(Error::Parse(lineno, format!("Invalid expiry: {}", e))),
+        };
+        let client_id = if *client_id == "*" {
+            parse_hex(mac)
+        } else {
+            parse_hex(client_id)
+        }
+        .ok_or_else(|| Error::Parse(lineno, "Invalid client identifier".into()))?;
+        leases.push(ImportedLease {
+            ip,
+            client_id,
+            /* Non ethernet addresses are written with their type, which we don't keep */
+            chaddr: parse_hex(mac).unwrap_or_default(),
+            start: std::cmp::min(ts, expire),
+            expire,
+            hostname: Some(hostname.to_string()).filter(|h| h != "*"),
+        });
+    }
+    Ok(leases)
+}
+
+/// Parses a lease file from either dhcpd or dnsmasq.  dnsmasq lines always start with the expiry
+/// time, which dhcpd files never do.
+pub fn parse(content: &str) -> Result<Vec<ImportedLease>, Error> {
+    let first = content
+        .lines()
+        .map(str::trim)
+        .find(|l| !l.is_empty() && !l.starts_with('#'));
+    match first {
+        Some(l) if l.starts_with(|c: char| c.is_ascii_digit()) => parse_dnsmasq_leases(content),
+        _ => parse_dhcpd_leases(content),
+    }
+}
+
+/* ---- importing ---- */
+
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Summary {
+    pub imported: usize,
+    pub skipped: usize,
+}
+
+/// Adds `leases` to the pool.  Leases for addresses that erbium isn't configured to hand out, or
+/// that are currently leased to some other client, are skipped with a warning.
+pub fn import(
+    pool: &mut pool::Pool,
+    conf: &crate::config::Config,
+    leases: &[ImportedLease],
+) -> Result<Summary, Error> {
+    let addresses = conf.dhcp.get_all_used_addresses();
+    let mut summary = Summary::default();
+    for lease in leases {
+        if !super::is_configured_address(conf, &addresses, lease.ip) {
+            log::warn!("Skipping {}: not in any configured range", lease.ip);
+            summary.skipped += 1;
+            continue;
+        }
+        match pool.import_lease(lease.to_lease_info()) {
+            Ok(()) => summary.imported += 1,
+            Err(pool::Error::RequestedAddressInUse) => {
+                log::warn!("Skipping {}: already leased to another client", lease.ip);
+                summary.skipped += 1;
+            }
+            Err(e) => return Err(Error::Pool(e)),
+        }
+    }
+    Ok(summary)
+}
+
+#[test]
+fn test_parse_time() {
+    let words = |s: &str| {
+        s.split(' ')
+            .map(|w| Token::Word(w.into()))
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(parse_time(&words("never")), Some(NEVER));
+    assert_eq!(parse_time(&words("epoch 1672912800")), Some(1672912800));
+    assert_eq!(
+        parse_time(&words("4 2023/01/05 10:00:00")),
+        Some(1672912800)
+    );
+    assert_eq!(
+        parse_time(&words("0 2024/02/29 00:00:01")),
+        Some(1709164801)
+    );
+    assert_eq!(parse_time(&words("4 2023/01/05")), None);
+}
+
+#[test]
+fn test_parse_dhcpd_leases() {
+    let leases = parse(
+        r#"# The format of this file is documented in the dhcpd.leases(5) manual page.
+# This lease file was written by isc-dhcp-4.4.3
+
+# authoring-byte-order entry is generated, DO NOT DELETE
+authoring-byte-order little-endian;
+
+server-duid "\000\001\000\001+\223\350\250RT\000\022\0244";
+
+lease 192.0.2.10 {
+  starts 4 2023/01/05 10:00:00;
+  ends 4 2023/01/05 22:00:00;
+  cltt 4 2023/01/05 10:00:00;
+  binding state active;
+  next binding state free;
+  rewind binding state free;
+  hardware ethernet 00:11:22:33:44:55;
+  uid "\001\000\021\"3DU";
+  client-hostname "laptop";
+}
+lease 192.0.2.11 {
+  starts epoch 1672912800; # 2023/01/05 10:00:00
+  ends never;
+  binding state active;
+  hardware ethernet 00:11:22:33:44:66;
+  on expiry { set x = "y"; }
+}
+lease 192.0.2.12 {
+  starts 4 2023/01/05 10:00:00;
+  ends 4 2023/01/05 22:00:00;
+  binding state active;
+  hardware ethernet 00:11:22:33:44:77;
+}
+lease 192.0.2.12 {
+  starts 4 2023/01/05 10:00:00;
+  ends 4 2023/01/05 12:00:00;
+  binding state free;
+  hardware ethernet 00:11:22:33:44:77;
+}
+lease6 "\001\000\000\000" {
+  cltt 4 2023/01/05 10:00:00;
+  iaaddr 2001:db8::1 {
+    binding state active;
+  }
+}
+"#,
+    )
+    .unwrap();
+    assert_eq!(
+        leases,
+        vec![
+            ImportedLease {
+                ip: "192.0.2.10".parse().unwrap(),
+                client_id: vec![1, 0, 0x11, 0x22, 0x33, 0x44, 0x55],
+                chaddr: vec![0, 0x11, 0x22, 0x33, 0x44, 0x55],
+                start: 1672912800,
+                expire: 1672956000,
+                hostname: Some("laptop".into()),
+            },
+            ImportedLease {
+                ip: "192.0.2.11".parse().unwrap(),
+                client_id: vec![0, 0x11, 0x22, 0x33, 0x44, 0x66],
+                chaddr: vec![0, 0x11, 0x22, 0x33, 0x44, 0x66],
+                start: 1672912800,
+                expire: NEVER,
+                hostname: None,
+            },
+        ]
+    );
+    assert!(matches!(
+        parse_dhcpd_leases("lease 192.0.2.1 {\n  ends never;\n"),
+        Err(Error::Parse(1, _))
+    ));
+    assert!(matches!(
+        parse_dhcpd_leases("lease 192.0.2.1 {\n  ends never;\n}\n"),
+        Err(Error::Parse(1, _))
+    ));
+}
+
+#[test]
+fn test_parse_dnsmasq_leases() {
+    let leases = parse(
+        "1672956000 00:11:22:33:44:55 192.0.2.10 laptop 01:00:11:22:33:44:55
+0 00:11:22:33:44:66 192.0.2.11 * *
+duid 00:01:00:01:2b:93:e8:a8:52:54:00:12:34:56
+1672956000 12345678 2001:db8::1 phone 00:01:00:01:2b:93:e8:a8:52:54:00:12:34:57
+",
+    )
+    .unwrap();
+    assert_eq!(leases.len(), 2);
+    assert_eq!(leases[0].ip, "192.0.2.10".parse::<Ipv4Addr>().unwrap());
+    assert_eq!(
+        leases[0].client_id,
+        vec![1, 0, 0x11, 0x22, 0x33, 0x44, 0x55]
+    );
+    assert_eq!(leases[0].chaddr, vec![0, 0x11, 0x22, 0x33, 0x44, 0x55]);
+    assert_eq!(leases[0].expire, 1672956000);
+    assert_eq!(leases[0].hostname, Some("laptop".into()));
+    assert_eq!(leases[1].client_id, vec![0, 0x11, 0x22, 0x33, 0x44, 0x66]);
+    assert_eq!(leases[1].expire, NEVER);
+    assert_eq!(leases[1].hostname, None);
+    assert!(matches!(
+        parse_dnsmasq_leases("soon 00:11:22:33:44:55 192.0.2.10 * *"),
+        Err(Error::Parse(1, _))
+    ));
+}
+
+#[tokio::test]
+async fn test_import() {
+    let conf = crate::config::load_config_from_string_for_test(
+        "---
+dhcp-policies:
+  - match-subnet: 192.0.2.0/24
+    apply-range: {start: 192.0.2.10, end: 192.0.2.20}
+",
+    )
+    .unwrap();
+    let conf = conf.read().await;
+    let mut pool = pool::Pool::new_in_memory().unwrap();
+    let lease = |ip: &str, client_id: &[u8]| ImportedLease {
+        ip: ip.parse().unwrap(),
+        client_id: client_id.to_vec(),
+        chaddr: vec![],
+        start: 0,
+        expire: NEVER,
+        hostname: Some("host".into()),
+    };
+    let summary = import(
+        &mut pool,
+        &conf,
+        &[
+            lease("192.0.2.10", b"one"),
+            /* Outside the configured range */
+            lease("192.0.2.30", b"two"),
+            /* Same address, different client */
+            lease("192.0.2.10", b"three"),
+        ],
+    )
+    .unwrap();
+    assert_eq!(
+        summary,
+        Summary {
+            imported: 1,
+            skipped: 2
+        }
+    );
+    let leases = pool.get_leases().unwrap();
+    assert_eq!(leases.len(), 1);
+    assert_eq!(leases[0].client_id, b"one");
+    let options =
+        dhcppkt::parse_options(crate::pktparser::Buffer::new(&leases[0].options)).unwrap();
+    assert_eq!(options.get_hostname(), Some("host".into()));
+}
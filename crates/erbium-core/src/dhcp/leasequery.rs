@@ -0,0 +1,896 @@
+/*   Copyright 2023 Perry Lorier
+ *
+ *  Licensed under the Apache License, Version 2.0 (the "License");
+ *  you may not use this file except in compliance with the License.
+ *  You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ *  Unless required by applicable law or agreed to in writing, software
+ *  distributed under the License is distributed on an "AS IS" BASIS,
+ *  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ *  See the License for the specific language governing permissions and
+ *  limitations under the License.
+ *
+ *  SPDX-License-Identifier: Apache-2.0
+ *
+ *  Sections quoted from RFCs are covered by the terms specified in RFC3978.
+ *
+ *  DHCP Leasequery, so relay agents and monitoring can ask who holds an address.
+ *
+ *  Single queries (RFC4388) arrive over UDP like any other DHCP packet.  Bulk leasequery (RFC6926)
+ *  and active leasequery (RFC7724) use a TCP connection to port 67, with each message prefixed by
+ *  its length.  Active leasequery streams every lease change to the requester as it happens.
+ */
+
+use super::dhcppkt;
+use super::pool::{self, LeaseInfo};
+use crate::acl;
+use std::net::Ipv4Addr;
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt as _, AsyncWriteExt as _};
+use tokio::sync::{broadcast, Mutex};
+
+/// Close bulk leasequery connections that haven't sent a query for this long.
+const IDLE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(120);
+/// How many lease changes can be waiting to be sent to an active leasequery requester, before it
+/// is told it has missed some.
+const MAX_QUEUED_UPDATES: usize = 1024;
+
+lazy_static::lazy_static! {
+    static ref DHCP_LEASEQUERIES: prometheus::IntCounterVec = prometheus::register_int_counter_vec!(
+        "dhcp_leasequeries",
+        "Number of DHCP leasequeries answered",
+        &["query"]
+    )
+    .unwrap();
+    static ref DHCP_LEASEQUERY_CONNECTIONS: prometheus::IntGauge = prometheus::register_int_gauge!(
+        "dhcp_leasequery_connections",
+        "Number of open bulk and active DHCP leasequery connections"
+    )
+    .unwrap();
+}
+
+/// RFC6926 Section 6.2.2 status codes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Status {
+    Success = 0,
+    UnspecFail = 1,
+    MalformedQuery = 3,
+    DataMissing = 5,
+    CatchUpComplete = 7,
+    TlsConnectionRefused = 8,
+}
+
+/// RFC6926 Section 6.2.7 lease states.
+const STATE_ACTIVE: u8 = 2;
+const STATE_EXPIRED: u8 = 3;
+
+#[derive(Debug, PartialEq, Eq)]
+enum Query {
+    Ip(Ipv4Addr),
+    ClientId(Vec<u8>),
+    HwAddr(Vec<u8>),
+    RelayId(Vec<u8>),
+    RemoteId(Vec<u8>),
+    All,
+}
+
+impl Query {
+    /// A query carries exactly one of these (RFC4388 Section 6.1, RFC6926 Section 7.2).
+    fn from_pkt(pkt: &dhcppkt::Dhcp) -> Self {
+        if !pkt.ciaddr.is_unspecified() {
+            Query::Ip(pkt.ciaddr)
+        } else if let Some(client_id) = pkt.options.get_clientid() {
+            Query::ClientId(client_id)
+        } else if pkt.chaddr.iter().any(|b| *b != 0) {
+            Query::HwAddr(pkt.chaddr.clone())
+        } else if let Some(relay_id) = pkt
+            .options
+            .get_relay_agent_suboption(dhcppkt::RELAY_RELAY_ID)
+        {
+            Query::RelayId(relay_id)
+        } else if let Some(remote_id) = pkt
+            .options
+            .get_relay_agent_suboption(dhcppkt::RELAY_REMOTE_ID)
+        {
+            Query::RemoteId(remote_id)
+        } else {
+            Query::All
+        }
+    }
+
+    fn get_variant_name(&self) -> &'static str {
+        match self {
+            Query::Ip(_) => "IP",
+            Query::ClientId(_) => "CLIENT_ID",
+            Query::HwAddr(_) => "HWADDR",
+            Query::RelayId(_) => "RELAY_ID",
+            Query::RemoteId(_) => "REMOTE_ID",
+            Query::All => "ALL",
+        }
+    }
+
+    fn matches(&self, lease: &LeaseInfo) -> bool {
+        match self {
+            Query::Ip(ip) => lease.ip == *ip,
+            Query::ClientId(client_id) => lease.client_id == *client_id,
+            /* Clients without a client identifier are identified by their chaddr, but most
+             * clients send an RFC2132 Section 9.14 type 1 identifier containing it instead.
+             */
+            Query::HwAddr(chaddr) => {
+                lease.client_id == *chaddr
+                    || (lease.client_id.first() == Some(&1) && lease.client_id[1..] == chaddr[..])
+            }
+            Query::RelayId(relay_id) => {
+                get_stored_options(lease).get_relay_agent_suboption(dhcppkt::RELAY_RELAY_ID)
+                    == Some(relay_id.clone())
+            }
+            Query::RemoteId(remote_id) => {
+                get_stored_options(lease).get_relay_agent_suboption(dhcppkt::RELAY_REMOTE_ID)
+                    == Some(remote_id.clone())
+            }
+            Query::All => true,
+        }
+    }
+
+    /// Every lease matching the query, most recent first.
+    fn get_leases(&self, pool: &mut pool::Pool) -> Result<Vec<LeaseInfo>, pool::Error> {
+        let mut leases = match self {
+            Query::Ip(ip) => pool.get_lease(*ip)?.into_iter().collect(),
+            Query::ClientId(client_id) => pool.get_client_leases(client_id)?,
+            Query::HwAddr(chaddr) => {
+                let mut leases = pool.get_client_leases(chaddr)?;
+                let mut client_id = vec![1];
+                client_id.extend(chaddr);
+                leases.extend(pool.get_client_leases(&client_id)?);
+                leases
+            }
+            _ => pool
+                .get_leases()?
+                .into_iter()
+                .filter(|lease| self.matches(lease))
+                .collect(),
+        };
+        leases.sort_by_key(|lease| std::cmp::Reverse(lease.start));
+        Ok(leases)
+    }
+}
+
+fn now() -> u32 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs() as u32)
+        .unwrap_or(0)
+}
+
+/// The options the client sent when it last got its lease.
+fn get_stored_options(lease: &LeaseInfo) -> dhcppkt::DhcpOptions {
+    dhcppkt::parse_options(crate::pktparser::Buffer::new(&lease.options)).unwrap_or_default()
+}
+
+fn build_reply(
+    req: &dhcppkt::Dhcp,
+    serverid: Ipv4Addr,
+    msgtype: dhcppkt::MessageType,
+) -> dhcppkt::Dhcp {
+    dhcppkt::Dhcp {
+        op: dhcppkt::OP_BOOTREPLY,
+        htype: dhcppkt::HWTYPE_ETHERNET,
+        hlen: 6,
+        hops: 0,
+        xid: req.xid,
+        secs: 0,
+        flags: 0,
+        ciaddr: Ipv4Addr::UNSPECIFIED,
+        yiaddr: Ipv4Addr::UNSPECIFIED,
+        siaddr: Ipv4Addr::UNSPECIFIED,
+        giaddr: req.giaddr,
+        chaddr: vec![0; 6],
+        sname: vec![],
+        file: vec![],
+        options: dhcppkt::DhcpOptions::default()
+            .set_option(&dhcppkt::OPTION_MSGTYPE, &msgtype)
+            .set_option(&dhcppkt::OPTION_SERVERID, &serverid),
+    }
+}
+
+fn build_status(
+    req: &dhcppkt::Dhcp,
+    serverid: Ipv4Addr,
+    msgtype: dhcppkt::MessageType,
+    status: Status,
+    message: &str,
+) -> dhcppkt::Dhcp {
+    let mut value = vec![status as u8];
+    value.extend(message.as_bytes());
+    let mut reply = build_reply(req, serverid, msgtype);
+    reply.options = reply
+        .options
+        .set_raw_option(&dhcppkt::OPTION_STATUS_CODE, &value);
+    reply
+}
+
+/// Describes `lease`.  `associated` is every address the client holds, which is only sent if
+/// there's more than one.  Bulk replies also carry the RFC6926 timing and state options.
+fn build_lease_reply(
+    req: &dhcppkt::Dhcp,
+    serverid: Ipv4Addr,
+    lease: &LeaseInfo,
+    associated: &[Ipv4Addr],
+    now: u32,
+    bulk: bool,
+) -> dhcppkt::Dhcp {
+    let active = lease.expire > now;
+    let mut reply = build_reply(
+        req,
+        serverid,
+        if active {
+            dhcppkt::DHCPLEASEACTIVE
+        } else {
+            dhcppkt::DHCPLEASEUNASSIGNED
+        },
+    );
+    reply.ciaddr = lease.ip;
+    /* RFC4388 Section 6.4.1: chaddr is the client's hardware address if we know it.  If we don't,
+     * htype and hlen are zero so it isn't mistaken for a hardware address of all zeros.
+     */
+    if lease.chaddr.is_empty() {
+        reply.htype = dhcppkt::HWTYPE_NONE;
+        reply.hlen = 0;
+        reply.chaddr = vec![];
+    } else {
+        reply.hlen = lease.chaddr.len() as u8;
+        reply.chaddr = lease.chaddr.clone();
+    }
+
+    /* RFC4388 Section 6.4.2: Options the requester asked for are returned from what the client
+     * sent us, followed by the ones describing the lease itself.
+     */
+    let stored = get_stored_options(lease);
+    let mut options = dhcppkt::DhcpOptions::default();
+    for option in req
+        .options
+        .get_option::<Vec<u8>>(&dhcppkt::OPTION_PARAMLIST)
+        .unwrap_or_default()
+        .into_iter()
+        .map(dhcppkt::DhcpOption::from)
+        .chain(std::iter::once(dhcppkt::OPTION_RELAYAGENTINFO))
+    {
+        if let Some(value) = stored.get_raw_option(&option) {
+            options = options.set_raw_option(&option, value);
+        }
+    }
+    /* A bare chaddr is what we use when there's no client identifier */
+    if lease.client_id.len() != 6 {
+        options = options.set_raw_option(&dhcppkt::OPTION_CLIENTID, &lease.client_id);
+    }
+    if active {
+        options = options.set_option(&dhcppkt::OPTION_LEASETIME, &(lease.expire - now));
+    }
+    options = options.set_option(
+        &dhcppkt::OPTION_CLIENT_LAST_TRANSACTION_TIME,
+        &now.saturating_sub(lease.start),
+    );
+    if associated.len() > 1 {
+        options = options.set_option(&dhcppkt::OPTION_ASSOCIATED_IP, &associated.to_vec());
+    }
+    if bulk {
+        options = options
+            .set_option(&dhcppkt::OPTION_BASE_TIME, &now)
+            .set_option(
+                &dhcppkt::OPTION_START_TIME_OF_STATE,
+                &now.saturating_sub(if active { lease.start } else { lease.expire }),
+            )
+            .set_option(
+                &dhcppkt::OPTION_DHCP_STATE,
+                &if active { STATE_ACTIVE } else { STATE_EXPIRED },
+            );
+    }
+    for (option, value) in reply.options.other {
+        options.other.insert(option, value);
+    }
+    reply.options = options;
+    reply
+}
+
+/// Answers a DHCPLEASEQUERY (RFC4388) received over UDP.
+pub fn handle_leasequery(
+    pools: &mut pool::Pool,
+    req: &super::DHCPRequest,
+    conf: &crate::config::Config,
+) -> Result<dhcppkt::Dhcp, super::DhcpError> {
+    let query = Query::from_pkt(&req.pkt);
+    DHCP_LEASEQUERIES
+        .with_label_values(&[query.get_variant_name()])
+        .inc();
+    let now = now();
+    let leases = match query {
+        Query::Ip(_) | Query::ClientId(_) | Query::HwAddr(_) => query
+            .get_leases(pools)
+            .map_err(super::DhcpError::PoolError)?,
+        /* These are only supported by bulk leasequery */
+        _ => {
+            return Ok(build_reply(
+                &req.pkt,
+                req.serverip,
+                dhcppkt::DHCPLEASEUNKNOWN,
+            ))
+        }
+    };
+    let active = leases
+        .iter()
+        .filter(|lease| lease.expire > now)
+        .collect::<Vec<_>>();
+    let associated = active.iter().map(|lease| lease.ip).collect::<Vec<_>>();
+    Ok(match (query, active.first(), leases.first()) {
+        /* RFC4388 Section 6.4.1: If the client has several leases, the most recent is returned */
+        (_, Some(lease), _) => {
+            build_lease_reply(&req.pkt, req.serverip, lease, &associated, now, false)
+        }
+        (Query::Ip(_), None, Some(lease)) => {
+            build_lease_reply(&req.pkt, req.serverip, lease, &[], now, false)
+        }
+        /* RFC4388 Section 6.4.2: An address we could hand out, but nobody holds */
+        (Query::Ip(ip), None, None)
+            if super::is_configured_address(conf, &conf.dhcp.get_all_used_addresses(), ip) =>
+        {
+            let mut reply = build_reply(&req.pkt, req.serverip, dhcppkt::DHCPLEASEUNASSIGNED);
+            reply.ciaddr = ip;
+            reply
+        }
+        (query, _, _) => {
+            let mut reply = build_reply(&req.pkt, req.serverip, dhcppkt::DHCPLEASEUNKNOWN);
+            if let Query::Ip(ip) = query {
+                reply.ciaddr = ip;
+            }
+            reply
+        }
+    })
+}
+
+/* ---- Bulk and active leasequery over TCP ---- */
+
+async fn read_message(
+    reader: &mut (impl tokio::io::AsyncRead + Unpin),
+) -> Result<Option<dhcppkt::Dhcp>, std::io::Error> {
+    let len = match reader.read_u16().await {
+        Ok(len) => len,
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let mut buf = vec![0; len.into()];
+    reader.read_exact(&mut buf).await?;
+    dhcppkt::parse(&buf)
+        .map(Some)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e.to_string()))
+}
+
+async fn write_message(
+    writer: &mut (impl tokio::io::AsyncWrite + Unpin),
+    pkt: &dhcppkt::Dhcp,
+) -> Result<(), std::io::Error> {
+    let buf = pkt.serialise();
+    let len = u16::try_from(buf.len())
+        .map_err(|_| std::io::Error::new(std::io::ErrorKind::InvalidData, "Message too long"))?;
+    writer.write_u16(len).await?;
+    writer.write_all(&buf).await
+}
+
+/// The start and end of the time range a query is interested in (RFC6926 Section 7.2.5).
+fn get_query_times(pkt: &dhcppkt::Dhcp) -> (Option<u32>, Option<u32>) {
+    (
+        pkt.options
+            .get_option::<u32>(&dhcppkt::OPTION_QUERY_START_TIME),
+        pkt.options
+            .get_option::<u32>(&dhcppkt::OPTION_QUERY_END_TIME),
+    )
+}
+
+fn in_time_range(lease: &LeaseInfo, (start, end): (Option<u32>, Option<u32>)) -> bool {
+    start.map(|start| lease.start >= start).unwrap_or(true)
+        && end.map(|end| lease.start <= end).unwrap_or(true)
+}
+
+pub struct Server {
+    conf: crate::config::SharedConfig,
+    pool: Arc<Mutex<pool::Pool>>,
+    updates: broadcast::Sender<LeaseInfo>,
+}
+
+impl Server {
+    /// Sets up leasequery, and starts watching the pool for changes to send to active
+    /// leasequery requesters.
+    pub async fn new(conf: crate::config::SharedConfig, pool: Arc<Mutex<pool::Pool>>) -> Arc<Self> {
+        let (updates, _) = broadcast::channel(MAX_QUEUED_UPDATES);
+        let sender = updates.clone();
+        pool.lock()
+            .await
+            .add_observer(Box::new(move |event: &pool::Event| {
+                /* Nobody listening is fine */
+                let _ = sender.send(event.lease.clone());
+            }));
+        Arc::new(Self {
+            conf,
+            pool,
+            updates,
+        })
+    }
+
+    /// Answers a DHCPBULKLEASEQUERY with every matching lease, followed by a DHCPLEASEQUERYDONE.
+    async fn bulk(
+        &self,
+        writer: &mut (impl tokio::io::AsyncWrite + Unpin),
+        req: &dhcppkt::Dhcp,
+        serverid: Ipv4Addr,
+    ) -> Result<(), std::io::Error> {
+        let query = Query::from_pkt(req);
+        DHCP_LEASEQUERIES
+            .with_label_values(&[query.get_variant_name()])
+            .inc();
+        let leases = query.get_leases(&mut *self.pool.lock().await);
+        let leases = match leases {
+            Ok(leases) => leases,
+            Err(e) => {
+                return write_message(
+                    writer,
+                    &build_status(
+                        req,
+                        serverid,
+                        dhcppkt::DHCPLEASEQUERYDONE,
+                        Status::UnspecFail,
+                        &e.to_string(),
+                    ),
+                )
+                .await
+            }
+        };
+        let now = now();
+        let times = get_query_times(req);
+        for lease in leases.iter().filter(|lease| in_time_range(lease, times)) {
+            write_message(
+                writer,
+                &build_lease_reply(req, serverid, lease, &[], now, true),
+            )
+            .await?;
+        }
+        write_message(
+            writer,
+            &build_status(
+                req,
+                serverid,
+                dhcppkt::DHCPLEASEQUERYDONE,
+                Status::Success,
+                "",
+            ),
+        )
+        .await
+    }
+
+    /// Answers a DHCPACTIVELEASEQUERY by sending every matching lease change, until the
+    /// requester goes away.  If the query asks to catch up, leases that changed since the
+    /// query start time are sent first.
+    async fn active(
+        &self,
+        reader: &mut (impl tokio::io::AsyncRead + Unpin),
+        writer: &mut (impl tokio::io::AsyncWrite + Unpin),
+        req: &dhcppkt::Dhcp,
+        serverid: Ipv4Addr,
+    ) -> Result<(), std::io::Error> {
+        let query = Query::from_pkt(req);
+        if !matches!(query, Query::RelayId(_) | Query::RemoteId(_) | Query::All) {
+            /* RFC7724 Section 8.2: Only these queries can be active */
+            return write_message(
+                writer,
+                &build_status(
+                    req,
+                    serverid,
+                    dhcppkt::DHCPLEASEQUERYSTATUS,
+                    Status::MalformedQuery,
+                    "Active leasequery only supports relay-id, remote-id or all leases",
+                ),
+            )
+            .await;
+        }
+        DHCP_LEASEQUERIES
+            .with_label_values(&[query.get_variant_name()])
+            .inc();
+        /* Subscribe before catching up, so nothing is missed in between */
+        let mut updates = self.updates.subscribe();
+        if let (Some(start), _) = get_query_times(req) {
+            let leases = query.get_leases(&mut *self.pool.lock().await);
+            let now = now();
+            for lease in leases
+                .map_err(|e| std::io::Error::other(e.to_string()))?
+                .iter()
+                .filter(|lease| lease.start >= start)
+            {
+                write_message(
+                    writer,
+                    &build_lease_reply(req, serverid, lease, &[], now, true),
+                )
+                .await?;
+            }
+            write_message(
+                writer,
+                &build_status(
+                    req,
+                    serverid,
+                    dhcppkt::DHCPLEASEQUERYSTATUS,
+                    Status::CatchUpComplete,
+                    "",
+                ),
+            )
+            .await?;
+        }
+        let mut buf = [0_u8; 1];
+        loop {
+            tokio::select! {
+                update = updates.recv() => match update {
+                    Ok(lease) if query.matches(&lease) => {
+                        write_message(
+                            writer,
+                            &build_lease_reply(req, serverid, &lease, &[], now(), true),
+                        )
+                        .await?
+                    }
+                    Ok(_) => (),
+                    Err(broadcast::error::RecvError::Lagged(missed)) => {
+                        log::warn!("Active leasequery requester missed {} lease changes", missed);
+                        write_message(
+                            writer,
+                            &build_status(
+                                req,
+                                serverid,
+                                dhcppkt::DHCPLEASEQUERYSTATUS,
+                                Status::DataMissing,
+                                "Too many lease changes to send",
+                            ),
+                        )
+                        .await?
+                    }
+                    Err(broadcast::error::RecvError::Closed) => return Ok(()),
+                },
+                /* The requester doesn't send anything else, so this is it closing the connection */
+                _ = reader.read(&mut buf) => return Ok(()),
+            }
+        }
+    }
+
+    async fn handle_connection(
+        &self,
+        mut stream: tokio::net::TcpStream,
+    ) -> Result<(), std::io::Error> {
+        let serverid = match stream.local_addr()?.ip() {
+            std::net::IpAddr::V4(ip) => ip,
+            std::net::IpAddr::V6(ip) => ip.to_ipv4_mapped().unwrap_or(Ipv4Addr::UNSPECIFIED),
+        };
+        let (mut reader, mut writer) = stream.split();
+        while let Some(req) = tokio::time::timeout(IDLE_TIMEOUT, read_message(&mut reader))
+            .await
+            .map_err(|_| std::io::Error::from(std::io::ErrorKind::TimedOut))??
+        {
+            match req.options.get_messagetype() {
+                Some(dhcppkt::DHCPBULKLEASEQUERY) => self.bulk(&mut writer, &req, serverid).await?,
+                Some(dhcppkt::DHCPACTIVELEASEQUERY) => {
+                    return self.active(&mut reader, &mut writer, &req, serverid).await
+                }
+                /* RFC7724 Section 8.3: We don't support TLS */
+                Some(dhcppkt::DHCPTLS) => {
+                    write_message(
+                        &mut writer,
+                        &build_status(
+                            &req,
+                            serverid,
+                            dhcppkt::DHCPTLS,
+                            Status::TlsConnectionRefused,
+                            "TLS is not supported",
+                        ),
+                    )
+                    .await?
+                }
+                x => {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        format!("Unexpected message {:?}", x),
+                    ))
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn run_with_listener(
+        self: Arc<Self>,
+        listener: tokio::net::TcpListener,
+    ) -> Result<(), std::io::Error> {
+        loop {
+            let (stream, addr) = listener.accept().await?;
+            let client = acl::Attributes { addr: addr.into() };
+            if let Err(e) = acl::require_permission(
+                &self.conf.read().await.acls,
+                &client,
+                acl::PermissionType::DhcpLeaseQuery,
+            ) {
+                log::debug!("Refusing leasequery connection from {}: {}", addr, e);
+                continue;
+            }
+            let self2 = self.clone();
+            tokio::spawn(async move {
+                DHCP_LEASEQUERY_CONNECTIONS.inc();
+                if let Err(e) = self2.handle_connection(stream).await {
+                    log::warn!("Leasequery connection from {} failed: {}", addr, e);
+                }
+                DHCP_LEASEQUERY_CONNECTIONS.dec();
+            });
+        }
+    }
+
+    pub async fn run(self: Arc<Self>) -> Result<(), std::io::Error> {
+        if !acl::any_has_permission(
+            &self.conf.read().await.acls,
+            acl::PermissionType::DhcpLeaseQuery,
+        ) {
+            log::debug!("No ACL allows dhcp-leasequery, not listening for bulk leasequery");
+            return Ok(());
+        }
+        let listener = tokio::net::TcpListener::bind(std::net::SocketAddr::new(
+            std::net::Ipv6Addr::UNSPECIFIED.into(),
+            67,
+        ))
+        .await?;
+        log::info!(
+            "Listening for DHCP bulk leasequery on {}",
+            listener.local_addr()?
+        );
+        self.run_with_listener(listener).await
+    }
+}
+
+#[cfg(test)]
+fn mk_lease(ip: &str, client_id: &[u8], start: u32, expire: u32) -> LeaseInfo {
+    LeaseInfo {
+        ip: ip.parse().unwrap(),
+        client_id: client_id.to_vec(),
+        chaddr: vec![],
+        start,
+        expire,
+        options: vec![],
+    }
+}
+
+#[test]
+fn test_query_matches() {
+    let mut pkt = super::test::mk_dhcp_request().pkt;
+    pkt.chaddr = vec![0x00, 0x00, 0x5E, 0x00, 0x53, 0x01];
+    let query = Query::from_pkt(&pkt);
+    assert_eq!(query, Query::HwAddr(pkt.chaddr.clone()));
+    assert!(query.matches(&mk_lease("192.0.2.10", &pkt.chaddr, 0, 0)));
+    assert!(query.matches(&mk_lease(
+        "192.0.2.10",
+        &[1, 0x00, 0x00, 0x5E, 0x00, 0x53, 0x01],
+        0,
+        0
+    )));
+    assert!(!query.matches(&mk_lease("192.0.2.10", b"other", 0, 0)));
+
+    pkt.ciaddr = "192.0.2.10".parse().unwrap();
+    assert_eq!(
+        Query::from_pkt(&pkt),
+        Query::Ip("192.0.2.10".parse().unwrap())
+    );
+
+    pkt.ciaddr = Ipv4Addr::UNSPECIFIED;
+    pkt.chaddr = vec![0; 6];
+    assert_eq!(Query::from_pkt(&pkt), Query::All);
+    pkt.options = pkt
+        .options
+        .set_raw_option(&dhcppkt::OPTION_RELAYAGENTINFO, &[12, 3, b'r', b'i', b'd']);
+    assert_eq!(Query::from_pkt(&pkt), Query::RelayId(b"rid".to_vec()));
+    let mut relayed = mk_lease("192.0.2.10", b"client", 0, 0);
+    use dhcppkt::Serialise as _;
+    dhcppkt::DhcpOptions::default()
+        .set_raw_option(&dhcppkt::OPTION_RELAYAGENTINFO, &[12, 3, b'r', b'i', b'd'])
+        .serialise(&mut relayed.options);
+    assert!(Query::from_pkt(&pkt).matches(&relayed));
+    assert!(!Query::from_pkt(&pkt).matches(&mk_lease("192.0.2.10", b"client", 0, 0)));
+}
+
+#[tokio::test]
+async fn test_leasequery() {
+    let mut p = pool::Pool::new_in_memory().unwrap();
+    let conf = crate::config::load_config_from_string_for_test(
+        "
+dhcp-policies:
+  - match-subnet: 192.0.2.0/24
+    apply-range: {start: 192.0.2.10, end: 192.0.2.20}
+",
+    )
+    .unwrap();
+    let conf = conf.read().await;
+    let now = now();
+    let client = [1, 0x00, 0x00, 0x5E, 0x00, 0x53, 0x01];
+    for (ip, start, expire) in [
+        ("192.0.2.10", now - 100, now - 10),
+        ("192.0.2.11", now - 60, now + 600),
+        ("192.0.2.12", now - 50, now + 600),
+    ] {
+        p.restore_lease(LeaseInfo {
+            chaddr: client[1..].to_vec(),
+            ..mk_lease(ip, &client, start, expire)
+        })
+        .unwrap();
+    }
+    /* A lease recorded without the client's hardware address */
+    p.restore_lease(mk_lease("192.0.2.13", b"other", now - 50, now + 600))
+        .unwrap();
+
+    let mut req = super::test::mk_dhcp_request();
+    req.pkt.options = req
+        .pkt
+        .options
+        .set_option(&dhcppkt::OPTION_MSGTYPE, &dhcppkt::DHCPLEASEQUERY);
+
+    /* By IP */
+    req.pkt.ciaddr = "192.0.2.11".parse().unwrap();
+    let reply = handle_leasequery(&mut p, &req, &conf).unwrap();
+    assert_eq!(
+        reply.options.get_messagetype(),
+        Some(dhcppkt::DHCPLEASEACTIVE)
+    );
+    assert_eq!(reply.ciaddr, req.pkt.ciaddr);
+    assert_eq!(reply.htype, dhcppkt::HWTYPE_ETHERNET);
+    assert_eq!(reply.hlen, 6);
+    assert_eq!(reply.chaddr, client[1..]);
+    assert_eq!(reply.xid, req.pkt.xid);
+    let remaining = reply
+        .options
+        .get_option::<u32>(&dhcppkt::OPTION_LEASETIME)
+        .unwrap();
+    assert!((599..=600).contains(&remaining), "{}", remaining);
+    assert_eq!(reply.options.get_clientid().as_deref(), Some(&client[..]));
+
+    /* We say we don't know the hardware address, rather than claiming it is all zeros */
+    req.pkt.ciaddr = "192.0.2.13".parse().unwrap();
+    let reply = handle_leasequery(&mut p, &req, &conf).unwrap();
+    assert_eq!(
+        reply.options.get_messagetype(),
+        Some(dhcppkt::DHCPLEASEACTIVE)
+    );
+    assert_eq!(reply.htype, dhcppkt::HWTYPE_NONE);
+    assert_eq!(reply.hlen, 0);
+    assert_eq!(reply.chaddr, b"");
+
+    /* An expired lease, a free address, and an address that isn't ours */
+    for (ip, msgtype) in [
+        ("192.0.2.10", dhcppkt::DHCPLEASEUNASSIGNED),
+        ("192.0.2.15", dhcppkt::DHCPLEASEUNASSIGNED),
+        ("198.51.100.1", dhcppkt::DHCPLEASEUNKNOWN),
+    ] {
+        req.pkt.ciaddr = ip.parse().unwrap();
+        let reply = handle_leasequery(&mut p, &req, &conf).unwrap();
+        assert_eq!(reply.options.get_messagetype(), Some(msgtype), "{}", ip);
+        assert_eq!(reply.ciaddr, req.pkt.ciaddr);
+    }
+
+    /* By hardware address, the most recent lease is returned with all the client's addresses */
+    req.pkt.ciaddr = Ipv4Addr::UNSPECIFIED;
+    req.pkt.chaddr = client[1..].to_vec();
+    let reply = handle_leasequery(&mut p, &req, &conf).unwrap();
+    assert_eq!(
+        reply.options.get_messagetype(),
+        Some(dhcppkt::DHCPLEASEACTIVE)
+    );
+    assert_eq!(reply.ciaddr, "192.0.2.12".parse::<Ipv4Addr>().unwrap());
+    assert_eq!(
+        reply
+            .options
+            .get_raw_option(&dhcppkt::OPTION_ASSOCIATED_IP)
+            .map(|v| v.len()),
+        Some(8)
+    );
+
+    /* By an unknown client */
+    req.pkt.chaddr = vec![0x00, 0x00, 0x5E, 0x00, 0x53, 0x02];
+    let reply = handle_leasequery(&mut p, &req, &conf).unwrap();
+    assert_eq!(
+        reply.options.get_messagetype(),
+        Some(dhcppkt::DHCPLEASEUNKNOWN)
+    );
+}
+
+#[tokio::test]
+async fn test_bulk_leasequery() {
+    let conf = crate::config::load_config_from_string_for_test(
+        "
+acls:
+  - match-subnets: [127.0.0.0/8]
+    apply-access: [dhcp-leasequery]
+",
+    )
+    .unwrap();
+    let pool = Arc::new(Mutex::new(pool::Pool::new_in_memory().unwrap()));
+    let now = now();
+    pool.lock()
+        .await
+        .restore_lease(mk_lease("192.0.2.10", b"one", now - 100, now + 100))
+        .unwrap();
+    pool.lock()
+        .await
+        .restore_lease(mk_lease("192.0.2.11", b"two", now - 200, now - 100))
+        .unwrap();
+    let server = Server::new(conf, pool.clone()).await;
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(server.clone().run_with_listener(listener));
+
+    let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+    let mut query = super::test::mk_dhcp_request().pkt;
+    query.chaddr = vec![0; 6];
+    query.options = dhcppkt::DhcpOptions::default()
+        .set_option(&dhcppkt::OPTION_MSGTYPE, &dhcppkt::DHCPBULKLEASEQUERY);
+    write_message(&mut stream, &query).await.unwrap();
+    let mut replies = vec![];
+    loop {
+        let reply = read_message(&mut stream).await.unwrap().unwrap();
+        assert_eq!(reply.xid, query.xid);
+        let msgtype = reply.options.get_messagetype().unwrap();
+        replies.push((msgtype, reply.ciaddr));
+        if msgtype == dhcppkt::DHCPLEASEQUERYDONE {
+            assert_eq!(
+                reply
+                    .options
+                    .get_raw_option(&dhcppkt::OPTION_STATUS_CODE)
+                    .map(|v| v[0]),
+                Some(Status::Success as u8)
+            );
+            break;
+        }
+    }
+    assert_eq!(
+        replies,
+        vec![
+            (
+                dhcppkt::DHCPLEASEACTIVE,
+                "192.0.2.10".parse::<Ipv4Addr>().unwrap()
+            ),
+            (
+                dhcppkt::DHCPLEASEUNASSIGNED,
+                "192.0.2.11".parse::<Ipv4Addr>().unwrap()
+            ),
+            (dhcppkt::DHCPLEASEQUERYDONE, Ipv4Addr::UNSPECIFIED),
+        ]
+    );
+
+    /* Active leasequery sends lease changes as they happen */
+    query.options = dhcppkt::DhcpOptions::default()
+        .set_option(&dhcppkt::OPTION_MSGTYPE, &dhcppkt::DHCPACTIVELEASEQUERY);
+    write_message(&mut stream, &query).await.unwrap();
+    /* Wait for the server to start listening for changes */
+    while server.updates.receiver_count() == 0 {
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+    }
+    let addresses = ["192.0.2.12".parse().unwrap()].into_iter().collect();
+    pool.lock()
+        .await
+        .allocate_address(
+            b"three",
+            b"",
+            None,
+            &addresses,
+            pool::DEFAULT_MIN_LEASE,
+            pool::DEFAULT_MAX_LEASE,
+            b"",
+        )
+        .unwrap();
+    let reply = read_message(&mut stream).await.unwrap().unwrap();
+    assert_eq!(
+        reply.options.get_messagetype(),
+        Some(dhcppkt::DHCPLEASEACTIVE)
+    );
+    assert_eq!(reply.ciaddr, "192.0.2.12".parse::<Ipv4Addr>().unwrap());
+    assert_eq!(
+        reply.options.get_option::<u8>(&dhcppkt::OPTION_DHCP_STATE),
+        Some(STATE_ACTIVE)
+    );
+}
@@ -23,19 +23,35 @@ use std::ops::Sub as _;
 use std::sync::Arc;
 use tokio::sync;
 
+use crate::acl;
 use crate::dhcp::dhcppkt::Serialise;
 use erbium_net::addr::{NetAddr, ToNetAddr, WithPort as _, UNSPECIFIED4};
 use erbium_net::packet;
 use erbium_net::raw;
 use erbium_net::udp;
 
+pub mod backup;
 pub mod config;
+pub mod conflict;
+#[cfg(feature = "dns")]
+pub mod ddns;
 pub mod dhcppkt;
+pub mod failover;
+pub mod forcerenew;
+pub mod hooks;
+pub mod import;
+pub mod leasequery;
 pub mod pool;
+pub mod ratelimit;
+pub mod store;
 #[cfg(test)]
 mod test;
 
 type UdpSocket = udp::UdpSocket;
+/// How many lease changes can be waiting for their hooks and DNS updates.
+const MAX_QUEUED_LEASE_CHANGES: usize = 1024;
+/// How often we look for expired leases, and update the lease metrics.
+const REAP_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60);
 type ServerIds = std::collections::HashSet<net::Ipv4Addr>;
 pub type SharedServerIds = Arc<sync::Mutex<ServerIds>>;
 
@@ -68,6 +84,28 @@ lazy_static::lazy_static! {
         "Counts of leases that are currently expired"
     )
     .unwrap();
+    static ref DHCP_REAPED_LEASES: prometheus::IntCounter = prometheus::register_int_counter!(
+        "dhcp_reaped_leases",
+        "Number of expired leases removed from the lease database"
+    )
+    .unwrap();
+    static ref DHCP_POOL_SIZE: prometheus::IntGaugeVec = prometheus::register_int_gauge_vec!(
+        "dhcp_pool_size",
+        "Number of addresses in each configured range",
+        &["pool"]
+    )
+    .unwrap();
+    static ref DHCP_POOL_LEASES: prometheus::IntGaugeVec = prometheus::register_int_gauge_vec!(
+        "dhcp_pool_leases",
+        "Number of addresses in each configured range that are active, expired or free",
+        &["pool", "state"]
+    )
+    .unwrap();
+    static ref DHCP_ADDRESS_CONFLICTS: prometheus::IntCounter = prometheus::register_int_counter!(
+        "dhcp_address_conflicts",
+        "Number of addresses found to already be in use by another device"
+    )
+    .unwrap();
 }
 
 #[derive(Debug, PartialEq, Eq)]
@@ -79,6 +117,8 @@ pub enum DhcpError {
     InternalError(String),
     OtherServer(std::net::Ipv4Addr),
     NoPolicyConfigured,
+    Denied,
+    UnknownClient,
 }
 
 impl std::error::Error for DhcpError {
@@ -96,6 +136,8 @@ impl std::fmt::Display for DhcpError {
             DhcpError::InternalError(e) => write!(f, "Internal Error: {:?}", e),
             DhcpError::OtherServer(s) => write!(f, "Packet for a different DHCP server: {}", s),
             DhcpError::NoPolicyConfigured => write!(f, "No policy configured for client"),
+            DhcpError::Denied => write!(f, "Client denied by policy"),
+            DhcpError::UnknownClient => write!(f, "No record of rebooting client"),
             DhcpError::PoolError(p) => write!(f, "Pool Error: {:?}", p),
         }
     }
@@ -111,13 +153,49 @@ impl DhcpError {
             InternalError(_) => "INTERNAL_ERROR",
             OtherServer(_) => "OTHER_SERVER",
             NoPolicyConfigured => "NO_POLICY",
+            Denied => "DENIED",
+            UnknownClient => "UNKNOWN_CLIENT",
             PoolError(pool::Error::NoAssignableAddress) => "NO_ADDRESS",
             PoolError(pool::Error::RequestedAddressInUse) => "ADDRESS_IN_USE",
+            PoolError(pool::Error::UnknownLease) => "UNKNOWN_LEASE",
             PoolError(_) => "INTERNAL_POOL_ERROR",
         }
     }
 }
 
+/// Why a client was sent a DHCPNAK.
+#[derive(Debug, PartialEq, Eq)]
+pub enum NakReason {
+    /// The client asked for an address that is not in the pool of the matching policy, usually
+    /// because it has moved to a different subnet.
+    WrongAddress(std::net::Ipv4Addr),
+    /// The client asked for an address that is leased to another client.
+    AddressInUse(std::net::Ipv4Addr),
+    /// A policy with apply-nak matched the client.
+    Denied,
+}
+
+impl std::fmt::Display for NakReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            NakReason::WrongAddress(ip) => write!(f, "{} is not valid on this network", ip),
+            NakReason::AddressInUse(ip) => write!(f, "{} is in use by another client", ip),
+            NakReason::Denied => write!(f, "Not permitted on this network"),
+        }
+    }
+}
+
+impl NakReason {
+    const fn get_variant_name(&self) -> &'static str {
+        use NakReason::*;
+        match self {
+            WrongAddress(_) => "NAK_WRONG_ADDRESS",
+            AddressInUse(_) => "NAK_ADDRESS_IN_USE",
+            Denied => "NAK_DENIED",
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct DHCPRequest {
     /// The DHCP request packet.
@@ -130,6 +208,26 @@ pub struct DHCPRequest {
     pub if_router: Option<std::net::Ipv4Addr>,
 }
 
+impl DHCPRequest {
+    /// Returns the address used to select which subnet the client is on.
+    ///
+    /// This is the Subnet Selection option (RFC3011) if present, otherwise the address of the
+    /// relay agent that forwarded the request, otherwise the address it was received on.
+    pub fn get_subnet_selector(&self) -> std::net::Ipv4Addr {
+        if let Some(subnet) = self
+            .pkt
+            .options
+            .get_option::<std::net::Ipv4Addr>(&dhcppkt::OPTION_SUBNETSELECT)
+        {
+            subnet
+        } else if !self.pkt.giaddr.is_unspecified() {
+            self.pkt.giaddr
+        } else {
+            self.serverip
+        }
+    }
+}
+
 #[cfg(test)]
 impl std::default::Default for DHCPRequest {
     fn default() -> Self {
@@ -183,7 +281,69 @@ fn check_policy(req: &DHCPRequest, policy: &config::Policy) -> PolicyMatch {
     }
     if let Some(match_subnet) = &policy.match_subnet {
         outcome = PolicyMatch::MatchSucceeded;
-        if !match_subnet.contains(req.serverip) {
+        if !match_subnet.contains(req.get_subnet_selector()) {
+            return PolicyMatch::MatchFailed;
+        }
+    }
+    if let Some(match_circuit_id) = &policy.match_circuit_id {
+        outcome = PolicyMatch::MatchSucceeded;
+        if req
+            .pkt
+            .options
+            .get_relay_agent_suboption(dhcppkt::RELAY_CIRCUIT_ID)
+            .as_ref()
+            != Some(match_circuit_id)
+        {
+            return PolicyMatch::MatchFailed;
+        }
+    }
+    if let Some(match_vendor_class) = &policy.match_vendor_class {
+        outcome = PolicyMatch::MatchSucceeded;
+        if !req
+            .pkt
+            .options
+            .get_raw_option(&dhcppkt::OPTION_VENDOR_CLASS)
+            .map(|class| match_vendor_class.matches(class))
+            .unwrap_or(false)
+        {
+            return PolicyMatch::MatchFailed;
+        }
+    }
+    if let Some(match_user_class) = &policy.match_user_class {
+        outcome = PolicyMatch::MatchSucceeded;
+        if !req
+            .pkt
+            .options
+            .get_user_classes()
+            .unwrap_or_default()
+            .iter()
+            .any(|class| match_user_class.matches(class))
+        {
+            return PolicyMatch::MatchFailed;
+        }
+    }
+    if let Some(match_parameter_list) = &policy.match_parameter_list {
+        outcome = PolicyMatch::MatchSucceeded;
+        /* Wrapped in commas so "1,3" doesn't match the start of "1,33" */
+        if !req
+            .pkt
+            .options
+            .get_parameter_list_fingerprint()
+            .map(|fp| match_parameter_list.matches(format!(",{},", fp).as_bytes()))
+            .unwrap_or(false)
+        {
+            return PolicyMatch::MatchFailed;
+        }
+    }
+    if let Some(match_remote_id) = &policy.match_remote_id {
+        outcome = PolicyMatch::MatchSucceeded;
+        if req
+            .pkt
+            .options
+            .get_relay_agent_suboption(dhcppkt::RELAY_REMOTE_ID)
+            .as_ref()
+            != Some(match_remote_id)
+        {
             return PolicyMatch::MatchFailed;
         }
     }
@@ -192,6 +352,12 @@ fn check_policy(req: &DHCPRequest, policy: &config::Policy) -> PolicyMatch {
         if match (m, req.pkt.options.other.get(k)) {
             (None, None) => true, /* Required that option doesn't exist, option doesn't exist */
             (None, Some(_)) => false, /* Required that option doesn't exist, option exists */
+            /* Lists (such as the client architectures in RFC4578) match if any entry matches */
+            (Some(dhcppkt::DhcpOptionTypeValue::U16List(mat)), Some(opt)) => {
+                <Vec<u16> as dhcppkt::DhcpParse>::parse_into(opt)
+                    .map(|opt| opt.iter().any(|x| mat.contains(x)))
+                    .unwrap_or(false)
+            }
             (Some(mat), Some(opt)) if &mat.as_bytes() == opt => true, /* Required it has value, and matches */
             (Some(_), Some(_)) => false, /* Required it has a value, option has some other value */
             (Some(_), None) => false, /* Required that option has a value, option doesn't exist */
@@ -252,6 +418,43 @@ fn apply_policy(req: &DHCPRequest, policy: &config::Policy, response: &mut Respo
         }
     }
 
+    /* PXE clients (RFC4578) expect the boot server and file in siaddr and file, but some ask for
+     * them as options too.
+     */
+    if let Some(next_server) = policy.apply_next_server {
+        response.next_server = Some(next_server);
+        if pl.contains(&dhcppkt::OPTION_TFTPSERVER) {
+            response
+                .options
+                .mutate_option(&dhcppkt::OPTION_TFTPSERVER, Some(&next_server.to_string()));
+        }
+    }
+    if let Some(boot_file) = &policy.apply_boot_file {
+        response.boot_file = Some(boot_file.clone());
+        /* If it doesn't fit in the file field, the only place left to send it is the option */
+        if pl.contains(&dhcppkt::OPTION_BOOTFILE) || boot_file.len() >= BOOT_FILE_LEN {
+            response
+                .options
+                .mutate_option(&dhcppkt::OPTION_BOOTFILE, Some(boot_file));
+        }
+    }
+
+    if let Some(deny) = policy.apply_deny {
+        response.deny = deny;
+    }
+    if let Some(nak) = policy.apply_nak {
+        response.nak = nak;
+    }
+    if let Some(rapid_commit) = policy.apply_rapid_commit {
+        response.rapid_commit = rapid_commit;
+    }
+    /* RFC8925 Section 3.3.2: Only clients that ask for it understand it. */
+    if let Some(wait) = policy.apply_ipv6_only_preferred {
+        if pl.contains(&dhcppkt::OPTION_IPV6_ONLY_PREFERRED) {
+            response.ipv6_only_preferred = Some(wait);
+        }
+    }
+
     /* And check to see if a subpolicy also matches */
     apply_policies(req, &policy.policies, response);
 
@@ -318,6 +521,34 @@ impl ResponseOptions {
         self.set_raw_option(option, &v)
     }
 
+    /// Sets the lease time.  The pool may have given a shorter lease than the policy expected, so
+    /// any renewal (T1) or rebinding (T2) time the policy gave that no longer falls within the
+    /// lease goes back to its default (RFC2131 Section 4.4.5).
+    fn set_lease_time(mut self, lease: std::time::Duration) -> Self {
+        let lease = u32::try_from(lease.as_secs()).unwrap_or(u32::MAX);
+        for (option, default) in [
+            (dhcppkt::OPTION_RENEWALTIME, lease / 2),
+            (dhcppkt::OPTION_REBINDTIME, lease / 8 * 7),
+        ] {
+            /* Policies may give these as 16 or 32 bit numbers */
+            if self
+                .option
+                .get(&option)
+                .and_then(Option::as_deref)
+                .map(|value| {
+                    value
+                        .iter()
+                        .fold(0_u64, |acc, byte| acc << 8 | u64::from(*byte))
+                        >= lease.into()
+                })
+                .unwrap_or(false)
+            {
+                self.mutate_option(&option, Some(&default));
+            }
+        }
+        self.set_option(&dhcppkt::OPTION_LEASETIME, &lease)
+    }
+
     pub fn mutate_option<T: dhcppkt::Serialise>(
         &mut self,
         option: &dhcppkt::DhcpOption,
@@ -358,12 +589,110 @@ impl ResponseOptions {
     }
 }
 
+/* The file field is 128 bytes, and must be NUL terminated */
+const BOOT_FILE_LEN: usize = 128;
+
 #[derive(Default)]
 struct Response {
     options: ResponseOptions,
     address: Option<pool::PoolAddresses>,
     minlease: Option<std::time::Duration>,
     maxlease: Option<std::time::Duration>,
+    next_server: Option<net::Ipv4Addr>,
+    boot_file: Option<String>,
+    deny: bool,
+    nak: bool,
+    rapid_commit: bool,
+    ipv6_only_preferred: Option<std::time::Duration>,
+}
+
+impl Response {
+    fn siaddr(&self) -> net::Ipv4Addr {
+        self.next_server.unwrap_or(net::Ipv4Addr::UNSPECIFIED)
+    }
+
+    fn file(&self) -> Vec<u8> {
+        match &self.boot_file {
+            Some(f) if f.len() < BOOT_FILE_LEN => f.as_bytes().to_vec(),
+            _ => vec![],
+        }
+    }
+}
+
+/// RFC8925 Section 3.3.2: Clients that are happy without IPv4 aren't given an address, just the
+/// IPv6-Only Preferred option to tell them how long to wait before trying again.
+fn build_ipv6_only_reply(
+    req: &DHCPRequest,
+    response: &Response,
+    msgtype: dhcppkt::MessageType,
+    wait: std::time::Duration,
+) -> dhcppkt::Dhcp {
+    log::info!(
+        "{}: Client is IPv6-only preferred, not allocating an address",
+        format_client(&req.pkt)
+    );
+    dhcppkt::Dhcp {
+        op: dhcppkt::OP_BOOTREPLY,
+        htype: dhcppkt::HWTYPE_ETHERNET,
+        hlen: 6,
+        hops: 0,
+        xid: req.pkt.xid,
+        secs: 0,
+        flags: req.pkt.flags,
+        ciaddr: net::Ipv4Addr::UNSPECIFIED,
+        yiaddr: net::Ipv4Addr::UNSPECIFIED,
+        siaddr: response.siaddr(),
+        giaddr: req.pkt.giaddr,
+        chaddr: req.pkt.chaddr.clone(),
+        sname: vec![],
+        file: response.file(),
+        options: response
+            .options
+            .clone()
+            .set_option(&dhcppkt::OPTION_MSGTYPE, &msgtype)
+            .set_option(&dhcppkt::OPTION_SERVERID, &req.serverip)
+            .set_option(
+                &dhcppkt::OPTION_IPV6_ONLY_PREFERRED,
+                &(wait.as_secs().min(u32::MAX.into()) as u32),
+            )
+            .to_options(),
+    }
+}
+
+/// In split failover mode new clients only get addresses from our half of the pool, but clients
+/// can keep any address they already have.
+fn get_failover_addresses<'a>(
+    pools: &mut pool::Pool,
+    req: &DHCPRequest,
+    conf: &super::config::Config,
+    addresses: &'a pool::PoolAddresses,
+) -> std::borrow::Cow<'a, pool::PoolAddresses> {
+    let held = match &conf.dhcp.failover {
+        Some(failover) if failover.mode == failover::Mode::Split => pools
+            .get_client_addresses(&req.pkt.get_client_id())
+            .unwrap_or_default(),
+        _ => vec![],
+    };
+    failover::filter_addresses(conf.dhcp.failover.as_ref(), addresses, &held)
+}
+
+/// RFC6704 Section 4: Gives a client that can authenticate a DHCPFORCERENEW the nonce that any
+/// DHCPFORCERENEW for the lease it was just acknowledged will be signed with.
+fn add_forcerenew_nonce(
+    options: ResponseOptions,
+    pools: &mut pool::Pool,
+    req: &DHCPRequest,
+    conf: &super::config::Config,
+    ip: net::Ipv4Addr,
+) -> ResponseOptions {
+    match (&conf.dhcp.forcerenew_secret, pools.get_lease(ip)) {
+        (Some(secret), Ok(Some(lease))) if forcerenew::is_capable(&req.pkt.options) => options
+            .set_raw_option(
+                &dhcppkt::OPTION_AUTHENTICATION,
+                &forcerenew::build_nonce_auth(secret, &lease),
+            ),
+        _ => options,
+    }
 }
 
 fn handle_discover(
@@ -389,12 +718,43 @@ fn handle_discover(
     if !base_policy && !conf_policy {
         /* If none of the policies applied at all, then provide a warning back to the caller */
         Err(DhcpError::NoPolicyConfigured)
-    } else if let Some(addresses) = response.address {
+    } else if response.deny || response.nak {
+        /* A DHCPDISCOVER can't be NAK'd (RFC2131 Section 4.3.1), so just don't make an offer */
+        Err(DhcpError::Denied)
+    } else if let Some(wait) = response.ipv6_only_preferred {
+        Ok(build_ipv6_only_reply(
+            req,
+            &response,
+            dhcppkt::DHCPOFFER,
+            wait,
+        ))
+    } else if let Some(addresses) = &response.address {
         /* At least one policy matched, and provided addresses.  So now go allocate an address */
+        let addresses = get_failover_addresses(pools, req, conf, addresses);
+        /* RFC4039 Section 3: If the client and the policy both allow it, skip the offer and
+         * commit the lease straight away.
+         */
+        let rapid_commit = response.rapid_commit && req.pkt.options.has_rapid_commit();
+        /* The stored options only carry Rapid Commit if the lease was committed, see get_binding */
         let mut raw_options = Vec::new();
-        req.pkt.options.serialise(&mut raw_options);
-        match pools.allocate_address(
+        if rapid_commit {
+            req.pkt.options.serialise(&mut raw_options);
+        } else {
+            req.pkt
+                .options
+                .clone()
+                .remove_option(&dhcppkt::OPTION_RAPID_COMMIT)
+                .serialise(&mut raw_options);
+        }
+        let allocate = if rapid_commit {
+            pool::Pool::allocate_address
+        } else {
+            pool::Pool::offer_address
+        };
+        match allocate(
+            pools,
             &req.pkt.get_client_id(),
+            &req.pkt.chaddr,
             req.pkt.options.get_address_request(),
             &addresses,
             response.minlease.unwrap_or(pool::DEFAULT_MIN_LEASE),
@@ -413,6 +773,19 @@ fn handle_discover(
                     lease.lease_type
                 );
 
+                let mut options = response
+                    .options
+                    .clone()
+                    .set_option(&dhcppkt::OPTION_SERVERID, &req.serverip);
+                if rapid_commit {
+                    log::info!("{}: Rapid committing lease", format_client(&req.pkt));
+                    options = options
+                        .set_option(&dhcppkt::OPTION_MSGTYPE, &dhcppkt::DHCPACK)
+                        .set_raw_option(&dhcppkt::OPTION_RAPID_COMMIT, &[])
+                        .set_lease_time(lease.expire);
+                    options = add_forcerenew_nonce(options, pools, req, conf, lease.ip);
+                }
+
                 Ok(dhcppkt::Dhcp {
                     op: dhcppkt::OP_BOOTREPLY,
                     htype: dhcppkt::HWTYPE_ETHERNET,
@@ -423,16 +796,12 @@ fn handle_discover(
                     flags: req.pkt.flags,
                     ciaddr: net::Ipv4Addr::UNSPECIFIED,
                     yiaddr: lease.ip,
-                    siaddr: net::Ipv4Addr::UNSPECIFIED,
+                    siaddr: response.siaddr(),
                     giaddr: req.pkt.giaddr,
                     chaddr: req.pkt.chaddr.clone(),
                     sname: vec![],
-                    file: vec![],
-                    options: response
-                        .options
-                        .clone()
-                        .set_option(&dhcppkt::OPTION_SERVERID, &req.serverip)
-                        .to_options(),
+                    file: response.file(),
+                    options: options.to_options(),
                 })
             }
             /* Some error occurred, document it. */
@@ -444,6 +813,42 @@ fn handle_discover(
     }
 }
 
+fn build_nak(req: &DHCPRequest, reason: NakReason) -> dhcppkt::Dhcp {
+    log::info!("{}: Sending DHCPNAK: {}", format_client(&req.pkt), reason);
+    DHCP_ERRORS
+        .with_label_values(&[reason.get_variant_name()])
+        .inc();
+    /* RFC2131 Section 4.3.2: The NAK must be broadcast, as the client may not have a usable
+     * address.  Setting the broadcast flag asks any relay agent to do the same.
+     */
+    dhcppkt::Dhcp {
+        op: dhcppkt::OP_BOOTREPLY,
+        htype: dhcppkt::HWTYPE_ETHERNET,
+        hlen: 6,
+        hops: 0,
+        xid: req.pkt.xid,
+        secs: 0,
+        flags: req.pkt.flags | dhcppkt::FLAG_BROADCAST,
+        ciaddr: net::Ipv4Addr::UNSPECIFIED,
+        yiaddr: net::Ipv4Addr::UNSPECIFIED,
+        siaddr: net::Ipv4Addr::UNSPECIFIED,
+        giaddr: req.pkt.giaddr,
+        chaddr: req.pkt.chaddr.clone(),
+        sname: vec![],
+        file: vec![],
+        options: ResponseOptions {
+            ..Default::default()
+        }
+        .set_option(&dhcppkt::OPTION_MSGTYPE, &dhcppkt::DHCPNAK)
+        .set_option(
+            &dhcppkt::OPTION_SERVERID,
+            &req.pkt.options.get_serverid().unwrap_or(req.serverip),
+        )
+        .set_option(&dhcppkt::OPTION_MESSAGE, &reason.to_string())
+        .to_options(),
+    }
+}
+
 fn handle_request(
     pools: &mut pool::Pool,
     req: &DHCPRequest,
@@ -468,21 +873,81 @@ fn handle_request(
     let conf_policy = apply_policies(req, &conf.dhcp.policies, &mut response);
     if !base_policy && !conf_policy {
         Err(DhcpError::NoPolicyConfigured)
-    } else if let Some(addresses) = response.address {
+    } else if response.deny {
+        Err(DhcpError::Denied)
+    } else if response.nak {
+        Ok(build_nak(req, NakReason::Denied))
+    } else if let Some(wait) = response.ipv6_only_preferred {
+        Ok(build_ipv6_only_reply(
+            req,
+            &response,
+            dhcppkt::DHCPACK,
+            wait,
+        ))
+    } else if let Some(addresses) = &response.address {
         let mut raw_options = Vec::new();
         req.pkt.options.serialise(&mut raw_options);
-        match pools.allocate_address(
-            &req.pkt.get_client_id(),
-            if !req.pkt.ciaddr.is_unspecified() {
-                Some(req.pkt.ciaddr)
-            } else {
-                req.pkt.options.get_address_request()
-            },
-            &addresses,
-            response.minlease.unwrap_or(pool::DEFAULT_MIN_LEASE),
-            response.maxlease.unwrap_or(pool::DEFAULT_MAX_LEASE),
-            &raw_options,
-        ) {
+        /* RFC2131 Section 4.3.2: The client is in RENEWING/REBINDING if ciaddr is set, otherwise
+         * it is SELECTING or INIT-REBOOT and puts the address in the 'requested IP address'
+         * option.  In every case it is asking for a particular address, which we must either ACK
+         * or NAK.
+         */
+        let requested = if !req.pkt.ciaddr.is_unspecified() {
+            Some(req.pkt.ciaddr)
+        } else {
+            req.pkt.options.get_address_request()
+        };
+        /* RFC2131 Section 4.3.2: A client in INIT-REBOOT that we have no record of may have its
+         * lease from another server, so we MUST remain silent, unless the address is wrong for
+         * this network or is held by someone else.
+         */
+        if let Some(addr) = requested {
+            let init_reboot =
+                req.pkt.ciaddr.is_unspecified() && req.pkt.options.get_serverid().is_none();
+            if init_reboot
+                && addresses.contains(&addr)
+                && pools
+                    .get_client_leases(&req.pkt.get_client_id())
+                    .map_err(DhcpError::PoolError)?
+                    .is_empty()
+            {
+                let ts = std::time::SystemTime::now()
+                    .duration_since(std::time::UNIX_EPOCH)
+                    .unwrap()
+                    .as_secs() as u32;
+                return match pools.get_lease(addr).map_err(DhcpError::PoolError)? {
+                    Some(lease) if lease.expire >= ts => {
+                        Ok(build_nak(req, NakReason::AddressInUse(addr)))
+                    }
+                    _ => Err(DhcpError::UnknownClient),
+                };
+            }
+        }
+        let allocation = match requested {
+            Some(addr) => pools.allocate_requested_address(
+                &req.pkt.get_client_id(),
+                &req.pkt.chaddr,
+                addr,
+                addresses,
+                response.minlease.unwrap_or(pool::DEFAULT_MIN_LEASE),
+                response.maxlease.unwrap_or(pool::DEFAULT_MAX_LEASE),
+                &raw_options,
+            ),
+            /* Broken clients that don't say what they want get whatever we'd have offered */
+            None => {
+                let addresses = get_failover_addresses(pools, req, conf, addresses);
+                pools.allocate_address(
+                    &req.pkt.get_client_id(),
+                    &req.pkt.chaddr,
+                    None,
+                    &addresses,
+                    response.minlease.unwrap_or(pool::DEFAULT_MIN_LEASE),
+                    response.maxlease.unwrap_or(pool::DEFAULT_MAX_LEASE),
+                    &raw_options,
+                )
+            }
+        };
+        match allocation {
             Ok(lease) => {
                 DHCP_ALLOCATIONS
                     .with_label_values(&[&format!("{:?}", lease.lease_type)])
@@ -493,6 +958,15 @@ fn handle_request(
                     lease.expire,
                     lease.lease_type
                 );
+                let options = response
+                    .options
+                    .clone()
+                    .set_option(&dhcppkt::OPTION_MSGTYPE, &dhcppkt::DHCPACK)
+                    .set_option(
+                        &dhcppkt::OPTION_SERVERID,
+                        &req.pkt.options.get_serverid().unwrap_or(req.serverip),
+                    )
+                    .set_lease_time(lease.expire);
                 Ok(dhcppkt::Dhcp {
                     op: dhcppkt::OP_BOOTREPLY,
                     htype: dhcppkt::HWTYPE_ETHERNET,
@@ -503,22 +977,20 @@ fn handle_request(
                     flags: req.pkt.flags,
                     ciaddr: req.pkt.ciaddr,
                     yiaddr: lease.ip,
-                    siaddr: net::Ipv4Addr::UNSPECIFIED,
+                    siaddr: response.siaddr(),
                     giaddr: req.pkt.giaddr,
                     chaddr: req.pkt.chaddr.clone(),
                     sname: vec![],
-                    file: vec![],
-                    options: response
-                        .options
-                        .set_option(&dhcppkt::OPTION_MSGTYPE, &dhcppkt::DHCPACK)
-                        .set_option(
-                            &dhcppkt::OPTION_SERVERID,
-                            &req.pkt.options.get_serverid().unwrap_or(req.serverip),
-                        )
-                        .set_option(&dhcppkt::OPTION_LEASETIME, &(lease.expire.as_secs() as u32))
-                        .to_options(),
+                    file: response.file(),
+                    options: add_forcerenew_nonce(options, pools, req, conf, lease.ip).to_options(),
                 })
             }
+            Err(pool::Error::NoAssignableAddress) if requested.is_some() => {
+                Ok(build_nak(req, NakReason::WrongAddress(requested.unwrap())))
+            }
+            Err(pool::Error::RequestedAddressInUse) if requested.is_some() => {
+                Ok(build_nak(req, NakReason::AddressInUse(requested.unwrap())))
+            }
             Err(e) => Err(DhcpError::PoolError(e)),
         }
     } else {
@@ -526,7 +998,117 @@ fn handle_request(
     }
 }
 
-fn format_mac(v: &[u8]) -> String {
+fn handle_inform(
+    req: &DHCPRequest,
+    base: &[config::Policy],
+    conf: &super::config::Config,
+) -> Result<dhcppkt::Dhcp, DhcpError> {
+    let mut response: Response = Response {
+        options: ResponseOptions {
+            ..Default::default()
+        }
+        .set_option(&dhcppkt::OPTION_MSGTYPE, &dhcppkt::DHCPACK)
+        .set_option(&dhcppkt::OPTION_SERVERID, &req.serverip),
+        ..Default::default()
+    };
+    let base_policy = apply_policies(req, base, &mut response);
+    let conf_policy = apply_policies(req, &conf.dhcp.policies, &mut response);
+    if !base_policy && !conf_policy {
+        return Err(DhcpError::NoPolicyConfigured);
+    }
+    /* There's no lease to NAK, so apply-nak ignores the client here too */
+    if response.deny || response.nak {
+        return Err(DhcpError::Denied);
+    }
+    /* RFC2131 Section 3.4: The client already has an address, so we MUST NOT check for an existing
+     * lease.  Section 4.3.5: The server MUST NOT send a lease expiration time, and should not fill
+     * in yiaddr.
+     */
+    response
+        .options
+        .mutate_option::<u32>(&dhcppkt::OPTION_LEASETIME, None);
+    Ok(dhcppkt::Dhcp {
+        op: dhcppkt::OP_BOOTREPLY,
+        htype: dhcppkt::HWTYPE_ETHERNET,
+        hlen: 6,
+        hops: 0,
+        xid: req.pkt.xid,
+        secs: 0,
+        flags: req.pkt.flags,
+        ciaddr: req.pkt.ciaddr,
+        yiaddr: net::Ipv4Addr::UNSPECIFIED,
+        siaddr: response.siaddr(),
+        giaddr: req.pkt.giaddr,
+        chaddr: req.pkt.chaddr.clone(),
+        sname: vec![],
+        file: response.file(),
+        options: response.options.to_options(),
+    })
+}
+
+fn handle_release(
+    pools: &mut pool::Pool,
+    req: &DHCPRequest,
+    serverids: &ServerIds,
+) -> Result<Option<dhcppkt::Dhcp>, DhcpError> {
+    if let Some(si) = req.pkt.options.get_serverid() {
+        if !serverids.contains(&si) {
+            return Err(DhcpError::OtherServer(si));
+        }
+    }
+    /* RFC2131 Section 4.3.4: The client identifies the lease being released with ciaddr, and the
+     * server does not reply.
+     */
+    let mut raw_options = Vec::new();
+    req.pkt.options.serialise(&mut raw_options);
+    pools
+        .release_address(&req.pkt.get_client_id(), req.pkt.ciaddr, &raw_options)
+        .map_err(DhcpError::PoolError)?;
+    log::info!(
+        "{}: Released lease {}",
+        format_client(&req.pkt),
+        req.pkt.ciaddr
+    );
+    Ok(None)
+}
+
+fn handle_decline(
+    pools: &mut pool::Pool,
+    req: &DHCPRequest,
+    serverids: &ServerIds,
+    conf: &super::config::Config,
+) -> Result<Option<dhcppkt::Dhcp>, DhcpError> {
+    if let Some(si) = req.pkt.options.get_serverid() {
+        if !serverids.contains(&si) {
+            return Err(DhcpError::OtherServer(si));
+        }
+    }
+    /* RFC2131 Section 4.3.3: The declined address is in the 'requested IP address' option.  The
+     * server MUST mark the address as not available, and SHOULD notify the local administrator of
+     * a possible configuration problem.
+     */
+    let addr = req
+        .pkt
+        .options
+        .get_address_request()
+        .ok_or(DhcpError::ParseError(dhcppkt::ParseError::InvalidPacket))?;
+    let quarantine = conf
+        .dhcp
+        .decline_quarantine
+        .unwrap_or(pool::DEFAULT_DECLINE_QUARANTINE);
+    pools
+        .decline_address(&req.pkt.get_client_id(), addr, quarantine)
+        .map_err(DhcpError::PoolError)?;
+    log::warn!(
+        "{}: Declined {}, possible address conflict.  Not allocating it for {:?}",
+        format_client(&req.pkt),
+        addr,
+        quarantine
+    );
+    Ok(None)
+}
+
+pub(crate) fn format_mac(v: &[u8]) -> String {
     v.iter()
         .map(|b| format!("{:0>2x}", b))
         .collect::<Vec<String>>()
@@ -615,6 +1197,13 @@ async fn log_pkt(request: &DHCPRequest, netinfo: &erbium_net::netinfo::SharedNet
                 .join(" "))
             .unwrap_or_else(|| "<none>".into())
     );
+    if let Some(fingerprint) = request.pkt.options.get_parameter_list_fingerprint() {
+        log::info!(
+            "{}: Parameter list fingerprint: {}",
+            format_client(&request.pkt),
+            fingerprint
+        );
+    }
 }
 
 /// Produce a default configuration.
@@ -721,19 +1310,63 @@ pub async fn handle_pkt(
     request: &DHCPRequest,
     serverids: ServerIds,
     conf: &super::config::Config,
-) -> Result<dhcppkt::Dhcp, DhcpError> {
+) -> Result<Option<dhcppkt::Dhcp>, DhcpError> {
+    /* Leasequery replies carry the client's relay agent information, not the requester's, so
+     * they skip the processing below.
+     */
+    if request.pkt.options.get_messagetype() == Some(dhcppkt::DHCPLEASEQUERY) {
+        return leasequery::handle_leasequery(pools, request, conf).map(Some);
+    }
     match request.pkt.options.get_messagetype() {
         Some(dhcppkt::DHCPDISCOVER) => {
             let base = [build_default_config(conf, request).await];
-            handle_discover(pools, request, &serverids, &base, conf)
+            handle_discover(pools, request, &serverids, &base, conf).map(Some)
         }
         Some(dhcppkt::DHCPREQUEST) => {
             let base = [build_default_config(conf, request).await];
-            handle_request(pools, request, &serverids, &base, conf)
+            handle_request(pools, request, &serverids, &base, conf).map(Some)
         }
+        Some(dhcppkt::DHCPINFORM) => {
+            let base = [build_default_config(conf, request).await];
+            handle_inform(request, &base, conf).map(Some)
+        }
+        Some(dhcppkt::DHCPRELEASE) => handle_release(pools, request, &serverids),
+        Some(dhcppkt::DHCPDECLINE) => handle_decline(pools, request, &serverids, conf),
         Some(x) => Err(DhcpError::UnknownMessageType(x)),
         None => Err(DhcpError::ParseError(dhcppkt::ParseError::InvalidPacket)),
     }
+    .map(|reply| {
+        reply.map(|mut reply| {
+            /* RFC3046 Section 2.2: Relay Agent Information is echoed back to the relay */
+            if let Some(relayinfo) = request
+                .pkt
+                .options
+                .other
+                .get(&dhcppkt::OPTION_RELAYAGENTINFO)
+            {
+                reply
+                    .options
+                    .other
+                    .insert(dhcppkt::OPTION_RELAYAGENTINFO, relayinfo.clone());
+            }
+            /* RFC4702 Section 4: Tell the client which DNS updates we will do for its lease */
+            #[cfg(feature = "dns")]
+            if !reply.yiaddr.is_unspecified() {
+                if let Some(fqdn) = request.pkt.options.get_client_fqdn() {
+                    if let (Some(reply_fqdn), _) = ddns::plan(
+                        conf.dhcp.dns_update.as_ref(),
+                        Some(&fqdn),
+                        request.pkt.options.get_hostname().as_deref(),
+                    ) {
+                        reply
+                            .options
+                            .mutate_option(&dhcppkt::OPTION_FQDN, &reply_fqdn);
+                    }
+                }
+            }
+            reply
+        })
+    })
 }
 
 async fn send_raw(raw: Arc<raw::RawSocket>, buf: &[u8], intf: i32) -> Result<(), std::io::Error> {
@@ -753,6 +1386,46 @@ async fn send_raw(raw: Arc<raw::RawSocket>, buf: &[u8], intf: i32) -> Result<(),
     .map(|_| ())
 }
 
+/// Where a reply should be sent.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ReplyDestination {
+    /// Over UDP to a relay agent, a leasequery requester, or a client that already has an address.
+    Unicast(NetAddr),
+    /// To 255.255.255.255 on the interface the request arrived on.
+    Broadcast,
+    /// To the client's hardware address, as it doesn't have its address yet.
+    LinkLayer(net::Ipv4Addr),
+}
+
+/// Decides where to send a reply, following RFC2131 Section 4.1.
+///
+/// `requester` is set for leasequery replies, which always go back to whoever asked (RFC4388
+/// Section 6.6).
+fn reply_destination(
+    request: &dhcppkt::Dhcp,
+    reply: &dhcppkt::Dhcp,
+    requester: Option<NetAddr>,
+) -> ReplyDestination {
+    if !request.giaddr.is_unspecified() {
+        /* If this came via a relay agent, send the reply back to it, and let it deal with
+         * getting it to the client.
+         */
+        ReplyDestination::Unicast(request.giaddr.with_port(67))
+    } else if let Some(requester) = requester {
+        ReplyDestination::Unicast(requester)
+    } else if reply.options.get_messagetype() == Some(dhcppkt::DHCPNAK) {
+        /* NAKs are broadcast, as the client may not have a usable address. */
+        ReplyDestination::Broadcast
+    } else if !request.ciaddr.is_unspecified() {
+        /* The client is RENEWING, REBINDING or INFORMing, so can already receive unicast. */
+        ReplyDestination::Unicast(request.ciaddr.with_port(68))
+    } else if request.flags & dhcppkt::FLAG_BROADCAST != 0 || reply.yiaddr.is_unspecified() {
+        ReplyDestination::Broadcast
+    } else {
+        ReplyDestination::LinkLayer(reply.yiaddr)
+    }
+}
+
 async fn get_serverids(s: &SharedServerIds) -> ServerIds {
     s.lock().await.clone()
 }
@@ -779,6 +1452,215 @@ impl ToString for RunError {
     }
 }
 
+#[derive(Debug)]
+pub enum ForceRenewError {
+    NoLease(net::Ipv4Addr),
+    NoServerId(net::Ipv4Addr),
+    NotConfigured,
+    NotCapable(net::Ipv4Addr),
+    NoHardwareAddress(net::Ipv4Addr),
+    PoolError(pool::Error),
+    Io(std::io::Error),
+}
+
+impl std::fmt::Display for ForceRenewError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        use ForceRenewError::*;
+        match self {
+            NoLease(ip) => write!(f, "No active lease for {}", ip),
+            NoServerId(ip) => write!(f, "No local address to send to {} from", ip),
+            NotConfigured => write!(f, "No dhcp-forcerenew-secret is configured"),
+            NotCapable(ip) => write!(
+                f,
+                "The client holding {} can't authenticate a DHCPFORCERENEW",
+                ip
+            ),
+            NoHardwareAddress(ip) => {
+                write!(
+                    f,
+                    "The hardware address of the client holding {} is unknown",
+                    ip
+                )
+            }
+            PoolError(e) => write!(f, "DHCP Pool Error: {}", e),
+            Io(e) => write!(f, "Failed to send DHCPFORCERENEW: {}", e),
+        }
+    }
+}
+
+/// How far a client has got with a lease.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Binding {
+    /// The client has been offered the address, but hasn't asked for it.
+    Offered,
+    /// The client holds the lease (or did, until it expired).
+    Bound,
+    /// The client gave the lease back.
+    Released,
+}
+
+/// Works out how far a client has got with a lease from the options of the last message it sent
+/// about it, which are stored with the lease.  Leases that weren't handed out by us (eg imported
+/// ones) count as bound.
+pub(crate) fn get_binding(lease: &pool::LeaseInfo) -> Binding {
+    match dhcppkt::parse_options(crate::pktparser::Buffer::new(&lease.options)) {
+        Ok(options) => match options.get_messagetype() {
+            Some(dhcppkt::DHCPDISCOVER) if !options.has_rapid_commit() => Binding::Offered,
+            Some(dhcppkt::DHCPRELEASE) => Binding::Released,
+            _ => Binding::Bound,
+        },
+        Err(_) => Binding::Bound,
+    }
+}
+
+/// RFC3203 Section 4: A DHCPFORCERENEW is unicast to the client, which then renews its lease as
+/// if T1 had expired.  Returns None if we don't know the client's hardware address, as the client
+/// would have no way to tell the message was meant for it.
+fn build_forcerenew(lease: &pool::LeaseInfo, serverid: net::Ipv4Addr) -> Option<dhcppkt::Dhcp> {
+    if lease.chaddr.is_empty() {
+        return None;
+    }
+    Some(dhcppkt::Dhcp {
+        op: dhcppkt::OP_BOOTREPLY,
+        htype: dhcppkt::HWTYPE_ETHERNET,
+        hlen: lease.chaddr.len() as u8,
+        hops: 0,
+        xid: rand::random(),
+        secs: 0,
+        flags: 0,
+        ciaddr: lease.ip,
+        yiaddr: net::Ipv4Addr::UNSPECIFIED,
+        siaddr: net::Ipv4Addr::UNSPECIFIED,
+        giaddr: net::Ipv4Addr::UNSPECIFIED,
+        chaddr: lease.chaddr.clone(),
+        sname: vec![],
+        file: vec![],
+        options: dhcppkt::DhcpOptions::default()
+            .set_option(&dhcppkt::OPTION_MSGTYPE, &dhcppkt::DHCPFORCERENEW)
+            .set_option(&dhcppkt::OPTION_SERVERID, &serverid),
+    })
+}
+
+/// Returns true if erbium could hand out `ip`, either from a policy (`addresses` is
+/// `conf.dhcp.get_all_used_addresses()`) or from one of the top level addresses.
+fn is_configured_address(
+    conf: &crate::config::Config,
+    addresses: &pool::PoolAddresses,
+    ip: net::Ipv4Addr,
+) -> bool {
+    use crate::config::Match as _;
+    addresses.contains(&ip)
+        || conf.addresses.iter().any(|prefix| match prefix {
+            crate::config::Prefix::V4(p4) => p4.contains(ip),
+            _ => false,
+        })
+}
+
+/// Every range of addresses we hand out from, both from policies and the top level addresses.
+fn get_address_ranges(conf: &crate::config::Config) -> Vec<pool::AddressRange> {
+    fn walk(policies: &[config::Policy], ranges: &mut Vec<pool::AddressRange>) {
+        for policy in policies {
+            ranges.extend(policy.apply_ranges.iter().cloned());
+            walk(&policy.policies, ranges);
+        }
+    }
+    let mut ranges = Vec::new();
+    walk(&conf.dhcp.policies, &mut ranges);
+    for prefix in &conf.addresses {
+        if let super::config::Prefix::V4(p4) = prefix {
+            use crate::config::PrefixOps as _;
+            if let Ok(subnet) = erbium_net::Ipv4Subnet::new(p4.network(), p4.prefixlen) {
+                ranges.extend(pool::AddressRange::from_subnet(&subnet));
+            }
+        }
+    }
+    ranges
+}
+
+/// Handles a packet, first probing the address we're about to offer if it hasn't been leased for
+/// a while.
+///
+/// If something else is already using the address it is quarantined, and the packet is handled
+/// again so a different address is offered.  `probe` returns the MAC address of whatever is using
+/// an address.
+async fn handle_pkt_probing<P, F>(
+    pool: &sync::Mutex<pool::Pool>,
+    offers: &conflict::OfferTracker,
+    request: &DHCPRequest,
+    serverids: ServerIds,
+    conf: &crate::config::SharedConfig,
+    probe: P,
+) -> Result<Option<dhcppkt::Dhcp>, DhcpError>
+where
+    P: Fn(net::Ipv4Addr, std::time::Duration) -> F,
+    F: std::future::Future<Output = Option<[u8; 6]>>,
+{
+    let mut probes = 0;
+    loop {
+        let reply;
+        let probe_for;
+        let quarantine;
+        {
+            /* Limit the amount of time we have these locked to just handling the packet */
+            let mut pool = pool.lock().await;
+            let lockedconf = conf.read().await;
+
+            /* ARP only reaches clients on directly attached networks, so relayed requests aren't
+             * probed.
+             */
+            let timeout = lockedconf
+                .dhcp
+                .conflict_detection
+                .filter(|timeout| !timeout.is_zero() && probes < conflict::MAX_PROBES)
+                .filter(|_| request.pkt.giaddr.is_unspecified());
+            quarantine = lockedconf
+                .dhcp
+                .decline_quarantine
+                .unwrap_or(pool::DEFAULT_DECLINE_QUARANTINE);
+
+            offers.take();
+            reply = handle_pkt(&mut pool, request, serverids.clone(), &lockedconf).await?;
+            /* A rapid commit has already handed out the address, it's too late to probe it */
+            let now = std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap()
+                .as_secs() as u32;
+            probe_for = offers
+                .take()
+                .filter(|offer| {
+                    reply.as_ref().map(|r| {
+                        r.yiaddr == offer.lease.ip
+                            && r.options.get_messagetype() == Some(dhcppkt::DHCPOFFER)
+                    }) == Some(true)
+                })
+                .filter(|offer| conflict::should_probe(offer, now))
+                .and_then(|offer| timeout.map(|timeout| (offer.lease.ip, timeout)));
+        }
+
+        /* If someone else is already using the address, quarantine it, and try again */
+        let (addr, squatter) = match probe_for {
+            Some((addr, timeout)) => match probe(addr, timeout).await {
+                Some(squatter) => (addr, squatter),
+                None => return Ok(reply),
+            },
+            None => return Ok(reply),
+        };
+        log::warn!(
+            "{}: Not offering {}, it is already in use by {}.  Quarantining it for {:?}",
+            format_client(&request.pkt),
+            addr,
+            format_mac(&squatter),
+            quarantine
+        );
+        DHCP_ADDRESS_CONFLICTS.inc();
+        if let Err(e) = pool.lock().await.quarantine_address(addr, quarantine) {
+            log::warn!("Failed to quarantine {}: {}", addr, e);
+            return Ok(reply);
+        }
+        probes += 1;
+    }
+}
+
 pub struct DhcpService {
     netinfo: erbium_net::netinfo::SharedNetInfo,
     conf: crate::config::SharedConfig,
@@ -786,13 +1668,19 @@ pub struct DhcpService {
     pool: std::sync::Arc<sync::Mutex<pool::Pool>>,
     serverids: SharedServerIds,
     listener: UdpSocket,
+    hooks: hooks::Dispatcher,
+    lease_changes: sync::Mutex<sync::mpsc::Receiver<pool::Event>>,
+    failover: Option<Arc<failover::Failover>>,
+    leasequery: Arc<leasequery::Server>,
+    ratelimit: ratelimit::RateLimiter,
+    offers: conflict::OfferTracker,
+    /// Opened the first time an address is probed, as most configurations never probe.
+    prober: sync::OnceCell<Arc<conflict::Prober>>,
 }
 
 impl DhcpService {
     async fn recvdhcp(&self, pkt: &[u8], src: NetAddr, intf: u32) {
-        let raw = self.rawsock.clone();
         /* First, lets find the various metadata IP addresses */
-        let ip4 = src.as_sockaddr_in().unwrap();
         let optional_dst = self.netinfo.get_ipv4_by_ifidx(intf).await;
         if optional_dst.is_none() {
             log::warn!(
@@ -834,46 +1722,108 @@ impl DhcpService {
         };
         log_pkt(&request, &self.netinfo).await;
 
+        let is_leasequery = request.pkt.options.get_messagetype() == Some(dhcppkt::DHCPLEASEQUERY);
+        if is_leasequery {
+            if let Err(e) = acl::require_permission(
+                &self.conf.read().await.acls,
+                &acl::Attributes { addr: src },
+                acl::PermissionType::DhcpLeaseQuery,
+            ) {
+                log::debug!("{}: Ignoring DHCPLEASEQUERY: {}", src, e);
+                DHCP_ERRORS
+                    .with_label_values(&["LEASEQUERY_NOT_ALLOWED"])
+                    .inc();
+                return;
+            }
+        }
+
+        /* In active/standby failover, the standby server stays quiet while the other is up */
+        if let Some(failover) = &self.failover {
+            if !failover.should_serve().await {
+                log::debug!(
+                    "{}: Ignoring packet while failover peer is active",
+                    format_client(&request.pkt)
+                );
+                DHCP_ERRORS.with_label_values(&["STANDBY"]).inc();
+                return;
+            }
+        }
+
         /* Now, lets process the packet we've found */
-        let reply;
+        let mut reply = match handle_pkt_probing(
+            &self.pool,
+            &self.offers,
+            &request,
+            self.get_serverids().await,
+            &self.conf,
+            |addr, timeout| self.probe(&request, addr, timeout),
+        )
+        .await
         {
-            /* Limit the amount of time we have these locked to just handling the packet */
-            let mut pool = self.pool.lock().await;
-            let lockedconf = self.conf.read().await;
+            Err(e) => {
+                /* Denied clients are expected, and may be noisy, as are packets for our failover
+                 * peer.
+                 */
+                let level = match e {
+                    DhcpError::Denied => log::Level::Info,
+                    DhcpError::OtherServer(si) if self.is_peer_serverid(si).await => {
+                        log::Level::Debug
+                    }
+                    _ => log::Level::Warn,
+                };
+                log::log!(
+                    level,
+                    "{}: Failed to handle {}: {}",
+                    format_client(&request.pkt),
+                    request
+                        .pkt
+                        .options
+                        .get_messagetype()
+                        .map(|x| x.to_string())
+                        .unwrap_or_else(|| "packet".into()),
+                    e
+                );
+                DHCP_ERRORS.with_label_values(&[e.get_variant_name()]).inc();
+                return;
+            }
+            Ok(r) => r,
+        };
 
-            reply = match handle_pkt(
-                &mut pool,
-                &request,
-                get_serverids(&self.serverids).await,
-                &lockedconf,
-            )
-            .await
-            {
-                Err(e) => {
-                    log::warn!(
-                        "{}: Failed to handle {}: {}",
-                        format_client(&request.pkt),
-                        request
-                            .pkt
-                            .options
-                            .get_messagetype()
-                            .map(|x| x.to_string())
-                            .unwrap_or_else(|| "packet".into()),
-                        e
-                    );
-                    DHCP_ERRORS.with_label_values(&[e.get_variant_name()]).inc();
-                    return;
+        /* Replies that don't hand out an address (NAKs, and IPv6-only preferred replies) don't
+         * touch a lease, so there's nothing for failover to take over.
+         */
+        if let (Some(failover), Some(reply)) = (
+            &self.failover,
+            reply
+                .as_mut()
+                .filter(|reply| !reply.yiaddr.is_unspecified()),
+        ) {
+            /* If we're answering for our failover peer, take the client over, rather than adopting
+             * the peer's server identifier as one of our own.
+             */
+            if let Some(si) = reply.options.get_serverid() {
+                if failover.is_peer_serverid(si).await {
+                    reply
+                        .options
+                        .mutate_option(&dhcppkt::OPTION_SERVERID, &request.serverip);
                 }
-                Ok(r) => r,
-            };
+            }
         }
 
+        /* Not all messages get a reply */
+        let reply = match reply {
+            Some(r) => r,
+            None => return,
+        };
+
         /* Now, we should have a packet ready to send */
         /* First, if we're claiming to be particular IP, we should remember that as an IP that is one
-         * of ours
+         * of ours (unless it's our failover peer's, which we only answer to while it is down)
          */
         if let Some(si) = reply.options.get_serverid() {
-            self.serverids.lock().await.insert(si);
+            if !self.is_peer_serverid(si).await {
+                self.serverids.lock().await.insert(si);
+            }
         }
 
         /* Log what we're sending */
@@ -897,27 +1847,85 @@ impl DhcpService {
         );
         log_options(&reply);
 
-        /* Collect metadata ready to send */
-        let srcll = if let Some(erbium_net::netinfo::LinkLayer::Ethernet(srcll)) =
-            self.netinfo.get_linkaddr_by_ifidx(intf).await
-        {
-            srcll
-        } else {
-            log::warn!("{}: Not a usable LinkLayer?!", format_client(&reply));
-            DHCP_ERRORS.with_label_values(&["UNUSABLE_LINKLAYER"]).inc();
-            return;
+        let dst = reply_destination(&request.pkt, &reply, is_leasequery.then_some(src));
+        let result = match dst {
+            ReplyDestination::LinkLayer(yiaddr) => {
+                self.send_link_layer(&request, &reply, yiaddr).await
+            }
+            _ => self.send_udp(&request, &reply, dst, intf).await,
         };
-
-        let chaddr = if let Some(chaddr) = to_array(&reply.chaddr) {
-            chaddr
-        } else {
+        if let Err(e) = result {
             log::warn!(
-                "{}: Cannot send reply to invalid client hardware addr {:?}",
+                "{}: Failed to send reply to {:?}: {:?}",
                 format_client(&reply),
-                reply.chaddr
+                dst,
+                e
             );
-            DHCP_ERRORS.with_label_values(&["INVALID_CHADDR"]).inc();
-            return;
+            DHCP_ERRORS.with_label_values(&["SEND_ERROR"]).inc();
+        }
+    }
+
+    /// Sends a reply over the normal UDP socket, either to a unicast address, or broadcast out
+    /// of the interface the request arrived on.
+    async fn send_udp(
+        &self,
+        request: &DHCPRequest,
+        reply: &dhcppkt::Dhcp,
+        dst: ReplyDestination,
+        intf: u32,
+    ) -> Result<(), std::io::Error> {
+        let (addr, cmsg) = match dst {
+            ReplyDestination::Unicast(addr) => (addr, udp::ControlMessage::new()),
+            _ => (
+                net::Ipv4Addr::BROADCAST.with_port(68),
+                udp::ControlMessage::new().set_src4_intf(intf),
+            ),
+        };
+        DHCP_TX_PACKETS.inc();
+        self.listener
+            .send_msg(
+                &reply.serialise(),
+                &cmsg.set_send_from(Some(request.serverip.into())),
+                udp::MsgFlags::empty(),
+                Some(&addr),
+            )
+            .await
+    }
+
+    /// Sends a reply straight to the client's hardware address, as it can't receive unicast IP
+    /// until it has configured the address.  Links without Ethernet addresses (eg tun and
+    /// wireguard interfaces) don't need this, so get the reply broadcast instead.
+    async fn send_link_layer(
+        &self,
+        request: &DHCPRequest,
+        reply: &dhcppkt::Dhcp,
+        yiaddr: net::Ipv4Addr,
+    ) -> Result<(), std::io::Error> {
+        let srcll = match self.netinfo.get_linkaddr_by_ifidx(request.ifindex).await {
+            Some(erbium_net::netinfo::LinkLayer::Ethernet(srcll)) => srcll,
+            _ => {
+                log::debug!(
+                    "{}: No Ethernet address on interface, broadcasting reply",
+                    format_client(reply)
+                );
+                return self
+                    .send_udp(request, reply, ReplyDestination::Broadcast, request.ifindex)
+                    .await;
+            }
+        };
+
+        let chaddr = match to_array(&reply.chaddr) {
+            Some(chaddr) if reply.htype == dhcppkt::HWTYPE_ETHERNET => chaddr,
+            _ => {
+                log::debug!(
+                    "{}: Client hardware address {:?} isn't Ethernet, broadcasting reply",
+                    format_client(reply),
+                    reply.chaddr
+                );
+                return self
+                    .send_udp(request, reply, ReplyDestination::Broadcast, request.ifindex)
+                    .await;
+            }
         };
 
         /* Construct the raw packet from the reply to send */
@@ -925,15 +1933,67 @@ impl DhcpService {
         let etherbuf = packet::Fragment::new_udp4(
             *request.serverip.with_port(67).as_sockaddr_in().unwrap(),
             &srcll,
-            *ip4,
+            *yiaddr.with_port(68).as_sockaddr_in().unwrap(),
             &chaddr,
             packet::Tail::Payload(&replybuf),
         )
         .flatten();
 
-        if let Err(e) = send_raw(raw, &etherbuf, intf.try_into().unwrap()).await {
-            log::warn!("{}: Failed to send reply: {:?}", format_client(&reply), e);
-            DHCP_ERRORS.with_label_values(&["SEND_ERROR"]).inc();
+        send_raw(
+            self.rawsock.clone(),
+            &etherbuf,
+            request.ifindex.try_into().unwrap(),
+        )
+        .await
+    }
+
+    /// Probes `addr` to see if something else on the network is already using it, returning the
+    /// MAC address of whatever is.
+    async fn probe(
+        &self,
+        request: &DHCPRequest,
+        addr: net::Ipv4Addr,
+        timeout: std::time::Duration,
+    ) -> Option<[u8; 6]> {
+        let srcll = if let Some(erbium_net::netinfo::LinkLayer::Ethernet(srcll)) =
+            self.netinfo.get_linkaddr_by_ifidx(request.ifindex).await
+        {
+            srcll
+        } else {
+            return None;
+        };
+        let result = match self
+            .prober
+            .get_or_try_init(|| async { conflict::Prober::new() })
+            .await
+        {
+            Ok(prober) => prober.probe(request.ifindex, &srcll, addr, timeout).await,
+            Err(e) => Err(e),
+        };
+        result.unwrap_or_else(|e| {
+            log::warn!(
+                "{}: Failed to probe {} for conflicts: {}",
+                format_client(&request.pkt),
+                addr,
+                e
+            );
+            None
+        })
+    }
+
+    /// The server identifiers we answer to, including our failover peer's while it is down.
+    async fn get_serverids(&self) -> ServerIds {
+        let serverids = get_serverids(&self.serverids).await;
+        match &self.failover {
+            Some(failover) => failover.get_serverids(serverids).await,
+            None => serverids,
+        }
+    }
+
+    async fn is_peer_serverid(&self, serverid: net::Ipv4Addr) -> bool {
+        match &self.failover {
+            Some(failover) => failover.is_peer_serverid(serverid).await,
+            None => false,
         }
     }
 
@@ -944,10 +2004,35 @@ impl DhcpService {
         let rawsock =
             Arc::new(raw::RawSocket::new(raw::EthProto::ALL).map_err(RunError::ListenError)?);
         let pool = Arc::new(sync::Mutex::new(
-            pool::Pool::new().map_err(RunError::PoolError)?,
+            pool::Pool::new(&*conf.read().await).map_err(RunError::PoolError)?,
         ));
+        let (changes, lease_changes) = sync::mpsc::channel(MAX_QUEUED_LEASE_CHANGES);
+        pool.lock()
+            .await
+            .add_observer(Box::new(move |change: &pool::Event| {
+                /* Never wait here, the pool is locked */
+                if changes.try_send(change.clone()).is_err() {
+                    log::warn!(
+                        "Too many lease changes queued, not running hooks or DNS updates for {}",
+                        change.lease.ip
+                    );
+                    DHCP_ERRORS
+                        .with_label_values(&["LEASE_CHANGES_DROPPED"])
+                        .inc();
+                }
+            }));
+        let offers = conflict::OfferTracker::default();
+        pool.lock().await.add_observer(offers.observer());
         let serverids: SharedServerIds =
             Arc::new(sync::Mutex::new(std::collections::HashSet::new()));
+        let failover_conf = conf.read().await.dhcp.failover.clone();
+        let failover = match failover_conf {
+            Some(failover_conf) => {
+                Some(failover::Failover::new(failover_conf, pool.clone(), serverids.clone()).await)
+            }
+            None => None,
+        };
+        let leasequery = leasequery::Server::new(conf.clone(), pool.clone()).await;
         let listener = UdpSocket::bind(&[UNSPECIFIED4.with_port(67)])
             .await
             .map_err(RunError::ListenError)?;
@@ -957,6 +2042,9 @@ impl DhcpService {
         listener
             .set_opt_reuse_port(true)
             .map_err(RunError::ListenError)?;
+        listener
+            .set_opt_broadcast(true)
+            .map_err(RunError::ListenError)?;
         log::info!(
             "Listening for DHCP on {}",
             listener.local_addr().map_err(RunError::Io)?
@@ -968,6 +2056,13 @@ impl DhcpService {
             pool,
             serverids,
             listener,
+            hooks: hooks::Dispatcher::new(),
+            lease_changes: sync::Mutex::new(lease_changes),
+            failover,
+            leasequery,
+            ratelimit: ratelimit::RateLimiter::new(),
+            offers,
+            prober: sync::OnceCell::new(),
         })
     }
 
@@ -981,10 +2076,106 @@ impl DhcpService {
         }
     }
 
+    /// The name of the interface with a subnet containing `ip`, or an empty string if there
+    /// isn't one, eg for relayed clients.
+    async fn get_interface_for(&self, ip: net::Ipv4Addr) -> String {
+        use crate::config::PrefixOps as _;
+        for ifidx in self.netinfo.get_ifindexes().await {
+            let on_link = self
+                .netinfo
+                .get_prefixes_by_ifidx(ifidx)
+                .await
+                .unwrap_or_default()
+                .into_iter()
+                .any(|(addr, prefixlen)| match addr {
+                    net::IpAddr::V4(addr) => {
+                        let prefix = crate::config::Prefix4::new(addr, prefixlen);
+                        u32::from(ip) & u32::from(prefix.netmask()) == prefix.network().into()
+                    }
+                    _ => false,
+                });
+            if on_link {
+                return self.netinfo.get_safe_name_by_ifidx(ifidx).await;
+            }
+        }
+        String::new()
+    }
+
+    /// Runs the lease hooks, and updates DNS, as leases change.
+    async fn run_lease_changes(self: std::sync::Arc<Self>) {
+        let mut lease_changes = self.lease_changes.lock().await;
+        while let Some(change) = lease_changes.recv().await {
+            let lease_hooks = self.conf.read().await.dhcp.lease_hooks.clone();
+            if !lease_hooks.is_empty() {
+                let interface = self.get_interface_for(change.lease.ip).await;
+                if let Some(event) = hooks::LeaseEvent::from_change(&change, interface) {
+                    self.hooks.dispatch(&lease_hooks, &event);
+                }
+            }
+            #[cfg(feature = "dns")]
+            if let Some(dns_update) = &self.conf.read().await.dhcp.dns_update {
+                ddns::handle(dns_update, &change);
+            }
+        }
+    }
+
+    /// Periodically announces leases that have expired, forgets leases that expired longer ago
+    /// than the retention period, and updates the lease metrics.
+    async fn run_reaper(self: std::sync::Arc<Self>) {
+        /* Leases that expired while we weren't running are not announced */
+        let mut since = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_secs() as u32)
+            .unwrap_or(0);
+        let mut interval = tokio::time::interval(REAP_INTERVAL);
+        loop {
+            interval.tick().await;
+            let retention = self
+                .conf
+                .read()
+                .await
+                .dhcp
+                .lease_retention
+                .unwrap_or(pool::DEFAULT_LEASE_RETENTION);
+            let mut pool = self.pool.lock().await;
+            match pool.expire_leases(since) {
+                Ok(until) => since = until,
+                Err(e) => log::warn!("Failed to look for expired leases: {}", e),
+            }
+            let reaped = pool.reap(retention);
+            drop(pool);
+            match reaped {
+                Ok(0) => (),
+                Ok(count) => {
+                    log::info!("Removed {} expired leases", count);
+                    DHCP_REAPED_LEASES.inc_by(count as u64);
+                }
+                Err(e) => log::warn!("Failed to remove expired leases: {}", e),
+            }
+            self.update_metrics().await;
+        }
+    }
+
     async fn run_internal(
         self: &std::sync::Arc<Self>,
         listener: &UdpSocket,
     ) -> Result<(), RunError> {
+        tokio::spawn(self.clone().run_lease_changes());
+        tokio::spawn(self.clone().run_reaper());
+        let leasequery = self.leasequery.clone();
+        tokio::spawn(async move {
+            if let Err(e) = leasequery.run().await {
+                log::warn!("DHCP bulk leasequery failed: {}", e);
+            }
+        });
+        if let Some(failover) = &self.failover {
+            let failover = failover.clone();
+            tokio::spawn(async move {
+                if let Err(e) = failover.run().await {
+                    log::error!("DHCP failover failed: {}", e);
+                }
+            });
+        }
         loop {
             let rm = match listener.recv_msg(65536, udp::MsgFlags::empty()).await {
                 Ok(m) => m,
@@ -993,16 +2184,20 @@ impl DhcpService {
                 Err(e) => return Err(RunError::RecvError(e)),
             };
             DHCP_RX_PACKETS.inc();
+            let intf = rm.local_intf().unwrap().try_into().unwrap();
+            /* Drop floods before doing any real work for them, so peek at the chaddr rather than
+             * parsing the whole packet.
+             */
+            let chaddr = rm.buffer.get(28..34).unwrap_or_default();
+            let rate_limit = self.conf.read().await.dhcp.rate_limit;
+            if let Err(e) = self.ratelimit.check(&rate_limit, intf, chaddr) {
+                log::trace!("{}: Dropping packet: {}", format_mac(chaddr), e);
+                continue;
+            }
             let self2 = self.clone();
-            tokio::spawn(async move {
-                self2
-                    .recvdhcp(
-                        &rm.buffer,
-                        rm.address.unwrap(),
-                        rm.local_intf().unwrap().try_into().unwrap(),
-                    )
-                    .await
-            });
+            tokio::spawn(
+                async move { self2.recvdhcp(&rm.buffer, rm.address.unwrap(), intf).await },
+            );
         }
     }
 
@@ -1013,16 +2208,117 @@ impl DhcpService {
         }
     }
 
+    /// Updates the lease gauges, both overall and for each configured range.
     pub async fn update_metrics(self: &std::sync::Arc<Self>) {
-        match self.pool.lock().await.get_pool_metrics() {
-            Ok((in_use, expired)) => {
-                DHCP_ACTIVE_LEASES.set(in_use.into());
-                DHCP_EXPIRED_LEASES.set(expired.into());
+        let ranges = get_address_ranges(&*self.conf.read().await);
+        let usage = match self.pool.lock().await.get_usage(&ranges) {
+            Ok(usage) => usage,
+            Err(e) => {
+                log::warn!("Failed to update metrics: {}", e);
+                return;
+            }
+        };
+        DHCP_ACTIVE_LEASES.set(usage.active as i64);
+        DHCP_EXPIRED_LEASES.set(usage.expired as i64);
+
+        /* Ranges can disappear when the config is reloaded */
+        DHCP_POOL_SIZE.reset();
+        DHCP_POOL_LEASES.reset();
+        for (range, usage) in ranges.iter().zip(usage.ranges) {
+            DHCP_POOL_SIZE
+                .with_label_values(&[&range.name])
+                .set(range.size() as i64);
+            for (state, count) in [
+                ("active", usage.active),
+                ("expired", usage.expired),
+                ("free", usage.free),
+            ] {
+                DHCP_POOL_LEASES
+                    .with_label_values(&[&range.name, state])
+                    .set(count as i64);
             }
-            Err(e) => log::warn!("Failed to update metrics: {}", e),
         }
     }
 
+    /// Picks the address the client knows us by: the address we have on the client's subnet,
+    /// or for relayed clients any address we've used as a server identifier.
+    async fn get_serverid_for(&self, ip: net::Ipv4Addr) -> Option<net::Ipv4Addr> {
+        use crate::config::PrefixOps as _;
+        self.netinfo
+            .get_if_prefixes()
+            .await
+            .into_iter()
+            .filter_map(|(addr, prefixlen)| match addr {
+                net::IpAddr::V4(addr) => Some(crate::config::Prefix4::new(addr, prefixlen)),
+                _ => None,
+            })
+            .find(|prefix| u32::from(ip) & u32::from(prefix.netmask()) == prefix.network().into())
+            .map(|prefix| prefix.addr)
+            .or(self.serverids.lock().await.iter().min().copied())
+    }
+
+    /// Asks the client holding `ip` to renew its lease now (RFC3203), for example because its
+    /// policy has changed.  The message is authenticated (RFC6704), so this only works for clients
+    /// that told us they could check it.
+    pub async fn force_renew(&self, ip: net::Ipv4Addr) -> Result<(), ForceRenewError> {
+        let secret = self
+            .conf
+            .read()
+            .await
+            .dhcp
+            .forcerenew_secret
+            .clone()
+            .ok_or(ForceRenewError::NotConfigured)?;
+        let lease = self
+            .pool
+            .lock()
+            .await
+            .get_lease(ip)
+            .map_err(ForceRenewError::PoolError)?
+            .filter(|lease| {
+                std::time::SystemTime::now()
+                    .duration_since(std::time::UNIX_EPOCH)
+                    .map(|d| d.as_secs() < lease.expire.into())
+                    .unwrap_or(false)
+            })
+            .ok_or(ForceRenewError::NoLease(ip))?;
+        /* The nonce is only given out in a DHCPACK to clients that asked for one */
+        if !dhcppkt::parse_options(crate::pktparser::Buffer::new(&lease.options))
+            .map(|options| forcerenew::is_capable(&options))
+            .unwrap_or(false)
+        {
+            return Err(ForceRenewError::NotCapable(ip));
+        }
+        let serverid = self
+            .get_serverid_for(ip)
+            .await
+            .ok_or(ForceRenewError::NoServerId(ip))?;
+        let pkt =
+            build_forcerenew(&lease, serverid).ok_or(ForceRenewError::NoHardwareAddress(ip))?;
+        log::info!(
+            "{}: Sending {} from {}",
+            format_client(&pkt),
+            dhcppkt::DHCPFORCERENEW.to_string(),
+            serverid
+        );
+        DHCP_TX_PACKETS.inc();
+        self.listener
+            .send_msg(
+                &forcerenew::serialise_signed(pkt, &secret, &lease),
+                &udp::ControlMessage::new().set_send_from(Some(serverid.into())),
+                udp::MsgFlags::empty(),
+                Some(&ip.with_port(68)),
+            )
+            .await
+            .map_err(ForceRenewError::Io)?;
+        Ok(())
+    }
+
+    /// The clients that have had the most packets dropped by rate limiting, worst first.
+    pub fn get_rate_limit_offenders(&self, count: usize) -> Vec<ratelimit::Offender> {
+        self.ratelimit.get_offenders(count)
+    }
+
     pub async fn get_leases(self: &std::sync::Arc<Self>) -> Vec<pool::LeaseInfo> {
         let ret = self.pool.lock().await.get_leases();
         match ret {
@@ -1048,7 +2344,7 @@ fn test_policy() {
     let mut resp = Default::default();
     let policies = vec![cfg];
 
-    assert_eq!(apply_policies(&req, policies.as_slice(), &mut resp), true);
+    assert!(apply_policies(&req, policies.as_slice(), &mut resp));
 }
 
 #[tokio::test]
@@ -1253,6 +2549,7 @@ async fn test_base() {
                 apply_address: Some(apply_address),
                 ..Default::default()
             }],
+            ..Default::default()
         },
         ..Default::default()
     };
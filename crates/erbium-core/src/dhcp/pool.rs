@@ -17,17 +17,19 @@
  *  DHCP Pool Management.
  */
 
-use rusqlite::OptionalExtension;
+use super::store::LeaseStore;
 use std::collections::hash_map::DefaultHasher;
 use std::hash::Hash;
 use std::hash::Hasher;
 
 pub const DEFAULT_MIN_LEASE: std::time::Duration = std::time::Duration::from_secs(300);
 pub const DEFAULT_MAX_LEASE: std::time::Duration = std::time::Duration::from_secs(86400);
+pub const DEFAULT_DECLINE_QUARANTINE: std::time::Duration = std::time::Duration::from_secs(3600);
+pub const DEFAULT_LEASE_RETENTION: std::time::Duration = std::time::Duration::from_secs(30 * 86400);
 
 pub type PoolAddresses = std::collections::HashSet<std::net::Ipv4Addr>;
 
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum LeaseType {
     NewAddress,
     ReusingLease,
@@ -42,17 +44,215 @@ pub struct Lease {
     pub lease_type: LeaseType,
 }
 
-#[derive(Ord, PartialOrd, Eq, PartialEq)]
+#[derive(Ord, PartialOrd, Eq, PartialEq, Clone, Debug)]
 pub struct LeaseInfo {
     pub ip: std::net::Ipv4Addr,
     pub client_id: Vec<u8>,
+    /// The client's hardware address, empty if we don't know it.
+    pub chaddr: Vec<u8>,
     pub start: u32,
     pub expire: u32,
     pub options: Vec<u8>,
 }
 
+/// Why a lease changed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Change {
+    /// The address has been offered to the client, but it hasn't asked for it yet.
+    Offered,
+    /// The client has been given the lease.
+    Committed(LeaseType),
+    /// The client has given the lease back.
+    Released,
+    /// Nobody can have the address for a while.
+    Quarantined,
+    /// The lease came from some other DHCP server.
+    Imported,
+    /// The lease ran out.  The lease itself isn't changed, so there's no previous lease.
+    Expired,
+}
+
+/// A change to a lease, and what the lease for the address was before.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Event {
+    pub change: Change,
+    pub lease: LeaseInfo,
+    pub previous: Option<LeaseInfo>,
+}
+
+/// Told about every lease the pool changes, eg to replicate it to a failover peer.
+pub type Observer = Box<dyn Fn(&Event) + Send>;
+
+/// Given an address and the client it is for, returns the longest lease the client can be given,
+/// eg so that a failover peer isn't left not knowing about a long lease.
+pub type LeaseLimit = Box<dyn Fn(std::net::Ipv4Addr, &[u8]) -> std::time::Duration + Send>;
+
 pub struct Pool {
-    conn: rusqlite::Connection,
+    store: Box<dyn LeaseStore>,
+    observers: Vec<Observer>,
+    lease_limit: Option<LeaseLimit>,
+    /// Only counted once someone asks for usage, as it has to look at every lease to start with.
+    usage: Option<UsageCounter>,
+}
+
+/// A named block of addresses from the configuration, that we report usage for.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AddressRange {
+    pub name: String,
+    pub start: std::net::Ipv4Addr,
+    pub end: std::net::Ipv4Addr,
+}
+
+/// How the addresses in an [`AddressRange`] are being used.  Every address is exactly one of
+/// active (including quarantined), expired, or free (never leased, or reaped).
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PoolUsage {
+    pub active: u64,
+    pub expired: u64,
+    pub free: u64,
+}
+
+/// How many leases there are, and how the addresses in each range asked about are being used.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Usage {
+    pub active: u64,
+    pub expired: u64,
+    pub ranges: Vec<PoolUsage>,
+}
+
+/// Keeps count of active and expired leases as leases change, so reporting usage doesn't have to
+/// look at every lease.
+#[derive(Debug, Default)]
+struct UsageCounter {
+    /// When the lease on each address expires.
+    expiries: std::collections::HashMap<std::net::Ipv4Addr, u32>,
+    /// The leases that were active when we last looked, by when they expire.
+    active: std::collections::BTreeSet<(u32, std::net::Ipv4Addr)>,
+    /// Every lease, ignoring free.
+    total: PoolUsage,
+    ranges: Vec<(AddressRange, PoolUsage)>,
+}
+
+impl UsageCounter {
+    fn new(leases: Vec<LeaseInfo>, ts: u32) -> Self {
+        let mut counter = Self::default();
+        for lease in leases {
+            counter.put(lease.ip, lease.expire, ts);
+        }
+        counter
+    }
+
+    fn count(&mut self, ip: std::net::Ipv4Addr, active: bool, add: bool) {
+        let ranges = self
+            .ranges
+            .iter_mut()
+            .filter(|(range, _)| range.contains(ip))
+            .map(|(_, usage)| usage);
+        for usage in std::iter::once(&mut self.total).chain(ranges) {
+            let count = if active {
+                &mut usage.active
+            } else {
+                &mut usage.expired
+            };
+            if add {
+                *count += 1;
+            } else {
+                *count -= 1;
+            }
+        }
+    }
+
+    /// Forgets the lease on `ip`, if there is one.
+    fn remove(&mut self, ip: std::net::Ipv4Addr) {
+        if let Some(expire) = self.expiries.remove(&ip) {
+            let active = self.active.remove(&(expire, ip));
+            self.count(ip, active, false);
+        }
+    }
+
+    fn put(&mut self, ip: std::net::Ipv4Addr, expire: u32, ts: u32) {
+        self.remove(ip);
+        let active = expire >= ts;
+        self.expiries.insert(ip, expire);
+        if active {
+            self.active.insert((expire, ip));
+        }
+        self.count(ip, active, true);
+    }
+
+    /// Moves leases that have run out by `ts` from active to expired.
+    fn expire(&mut self, ts: u32) {
+        while let Some(&(expire, ip)) = self.active.first() {
+            if expire >= ts {
+                break;
+            }
+            self.active.pop_first();
+            self.count(ip, true, false);
+            self.count(ip, false, true);
+        }
+    }
+
+    /// Starts counting `ranges` if they aren't the ones already being counted, which means looking
+    /// at every lease again.
+    fn set_ranges(&mut self, ranges: &[AddressRange]) {
+        if self.ranges.iter().map(|(range, _)| range).eq(ranges) {
+            return;
+        }
+        self.ranges = ranges
+            .iter()
+            .map(|range| (range.clone(), PoolUsage::default()))
+            .collect();
+        for (range, usage) in &mut self.ranges {
+            for (&ip, &expire) in &self.expiries {
+                if !range.contains(ip) {
+                    continue;
+                }
+                if self.active.contains(&(expire, ip)) {
+                    usage.active += 1;
+                } else {
+                    usage.expired += 1;
+                }
+            }
+        }
+    }
+
+    fn usage(&self) -> Usage {
+        Usage {
+            active: self.total.active,
+            expired: self.total.expired,
+            ranges: self
+                .ranges
+                .iter()
+                .map(|(range, usage)| PoolUsage {
+                    free: range.size() - usage.active - usage.expired,
+                    ..usage.clone()
+                })
+                .collect(),
+        }
+    }
+}
+
+impl AddressRange {
+    /// The addresses handed out from a subnet, which are the same as apply-subnet allocates.
+    pub fn from_subnet(subnet: &erbium_net::Ipv4Subnet) -> Option<Self> {
+        let base = u32::from(subnet.network());
+        let last = (1u64 << (32 - subnet.prefixlen))
+            .checked_sub(3)
+            .filter(|last| *last >= 1)?;
+        Some(AddressRange {
+            name: subnet.to_string(),
+            start: (base + 1).into(),
+            end: (base + last as u32).into(),
+        })
+    }
+
+    pub fn size(&self) -> u64 {
+        (u64::from(u32::from(self.end)) + 1).saturating_sub(u32::from(self.start).into())
+    }
+
+    pub fn contains(&self, ip: std::net::Ipv4Addr) -> bool {
+        self.start <= ip && ip <= self.end
+    }
 }
 
 #[derive(Debug, PartialEq, Eq)]
@@ -61,6 +261,7 @@ pub enum Error {
     CorruptDatabase(String),
     NoAssignableAddress,
     RequestedAddressInUse,
+    UnknownLease,
 }
 
 impl std::fmt::Display for Error {
@@ -70,18 +271,13 @@ impl std::fmt::Display for Error {
             Error::CorruptDatabase(s) => write!(f, "Corrupt Database: {}", s),
             Error::NoAssignableAddress => write!(f, "No Assignable Address"),
             Error::RequestedAddressInUse => write!(f, "Requested address is in use"),
+            Error::UnknownLease => write!(f, "No matching lease"),
         }
     }
 }
 
 impl std::error::Error for Error {}
 
-impl Error {
-    fn emit(reason: &str, e: &rusqlite::Error) -> Error {
-        Error::DbError(format!("{} ({})", reason, e))
-    }
-}
-
 fn calculate_hash<S: Hash, T: Hash>(s: &S, t: &T) -> u64 {
     let mut h = DefaultHasher::new();
     s.hash(&mut h);
@@ -90,168 +286,137 @@ fn calculate_hash<S: Hash, T: Hash>(s: &S, t: &T) -> u64 {
 }
 
 impl Pool {
-    // - upgrade_schema_from_no_version should install the latest schema
-    //   directly so that new installations need not go through the
-    //   upgrade chain.
-    // - upgrade_schema_from_version_* should perform upgrades one
-    //   version at a time since that is the only thing that is tested.
+    pub fn new_with_store(store: Box<dyn LeaseStore>) -> Pool {
+        Pool {
+            store,
+            observers: vec![],
+            lease_limit: None,
+            usage: None,
+        }
+    }
 
-    fn upgrade_schema_from_no_version(&self) -> Result<usize, Error> {
-        if self
-            .conn
-            .query_row("SELECT 1 FROM leases LIMIT 1", rusqlite::params![], |_| {
-                Ok(())
-            })
-            .optional()
-            .err()
-            .is_none()
-        {
-            // This is not a fresh new database but just one with
-            // the schema_version missing. We know that this is the
-            // same as version 0.
-            return Ok(0);
+    //#[cfg(any(test, fuzzing))]
+    pub fn new_in_memory() -> Result<Pool, Error> {
+        Ok(Self::new_with_store(
+            Box::<super::store::MemoryStore>::default(),
+        ))
+    }
+
+    /// Opens the lease store configured in the top level config.
+    pub fn new(conf: &crate::config::Config) -> Result<Pool, Error> {
+        super::store::open(&conf.state_directory, conf.lease_store, false).map(Self::new_with_store)
+    }
+
+    /// Opens the lease store configured in the top level config, for other services to look at.
+    pub fn new_read_only(conf: &crate::config::Config) -> Result<Pool, Error> {
+        super::store::open(&conf.state_directory, conf.lease_store, true).map(Self::new_with_store)
+    }
+
+    pub fn add_observer(&mut self, observer: Observer) {
+        self.observers.push(observer);
+    }
+
+    /// Limits the leases handed out from now on.  The limit applies even if it is shorter than
+    /// the minimum lease time.
+    pub fn set_lease_limit(&mut self, limit: LeaseLimit) {
+        self.lease_limit = Some(limit);
+    }
+
+    fn notify(&self, event: Event) {
+        for observer in &self.observers {
+            observer(&event);
         }
-        // Else, probably a brand new database. Create it directly
-        // with the latest version.
-        self.conn
-            .execute(
-                "CREATE TABLE leases (
-                address TEXT NOT NULL,
-                chaddr BLOB,
-                clientid BLOB,
-                start INTEGER NOT NULL,
-                expiry INTEGER NOT NULL,
-                options BLOB,
-                PRIMARY KEY (address)
-              )",
-                rusqlite::params![],
-            )
-            .map_err(|e| Error::emit("Creating table leases", &e))?;
-        Ok(1)
     }
 
-    fn upgrade_schema_from_version_0(&self) -> Result<usize, Error> {
-        self.conn
-            .execute(
-                "ALTER TABLE leases ADD COLUMN options BLOB",
-                rusqlite::params![],
-            )
-            .map_err(|e| Error::emit("Upgrading to schema version 1", &e))?;
-        Ok(1)
-    }
-
-    fn setup_db(self) -> Result<Self, Error> {
-        // Dummy primary key for the schema_version table.
-        // If the same sqlite database were used by another module,
-        // that module could use a different key within the same schema_version
-        // table to track the schema of its own table(s).
-        const DB_SCHEMA_KEY: &str = "pool";
-
-        self.conn
-            .execute(
-                "CREATE TABLE IF NOT EXISTS schema_version (
-                    key TEXT NOT NULL,
-                    version INTEGER NOT NULL,
-                    PRIMARY KEY (key)
-                )",
-                rusqlite::params![],
-            )
-            .map_err(|e| Error::emit("Creating table schema_version", &e))?;
-
-        loop {
-            let upgraded_to_version = match self.conn
-                .query_row(
-                    "SELECT version FROM schema_version
-                        WHERE key = ?1",
-                    rusqlite::params![DB_SCHEMA_KEY],
-                    |row| row.get(0),
-                )
-                .optional()
-                .map_err(|e| Error::emit("Querying schema version", &e))?
-            {
-                None => self.upgrade_schema_from_no_version()?,
-                Some(0) => self.upgrade_schema_from_version_0()?,
-                Some(1) => break,  // up to date
-                Some(v) => return Err(Error::DbError(format!(
-                    "Lease database has version {} which is newer than 1, the newest supported version",
-                    v
-                ))),
-            };
-            self.conn
-                .execute(
-                    "INSERT OR REPLACE INTO schema_version (key, version)
-                 VALUES (?1, ?2)",
-                    rusqlite::params![DB_SCHEMA_KEY, upgraded_to_version],
-                )
-                .map_err(|e| Error::emit("Creating updating schema version", &e))?;
+    /// Records a lease, keeping the usage counts up to date.
+    fn store_lease(&mut self, lease: LeaseInfo) -> Result<(), Error> {
+        let (ip, expire) = (lease.ip, lease.expire);
+        self.store.put_lease(lease)?;
+        if let Some(usage) = &mut self.usage {
+            usage.put(ip, expire, now());
         }
-        Ok(self)
+        Ok(())
     }
 
-    fn new_with_conn(conn: rusqlite::Connection) -> Result<Self, Error> {
-        Pool { conn }.setup_db()
+    /// Records a lease, and tells the observers about it.
+    fn put_lease(&mut self, lease: LeaseInfo, change: Change) -> Result<(), Error> {
+        if self.observers.is_empty() {
+            return self.store_lease(lease);
+        }
+        let previous = self.store.get_lease(lease.ip)?;
+        self.store_lease(lease.clone())?;
+        self.notify(Event {
+            change,
+            lease,
+            previous,
+        });
+        Ok(())
     }
 
-    //#[cfg(any(test, fuzzing))]
-    pub fn new_in_memory() -> Result<Pool, Error> {
-        let conn = rusqlite::Connection::open_in_memory()
-            .map_err(|e| Error::emit("Creating database in memory database", &e))?;
+    pub fn get_leases(&mut self) -> Result<Vec<LeaseInfo>, Error> {
+        self.store.get_leases()
+    }
 
-        Self::new_with_conn(conn)
+    pub fn get_lease(&mut self, addr: std::net::Ipv4Addr) -> Result<Option<LeaseInfo>, Error> {
+        self.store.get_lease(addr)
     }
 
-    pub fn new() -> Result<Pool, Error> {
-        let conn = rusqlite::Connection::open("/var/lib/erbium/leases.sqlite")
-            .map_err(|e| Error::emit("Creating database /var/lib/erbium/leases.sqlite", &e))?;
+    /// Every lease `clientid` has (or has had).
+    pub fn get_client_leases(&mut self, clientid: &[u8]) -> Result<Vec<LeaseInfo>, Error> {
+        self.store.get_client_leases(clientid)
+    }
 
-        Self::new_with_conn(conn)
+    /// Every address `clientid` has (or has had) a lease on.
+    pub fn get_client_addresses(
+        &mut self,
+        clientid: &[u8],
+    ) -> Result<Vec<std::net::Ipv4Addr>, Error> {
+        Ok(self
+            .get_client_leases(clientid)?
+            .iter()
+            .map(|lease| lease.ip)
+            .collect())
     }
 
-    pub fn get_pool_metrics(&mut self) -> Result<(u32, u32), Error> {
-        let ts: u32 = std::time::SystemTime::now()
-            .duration_since(std::time::SystemTime::UNIX_EPOCH)
-            .expect("clock failure")
-            .as_secs() as u32;
-        self.conn
-            .query_row(
-                "SELECT
-             SUM(CASE WHEN expiry < ?1 THEN 1 ELSE 0 END) as active,
-             SUM(CASE WHEN expiry >= ?1 THEN 1 ELSE 0 END) as expired
-             FROM leases",
-                rusqlite::params![ts],
-                |row| Ok((row.get(0)?, row.get(1)?)),
-            )
-            .map_err(|e| Error::DbError(e.to_string()))
+    /// Forgets leases (and quarantines) that expired more than `retention` ago.  Expired leases
+    /// are kept for a while so returning clients get their old address back.
+    pub fn reap(&mut self, retention: std::time::Duration) -> Result<usize, Error> {
+        let retention = u32::try_from(retention.as_secs()).unwrap_or(u32::MAX);
+        let before = now().saturating_sub(retention);
+        let reaped = match (&self.usage, before.checked_sub(1)) {
+            (Some(_), Some(last)) => self.store.get_expiring(0, last)?,
+            _ => vec![],
+        };
+        let count = self.store.remove_expired(before)?;
+        if let Some(usage) = &mut self.usage {
+            for lease in reaped {
+                usage.remove(lease.ip);
+            }
+        }
+        Ok(count)
     }
 
-    pub fn get_leases(&mut self) -> Result<Vec<LeaseInfo>, Error> {
-        self.conn
-            .prepare_cached(
-                "SELECT
-                  address,
-                  clientid,
-                  start,
-                  expiry,
-                  options
-                 FROM
-                  leases",
-            )
-            .map_err(|e| Error::DbError(e.to_string()))?
-            .query_map([], |row| {
-                Ok(LeaseInfo {
-                    ip: row
-                        .get::<_, String>(0)?
-                        .parse::<std::net::Ipv4Addr>()
-                        .map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))?,
-                    client_id: row.get(1)?,
-                    start: row.get(2)?,
-                    expire: row.get(3)?,
-                    options: row.get::<usize, Option<Vec<u8>>>(4)?.unwrap_or_default(),
-                })
-            })
-            .map_err(|e| Error::DbError(e.to_string()))?
-            .collect::<Result<Vec<_>, _>>()
-            .map_err(|e| Error::DbError(e.to_string()))
+    /// How many leases there are, and how the addresses in each of `ranges` are being used.
+    pub fn get_usage(&mut self, ranges: &[AddressRange]) -> Result<Usage, Error> {
+        let ts = now();
+        let usage = match &mut self.usage {
+            Some(usage) => usage,
+            None => self
+                .usage
+                .insert(UsageCounter::new(self.store.get_leases()?, ts)),
+        };
+        usage.expire(ts);
+        usage.set_ranges(ranges);
+        Ok(usage.usage())
+    }
+
+    /// Returns true if someone (possibly a quarantine) currently holds `addr`.
+    fn is_in_use(&mut self, addr: std::net::Ipv4Addr, ts: u32) -> Result<bool, Error> {
+        Ok(self
+            .store
+            .get_lease(addr)?
+            .map(|lease| lease.expire >= ts)
+            .unwrap_or(false))
     }
 
     fn select_requested_address(
@@ -262,21 +427,7 @@ impl Pool {
     ) -> Result<Lease, Error> {
         if !addresses.contains(&requested) {
             Err(Error::NoAssignableAddress)
-        } else if self
-            .conn
-            .query_row(
-                "SELECT
-                      true
-                     FROM
-                      leases
-                     WHERE expiry >= ?1
-                     AND address = ?2",
-                rusqlite::params![ts, requested.to_string()],
-                |_row| Ok(Some(())),
-            )
-            .or_else(map_no_row_to_none)?
-            .is_none()
-        {
+        } else if !self.is_in_use(requested, ts)? {
             Ok(Lease {
                 ip: requested,
                 expire: std::time::Duration::from_secs(0), /* We rely on the min_lease_time below */
@@ -309,21 +460,7 @@ impl Pool {
             .collect::<Vec<_>>();
         /* Now for each address, see if it's in use, and if so, return it */
         for i in addresses {
-            if self
-                .conn
-                .query_row(
-                    "SELECT
-                      true
-                     FROM
-                      leases
-                     WHERE expiry >= ?1
-                     AND address = ?2",
-                    rusqlite::params![ts, i.to_string()],
-                    |_row| Ok(Some(())),
-                )
-                .or_else(map_no_row_to_none)?
-                .is_none()
-            {
+            if !self.is_in_use(*i, ts)? {
                 return Ok(Lease {
                     ip: *i,
                     expire: std::time::Duration::from_secs(0), /* We rely on the min_lease_time below */
@@ -340,61 +477,34 @@ impl Pool {
         requested: Option<std::net::Ipv4Addr>,
         addresses: &PoolAddresses,
     ) -> Result<Lease, Error> {
-        let ts = std::time::SystemTime::now()
-            .duration_since(std::time::SystemTime::UNIX_EPOCH)
-            .expect("clock failure")
-            .as_secs();
+        let ts = now();
+        /* Prefer the lease on the requested address, then the longest lived lease */
+        let mut leases = self.store.get_client_leases(clientid)?;
+        leases.sort_by_key(|lease| {
+            (
+                std::cmp::Reverse(Some(lease.ip) == requested),
+                std::cmp::Reverse(lease.expire),
+            )
+        });
+
         /* RFC2131 Section 4.3.1:
          * If an address is available, the new address SHOULD be chosen as follows:
          *
          * o The client's current address as recorded in the client's current
          *   binding, ELSE */
-        if let Some(lease) = self
-            .conn
-            .query_row(
-                "SELECT
-               address,
-               expiry,
-               start
-             FROM
-               leases
-             WHERE clientid = ?1
-             AND expiry > ?2
-             ORDER BY
-              address=?3 DESC,
-              expiry DESC
-             LIMIT 1",
-                rusqlite::params![
-                    clientid,
-                    ts as u32,
-                    requested
-                        .map(|ip| ip.to_string())
-                        .unwrap_or_else(|| "".into())
-                ],
-                |row| {
-                    Ok(Some((
-                        row.get::<usize, String>(0)?,
-                        row.get::<usize, u32>(1)?,
-                        row.get::<usize, u32>(2)?,
-                    )))
-                },
-            )
-            .or_else(map_no_row_to_none)?
-        {
-            if let Ok(ip) = lease.0.parse::<std::net::Ipv4Addr>() {
-                if addresses.contains(&ip) {
-                    // We want leases to double in size.  But normally you renew your
-                    // lease at ½ the duration.  We don't want to always just double
-                    // the lease, because you can accidentally end up with a ridiculously
-                    // long lease if you renew rapidly.
-                    // So instead we just use 3*renew.
-                    let expiry = (ts as u32).saturating_sub(lease.2).saturating_mul(3);
-                    return Ok(Lease {
-                        ip,
-                        expire: std::time::Duration::from_secs(expiry.into()),
-                        lease_type: LeaseType::ReusingLease,
-                    });
-                }
+        if let Some(lease) = leases.iter().find(|lease| lease.expire > ts) {
+            if addresses.contains(&lease.ip) {
+                // We want leases to double in size.  But normally you renew your
+                // lease at ½ the duration.  We don't want to always just double
+                // the lease, because you can accidentally end up with a ridiculously
+                // long lease if you renew rapidly.
+                // So instead we just use 3*renew.
+                let expiry = ts.saturating_sub(lease.start).saturating_mul(3);
+                return Ok(Lease {
+                    ip: lease.ip,
+                    expire: std::time::Duration::from_secs(expiry.into()),
+                    lease_type: LeaseType::ReusingLease,
+                });
             }
         }
 
@@ -402,50 +512,19 @@ impl Pool {
          * expired or released) binding, if that address is in the server's
          * pool of available addresses and not already allocated, ELSE */
 
-        if let Some(lease) = self
-            .conn
-            .query_row(
-                "SELECT
-               address,
-               start,
-               max(expiry) as expire_time
-             FROM
-               leases
-             WHERE clientid = ?1
-             GROUP BY 1
-             ORDER BY
-               address=?2 DESC,
-               expire_time DESC
-             LIMIT 1
-             ",
-                rusqlite::params![
-                    clientid,
-                    requested
-                        .map(|ip| ip.to_string())
-                        .unwrap_or_else(|| "".into())
-                ],
-                |row| {
-                    Ok(Some((
-                        row.get::<usize, String>(0)?,
-                        row.get::<usize, u32>(1)?,
-                        row.get::<usize, u32>(2)?,
-                    )))
-                },
-            )
-            .or_else(map_no_row_to_none)?
-        {
-            if let Ok(ip) = lease.0.parse::<std::net::Ipv4Addr>() {
-                if addresses.contains(&ip) {
-                    return Ok(Lease {
-                        ip,
-                        /* If a device is constantly asking for the same lease, we should double
-                         * the lease time.  This means transient devices get short leases, and
-                         * devices that are more permanent get longer leases.
-                         */
-                        expire: std::time::Duration::from_secs(2 * (lease.2 - lease.1) as u64),
-                        lease_type: LeaseType::Revived,
-                    });
-                }
+        if let Some(lease) = leases.first() {
+            if addresses.contains(&lease.ip) {
+                return Ok(Lease {
+                    ip: lease.ip,
+                    /* If a device is constantly asking for the same lease, we should double
+                     * the lease time.  This means transient devices get short leases, and
+                     * devices that are more permanent get longer leases.
+                     */
+                    expire: std::time::Duration::from_secs(
+                        2 * lease.expire.saturating_sub(lease.start) as u64,
+                    ),
+                    lease_type: LeaseType::Revived,
+                });
             }
         }
 
@@ -453,7 +532,7 @@ impl Pool {
          * address is valid and not already allocated, ELSE
          */
         if let Some(addr) = requested {
-            match self.select_requested_address(addr, ts as u32, addresses) {
+            match self.select_requested_address(addr, ts, addresses) {
                 Err(Error::NoAssignableAddress) => (),
                 Err(Error::RequestedAddressInUse) => (),
                 x => return x,
@@ -465,12 +544,13 @@ impl Pool {
          *   the message was received (if 'giaddr' is 0) or on the address of
          *   the relay agent that forwarded the message ('giaddr' when not 0).
          */
-        self.select_new_address(ts as u32, addresses, clientid)
+        self.select_new_address(ts, addresses, clientid)
     }
-
+    #[allow(clippy::too_many_arguments)]
     pub fn allocate_address(
         &mut self,
         clientid: &[u8],
+        chaddr: &[u8],
         requested: Option<std::net::Ipv4Addr>,
         addresses: &PoolAddresses,
         min_expire_time: std::time::Duration,
@@ -479,37 +559,260 @@ impl Pool {
     ) -> Result<Lease, Error> {
         let lease = self.select_address(clientid, requested, addresses)?;
 
-        let lease = Lease {
-            expire: std::cmp::min(
-                std::cmp::max(lease.expire, min_expire_time),
-                max_expire_time,
-            ),
-            ..lease
+        self.record_lease(
+            clientid,
+            chaddr,
+            lease,
+            min_expire_time,
+            max_expire_time,
+            raw_options,
+            true,
+        )
+    }
+
+    /// Like allocate_address, but the client has only been offered the address.
+    ///
+    /// The address is held for the client until it asks for it.  If the client already holds
+    /// the address, its lease is left as it is.
+    #[allow(clippy::too_many_arguments)]
+    pub fn offer_address(
+        &mut self,
+        clientid: &[u8],
+        chaddr: &[u8],
+        requested: Option<std::net::Ipv4Addr>,
+        addresses: &PoolAddresses,
+        min_expire_time: std::time::Duration,
+        max_expire_time: std::time::Duration,
+        raw_options: &[u8],
+    ) -> Result<Lease, Error> {
+        let lease = self.select_address(clientid, requested, addresses)?;
+
+        self.record_lease(
+            clientid,
+            chaddr,
+            lease,
+            min_expire_time,
+            max_expire_time,
+            raw_options,
+            false,
+        )
+    }
+
+    /// Like allocate_address, but only ever allocates `requested`.
+    ///
+    /// This is used when a client is asking to confirm or extend a particular address, where
+    /// giving it some other address is not an option.  Fails with NoAssignableAddress if the
+    /// address is not in this pool, or RequestedAddressInUse if another client holds it.
+    #[allow(clippy::too_many_arguments)]
+    pub fn allocate_requested_address(
+        &mut self,
+        clientid: &[u8],
+        chaddr: &[u8],
+        requested: std::net::Ipv4Addr,
+        addresses: &PoolAddresses,
+        min_expire_time: std::time::Duration,
+        max_expire_time: std::time::Duration,
+        raw_options: &[u8],
+    ) -> Result<Lease, Error> {
+        if !addresses.contains(&requested) {
+            return Err(Error::NoAssignableAddress);
+        }
+
+        let ts = now();
+
+        /* Quarantined addresses have an empty clientid, so are held by "someone else" too. */
+        if self
+            .store
+            .get_lease(requested)?
+            .map(|lease| lease.expire >= ts && lease.client_id != clientid)
+            .unwrap_or(false)
+        {
+            return Err(Error::RequestedAddressInUse);
+        }
+
+        /* If we've seen this client with this address before, reuse that lease's history */
+        let lease = match self.select_address(clientid, Some(requested), addresses)? {
+            lease if lease.ip == requested => lease,
+            _ => Lease {
+                ip: requested,
+                expire: std::time::Duration::from_secs(0), /* We rely on the min_lease_time below */
+                lease_type: LeaseType::Requested,
+            },
         };
 
-        let ts = std::time::SystemTime::now()
-            .duration_since(std::time::SystemTime::UNIX_EPOCH)
-            .expect("clock failure")
-            .as_secs();
-
-        self.conn
-            .execute(
-                "INSERT OR REPLACE
-                 INTO leases (address, clientid, start, expiry, options)
-                 VALUES (?1, ?2, ?3, ?4, ?5)",
-                rusqlite::params![
-                    lease.ip.to_string(),
-                    clientid,
-                    ts as u32,
-                    (ts + lease.expire.as_secs()) as u32,
-                    raw_options,
-                ],
-            )
-            .map_err(|e| Error::DbError(format!("Failed to update lease: {}", e)))?;
+        self.record_lease(
+            clientid,
+            chaddr,
+            lease,
+            min_expire_time,
+            max_expire_time,
+            raw_options,
+            true,
+        )
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn record_lease(
+        &mut self,
+        clientid: &[u8],
+        chaddr: &[u8],
+        lease: Lease,
+        min_expire_time: std::time::Duration,
+        max_expire_time: std::time::Duration,
+        raw_options: &[u8],
+        commit: bool,
+    ) -> Result<Lease, Error> {
+        let mut expire = std::cmp::min(
+            std::cmp::max(lease.expire, min_expire_time),
+            max_expire_time,
+        );
+        if let Some(limit) = &self.lease_limit {
+            expire = std::cmp::min(expire, limit(lease.ip, clientid));
+        }
+        let lease = Lease { expire, ..lease };
+
+        /* Offering an address the client already holds mustn't change its lease */
+        if !commit && matches!(lease.lease_type, LeaseType::ReusingLease) {
+            return Ok(lease);
+        }
+
+        let ts = now();
+
+        self.put_lease(
+            LeaseInfo {
+                ip: lease.ip,
+                client_id: clientid.to_vec(),
+                chaddr: chaddr.to_vec(),
+                start: ts,
+                expire: (ts as u64 + lease.expire.as_secs()) as u32,
+                options: raw_options.to_vec(),
+            },
+            if commit {
+                Change::Committed(lease.lease_type)
+            } else {
+                Change::Offered
+            },
+        )?;
 
         Ok(lease)
     }
 
+    /// Expires the lease on `addr` held by `clientid` immediately (RFC2131 Section 4.3.4).
+    ///
+    /// The lease is kept so that the client can be given the same address again if it comes back.
+    /// Like any other lease it keeps the options from the client's last message, which is the
+    /// release, so released leases can be told apart from ones that ran out.
+    pub fn release_address(
+        &mut self,
+        clientid: &[u8],
+        addr: std::net::Ipv4Addr,
+        raw_options: &[u8],
+    ) -> Result<(), Error> {
+        let ts = now();
+
+        match self.store.get_lease(addr)? {
+            Some(lease) if lease.client_id == clientid && lease.expire >= ts => self.put_lease(
+                LeaseInfo {
+                    start: std::cmp::min(lease.start, ts - 1),
+                    expire: ts - 1,
+                    options: raw_options.to_vec(),
+                    ..lease
+                },
+                Change::Released,
+            ),
+            _ => Err(Error::UnknownLease),
+        }
+    }
+
+    /// Marks `addr` as unusable for `quarantine`, because the client that held it found it was
+    /// already in use by someone else (RFC2131 Section 4.3.3).
+    ///
+    /// The client is disassociated from the address, so neither the client nor anyone else will
+    /// be allocated it until the quarantine expires.
+    pub fn decline_address(
+        &mut self,
+        clientid: &[u8],
+        addr: std::net::Ipv4Addr,
+        quarantine: std::time::Duration,
+    ) -> Result<(), Error> {
+        match self.store.get_lease(addr)? {
+            Some(lease) if lease.client_id == clientid => self.quarantine_address(addr, quarantine),
+            _ => Err(Error::UnknownLease),
+        }
+    }
+
+    /// Marks `addr` as unusable for `quarantine` regardless of who currently holds it.
+    ///
+    /// This is used when we discover that something else on the network is already using an
+    /// address, such as a device with a statically configured address.
+    pub fn quarantine_address(
+        &mut self,
+        addr: std::net::Ipv4Addr,
+        quarantine: std::time::Duration,
+    ) -> Result<(), Error> {
+        let ts = now();
+
+        self.put_lease(
+            LeaseInfo {
+                ip: addr,
+                client_id: vec![],
+                chaddr: vec![],
+                start: ts,
+                expire: (ts as u64 + quarantine.as_secs()) as u32,
+                options: vec![],
+            },
+            Change::Quarantined,
+        )
+    }
+
+    /// Records a lease handed out by some other DHCP server, unless the address is currently
+    /// leased to a different client.
+    pub fn import_lease(&mut self, lease: LeaseInfo) -> Result<(), Error> {
+        if self
+            .store
+            .get_lease(lease.ip)?
+            .map(|existing| existing.expire >= now() && existing.client_id != lease.client_id)
+            .unwrap_or(false)
+        {
+            return Err(Error::RequestedAddressInUse);
+        }
+        self.put_lease(lease, Change::Imported)
+    }
+
+    /// Adds a lease from a backup or a failover peer, replacing whatever we have for that
+    /// address.  Observers aren't told about it.
+    pub fn restore_lease(&mut self, lease: LeaseInfo) -> Result<(), Error> {
+        self.store_lease(lease)
+    }
+
+    /// Tells the observers about client leases that ran out at or after `since`, and returns when
+    /// it checked up to, to pass as `since` next time.
+    pub fn expire_leases(&mut self, since: u32) -> Result<u32, Error> {
+        let ts = now();
+        if self.observers.is_empty() {
+            return Ok(ts);
+        }
+        /* Quarantines have no client */
+        for lease in self
+            .store
+            .get_expiring(since, ts.saturating_sub(1))?
+            .into_iter()
+            .filter(|lease| !lease.client_id.is_empty())
+        {
+            self.notify(Event {
+                change: Change::Expired,
+                lease,
+                previous: None,
+            });
+        }
+        Ok(ts)
+    }
+
+    /// The schema version of the underlying lease store.
+    pub fn schema_version(&mut self) -> Result<u32, Error> {
+        self.store.schema_version()
+    }
+
     #[cfg(test)]
     fn reserve_address_internal(
         &mut self,
@@ -517,22 +820,19 @@ impl Pool {
         addr: std::net::Ipv4Addr,
         expired: bool,
     ) {
-        self.conn
-            .execute(
-                "INSERT INTO leases (address, clientid, start, expiry)
-             VALUES (?1, ?2, ?3, ?4)",
-                rusqlite::params![
-                    addr.to_string(),
-                    client_id,
-                    0, /* Reserved from the beginning of time */
-                    if expired {
-                        0
-                    } else {
-                        0xFFFFFFFFu32 /* Until the end of time */
-                    }
-                ],
-            )
-            .expect("Failed to add existing lease to pool");
+        self.store_lease(LeaseInfo {
+            ip: addr,
+            client_id: client_id.to_vec(),
+            chaddr: vec![],
+            start: 0, /* Reserved from the beginning of time */
+            expire: if expired {
+                0
+            } else {
+                0xFFFFFFFFu32 /* Until the end of time */
+            },
+            options: vec![],
+        })
+        .expect("Failed to add existing lease to pool");
     }
 
     #[cfg(test)]
@@ -546,34 +846,11 @@ impl Pool {
     }
 }
 
-fn map_no_row_to_none<T>(e: rusqlite::Error) -> Result<Option<T>, Error> {
-    if e == rusqlite::Error::QueryReturnedNoRows {
-        Ok(None)
-    } else {
-        Err(Error::emit("Database query Error", &e))
-    }
-}
-
-#[test]
-fn schema_upgrade_test() {
-    let conn =
-        rusqlite::Connection::open_in_memory().expect("Failed to create in-memory sqlite database");
-    // Install a copy of the original unversioned schema and expect all
-    // of the upgrades to happen one step at a time. That way, this
-    // single test should invoke them all.
-    conn.execute(
-        "CREATE TABLE IF NOT EXISTS leases (
-            address TEXT NOT NULL,
-            chaddr BLOB,
-            clientid BLOB,
-            start INTEGER NOT NULL,
-            expiry INTEGER NOT NULL,
-            PRIMARY KEY (address)
-         )",
-        rusqlite::params![],
-    )
-    .expect("Failed to set up test database with old schema");
-    Pool::new_with_conn(conn).expect("setup_db failed");
+fn now() -> u32 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::SystemTime::UNIX_EPOCH)
+        .expect("clock failure")
+        .as_secs() as u32
 }
 
 #[test]
@@ -585,6 +862,7 @@ fn smoke_test() {
     addrpool.insert("192.168.0.102".parse().unwrap());
     p.allocate_address(
         b"client",
+        b"",
         None,
         &addrpool,
         DEFAULT_MIN_LEASE,
@@ -608,6 +886,7 @@ fn empty_pool() {
     assert_eq!(
         p.allocate_address(
             b"client",
+            b"",
             None,
             &addrpool,
             DEFAULT_MIN_LEASE,
@@ -630,6 +909,7 @@ fn reacquire_lease() {
     let lease = p
         .allocate_address(
             b"client",
+            b"",
             Some(requested),
             &addrpool,
             DEFAULT_MIN_LEASE,
@@ -658,6 +938,7 @@ fn reacquire_expired_lease() {
     let lease = p
         .allocate_address(
             b"client",
+            b"",
             Some(requested),
             &addrpool,
             DEFAULT_MIN_LEASE,
@@ -686,6 +967,7 @@ fn acquire_requested_address_success() {
     let lease = p
         .allocate_address(
             b"client",
+            b"",
             Some(requested),
             &addrpool,
             DEFAULT_MIN_LEASE,
@@ -712,6 +994,7 @@ fn acquire_requested_address_in_use() {
     let lease = p
         .allocate_address(
             b"client",
+            b"",
             Some(requested),
             &addrpool,
             DEFAULT_MIN_LEASE,
@@ -763,3 +1046,439 @@ fn dont_hand_out_old_stale_lease() {
     /* Do not assigned the old_reserved address! */
     assert_ne!(lease.ip, old_reserved);
 }
+
+#[test]
+fn release_lease() {
+    /* Once a lease is released, the address should be available to other clients */
+    let mut p = Pool::new_in_memory().expect("Failed to create in memory pools");
+    let mut addrpool: PoolAddresses = Default::default();
+    addrpool.insert("192.168.0.100".parse().unwrap());
+
+    let lease = p
+        .allocate_address(
+            b"client",
+            b"",
+            None,
+            &addrpool,
+            DEFAULT_MIN_LEASE,
+            DEFAULT_MAX_LEASE,
+            b"",
+        )
+        .expect("Failed to allocate address");
+
+    assert_eq!(
+        p.release_address(b"other-client", lease.ip, b""),
+        Err(Error::UnknownLease)
+    );
+    p.release_address(b"client", lease.ip, b"")
+        .expect("Failed to release address");
+
+    let other = p
+        .allocate_address(
+            b"other-client",
+            b"",
+            None,
+            &addrpool,
+            DEFAULT_MIN_LEASE,
+            DEFAULT_MAX_LEASE,
+            b"",
+        )
+        .expect("Failed to allocate released address");
+    assert_eq!(other.ip, lease.ip);
+}
+
+#[test]
+fn observe_changes() {
+    let mut p = Pool::new_in_memory().expect("Failed to create in memory pools");
+    let mut addrpool: PoolAddresses = Default::default();
+    addrpool.insert("192.168.0.100".parse().unwrap());
+    let events = std::sync::Arc::new(std::sync::Mutex::new(vec![]));
+    let observed = events.clone();
+    p.add_observer(Box::new(move |event: &Event| {
+        observed.lock().unwrap().push(event.clone())
+    }));
+    let changes = || {
+        events
+            .lock()
+            .unwrap()
+            .drain(..)
+            .map(|event| event.change)
+            .collect::<Vec<_>>()
+    };
+
+    let offer = |p: &mut Pool| {
+        p.offer_address(
+            b"client",
+            b"",
+            None,
+            &addrpool,
+            DEFAULT_MIN_LEASE,
+            DEFAULT_MAX_LEASE,
+            b"",
+        )
+        .expect("Failed to offer address")
+    };
+    let lease = offer(&mut p);
+    assert_eq!(changes(), vec![Change::Offered]);
+    p.allocate_address(
+        b"client",
+        b"",
+        Some(lease.ip),
+        &addrpool,
+        DEFAULT_MIN_LEASE,
+        DEFAULT_MAX_LEASE,
+        b"",
+    )
+    .expect("Failed to allocate address");
+    assert_eq!(changes(), vec![Change::Committed(LeaseType::ReusingLease)]);
+
+    /* Offering the client the address it already holds leaves its lease alone */
+    offer(&mut p);
+    assert_eq!(changes(), vec![]);
+
+    p.release_address(b"client", lease.ip, b"")
+        .expect("Failed to release address");
+    let released = events.lock().unwrap()[0].clone();
+    assert_eq!(released.change, Change::Released);
+    assert_eq!(
+        released.previous.map(|previous| previous.client_id),
+        Some(b"client".to_vec())
+    );
+    changes();
+
+    /* The released lease ran out a second ago */
+    let since = p
+        .expire_leases(now() - 10)
+        .expect("Failed to expire leases");
+    assert_eq!(changes(), vec![Change::Expired]);
+    p.expire_leases(since).expect("Failed to expire leases");
+    assert_eq!(changes(), vec![]);
+}
+
+#[test]
+fn limit_lease() {
+    let mut p = Pool::new_in_memory().expect("Failed to create in memory pools");
+    let mut addrpool: PoolAddresses = Default::default();
+    addrpool.insert("192.168.0.100".parse().unwrap());
+    p.set_lease_limit(Box::new(|_, client_id| {
+        std::time::Duration::from_secs(if client_id == b"client" { 60 } else { 600 })
+    }));
+
+    /* The limit wins over the minimum lease, and is what gets stored */
+    let lease = p
+        .allocate_address(
+            b"client",
+            b"",
+            None,
+            &addrpool,
+            DEFAULT_MIN_LEASE,
+            DEFAULT_MAX_LEASE,
+            b"",
+        )
+        .expect("Failed to allocate address");
+    assert_eq!(lease.expire, std::time::Duration::from_secs(60));
+    let stored = p.get_lease(lease.ip).unwrap().unwrap();
+    assert_eq!(stored.expire - stored.start, 60);
+
+    /* Leases shorter than the limit are left alone */
+    p.release_address(b"client", lease.ip, b"")
+        .expect("Failed to release address");
+    let lease = p
+        .allocate_address(
+            b"other",
+            b"",
+            None,
+            &addrpool,
+            DEFAULT_MIN_LEASE,
+            DEFAULT_MAX_LEASE,
+            b"",
+        )
+        .expect("Failed to allocate address");
+    assert_eq!(lease.expire, DEFAULT_MIN_LEASE);
+}
+
+#[test]
+fn decline_lease() {
+    /* A declined address should not be handed out again, not even to the client that declined it
+     */
+    let mut p = Pool::new_in_memory().expect("Failed to create in memory pools");
+    let declined = "192.168.0.100".parse().unwrap();
+    let mut addrpool: PoolAddresses = Default::default();
+    addrpool.insert(declined);
+    addrpool.insert("192.168.0.101".parse().unwrap());
+    p.reserve_address(b"client", declined);
+
+    assert_eq!(
+        p.decline_address(b"other-client", declined, DEFAULT_DECLINE_QUARANTINE),
+        Err(Error::UnknownLease)
+    );
+    p.decline_address(b"client", declined, DEFAULT_DECLINE_QUARANTINE)
+        .expect("Failed to decline address");
+
+    let lease = p
+        .allocate_address(
+            b"client",
+            b"",
+            Some(declined),
+            &addrpool,
+            DEFAULT_MIN_LEASE,
+            DEFAULT_MAX_LEASE,
+            b"",
+        )
+        .expect("Failed to allocate address");
+    assert_ne!(lease.ip, declined);
+
+    assert_eq!(
+        p.allocate_address(
+            b"third-client",
+            b"",
+            None,
+            &addrpool,
+            DEFAULT_MIN_LEASE,
+            DEFAULT_MAX_LEASE,
+            b"",
+        )
+        .expect_err("Allocated a quarantined address"),
+        Error::NoAssignableAddress
+    );
+}
+
+#[test]
+fn quarantine_lease() {
+    /* An address found to be in use by some other device is taken away from the client that was
+     * about to be offered it, and isn't handed out to anyone else either.
+     */
+    let mut p = Pool::new_in_memory().expect("Failed to create in memory pools");
+    let conflicting = "192.168.0.100".parse().unwrap();
+    let mut addrpool: PoolAddresses = Default::default();
+    addrpool.insert(conflicting);
+    addrpool.insert("192.168.0.101".parse().unwrap());
+
+    let lease = p
+        .allocate_address(
+            b"client",
+            b"",
+            Some(conflicting),
+            &addrpool,
+            DEFAULT_MIN_LEASE,
+            DEFAULT_MAX_LEASE,
+            b"",
+        )
+        .expect("Failed to allocate address");
+    assert_eq!(lease.ip, conflicting);
+
+    p.quarantine_address(conflicting, DEFAULT_DECLINE_QUARANTINE)
+        .expect("Failed to quarantine address");
+
+    let lease = p
+        .allocate_address(
+            b"client",
+            b"",
+            Some(conflicting),
+            &addrpool,
+            DEFAULT_MIN_LEASE,
+            DEFAULT_MAX_LEASE,
+            b"",
+        )
+        .expect("Failed to allocate address");
+    assert_ne!(lease.ip, conflicting);
+}
+
+#[test]
+fn reap_expired_leases() {
+    let mut p = Pool::new_in_memory().expect("Failed to create in memory pools");
+    let ts = now();
+    for (ip, expire) in [
+        ("192.168.0.100", ts + 300),
+        ("192.168.0.101", ts - 300),
+        ("192.168.0.102", ts - 7200),
+        /* Outside the range */
+        ("192.168.0.99", ts + 300),
+        ("192.168.0.110", ts + 300),
+    ] {
+        p.store
+            .put_lease(LeaseInfo {
+                ip: ip.parse().unwrap(),
+                client_id: ip.as_bytes().to_vec(),
+                chaddr: vec![],
+                start: 0,
+                expire,
+                options: vec![],
+            })
+            .unwrap();
+    }
+    let range = AddressRange {
+        name: "192.168.0.100-192.168.0.109".into(),
+        start: "192.168.0.100".parse().unwrap(),
+        end: "192.168.0.109".parse().unwrap(),
+    };
+    assert_eq!(range.size(), 10);
+    let ranges = [range];
+    assert_eq!(
+        p.get_usage(&ranges).unwrap(),
+        Usage {
+            active: 3,
+            expired: 2,
+            ranges: vec![PoolUsage {
+                active: 1,
+                expired: 2,
+                free: 7
+            }],
+        }
+    );
+
+    assert_eq!(p.reap(std::time::Duration::from_secs(3600)).unwrap(), 1);
+    assert_eq!(p.reap(std::time::Duration::from_secs(3600)).unwrap(), 0);
+    assert_eq!(
+        p.get_usage(&ranges).unwrap(),
+        Usage {
+            active: 3,
+            expired: 1,
+            ranges: vec![PoolUsage {
+                active: 1,
+                expired: 1,
+                free: 8
+            }],
+        }
+    );
+
+    /* Changes after we started counting are counted too */
+    p.restore_lease(LeaseInfo {
+        ip: "192.168.0.101".parse().unwrap(),
+        client_id: b"restored".to_vec(),
+        chaddr: vec![],
+        start: 0,
+        expire: ts + 300,
+        options: vec![],
+    })
+    .unwrap();
+    p.quarantine_address(
+        "192.168.0.105".parse().unwrap(),
+        std::time::Duration::from_secs(60),
+    )
+    .unwrap();
+    assert_eq!(
+        p.get_usage(&ranges).unwrap(),
+        Usage {
+            active: 5,
+            expired: 0,
+            ranges: vec![PoolUsage {
+                active: 3,
+                expired: 0,
+                free: 7
+            }],
+        }
+    );
+    assert_eq!(p.get_usage(&[]).unwrap().ranges, vec![]);
+}
+
+#[test]
+fn usage_counter() {
+    let ip = |s: &str| s.parse::<std::net::Ipv4Addr>().unwrap();
+    let range = AddressRange {
+        name: "192.0.2.0/28".into(),
+        start: ip("192.0.2.1"),
+        end: ip("192.0.2.14"),
+    };
+    let mut counter = UsageCounter::new(vec![], 100);
+    counter.put(ip("192.0.2.1"), 200, 100);
+    counter.put(ip("192.0.2.2"), 300, 100);
+    counter.put(ip("192.0.2.3"), 50, 100);
+    counter.put(ip("198.51.100.1"), 400, 100);
+    counter.set_ranges(std::slice::from_ref(&range));
+    let usage = |active, expired, free| Usage {
+        active,
+        expired,
+        ranges: vec![PoolUsage {
+            active: active - 1,
+            expired,
+            free,
+        }],
+    };
+    assert_eq!(counter.usage(), usage(3, 1, 11));
+
+    counter.expire(250);
+    assert_eq!(counter.usage(), usage(2, 2, 11));
+    /* Renewing an expired lease */
+    counter.put(ip("192.0.2.1"), 500, 250);
+    assert_eq!(counter.usage(), usage(3, 1, 11));
+    /* The old expiry doesn't count any more */
+    counter.expire(301);
+    assert_eq!(counter.usage(), usage(2, 2, 11));
+    counter.remove(ip("192.0.2.3"));
+    counter.remove(ip("192.0.2.4"));
+    assert_eq!(counter.usage(), usage(2, 1, 12));
+}
+
+#[test]
+fn address_range_from_subnet() {
+    let range = AddressRange::from_subnet(
+        &erbium_net::Ipv4Subnet::new("192.0.2.0".parse().unwrap(), 24).unwrap(),
+    )
+    .unwrap();
+    assert_eq!(range.name, "192.0.2.0/24");
+    assert_eq!(
+        range.start,
+        "192.0.2.1".parse::<std::net::Ipv4Addr>().unwrap()
+    );
+    assert_eq!(range.size(), 253);
+    assert_eq!(
+        AddressRange::from_subnet(
+            &erbium_net::Ipv4Subnet::new("192.0.2.0".parse().unwrap(), 31).unwrap()
+        ),
+        None
+    );
+}
+
+#[test]
+fn allocate_requested_address() {
+    let mut p = Pool::new_in_memory().expect("Failed to create in memory pools");
+    let requested = "192.168.0.100".parse().unwrap();
+    let mut addrpool: PoolAddresses = Default::default();
+    addrpool.insert(requested);
+    addrpool.insert("192.168.0.101".parse().unwrap());
+
+    /* Addresses outside of the pool are refused, rather than replaced with another address */
+    assert_eq!(
+        p.allocate_requested_address(
+            b"client",
+            b"",
+            "192.0.2.1".parse().unwrap(),
+            &addrpool,
+            DEFAULT_MIN_LEASE,
+            DEFAULT_MAX_LEASE,
+            b"",
+        )
+        .expect_err("Allocated address outside of the pool"),
+        Error::NoAssignableAddress
+    );
+
+    p.reserve_address(b"other-client", requested);
+    assert_eq!(
+        p.allocate_requested_address(
+            b"client",
+            b"",
+            requested,
+            &addrpool,
+            DEFAULT_MIN_LEASE,
+            DEFAULT_MAX_LEASE,
+            b"",
+        )
+        .expect_err("Allocated address in use by another client"),
+        Error::RequestedAddressInUse
+    );
+
+    /* But the client that holds it can extend it */
+    let lease = p
+        .allocate_requested_address(
+            b"other-client",
+            b"",
+            requested,
+            &addrpool,
+            DEFAULT_MIN_LEASE,
+            DEFAULT_MAX_LEASE,
+            b"",
+        )
+        .expect("Failed to extend lease");
+    assert_eq!(lease.ip, requested);
+}
@@ -0,0 +1,426 @@
+/*   Copyright 2023 Perry Lorier
+ *
+ *  Licensed under the Apache License, Version 2.0 (the "License");
+ *  you may not use this file except in compliance with the License.
+ *  You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ *  Unless required by applicable law or agreed to in writing, software
+ *  distributed under the License is distributed on an "AS IS" BASIS,
+ *  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ *  See the License for the specific language governing permissions and
+ *  limitations under the License.
+ *
+ *  SPDX-License-Identifier: Apache-2.0
+ *
+ *  Rate limiting of DHCP packets, so a single misbehaving client (or a storm on one interface)
+ *  can't keep the server busy.
+ */
+
+/* Each client hardware address, and each interface, gets a token bucket.  Every packet takes a
+ * token, and tokens are put back at a fixed rate, up to the size of the bucket.  Packets that
+ * arrive when the bucket is empty are dropped before we do any real work for them.
+ */
+
+use crate::bucket;
+use crate::config::{parse_num, Error};
+use std::collections::{HashMap, VecDeque};
+use std::time::Duration;
+use yaml_rust::yaml;
+
+pub const DEFAULT_CLIENT_RATE: u32 = 10;
+pub const DEFAULT_CLIENT_BURST: u32 = 20;
+pub const DEFAULT_INTERFACE_RATE: u32 = 1000;
+pub const DEFAULT_INTERFACE_BURST: u32 = 2000;
+/// How many clients to track, after which the least recently seen are forgotten.
+const MAX_TRACKED_CLIENTS: usize = 16384;
+/// How long a client that has been rate limited is reported for.
+const OFFENDER_RETENTION: Duration = Duration::from_secs(86400);
+
+lazy_static::lazy_static! {
+    static ref DHCP_RATE_LIMITED: prometheus::IntCounterVec =
+        prometheus::register_int_counter_vec!(
+            "dhcp_rate_limited",
+            "Number of DHCP packets dropped by rate limiting",
+            &["limit"]
+        )
+        .unwrap();
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Config {
+    /// Packets per second allowed from each client hardware address.  Zero disables the limit.
+    pub client_rate: u32,
+    pub client_burst: u32,
+    /// Packets per second allowed on each interface.  Zero disables the limit.
+    pub interface_rate: u32,
+    pub interface_burst: u32,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            client_rate: DEFAULT_CLIENT_RATE,
+            client_burst: DEFAULT_CLIENT_BURST,
+            interface_rate: DEFAULT_INTERFACE_RATE,
+            interface_burst: DEFAULT_INTERFACE_BURST,
+        }
+    }
+}
+
+pub fn parse(name: &str, fragment: &yaml::Yaml) -> Result<Config, Error> {
+    let h = match fragment {
+        yaml::Yaml::Null => return Ok(Config::default()),
+        yaml::Yaml::Hash(h) => h,
+        e => {
+            return Err(Error::InvalidConfig(format!(
+                "{} should be of type Hash, not {}",
+                name,
+                crate::config::type_to_name(e)
+            )))
+        }
+    };
+    let mut conf = Config::default();
+    for (k, v) in h {
+        match k.as_str() {
+            Some("client-rate") => {
+                conf.client_rate = parse_num("client-rate", v)?.unwrap_or(DEFAULT_CLIENT_RATE)
+            }
+            Some("client-burst") => {
+                conf.client_burst = parse_num("client-burst", v)?.unwrap_or(DEFAULT_CLIENT_BURST)
+            }
+            Some("interface-rate") => {
+                conf.interface_rate =
+                    parse_num("interface-rate", v)?.unwrap_or(DEFAULT_INTERFACE_RATE)
+            }
+            Some("interface-burst") => {
+                conf.interface_burst =
+                    parse_num("interface-burst", v)?.unwrap_or(DEFAULT_INTERFACE_BURST)
+            }
+            Some(e) => {
+                return Err(Error::InvalidConfig(format!(
+                    "Unexpected key in {}: {}",
+                    name, e
+                )))
+            }
+            None => {
+                return Err(Error::InvalidConfig(format!(
+                    "{} key is not a string, instead: '{:?}'",
+                    name, k
+                )))
+            }
+        }
+    }
+    /* A burst smaller than one packet would drop everything */
+    if (conf.client_rate != 0 && conf.client_burst == 0)
+        || (conf.interface_rate != 0 && conf.interface_burst == 0)
+    {
+        return Err(Error::InvalidConfig(format!(
+            "{}: burst must be at least 1",
+            name
+        )));
+    }
+    Ok(conf)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Limit {
+    Client,
+    Interface,
+}
+
+impl Limit {
+    pub const fn get_variant_name(&self) -> &'static str {
+        match self {
+            Limit::Client => "client",
+            Limit::Interface => "interface",
+        }
+    }
+}
+
+impl std::fmt::Display for Limit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Limit::Client => write!(f, "Client is sending too many packets"),
+            Limit::Interface => write!(f, "Interface is receiving too many packets"),
+        }
+    }
+}
+
+/// The time in microseconds, as the token buckets see it.
+fn now() -> u64 {
+    <bucket::RealTimeClock as bucket::Clock>::now()
+}
+
+const fn client_rate(conf: &Config) -> bucket::Rate {
+    bucket::Rate {
+        tokens_per_second: conf.client_rate,
+        max_tokens: conf.client_burst,
+    }
+}
+
+const fn interface_rate(conf: &Config) -> bucket::Rate {
+    bucket::Rate {
+        tokens_per_second: conf.interface_rate,
+        max_tokens: conf.interface_burst,
+    }
+}
+
+/// Takes a token from the bucket, returning false if there weren't any left.
+fn take(bucket: &mut bucket::GenericTokenBucket, rate: &bucket::Rate, now: u64) -> bool {
+    rate.tokens_per_second == 0 || bucket.take_at(rate, 1, now)
+}
+
+#[derive(Default)]
+struct Client {
+    bucket: bucket::GenericTokenBucket,
+    dropped: u64,
+    last_dropped: Option<u64>,
+    /// When this client was last seen, as a position in [`State::recent`].
+    seen: u64,
+}
+
+/// A client that has had packets dropped.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Offender {
+    pub chaddr: Vec<u8>,
+    pub dropped: u64,
+    /// How long ago the most recent packet was dropped.
+    pub last_dropped: Duration,
+}
+
+#[derive(Default)]
+struct State {
+    clients: HashMap<Vec<u8>, Client>,
+    /// Clients in the order they were last seen, least recently seen first.  Seeing a client
+    /// again adds a new entry, and leaves the old one behind to be skipped over.
+    recent: VecDeque<(u64, Vec<u8>)>,
+    seen: u64,
+    interfaces: HashMap<u32, bucket::GenericTokenBucket>,
+}
+
+impl State {
+    fn is_current(&self, seen: u64, chaddr: &[u8]) -> bool {
+        self.clients.get(chaddr).map(|client| client.seen) == Some(seen)
+    }
+
+    /// Makes room for a new client by forgetting the least recently seen ones.
+    fn evict(&mut self) {
+        while self.clients.len() >= MAX_TRACKED_CLIENTS {
+            match self.recent.pop_front() {
+                Some((seen, chaddr)) if self.is_current(seen, &chaddr) => {
+                    self.clients.remove(&chaddr);
+                }
+                Some(_) => (),
+                None => break,
+            }
+        }
+    }
+
+    /// Finds (or starts tracking) a client, and marks it as the most recently seen.
+    fn touch(&mut self, chaddr: &[u8]) -> &mut Client {
+        if !self.clients.contains_key(chaddr) {
+            self.evict();
+        }
+        /* Drop stale entries once they outnumber the real ones, which keeps this amortised O(1) */
+        if self.recent.len() >= 2 * MAX_TRACKED_CLIENTS {
+            let recent = std::mem::take(&mut self.recent);
+            self.recent = recent
+                .into_iter()
+                .filter(|(seen, chaddr)| self.is_current(*seen, chaddr))
+                .collect();
+        }
+        self.seen += 1;
+        self.recent.push_back((self.seen, chaddr.to_vec()));
+        let client = self.clients.entry(chaddr.to_vec()).or_default();
+        client.seen = self.seen;
+        client
+    }
+}
+
+#[derive(Default)]
+pub struct RateLimiter {
+    state: std::sync::Mutex<State>,
+}
+
+impl RateLimiter {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Decides if a packet from `chaddr` that arrived on `intf` should be processed.
+    pub fn check(&self, conf: &Config, intf: u32, chaddr: &[u8]) -> Result<(), Limit> {
+        self.check_at(conf, intf, chaddr, now())
+    }
+
+    fn check_at(&self, conf: &Config, intf: u32, chaddr: &[u8], now: u64) -> Result<(), Limit> {
+        let mut state = self.state.lock().unwrap();
+
+        /* Check the client first, so a noisy client doesn't use up the interface's tokens */
+        let client = state.touch(chaddr);
+        if !take(&mut client.bucket, &client_rate(conf), now) {
+            client.dropped += 1;
+            client.last_dropped = Some(now);
+            DHCP_RATE_LIMITED
+                .with_label_values(&[Limit::Client.get_variant_name()])
+                .inc();
+            return Err(Limit::Client);
+        }
+
+        let interface = state.interfaces.entry(intf).or_default();
+        if !take(interface, &interface_rate(conf), now) {
+            DHCP_RATE_LIMITED
+                .with_label_values(&[Limit::Interface.get_variant_name()])
+                .inc();
+            return Err(Limit::Interface);
+        }
+        Ok(())
+    }
+
+    /// Returns the clients that have had the most packets dropped, worst first.
+    pub fn get_offenders(&self, count: usize) -> Vec<Offender> {
+        self.get_offenders_at(count, now())
+    }
+
+    fn get_offenders_at(&self, count: usize, now: u64) -> Vec<Offender> {
+        let mut offenders = self
+            .state
+            .lock()
+            .unwrap()
+            .clients
+            .iter()
+            .filter_map(|(chaddr, client)| {
+                let last_dropped = Duration::from_micros(now.saturating_sub(client.last_dropped?));
+                (last_dropped < OFFENDER_RETENTION).then(|| Offender {
+                    chaddr: chaddr.clone(),
+                    dropped: client.dropped,
+                    last_dropped,
+                })
+            })
+            .collect::<Vec<_>>();
+        offenders.sort_by(|a, b| {
+            b.dropped
+                .cmp(&a.dropped)
+                .then_with(|| a.last_dropped.cmp(&b.last_dropped))
+        });
+        offenders.truncate(count);
+        offenders
+    }
+}
+
+#[test]
+fn test_parse() {
+    let conf = parse(
+        "dhcp-rate-limit",
+        &yaml::YamlLoader::load_from_str("client-rate: 5\ninterface-rate: 0\n").unwrap()[0],
+    )
+    .unwrap();
+    assert_eq!(
+        conf,
+        Config {
+            client_rate: 5,
+            interface_rate: 0,
+            ..Default::default()
+        }
+    );
+    assert_eq!(
+        parse("dhcp-rate-limit", &yaml::Yaml::Null).unwrap(),
+        Config::default()
+    );
+    assert!(parse(
+        "dhcp-rate-limit",
+        &yaml::YamlLoader::load_from_str("client-burst: 0\n").unwrap()[0],
+    )
+    .is_err());
+}
+
+#[test]
+fn test_client_limit() {
+    let limiter = RateLimiter::new();
+    let conf = Config {
+        client_rate: 2,
+        client_burst: 4,
+        ..Default::default()
+    };
+    let now = now();
+    let noisy = [0x00, 0x00, 0x5E, 0x00, 0x53, 0x01];
+    let quiet = [0x00, 0x00, 0x5E, 0x00, 0x53, 0x02];
+
+    /* The burst gets through, and then packets are dropped */
+    for _ in 0..4 {
+        assert_eq!(limiter.check_at(&conf, 1, &noisy, now), Ok(()));
+    }
+    assert_eq!(limiter.check_at(&conf, 1, &noisy, now), Err(Limit::Client));
+    assert_eq!(limiter.check_at(&conf, 1, &noisy, now), Err(Limit::Client));
+    /* Other clients are unaffected */
+    assert_eq!(limiter.check_at(&conf, 1, &quiet, now), Ok(()));
+
+    /* After half a second, there's another token */
+    let later = now + 500_000;
+    assert_eq!(limiter.check_at(&conf, 1, &noisy, later), Ok(()));
+    assert_eq!(
+        limiter.check_at(&conf, 1, &noisy, later),
+        Err(Limit::Client)
+    );
+
+    let offenders = limiter.get_offenders_at(10, later);
+    assert_eq!(offenders.len(), 1);
+    assert_eq!(offenders[0].chaddr, noisy);
+    assert_eq!(offenders[0].dropped, 3);
+    /* Offenders are only reported for a while */
+    let tomorrow = later + OFFENDER_RETENTION.as_micros() as u64;
+    assert!(limiter.get_offenders_at(10, tomorrow).is_empty());
+}
+
+#[test]
+fn test_interface_limit() {
+    let limiter = RateLimiter::new();
+    let conf = Config {
+        client_rate: 0,
+        interface_rate: 1,
+        interface_burst: 2,
+        ..Default::default()
+    };
+    let now = now();
+    let client = [0x00, 0x00, 0x5E, 0x00, 0x53, 0x01];
+
+    assert_eq!(limiter.check_at(&conf, 1, &client, now), Ok(()));
+    assert_eq!(limiter.check_at(&conf, 1, &client, now), Ok(()));
+    assert_eq!(
+        limiter.check_at(&conf, 1, &client, now),
+        Err(Limit::Interface)
+    );
+    /* Other interfaces have their own bucket */
+    assert_eq!(limiter.check_at(&conf, 2, &client, now), Ok(()));
+    /* Interface drops aren't blamed on the client */
+    assert!(limiter.get_offenders(10).is_empty());
+}
+
+#[test]
+fn test_tracked_clients_limit() {
+    let limiter = RateLimiter::new();
+    let conf = Config::default();
+    let now = now();
+    let noisy = vec![0x00, 0x00, 0x5E, 0x00, 0x53, 0x01];
+    for _ in 0..DEFAULT_CLIENT_BURST {
+        assert_eq!(limiter.check_at(&conf, 1, &noisy, now), Ok(()));
+    }
+    assert_eq!(limiter.check_at(&conf, 1, &noisy, now), Err(Limit::Client));
+
+    /* A storm of random hardware addresses doesn't grow the table past the limit, and a client
+     * that keeps sending isn't forgotten.
+     */
+    for i in 0..(3 * MAX_TRACKED_CLIENTS as u32) {
+        let _ = limiter.check_at(&conf, 1, &i.to_be_bytes(), now);
+        if i % 1000 == 0 {
+            let _ = limiter.check_at(&conf, 1, &noisy, now);
+        }
+    }
+    let state = limiter.state.lock().unwrap();
+    assert_eq!(state.clients.len(), MAX_TRACKED_CLIENTS);
+    assert!(state.recent.len() < 2 * MAX_TRACKED_CLIENTS);
+    assert!(state.clients[&noisy].dropped > 1);
+    /* The earliest of the storm have been forgotten */
+    assert!(!state.clients.contains_key(&0u32.to_be_bytes()[..]));
+}
@@ -0,0 +1,891 @@
+/*   Copyright 2023 Perry Lorier
+ *
+ *  Licensed under the Apache License, Version 2.0 (the "License");
+ *  you may not use this file except in compliance with the License.
+ *  You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ *  Unless required by applicable law or agreed to in writing, software
+ *  distributed under the License is distributed on an "AS IS" BASIS,
+ *  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ *  See the License for the specific language governing permissions and
+ *  limitations under the License.
+ *
+ *  SPDX-License-Identifier: Apache-2.0
+ *
+ *  DHCP lease storage backends.
+ *
+ *  The pool decides which leases to hand out, a store just remembers them.  Each lease is keyed
+ *  by its address, and quarantined addresses are stored with an empty client id.
+ */
+
+use super::pool::{Error, LeaseInfo};
+use crate::config::LeaseStoreType;
+use rusqlite::OptionalExtension;
+use std::collections::HashMap;
+use std::io::{BufRead as _, Write as _};
+use std::net::Ipv4Addr;
+
+const SQLITE_FILENAME: &str = "leases.sqlite";
+const APPEND_ONLY_FILENAME: &str = "leases.log";
+/// The newest version of the sqlite schema, and of lease backups.
+pub const SCHEMA_VERSION: u32 = 2;
+// Dummy primary key for the schema_version table.
+// If the same sqlite database were used by another module,
+// that module could use a different key within the same schema_version
+// table to track the schema of its own table(s).
+const DB_SCHEMA_KEY: &str = "pool";
+
+pub trait LeaseStore: Send {
+    /// Every lease, including expired leases and quarantined addresses.
+    fn get_leases(&mut self) -> Result<Vec<LeaseInfo>, Error>;
+    /// The lease for an address, if there has ever been one.
+    fn get_lease(&mut self, addr: Ipv4Addr) -> Result<Option<LeaseInfo>, Error>;
+    /// Every lease (current or historic) held by a client.
+    fn get_client_leases(&mut self, clientid: &[u8]) -> Result<Vec<LeaseInfo>, Error>;
+    /// Every lease, including quarantined addresses, that expires between `from` and `to`
+    /// inclusive.
+    fn get_expiring(&mut self, from: u32, to: u32) -> Result<Vec<LeaseInfo>, Error>;
+    /// Adds a lease, replacing any previous lease for the same address.
+    fn put_lease(&mut self, lease: LeaseInfo) -> Result<(), Error>;
+    /// Forgets every lease that expired before `before`, returning how many were removed.
+    fn remove_expired(&mut self, before: u32) -> Result<usize, Error>;
+    /// The version of the schema the leases are stored with.
+    fn schema_version(&mut self) -> Result<u32, Error> {
+        Ok(SCHEMA_VERSION)
+    }
+}
+
+/// Opens the configured lease store in `state_directory`.
+///
+/// A read only store is for other services (eg DNS) that want to look at the leases, it never
+/// modifies the underlying storage.
+pub fn open(
+    state_directory: &std::path::Path,
+    store: LeaseStoreType,
+    read_only: bool,
+) -> Result<Box<dyn LeaseStore>, Error> {
+    Ok(match store {
+        LeaseStoreType::Sqlite => Box::new(SqliteStore::new(
+            &state_directory.join(SQLITE_FILENAME),
+            read_only,
+        )?),
+        LeaseStoreType::AppendOnly => Box::new(AppendOnlyStore::new(
+            &state_directory.join(APPEND_ONLY_FILENAME),
+            read_only,
+        )?),
+        LeaseStoreType::Memory if read_only => {
+            return Err(Error::DbError(
+                "An in memory lease store cannot be shared".into(),
+            ))
+        }
+        LeaseStoreType::Memory => Box::<MemoryStore>::default(),
+    })
+}
+
+/// Opens the sqlite database that other tables (eg DHCPv6 leases) share with the configured
+/// lease store.
+///
+/// Only sqlite and in memory stores have a database, the append only store is plain text.
+pub fn open_database(
+    state_directory: &std::path::Path,
+    store: LeaseStoreType,
+) -> Result<rusqlite::Connection, Error> {
+    match store {
+        LeaseStoreType::Sqlite => {
+            let path = state_directory.join(SQLITE_FILENAME);
+            rusqlite::Connection::open(&path)
+                .map_err(|e| Error::emit(&format!("Creating database {}", path.display()), &e))
+        }
+        LeaseStoreType::Memory => rusqlite::Connection::open_in_memory()
+            .map_err(|e| Error::emit("Creating database in memory database", &e)),
+        LeaseStoreType::AppendOnly => Err(Error::DbError(
+            "The append only lease store can only hold DHCPv4 leases".into(),
+        )),
+    }
+}
+
+/* ---- sqlite ---- */
+
+pub struct SqliteStore {
+    conn: rusqlite::Connection,
+}
+
+impl Error {
+    fn emit(reason: &str, e: &rusqlite::Error) -> Error {
+        Error::DbError(format!("{} ({})", reason, e))
+    }
+}
+
+fn row_to_lease(row: &rusqlite::Row) -> rusqlite::Result<LeaseInfo> {
+    Ok(LeaseInfo {
+        ip: row
+            .get::<_, String>(0)?
+            .parse::<Ipv4Addr>()
+            .map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))?,
+        /* Quarantined addresses have no client */
+        client_id: row.get::<usize, Option<Vec<u8>>>(1)?.unwrap_or_default(),
+        chaddr: row.get::<usize, Option<Vec<u8>>>(5)?.unwrap_or_default(),
+        start: row.get(2)?,
+        expire: row.get(3)?,
+        options: row.get::<usize, Option<Vec<u8>>>(4)?.unwrap_or_default(),
+    })
+}
+
+impl SqliteStore {
+    // - upgrade_schema_from_no_version should install the latest schema
+    //   directly so that new installations need not go through the
+    //   upgrade chain.
+    // - upgrade_schema_from_version_* should perform upgrades one
+    //   version at a time since that is the only thing that is tested.
+
+    fn upgrade_schema_from_no_version(&self) -> Result<usize, Error> {
+        if self
+            .conn
+            .query_row("SELECT 1 FROM leases LIMIT 1", rusqlite::params![], |_| {
+                Ok(())
+            })
+            .optional()
+            .err()
+            .is_none()
+        {
+            // This is not a fresh new database but just one with
+            // the schema_version missing. We know that this is the
+            // same as version 0.
+            return Ok(0);
+        }
+        // Else, probably a brand new database. Create it directly
+        // with the latest version.
+        self.conn
+            .execute(
+                "CREATE TABLE leases (
+                address TEXT NOT NULL,
+                chaddr BLOB,
+                clientid BLOB,
+                start INTEGER NOT NULL,
+                expiry INTEGER NOT NULL,
+                options BLOB,
+                PRIMARY KEY (address)
+              )",
+                rusqlite::params![],
+            )
+            .map_err(|e| Error::emit("Creating table leases", &e))?;
+        self.conn
+            .execute(
+                "CREATE INDEX leases_expiry ON leases (expiry)",
+                rusqlite::params![],
+            )
+            .map_err(|e| Error::emit("Creating index leases_expiry", &e))?;
+        Ok(2)
+    }
+
+    fn upgrade_schema_from_version_0(&self) -> Result<usize, Error> {
+        self.conn
+            .execute(
+                "ALTER TABLE leases ADD COLUMN options BLOB",
+                rusqlite::params![],
+            )
+            .map_err(|e| Error::emit("Upgrading to schema version 1", &e))?;
+        Ok(1)
+    }
+
+    fn upgrade_schema_from_version_1(&self) -> Result<usize, Error> {
+        self.conn
+            .execute(
+                "CREATE INDEX leases_expiry ON leases (expiry)",
+                rusqlite::params![],
+            )
+            .map_err(|e| Error::emit("Upgrading to schema version 2", &e))?;
+        Ok(2)
+    }
+
+    fn setup_db(self) -> Result<Self, Error> {
+        self.conn
+            .execute(
+                "CREATE TABLE IF NOT EXISTS schema_version (
+                    key TEXT NOT NULL,
+                    version INTEGER NOT NULL,
+                    PRIMARY KEY (key)
+                )",
+                rusqlite::params![],
+            )
+            .map_err(|e| Error::emit("Creating table schema_version", &e))?;
+
+        loop {
+            let upgraded_to_version = match self.conn
+                .query_row(
+                    "SELECT version FROM schema_version
+                        WHERE key = ?1",
+                    rusqlite::params![DB_SCHEMA_KEY],
+                    |row| row.get(0),
+                )
+                .optional()
+                .map_err(|e| Error::emit("Querying schema version", &e))?
+            {
+                None => self.upgrade_schema_from_no_version()?,
+                Some(0) => self.upgrade_schema_from_version_0()?,
+                Some(1) => self.upgrade_schema_from_version_1()?,
+                Some(v) if v == SCHEMA_VERSION => break,  // up to date
+                Some(v) => return Err(Error::DbError(format!(
+                    "Lease database has version {} which is newer than {}, the newest supported version",
+                    v, SCHEMA_VERSION
+                ))),
+            };
+            self.conn
+                .execute(
+                    "INSERT OR REPLACE INTO schema_version (key, version)
+                 VALUES (?1, ?2)",
+                    rusqlite::params![DB_SCHEMA_KEY, upgraded_to_version],
+                )
+                .map_err(|e| Error::emit("Creating updating schema version", &e))?;
+        }
+        Ok(self)
+    }
+
+    fn new_with_conn(conn: rusqlite::Connection) -> Result<Self, Error> {
+        SqliteStore { conn }.setup_db()
+    }
+
+    pub fn new_in_memory() -> Result<Self, Error> {
+        let conn = rusqlite::Connection::open_in_memory()
+            .map_err(|e| Error::emit("Creating database in memory database", &e))?;
+
+        Self::new_with_conn(conn)
+    }
+
+    /// Opens the database at `path`.
+    ///
+    /// A read only store neither creates nor upgrades the database, that's left to the server.
+    pub fn new(path: &std::path::Path, read_only: bool) -> Result<Self, Error> {
+        if read_only {
+            let conn = rusqlite::Connection::open_with_flags(
+                path,
+                rusqlite::OpenFlags::SQLITE_OPEN_READ_ONLY,
+            )
+            .map_err(|e| Error::emit(&format!("Opening database {}", path.display()), &e))?;
+            return Ok(SqliteStore { conn });
+        }
+        let conn = rusqlite::Connection::open(path)
+            .map_err(|e| Error::emit(&format!("Creating database {}", path.display()), &e))?;
+
+        Self::new_with_conn(conn)
+    }
+}
+
+impl LeaseStore for SqliteStore {
+    fn get_leases(&mut self) -> Result<Vec<LeaseInfo>, Error> {
+        self.conn
+            .prepare_cached(
+                "SELECT
+                  address,
+                  clientid,
+                  start,
+                  expiry,
+                  options,
+                  chaddr
+                 FROM
+                  leases",
+            )
+            .map_err(|e| Error::DbError(e.to_string()))?
+            .query_map([], row_to_lease)
+            .map_err(|e| Error::DbError(e.to_string()))?
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| Error::DbError(e.to_string()))
+    }
+
+    fn get_lease(&mut self, addr: Ipv4Addr) -> Result<Option<LeaseInfo>, Error> {
+        self.conn
+            .query_row(
+                "SELECT
+                  address,
+                  clientid,
+                  start,
+                  expiry,
+                  options,
+                  chaddr
+                 FROM
+                  leases
+                 WHERE address = ?1",
+                rusqlite::params![addr.to_string()],
+                row_to_lease,
+            )
+            .optional()
+            .map_err(|e| Error::emit("Database query Error", &e))
+    }
+
+    fn get_client_leases(&mut self, clientid: &[u8]) -> Result<Vec<LeaseInfo>, Error> {
+        self.conn
+            .prepare_cached(
+                "SELECT
+                  address,
+                  clientid,
+                  start,
+                  expiry,
+                  options,
+                  chaddr
+                 FROM
+                  leases
+                 WHERE clientid = ?1",
+            )
+            .map_err(|e| Error::DbError(e.to_string()))?
+            .query_map(rusqlite::params![clientid], row_to_lease)
+            .map_err(|e| Error::DbError(e.to_string()))?
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| Error::DbError(e.to_string()))
+    }
+
+    fn get_expiring(&mut self, from: u32, to: u32) -> Result<Vec<LeaseInfo>, Error> {
+        self.conn
+            .prepare_cached(
+                "SELECT
+                  address,
+                  clientid,
+                  start,
+                  expiry,
+                  options,
+                  chaddr
+                 FROM
+                  leases
+                 WHERE expiry BETWEEN ?1 AND ?2",
+            )
+            .map_err(|e| Error::DbError(e.to_string()))?
+            .query_map(rusqlite::params![from, to], row_to_lease)
+            .map_err(|e| Error::DbError(e.to_string()))?
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| Error::DbError(e.to_string()))
+    }
+
+    fn put_lease(&mut self, lease: LeaseInfo) -> Result<(), Error> {
+        self.conn
+            .execute(
+                "INSERT OR REPLACE
+                 INTO leases (address, clientid, start, expiry, options, chaddr)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                rusqlite::params![
+                    lease.ip.to_string(),
+                    Some(lease.client_id).filter(|c| !c.is_empty()),
+                    lease.start,
+                    lease.expire,
+                    Some(lease.options).filter(|o| !o.is_empty()),
+                    Some(lease.chaddr).filter(|c| !c.is_empty()),
+                ],
+            )
+            .map_err(|e| Error::DbError(format!("Failed to update lease: {}", e)))?;
+        Ok(())
+    }
+
+    fn remove_expired(&mut self, before: u32) -> Result<usize, Error> {
+        self.conn
+            .execute(
+                "DELETE FROM leases WHERE expiry < ?1",
+                rusqlite::params![before],
+            )
+            .map_err(|e| Error::DbError(format!("Failed to remove expired leases: {}", e)))
+    }
+
+    fn schema_version(&mut self) -> Result<u32, Error> {
+        self.conn
+            .query_row(
+                "SELECT version FROM schema_version WHERE key = ?1",
+                rusqlite::params![DB_SCHEMA_KEY],
+                |row| row.get(0),
+            )
+            .map_err(|e| Error::emit("Querying schema version", &e))
+    }
+}
+
+/* ---- in memory ---- */
+
+/// Keeps leases in memory only, they are lost on restart.
+#[derive(Default)]
+pub struct MemoryStore {
+    leases: HashMap<Ipv4Addr, LeaseInfo>,
+}
+
+impl LeaseStore for MemoryStore {
+    fn get_leases(&mut self) -> Result<Vec<LeaseInfo>, Error> {
+        Ok(self.leases.values().cloned().collect())
+    }
+
+    fn get_lease(&mut self, addr: Ipv4Addr) -> Result<Option<LeaseInfo>, Error> {
+        Ok(self.leases.get(&addr).cloned())
+    }
+
+    fn get_client_leases(&mut self, clientid: &[u8]) -> Result<Vec<LeaseInfo>, Error> {
+        Ok(self
+            .leases
+            .values()
+            .filter(|l| !l.client_id.is_empty() && l.client_id == clientid)
+            .cloned()
+            .collect())
+    }
+
+    fn get_expiring(&mut self, from: u32, to: u32) -> Result<Vec<LeaseInfo>, Error> {
+        Ok(self
+            .leases
+            .values()
+            .filter(|l| (from..=to).contains(&l.expire))
+            .cloned()
+            .collect())
+    }
+
+    fn put_lease(&mut self, lease: LeaseInfo) -> Result<(), Error> {
+        self.leases.insert(lease.ip, lease);
+        Ok(())
+    }
+
+    fn remove_expired(&mut self, before: u32) -> Result<usize, Error> {
+        let count = self.leases.len();
+        self.leases.retain(|_, l| l.expire >= before);
+        Ok(count - self.leases.len())
+    }
+}
+
+/* ---- append only file ---- */
+
+/// The log is rewritten once it has this many times more records than there are leases...
+const COMPACT_RATIO: usize = 4;
+/// ...as long as it has at least this many records.
+const COMPACT_MIN_RECORDS: usize = 1000;
+
+/// Keeps leases in memory, and appends every change to a file, one lease per line:
+///
+/// `address client-id start expiry options chaddr`
+///
+/// Binary fields are hex, with "-" for empty.  Files written before the hardware address was
+/// recorded don't have the chaddr field.  On startup the file is replayed (later lines win)
+/// and then rewritten with just the current leases.  It is rewritten again whenever it grows to
+/// be mostly replaced leases.
+pub struct AppendOnlyStore {
+    path: std::path::PathBuf,
+    leases: MemoryStore,
+    /// None if we're read only, in which case we read any new records every time we're asked.
+    file: Option<std::fs::File>,
+    /// How many records are in the file.
+    records: usize,
+    /// The inode of the file we've read, and how far through it we've read.  The file is
+    /// replaced with a new one when it is rewritten.
+    position: Option<(u64, u64)>,
+}
+
+pub(super) fn encode_hex(v: &[u8]) -> String {
+    if v.is_empty() {
+        "-".into()
+    } else {
+        v.iter().map(|b| format!("{:0>2x}", b)).collect()
+    }
+}
+
+pub(super) fn decode_hex(s: &str) -> Option<Vec<u8>> {
+    if s == "-" {
+        return Some(vec![]);
+    }
+    s.as_bytes()
+        .chunks(2)
+        .map(|pair| {
+            u8::from_str_radix(std::str::from_utf8(pair).ok().filter(|p| p.len() == 2)?, 16).ok()
+        })
+        .collect()
+}
+
+pub(super) fn format_line(lease: &LeaseInfo) -> String {
+    format!(
+        "{} {} {} {} {} {}\n",
+        lease.ip,
+        encode_hex(&lease.client_id),
+        lease.start,
+        lease.expire,
+        encode_hex(&lease.options),
+        encode_hex(&lease.chaddr)
+    )
+}
+
+pub(super) fn parse_line(line: &str) -> Option<LeaseInfo> {
+    let mut fields = line.split(' ');
+    let lease = LeaseInfo {
+        ip: fields.next()?.parse().ok()?,
+        client_id: decode_hex(fields.next()?)?,
+        start: fields.next()?.parse().ok()?,
+        expire: fields.next()?.parse().ok()?,
+        options: decode_hex(fields.next()?)?,
+        /* Not recorded by older versions */
+        chaddr: fields.next().map_or(Some(vec![]), decode_hex)?,
+    };
+    if fields.next().is_some() {
+        None
+    } else {
+        Some(lease)
+    }
+}
+
+impl AppendOnlyStore {
+    pub fn new(path: &std::path::Path, read_only: bool) -> Result<Self, Error> {
+        let mut store = AppendOnlyStore {
+            path: path.to_path_buf(),
+            leases: MemoryStore::default(),
+            file: None,
+            records: 0,
+            position: None,
+        };
+        store.replay()?;
+        if !read_only {
+            store.compact()?;
+        }
+        Ok(store)
+    }
+
+    /// Reads the records added to the file since we last looked, or the whole file if it has
+    /// been rewritten since.
+    fn replay(&mut self) -> Result<(), Error> {
+        use std::io::Seek as _;
+        use std::os::unix::fs::MetadataExt as _;
+        let err = |e: std::io::Error| {
+            Error::DbError(format!("Failed to read {}: {}", self.path.display(), e))
+        };
+        let mut file = match std::fs::File::open(&self.path) {
+            Ok(f) => f,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                self.leases = MemoryStore::default();
+                self.records = 0;
+                self.position = None;
+                return Ok(());
+            }
+            Err(e) => {
+                return Err(Error::DbError(format!(
+                    "Failed to open {}: {}",
+                    self.path.display(),
+                    e
+                )))
+            }
+        };
+        let metadata = file.metadata().map_err(err)?;
+        let mut offset = match self.position {
+            Some((inode, offset)) if inode == metadata.ino() && offset <= metadata.len() => offset,
+            _ => {
+                self.leases = MemoryStore::default();
+                self.records = 0;
+                0
+            }
+        };
+        file.seek(std::io::SeekFrom::Start(offset)).map_err(err)?;
+        let mut reader = std::io::BufReader::new(file);
+        let mut line = String::new();
+        loop {
+            line.clear();
+            let len = match reader.read_line(&mut line) {
+                Ok(0) => break,
+                Ok(len) => len,
+                Err(e) => return Err(err(e)),
+            };
+            /* A crash part way through appending leaves a partial line at the end, which we can
+             * safely ignore, as the change it was recording was never acknowledged.  Readers may
+             * also see a record that is still being written, which they'll pick up next time.
+             */
+            if !line.ends_with('\n') {
+                log::warn!(
+                    "{}:{}: Ignoring incomplete lease record",
+                    self.path.display(),
+                    self.records + 1
+                );
+                break;
+            }
+            let lease = parse_line(line.trim_end()).ok_or_else(|| {
+                Error::CorruptDatabase(format!("{}:{}", self.path.display(), self.records + 1))
+            })?;
+            self.leases.put_lease(lease)?;
+            self.records += 1;
+            offset += len as u64;
+        }
+        self.position = Some((metadata.ino(), offset));
+        Ok(())
+    }
+
+    /// Rewrites the file with only the current leases, and opens it ready for appending.
+    fn compact(&mut self) -> Result<(), Error> {
+        let tmp = self.path.with_extension("log.tmp");
+        let err =
+            |e: std::io::Error| Error::DbError(format!("Failed to write {}: {}", tmp.display(), e));
+        let mut leases = self.leases.get_leases()?;
+        leases.sort();
+        let mut file = std::fs::File::create(&tmp).map_err(err)?;
+        for lease in &leases {
+            file.write_all(format_line(lease).as_bytes()).map_err(err)?;
+        }
+        file.sync_all().map_err(err)?;
+        std::fs::rename(&tmp, &self.path).map_err(err)?;
+        self.file = Some(
+            std::fs::OpenOptions::new()
+                .append(true)
+                .open(&self.path)
+                .map_err(|e| {
+                    Error::DbError(format!("Failed to open {}: {}", self.path.display(), e))
+                })?,
+        );
+        self.records = leases.len();
+        Ok(())
+    }
+
+    fn refresh(&mut self) -> Result<(), Error> {
+        if self.file.is_none() {
+            self.replay()?;
+        }
+        Ok(())
+    }
+}
+
+impl LeaseStore for AppendOnlyStore {
+    fn get_leases(&mut self) -> Result<Vec<LeaseInfo>, Error> {
+        self.refresh()?;
+        self.leases.get_leases()
+    }
+
+    fn get_lease(&mut self, addr: Ipv4Addr) -> Result<Option<LeaseInfo>, Error> {
+        self.refresh()?;
+        self.leases.get_lease(addr)
+    }
+
+    fn get_client_leases(&mut self, clientid: &[u8]) -> Result<Vec<LeaseInfo>, Error> {
+        self.refresh()?;
+        self.leases.get_client_leases(clientid)
+    }
+
+    fn get_expiring(&mut self, from: u32, to: u32) -> Result<Vec<LeaseInfo>, Error> {
+        self.refresh()?;
+        self.leases.get_expiring(from, to)
+    }
+
+    fn put_lease(&mut self, lease: LeaseInfo) -> Result<(), Error> {
+        let file = self
+            .file
+            .as_mut()
+            .ok_or_else(|| Error::DbError("Lease store is read only".into()))?;
+        file.write_all(format_line(&lease).as_bytes())
+            .and_then(|_| file.sync_data())
+            .map_err(|e| Error::DbError(format!("Failed to record lease: {}", e)))?;
+        self.leases.put_lease(lease)?;
+        self.records += 1;
+        if self.records >= COMPACT_MIN_RECORDS
+            && self.records > COMPACT_RATIO * self.leases.leases.len()
+        {
+            self.compact()?;
+        }
+        Ok(())
+    }
+
+    /// There is no way to record a removal in the log, so removing anything rewrites the file.
+    fn remove_expired(&mut self, before: u32) -> Result<usize, Error> {
+        if self.file.is_none() {
+            return Err(Error::DbError("Lease store is read only".into()));
+        }
+        let removed = self.leases.remove_expired(before)?;
+        if removed > 0 {
+            self.compact()?;
+        }
+        Ok(removed)
+    }
+}
+
+#[cfg(test)]
+fn mk_lease(ip: &str, client_id: &[u8], expire: u32) -> LeaseInfo {
+    LeaseInfo {
+        ip: ip.parse().unwrap(),
+        client_id: client_id.to_vec(),
+        chaddr: if client_id.is_empty() {
+            vec![]
+        } else {
+            vec![0x00, 0x00, 0x5E, 0x00, 0x53, 0x00]
+        },
+        start: 100,
+        expire,
+        options: if client_id.is_empty() {
+            vec![]
+        } else {
+            vec![12, 1, b'a', 255]
+        },
+    }
+}
+
+#[cfg(test)]
+fn check_store(store: &mut dyn LeaseStore) {
+    store.put_lease(mk_lease("192.0.2.1", b"one", 200)).unwrap();
+    store.put_lease(mk_lease("192.0.2.2", b"two", 200)).unwrap();
+    store.put_lease(mk_lease("192.0.2.3", b"one", 300)).unwrap();
+    /* Quarantined */
+    store.put_lease(mk_lease("192.0.2.4", b"", 300)).unwrap();
+    /* Replaces the first lease */
+    store.put_lease(mk_lease("192.0.2.1", b"two", 400)).unwrap();
+
+    let mut leases = store.get_leases().unwrap();
+    leases.sort();
+    assert_eq!(
+        leases,
+        vec![
+            mk_lease("192.0.2.1", b"two", 400),
+            mk_lease("192.0.2.2", b"two", 200),
+            mk_lease("192.0.2.3", b"one", 300),
+            mk_lease("192.0.2.4", b"", 300),
+        ]
+    );
+    assert_eq!(
+        store.get_lease("192.0.2.4".parse().unwrap()).unwrap(),
+        Some(mk_lease("192.0.2.4", b"", 300))
+    );
+    assert_eq!(store.get_lease("192.0.2.5".parse().unwrap()).unwrap(), None);
+    assert_eq!(
+        store.get_client_leases(b"one").unwrap(),
+        vec![mk_lease("192.0.2.3", b"one", 300)]
+    );
+    assert_eq!(store.get_client_leases(b"two").unwrap().len(), 2);
+    assert_eq!(store.get_client_leases(b"").unwrap(), vec![]);
+    let mut expiring = store.get_expiring(300, 400).unwrap();
+    expiring.sort();
+    assert_eq!(
+        expiring,
+        vec![
+            mk_lease("192.0.2.1", b"two", 400),
+            mk_lease("192.0.2.3", b"one", 300),
+            mk_lease("192.0.2.4", b"", 300),
+        ]
+    );
+    assert_eq!(store.get_expiring(201, 299).unwrap(), vec![]);
+
+    /* Expiring at exactly the cutoff is kept */
+    assert_eq!(store.remove_expired(300).unwrap(), 1);
+    assert_eq!(store.get_lease("192.0.2.2".parse().unwrap()).unwrap(), None);
+    assert_eq!(store.get_leases().unwrap().len(), 3);
+    assert_eq!(store.remove_expired(300).unwrap(), 0);
+    store.put_lease(mk_lease("192.0.2.2", b"two", 200)).unwrap();
+}
+
+#[test]
+fn test_sqlite_store() {
+    check_store(&mut SqliteStore::new_in_memory().unwrap());
+}
+
+#[test]
+fn test_sqlite_read_only() {
+    let dir = std::env::temp_dir().join(format!("erbium-test-{}", rand::random::<u64>()));
+    std::fs::create_dir(&dir).unwrap();
+    /* Readers don't create the database, the server does */
+    assert!(open(&dir, LeaseStoreType::Sqlite, true).is_err());
+    assert!(!dir.join(SQLITE_FILENAME).exists());
+    let mut store = open(&dir, LeaseStoreType::Sqlite, false).unwrap();
+    store.put_lease(mk_lease("192.0.2.1", b"one", 200)).unwrap();
+    let mut reader = open(&dir, LeaseStoreType::Sqlite, true).unwrap();
+    assert_eq!(reader.get_leases().unwrap().len(), 1);
+    assert!(reader
+        .put_lease(mk_lease("192.0.2.2", b"two", 200))
+        .is_err());
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_memory_store() {
+    check_store(&mut MemoryStore::default());
+}
+
+#[test]
+fn test_append_only_store() {
+    let dir = std::env::temp_dir().join(format!("erbium-test-{}", rand::random::<u64>()));
+    std::fs::create_dir(&dir).unwrap();
+    let path = dir.join(APPEND_ONLY_FILENAME);
+    check_store(&mut AppendOnlyStore::new(&path, false).unwrap());
+    let mut reader = AppendOnlyStore::new(&path, true).unwrap();
+    assert_eq!(reader.get_leases().unwrap().len(), 4);
+
+    /* Simulate a crash part way through writing a record */
+    let mut file = std::fs::OpenOptions::new()
+        .append(true)
+        .open(&path)
+        .unwrap();
+    file.write_all(b"192.0.2.5 6e").unwrap();
+
+    /* Reopening compacts the file, dropping the replaced lease and the partial record */
+    let mut store = AppendOnlyStore::new(&path, false).unwrap();
+    assert_eq!(store.get_leases().unwrap().len(), 4);
+    assert_eq!(std::fs::read_to_string(&path).unwrap().lines().count(), 4);
+    /* The reader notices new leases */
+    store
+        .put_lease(mk_lease("192.0.2.5", b"three", 500))
+        .unwrap();
+    assert_eq!(reader.get_leases().unwrap().len(), 5);
+
+    /* The reader only reads the new records, so doesn't notice this corrupting one it has
+     * already read.
+     */
+    let mut file = std::fs::OpenOptions::new().write(true).open(&path).unwrap();
+    file.write_all(b"x").unwrap();
+    drop(file);
+    store
+        .put_lease(mk_lease("192.0.2.6", b"three", 500))
+        .unwrap();
+    assert_eq!(reader.get_leases().unwrap().len(), 6);
+
+    /* Replacing leases over and over eventually rewrites the file */
+    for expire in 0..COMPACT_MIN_RECORDS as u32 {
+        store
+            .put_lease(mk_lease("192.0.2.6", b"three", 1000 + expire))
+            .unwrap();
+    }
+    assert!(std::fs::read_to_string(&path).unwrap().lines().count() < COMPACT_MIN_RECORDS);
+    /* And the reader notices the file was replaced, and reads it from the start */
+    assert_eq!(
+        reader.get_lease("192.0.2.6".parse().unwrap()).unwrap(),
+        Some(mk_lease(
+            "192.0.2.6",
+            b"three",
+            1000 + COMPACT_MIN_RECORDS as u32 - 1
+        ))
+    );
+    assert_eq!(reader.get_leases().unwrap().len(), 6);
+
+    /* Logs from before the hardware address was recorded are still readable */
+    std::fs::write(&path, "192.0.2.1 6f6e65 100 200 -\n").unwrap();
+    let mut store = AppendOnlyStore::new(&path, false).unwrap();
+    let lease = store
+        .get_lease("192.0.2.1".parse().unwrap())
+        .unwrap()
+        .unwrap();
+    assert_eq!(lease.client_id, b"one");
+    assert_eq!(lease.chaddr, b"");
+
+    /* Corruption in the middle of the file is an error */
+    std::fs::write(&path, "192.0.2.1 zz 0 0 -\n").unwrap();
+    assert!(matches!(
+        AppendOnlyStore::new(&path, false),
+        Err(Error::CorruptDatabase(_))
+    ));
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn schema_upgrade_test() {
+    let conn =
+        rusqlite::Connection::open_in_memory().expect("Failed to create in-memory sqlite database");
+    // Install a copy of the original unversioned schema and expect all
+    // of the upgrades to happen one step at a time. That way, this
+    // single test should invoke them all.
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS leases (
+            address TEXT NOT NULL,
+            chaddr BLOB,
+            clientid BLOB,
+            start INTEGER NOT NULL,
+            expiry INTEGER NOT NULL,
+            PRIMARY KEY (address)
+         )",
+        rusqlite::params![],
+    )
+    .expect("Failed to set up test database with old schema");
+    let mut store = SqliteStore::new_with_conn(conn).expect("setup_db failed");
+    assert_eq!(store.schema_version().unwrap(), SCHEMA_VERSION);
+    store
+        .conn
+        .query_row(
+            "SELECT 1 FROM sqlite_master WHERE type = 'index' AND name = 'leases_expiry'",
+            rusqlite::params![],
+            |_| Ok(()),
+        )
+        .expect("Missing expiry index");
+}
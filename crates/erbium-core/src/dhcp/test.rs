@@ -92,6 +92,7 @@ pub fn mk_default_config() -> crate::config::Config {
                 apply_address: Some(apply_address),
                 ..Default::default()
             }],
+            ..Default::default()
         },
         ..Default::default()
     }
@@ -140,7 +141,8 @@ async fn test_handle_pkt() {
     let conf = mk_default_config();
     dhcp::handle_pkt(&mut p, &request, serverids, &conf)
         .await
-        .expect("Failed to handle request");
+        .expect("Failed to handle request")
+        .expect("Missing reply");
 }
 
 #[test]
@@ -247,8 +249,45 @@ async fn confirm_yiaddr_set() {
 
 /* rfc2131 Section 3.2 Step 1: The server MUST broadcast the DHCPNAK message to the 0xffffffff broadcast address because the client may not have a correct network address or subnet mask, and the client may not be answering ARP requests.  Otherwise, the server MUST send the DHCPNAK message to the IP address of the BOOTP relay agent, as recorded in 'giaddr'.
  */
-fn broadcast_failed_renew() {
-    /* TODO */
+#[tokio::test]
+async fn broadcast_failed_renew() {
+    let mut p = pool::Pool::new_in_memory().expect("Failed to create pool");
+    let serverids: dhcp::ServerIds = dhcp::ServerIds::new();
+    let conf = mk_default_config();
+
+    /* Someone else already holds the address the client is trying to renew. */
+    p.allocate_address(
+        &[0x01, 0x02],
+        b"",
+        Some(EXAMPLE_IP2),
+        &[EXAMPLE_IP2].into_iter().collect(),
+        pool::DEFAULT_MIN_LEASE,
+        pool::DEFAULT_MAX_LEASE,
+        &[],
+    )
+    .expect("Failed to allocate address");
+
+    let mut request = mk_dhcp_request();
+    request.pkt.ciaddr = EXAMPLE_IP2;
+    request.pkt.options = request
+        .pkt
+        .options
+        .set_option(&dhcppkt::OPTION_CLIENTID, &CLIENTID)
+        .set_option(&dhcppkt::OPTION_MSGTYPE, &dhcppkt::DHCPREQUEST);
+    let nak = dhcp::handle_pkt(&mut p, &request, serverids, &conf)
+        .await
+        .expect("Failed to handle request")
+        .expect("Missing reply");
+
+    assert_eq!(nak.options.get_messagetype(), Some(dhcppkt::DHCPNAK));
+    assert_eq!(nak.flags & dhcppkt::FLAG_BROADCAST, dhcppkt::FLAG_BROADCAST);
+    assert_eq!(nak.ciaddr, net::Ipv4Addr::UNSPECIFIED);
+    assert_eq!(nak.yiaddr, net::Ipv4Addr::UNSPECIFIED);
+    assert_eq!(
+        nak.options.get_option::<u32>(&dhcppkt::OPTION_LEASETIME),
+        None
+    );
+    assert!(nak.options.get_serverid().is_some());
 }
 
 /* rfc2131 Section 3.2 Step 3: If the client detects that the IP address in the DHCPACK message is
@@ -261,8 +300,61 @@ fn broadcast_failed_renew() {
 /* rfc2131 Section 3.4: The server SHOULD check the network address in a DHCPINFORM message for
  * consistency, but MUST NOT check for an existing lease.
  */
-fn dhcpinform_dont_check_existing_lease() {
-    /* TODO */
+#[tokio::test]
+async fn dhcpinform_dont_check_existing_lease() {
+    let mut p = pool::Pool::new_in_memory().expect("Failed to create pool");
+    let serverids: dhcp::ServerIds = dhcp::ServerIds::new();
+    let conf = crate::config::load_config_from_string_for_test(
+        "
+dns-servers: [192.0.2.53]
+dhcp-policies:
+  - match-subnet: 192.0.2.0/24
+    apply-subnet: 192.0.2.0/24
+",
+    )
+    .expect("Failed to parse test config");
+    let lockedconf = conf.read().await;
+
+    /* Some other client holds a lease on the address */
+    let mut request = mk_dhcp_request();
+    request.pkt.options = request
+        .pkt
+        .options
+        .set_option(&dhcppkt::OPTION_CLIENTID, &vec![0x01, 0x02])
+        .set_option(&dhcppkt::OPTION_MSGTYPE, &dhcppkt::DHCPDISCOVER);
+    let offer = dhcp::handle_pkt(&mut p, &request, serverids.clone(), &lockedconf)
+        .await
+        .expect("Failed to handle request")
+        .expect("Missing reply");
+    let leases = p.get_leases().expect("Failed to get leases");
+
+    /* But a host has been statically configured with it anyway */
+    let mut request = mk_dhcp_request();
+    request.pkt.ciaddr = offer.yiaddr;
+    request.pkt.options = request
+        .pkt
+        .options
+        .set_option(&dhcppkt::OPTION_CLIENTID, &CLIENTID)
+        .set_option(&dhcppkt::OPTION_MSGTYPE, &dhcppkt::DHCPINFORM);
+    let ack = dhcp::handle_pkt(&mut p, &request, serverids, &lockedconf)
+        .await
+        .expect("Failed to handle inform")
+        .expect("Missing reply");
+
+    assert_eq!(ack.options.get_messagetype(), Some(dhcppkt::DHCPACK));
+    assert_eq!(ack.ciaddr, offer.yiaddr);
+    assert_eq!(ack.yiaddr, net::Ipv4Addr::UNSPECIFIED);
+    assert_eq!(
+        ack.options.get_option::<u32>(&dhcppkt::OPTION_LEASETIME),
+        None
+    );
+    assert_eq!(
+        ack.options
+            .get_option::<Vec<u8>>(&dhcppkt::OPTION_DOMAINSERVER),
+        Some(vec![192, 0, 2, 53])
+    );
+    /* No lease should have been touched */
+    assert!(leases == p.get_leases().expect("Failed to get leases"));
 }
 
 /* rfc2131 Section 3.5: If the client includes a list of parameters in a DHCPDISCOVER message, it
@@ -472,7 +564,8 @@ async fn offer_required() {
     let conf = mk_default_config();
     let reply = dhcp::handle_pkt(&mut p, &request, serverids, &conf)
         .await
-        .expect("Failed to handle request");
+        .expect("Failed to handle request")
+        .expect("Missing reply");
     assert_eq!(reply.op, dhcppkt::OP_BOOTREPLY);
     assert_eq!(reply.htype, dhcppkt::HWTYPE_ETHERNET);
     assert_eq!(reply.hlen, 6);
@@ -525,7 +618,8 @@ async fn ack_required() {
     let conf = mk_default_config();
     let reply = dhcp::handle_pkt(&mut p, &request, serverids, &conf)
         .await
-        .expect("Failed to handle request");
+        .expect("Failed to handle request")
+        .expect("Missing reply");
     assert_eq!(reply.op, dhcppkt::OP_BOOTREPLY);
     assert_eq!(reply.htype, dhcppkt::HWTYPE_ETHERNET);
     assert_eq!(reply.hlen, 6);
@@ -582,8 +676,189 @@ async fn test_renew_unknown() {
     let conf = mk_default_config();
     let reply = dhcp::handle_pkt(&mut p, &request, serverids, &conf)
         .await
-        .expect("Failed to handle request");
+        .expect("Failed to handle request")
+        .expect("Missing reply");
+    assert_eq!(reply.yiaddr, EXAMPLE_IP2);
+}
+
+#[tokio::test]
+async fn nak_init_reboot_wrong_subnet() {
+    /* A client that has moved from another network reboots and asks for its old address. */
+    let mut request = mk_dhcp_request();
+    request.pkt.options = request
+        .pkt
+        .options
+        .set_option(&dhcppkt::OPTION_CLIENTID, &CLIENTID)
+        .set_option(&dhcppkt::OPTION_MSGTYPE, &dhcppkt::DHCPREQUEST)
+        .set_option(
+            &dhcppkt::OPTION_ADDRESSREQUEST,
+            &"198.51.100.10".parse::<net::Ipv4Addr>().unwrap(),
+        );
+
+    let mut p = pool::Pool::new_in_memory().expect("Failed to create pool");
+    let serverids: dhcp::ServerIds = dhcp::ServerIds::new();
+    let conf = mk_default_config();
+    let reply = dhcp::handle_pkt(&mut p, &request, serverids, &conf)
+        .await
+        .expect("Failed to handle request")
+        .expect("Missing reply");
+    assert_eq!(reply.options.get_messagetype(), Some(dhcppkt::DHCPNAK));
+    assert!(reply
+        .options
+        .get_option::<Vec<u8>>(&dhcppkt::OPTION_MESSAGE)
+        .is_some());
+    /* And we shouldn't have given it some other address instead */
+    assert!(p.get_leases().expect("Failed to get leases").is_empty());
+}
+
+#[tokio::test]
+async fn silent_init_reboot_unknown_client() {
+    /* A client we have never heard of reboots and asks for an address that is valid here.  Its
+     * lease may be from another server, so we mustn't NAK it.
+     */
+    let mut request = mk_dhcp_request();
+    request.pkt.options = request
+        .pkt
+        .options
+        .set_option(&dhcppkt::OPTION_CLIENTID, &CLIENTID)
+        .set_option(&dhcppkt::OPTION_MSGTYPE, &dhcppkt::DHCPREQUEST)
+        .set_option(&dhcppkt::OPTION_ADDRESSREQUEST, &EXAMPLE_IP2);
+
+    let mut p = pool::Pool::new_in_memory().expect("Failed to create pool");
+    let conf = mk_default_config();
+    assert_eq!(
+        dhcp::handle_pkt(&mut p, &request, dhcp::ServerIds::new(), &conf).await,
+        Err(dhcp::DhcpError::UnknownClient)
+    );
+    assert!(p.get_leases().expect("Failed to get leases").is_empty());
+
+    /* But once we know about the client, it gets its address back */
+    p.allocate_address(
+        CLIENTID,
+        b"",
+        Some(EXAMPLE_IP2),
+        &[EXAMPLE_IP2].into_iter().collect(),
+        pool::DEFAULT_MIN_LEASE,
+        pool::DEFAULT_MAX_LEASE,
+        &[],
+    )
+    .expect("Failed to allocate address");
+    let reply = dhcp::handle_pkt(&mut p, &request, dhcp::ServerIds::new(), &conf)
+        .await
+        .expect("Failed to handle request")
+        .expect("Missing reply");
+    assert_eq!(reply.options.get_messagetype(), Some(dhcppkt::DHCPACK));
     assert_eq!(reply.yiaddr, EXAMPLE_IP2);
+
+    /* And an unknown client asking for an address someone else holds is NAKed */
+    request.pkt.options = request
+        .pkt
+        .options
+        .set_option(&dhcppkt::OPTION_CLIENTID, &b"Another Client".to_vec());
+    let reply = dhcp::handle_pkt(&mut p, &request, dhcp::ServerIds::new(), &conf)
+        .await
+        .expect("Failed to handle request")
+        .expect("Missing reply");
+    assert_eq!(reply.options.get_messagetype(), Some(dhcppkt::DHCPNAK));
+}
+
+#[tokio::test]
+async fn relayed_request() {
+    let mut p = pool::Pool::new_in_memory().expect("Failed to create pool");
+    let serverids: dhcp::ServerIds = dhcp::ServerIds::new();
+    let conf = crate::config::load_config_from_string_for_test(
+        "
+dhcp-policies:
+  - match-subnet: 192.0.2.0/24
+    apply-subnet: 192.0.2.0/24
+  - match-subnet: 198.51.100.0/24
+    apply-subnet: 198.51.100.0/24
+    policies:
+      - match-circuit-id: port1
+        apply-address: 198.51.100.100
+  - match-subnet: 203.0.113.0/24
+    apply-subnet: 203.0.113.0/24
+",
+    )
+    .expect("Failed to parse test config");
+    let lockedconf = conf.read().await;
+    /* Circuit ID "port1", Remote ID "switch" */
+    let relayinfo = b"\x01\x05port1\x02\x06switch".to_vec();
+
+    /* Requests forwarded by a relay use the relay's subnet */
+    let mut request = mk_dhcp_request();
+    request.pkt.giaddr = "198.51.100.1".parse().unwrap();
+    request.pkt.options = request
+        .pkt
+        .options
+        .set_option(&dhcppkt::OPTION_CLIENTID, &CLIENTID)
+        .set_option(&dhcppkt::OPTION_MSGTYPE, &dhcppkt::DHCPDISCOVER);
+    let offer = dhcp::handle_pkt(&mut p, &request, serverids.clone(), &lockedconf)
+        .await
+        .expect("Failed to handle request")
+        .expect("Missing reply");
+    assert!(
+        erbium_net::Ipv4Subnet::new("198.51.100.0".parse().unwrap(), 24)
+            .unwrap()
+            .contains(offer.yiaddr),
+        "{} is not in the relay's subnet",
+        offer.yiaddr
+    );
+    assert_eq!(offer.giaddr, request.pkt.giaddr);
+    assert_eq!(
+        offer
+            .options
+            .get_option::<Vec<u8>>(&dhcppkt::OPTION_RELAYAGENTINFO),
+        None
+    );
+
+    /* Relay agent information can be matched on, and is echoed back */
+    request.pkt.options = request
+        .pkt
+        .options
+        .set_raw_option(&dhcppkt::OPTION_RELAYAGENTINFO, &relayinfo);
+    assert_eq!(
+        request
+            .pkt
+            .options
+            .get_relay_agent_suboption(dhcppkt::RELAY_REMOTE_ID),
+        Some(b"switch".to_vec())
+    );
+    let offer = dhcp::handle_pkt(&mut p, &request, serverids.clone(), &lockedconf)
+        .await
+        .expect("Failed to handle request")
+        .expect("Missing reply");
+    assert_eq!(
+        offer.yiaddr,
+        "198.51.100.100".parse::<net::Ipv4Addr>().unwrap()
+    );
+    assert_eq!(
+        offer
+            .options
+            .get_option::<Vec<u8>>(&dhcppkt::OPTION_RELAYAGENTINFO),
+        Some(relayinfo)
+    );
+
+    /* The subnet selection option overrides the relay address */
+    request.pkt.options = request
+        .pkt
+        .options
+        .remove_option(&dhcppkt::OPTION_RELAYAGENTINFO)
+        .set_option(
+            &dhcppkt::OPTION_SUBNETSELECT,
+            &"203.0.113.0".parse::<net::Ipv4Addr>().unwrap(),
+        );
+    let offer = dhcp::handle_pkt(&mut p, &request, serverids, &lockedconf)
+        .await
+        .expect("Failed to handle request")
+        .expect("Missing reply");
+    assert!(
+        erbium_net::Ipv4Subnet::new("203.0.113.0".parse().unwrap(), 24)
+            .unwrap()
+            .contains(offer.yiaddr),
+        "{} is not in the selected subnet",
+        offer.yiaddr
+    );
 }
 
 #[tokio::test]
@@ -609,7 +884,8 @@ async fn test_full() {
 
     let offer = dhcp::handle_pkt(&mut p, &request, serverids.clone(), &conf)
         .await
-        .expect("Failed to handle request");
+        .expect("Failed to handle request")
+        .expect("Missing reply");
 
     serverids.insert(offer.options.get_serverid().unwrap());
 
@@ -628,7 +904,8 @@ async fn test_full() {
 
     let ack = dhcp::handle_pkt(&mut p, &request, serverids.clone(), &conf)
         .await
-        .expect("Failed to handle request");
+        .expect("Failed to handle request")
+        .expect("Missing reply");
 
     assert_eq!(ack.options.get_messagetype(), Some(dhcppkt::DHCPACK));
     assert_eq!(ack.yiaddr, offer.yiaddr); /* make sure we don't needlessly change our mind */
@@ -646,24 +923,252 @@ async fn test_full() {
     /* no server id */
     let ack = dhcp::handle_pkt(&mut p, &request, serverids.clone(), &conf)
         .await
-        .expect("Failed to handle request");
+        .expect("Failed to handle request")
+        .expect("Missing reply");
     assert_eq!(ack.options.get_messagetype(), Some(dhcppkt::DHCPACK));
     assert_eq!(ack.yiaddr, offer.yiaddr); /* Did we get back the same address? */
+    /* The lease remembers the client's hardware address, even though it sent a client identifier */
+    let lease = p
+        .get_leases()
+        .expect("Failed to get leases")
+        .into_iter()
+        .find(|lease| lease.ip == ack.yiaddr)
+        .expect("Missing lease");
+    assert_eq!(lease.client_id, CLIENTID);
+    assert_eq!(lease.chaddr, request.pkt.chaddr);
 
     /* Okay, now it's time to RELEASE the address */
     let mut request = mk_dhcp_request();
     /* xid and seconds are not copied from the previous requests */
     request.pkt.secs = 0;
+    request.pkt.ciaddr = offer.yiaddr;
     request.pkt.options = request
         .pkt
         .options
         .set_option(&dhcppkt::OPTION_CLIENTID, &CLIENTID)
-        .set_option(&dhcppkt::OPTION_MSGTYPE, &dhcppkt::DHCPRELEASE);
-    /* no server id */
-    /* release is not supported, so we expect an error here.  But we shouldn't crash */
-    let _ack = dhcp::handle_pkt(&mut p, &request, serverids, &conf)
+        .set_option(&dhcppkt::OPTION_MSGTYPE, &dhcppkt::DHCPRELEASE)
+        .set_option(
+            &dhcppkt::OPTION_SERVERID,
+            &offer.options.get_serverid().unwrap(),
+        );
+    /* There is no reply to a release */
+    assert_eq!(
+        dhcp::handle_pkt(&mut p, &request, serverids.clone(), &conf)
+            .await
+            .expect("Failed to handle release"),
+        None
+    );
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as u32;
+    assert!(p
+        .get_leases()
+        .expect("Failed to get leases")
+        .iter()
+        .all(|lease| lease.expire < now || lease.ip != offer.yiaddr));
+
+    /* Releasing it a second time should fail, the client no longer holds it */
+    dhcp::handle_pkt(&mut p, &request, serverids, &conf)
+        .await
+        .expect_err("Released an address that was already released");
+}
+
+#[tokio::test]
+async fn test_decline() {
+    let mut p = pool::Pool::new_in_memory().expect("Failed to create pool");
+    let mut serverids: dhcp::ServerIds = dhcp::ServerIds::new();
+    let conf = mk_default_config();
+
+    /* Get an offer */
+    let mut request = mk_dhcp_request();
+    request.pkt.options = request
+        .pkt
+        .options
+        .set_option(&dhcppkt::OPTION_CLIENTID, &CLIENTID)
+        .set_option(&dhcppkt::OPTION_MSGTYPE, &dhcppkt::DHCPDISCOVER);
+    let offer = dhcp::handle_pkt(&mut p, &request, serverids.clone(), &conf)
+        .await
+        .expect("Failed to handle request")
+        .expect("Missing reply");
+    serverids.insert(offer.options.get_serverid().unwrap());
+
+    /* The client discovers the address is in use by someone else, and declines it */
+    let mut request = mk_dhcp_request();
+    request.pkt.options = request
+        .pkt
+        .options
+        .set_option(&dhcppkt::OPTION_CLIENTID, &CLIENTID)
+        .set_option(&dhcppkt::OPTION_MSGTYPE, &dhcppkt::DHCPDECLINE)
+        .set_option(&dhcppkt::OPTION_ADDRESSREQUEST, &offer.yiaddr)
+        .set_option(
+            &dhcppkt::OPTION_SERVERID,
+            &offer.options.get_serverid().unwrap(),
+        );
+    assert_eq!(
+        dhcp::handle_pkt(&mut p, &request, serverids.clone(), &conf)
+            .await
+            .expect("Failed to handle decline"),
+        None
+    );
+
+    /* When the client tries again, it should be offered a different address */
+    let mut request = mk_dhcp_request();
+    request.pkt.options = request
+        .pkt
+        .options
+        .set_option(&dhcppkt::OPTION_CLIENTID, &CLIENTID)
+        .set_option(&dhcppkt::OPTION_MSGTYPE, &dhcppkt::DHCPDISCOVER);
+    let reoffer = dhcp::handle_pkt(&mut p, &request, serverids, &conf)
+        .await
+        .expect("Failed to handle request")
+        .expect("Missing reply");
+    assert_ne!(reoffer.yiaddr, offer.yiaddr);
+}
+
+fn mk_probing_config() -> crate::config::SharedConfig {
+    let mut conf = mk_default_config();
+    conf.dhcp.conflict_detection = Some(std::time::Duration::from_secs(1));
+    std::sync::Arc::new(sync::RwLock::new(conf))
+}
+
+#[tokio::test]
+async fn quarantine_conflicting_address() {
+    let mut p = pool::Pool::new_in_memory().expect("Failed to create pool");
+    let offers = dhcp::conflict::OfferTracker::default();
+    p.add_observer(offers.observer());
+    let p = sync::Mutex::new(p);
+    let serverids: dhcp::ServerIds = dhcp::ServerIds::new();
+    let conf = mk_probing_config();
+    const SQUATTER: [u8; 6] = [0x00, 0x00, 0x5E, 0x00, 0x53, 0x01];
+
+    let mut request = mk_dhcp_request();
+    request.pkt.options = request
+        .pkt
+        .options
+        .set_option(&dhcppkt::OPTION_CLIENTID, &CLIENTID)
+        .set_option(&dhcppkt::OPTION_MSGTYPE, &dhcppkt::DHCPDISCOVER);
+
+    /* Something else on the network answers for the first address we probe */
+    let probed = std::cell::RefCell::new(vec![]);
+    let offer = dhcp::handle_pkt_probing(&p, &offers, &request, serverids, &conf, |addr, _| {
+        probed.borrow_mut().push(addr);
+        let squatted = probed.borrow().len() == 1;
+        async move { squatted.then_some(SQUATTER) }
+    })
+    .await
+    .expect("Failed to handle request")
+    .expect("Missing reply");
+    let probed = probed.into_inner();
+
+    /* So the client is offered a different address, which is also probed */
+    assert_eq!(probed.len(), 2);
+    assert_ne!(offer.yiaddr, probed[0]);
+    assert_eq!(offer.yiaddr, probed[1]);
+
+    /* And the squatted address is quarantined */
+    let quarantined = p
+        .lock()
+        .await
+        .get_leases()
+        .expect("Failed to get leases")
+        .into_iter()
+        .find(|lease| lease.ip == probed[0])
+        .expect("Conflicting address wasn't quarantined");
+    assert!(quarantined.client_id.is_empty());
+}
+
+#[tokio::test]
+async fn dont_probe_reused_lease() {
+    let mut p = pool::Pool::new_in_memory().expect("Failed to create pool");
+    let offers = dhcp::conflict::OfferTracker::default();
+    p.add_observer(offers.observer());
+    let p = sync::Mutex::new(p);
+    let serverids: dhcp::ServerIds = dhcp::ServerIds::new();
+    let conf = mk_probing_config();
+
+    let mut request = mk_dhcp_request();
+    request.pkt.options = request
+        .pkt
+        .options
+        .set_option(&dhcppkt::OPTION_CLIENTID, &CLIENTID)
+        .set_option(&dhcppkt::OPTION_MSGTYPE, &dhcppkt::DHCPDISCOVER);
+
+    let probes = std::cell::Cell::new(0);
+    let probe = |_, _| {
+        probes.set(probes.get() + 1);
+        async { None }
+    };
+
+    /* An address that has never been leased is probed */
+    let offer = dhcp::handle_pkt_probing(&p, &offers, &request, serverids.clone(), &conf, probe)
+        .await
+        .expect("Failed to handle request")
+        .expect("Missing reply");
+    assert_eq!(probes.get(), 1);
+
+    /* But when the client asks again, the address it was just offered isn't probed again */
+    let reoffer = dhcp::handle_pkt_probing(&p, &offers, &request, serverids, &conf, probe)
+        .await
+        .expect("Failed to handle request")
+        .expect("Missing reply");
+    assert_eq!(reoffer.yiaddr, offer.yiaddr);
+    assert_eq!(probes.get(), 1);
+}
+
+#[tokio::test]
+async fn client_fqdn() {
+    let mut p = pool::Pool::new_in_memory().expect("Failed to create pool");
+    let mut serverids: dhcp::ServerIds = dhcp::ServerIds::new();
+    serverids.insert(SERVER_IP);
+    let mut conf = mk_default_config();
+    let mut request = mk_dhcp_request();
+    request.pkt.options = request
+        .pkt
+        .options
+        .set_option(&dhcppkt::OPTION_CLIENTID, &CLIENTID)
+        .set_option(&dhcppkt::OPTION_SERVERID, &SERVER_IP)
+        .set_option(
+            &dhcppkt::OPTION_FQDN,
+            &dhcppkt::ClientFqdn {
+                flags: dhcppkt::FQDN_FLAG_E | dhcppkt::FQDN_FLAG_S,
+                name: "Laptop".into(),
+            },
+        );
+
+    /* Without dynamic DNS configured, we tell the client we won't be updating anything */
+    let reply = dhcp::handle_pkt(&mut p, &request, serverids.clone(), &conf)
+        .await
+        .expect("Failed to handle request")
+        .expect("Missing reply");
+    assert_eq!(
+        reply.options.get_client_fqdn(),
+        Some(dhcppkt::ClientFqdn {
+            flags: dhcppkt::FQDN_FLAG_E | dhcppkt::FQDN_FLAG_N,
+            name: "Laptop".into(),
+        })
+    );
+
+    /* With it configured, we agree to do the A update, and fully qualify the name */
+    conf.dhcp.dns_update = Some(dhcp::ddns::Config {
+        server: "192.0.2.53:53".parse().unwrap(),
+        zone: "example.com".into(),
+        reverse_zone: None,
+        ttl: 300,
+        tsig: None,
+        override_client_updates: false,
+    });
+    let reply = dhcp::handle_pkt(&mut p, &request, serverids, &conf)
         .await
-        .expect_err("Failed to handle request");
+        .expect("Failed to handle request")
+        .expect("Missing reply");
+    assert_eq!(
+        reply.options.get_client_fqdn(),
+        Some(dhcppkt::ClientFqdn {
+            flags: dhcppkt::FQDN_FLAG_E | dhcppkt::FQDN_FLAG_S,
+            name: "laptop.example.com".into(),
+        })
+    );
 }
 
 #[tokio::test]
@@ -699,6 +1204,536 @@ dhcp-policies:
     );
 }
 
+#[tokio::test]
+async fn network_boot() {
+    let mut p = pool::Pool::new_in_memory().expect("Failed to create pool");
+    let conf = crate::config::load_config_from_string_for_test(
+        "
+dhcp-policies:
+  - match-subnet: 192.0.2.0/24
+    apply-range: {start: 192.0.2.10, end: 192.0.2.20}
+    apply-next-server: 192.0.2.2
+    apply-boot-file: undionly.kpxe
+    policies:
+      - match-user-class: iPXE
+        apply-boot-file: http://192.0.2.2/boot.ipxe
+      - match-client-architecture: 7
+        apply-boot-file: ipxe.efi
+",
+    )
+    .expect("Failed to parse test config");
+    let lockedconf = conf.read().await;
+    let serverids: dhcp::ServerIds = dhcp::ServerIds::new();
+
+    /* Legacy BIOS clients get the default */
+    let pkt = mk_dhcp_request();
+    let reply = dhcp::handle_discover(&mut p, &pkt, &serverids, &[], &lockedconf)
+        .expect("Failed to handle request");
+    assert_eq!(reply.siaddr, EXAMPLE_IP2);
+    assert_eq!(reply.file, b"undionly.kpxe");
+    assert_eq!(
+        reply.options.get_raw_option(&dhcppkt::OPTION_BOOTFILE),
+        None
+    );
+
+    /* UEFI clients get a UEFI binary */
+    let mut pkt = mk_dhcp_request();
+    pkt.pkt.options = pkt
+        .pkt
+        .options
+        .set_option(&dhcppkt::OPTION_CLIENTARCH, &7_u16);
+    let reply = dhcp::handle_discover(&mut p, &pkt, &serverids, &[], &lockedconf)
+        .expect("Failed to handle request");
+    assert_eq!(reply.siaddr, EXAMPLE_IP2);
+    assert_eq!(reply.file, b"ipxe.efi");
+
+    /* The option is a list, and clients that support more than one architecture match on any */
+    let mut pkt = mk_dhcp_request();
+    pkt.pkt.options = pkt
+        .pkt
+        .options
+        .set_option(&dhcppkt::OPTION_CLIENTARCH, &vec![6_u16, 7_u16]);
+    let reply = dhcp::handle_discover(&mut p, &pkt, &serverids, &[], &lockedconf)
+        .expect("Failed to handle request");
+    assert_eq!(reply.file, b"ipxe.efi");
+
+    /* Once iPXE is running, it gets its script, and as an option too if it asks for it */
+    let mut pkt = mk_dhcp_request();
+    pkt.pkt.options = pkt
+        .pkt
+        .options
+        .set_option(&dhcppkt::OPTION_CLIENTARCH, &7_u16)
+        .set_raw_option(&dhcppkt::OPTION_USERCLASS, b"iPXE")
+        .set_option(&dhcppkt::OPTION_PARAMLIST, &vec![1u8, 3, 6, 66, 67]);
+    let reply = dhcp::handle_discover(&mut p, &pkt, &serverids, &[], &lockedconf)
+        .expect("Failed to handle request");
+    assert_eq!(reply.file, b"http://192.0.2.2/boot.ipxe");
+    assert_eq!(
+        reply.options.get_raw_option(&dhcppkt::OPTION_BOOTFILE),
+        Some(&b"http://192.0.2.2/boot.ipxe"[..])
+    );
+    assert_eq!(
+        reply.options.get_raw_option(&dhcppkt::OPTION_TFTPSERVER),
+        Some(&b"192.0.2.2"[..])
+    );
+}
+
+#[tokio::test]
+async fn match_client_classes() {
+    let conf = crate::config::load_config_from_string_for_test(
+        "
+dhcp-policies:
+  - match-subnet: 192.0.2.0/24
+    apply-range: {start: 192.0.2.10, end: 192.0.2.20}
+    policies:
+      - match-vendor-class-prefix: PXEClient
+        apply-boot-file: vendor
+      - match-user-class-substring: lab
+        apply-boot-file: user
+      - match-parameter-list-prefix: 1, 3, 6, 15
+        apply-boot-file: fingerprint
+",
+    )
+    .expect("Failed to parse test config");
+    let lockedconf = conf.read().await;
+    let serverids: dhcp::ServerIds = dhcp::ServerIds::new();
+    let boot_file = |options: &dyn Fn(dhcppkt::DhcpOptions) -> dhcppkt::DhcpOptions| {
+        let mut pkt = mk_dhcp_request();
+        pkt.pkt.options = options(pkt.pkt.options.clone());
+        let mut p = pool::Pool::new_in_memory().expect("Failed to create pool");
+        dhcp::handle_discover(&mut p, &pkt, &serverids, &[], &lockedconf)
+            .expect("Failed to handle request")
+            .file
+    };
+
+    assert_eq!(
+        boot_file(&|o| o.set_raw_option(&dhcppkt::OPTION_VENDOR_CLASS, b"PXEClient:Arch:00000")),
+        b"vendor"
+    );
+    assert_eq!(
+        boot_file(&|o| o.set_raw_option(&dhcppkt::OPTION_USERCLASS, b"\x06my-lab")),
+        b"user"
+    );
+    /* The default request's parameter list starts with 1,3,6,15 */
+    assert_eq!(boot_file(&|o| o), b"fingerprint");
+    /* 1,3,6,150 isn't the same as 1,3,6,15 */
+    assert_eq!(
+        boot_file(&|o| o.set_option(&dhcppkt::OPTION_PARAMLIST, &vec![1u8, 3, 6, 150])),
+        b""
+    );
+    assert_eq!(
+        boot_file(&|o| o
+            .set_raw_option(&dhcppkt::OPTION_VENDOR_CLASS, b"MSFT 5.0")
+            .set_option(&dhcppkt::OPTION_PARAMLIST, &vec![1u8, 3, 6])),
+        b""
+    );
+}
+
+#[tokio::test]
+async fn deny_unknown_clients() {
+    let mut p = pool::Pool::new_in_memory().expect("Failed to create pool");
+    let conf = crate::config::load_config_from_string_for_test(
+        "
+dhcp-policies:
+  - match-subnet: 192.0.2.0/24
+    apply-deny: true
+    policies:
+      - match-hardware-address: 00:00:5e:00:53:00
+        apply-deny: false
+        apply-address: 192.0.2.10
+",
+    )
+    .expect("Failed to parse test config");
+    let lockedconf = conf.read().await;
+    let serverids: dhcp::ServerIds = dhcp::ServerIds::new();
+
+    /* Listed hardware addresses get an address */
+    let pkt = mk_dhcp_request();
+    let reply = dhcp::handle_discover(&mut p, &pkt, &serverids, &[], &lockedconf)
+        .expect("Failed to handle request");
+    assert_eq!(reply.yiaddr, "192.0.2.10".parse::<net::Ipv4Addr>().unwrap());
+
+    /* Everyone else is ignored */
+    let mut pkt = mk_dhcp_request();
+    pkt.pkt.chaddr = vec![0x00, 0x00, 0x5E, 0x00, 0x53, 0x01];
+    assert_eq!(
+        dhcp::handle_discover(&mut p, &pkt, &serverids, &[], &lockedconf),
+        Err(dhcp::DhcpError::Denied)
+    );
+    assert_eq!(
+        dhcp::handle_request(&mut p, &pkt, &serverids, &[], &lockedconf),
+        Err(dhcp::DhcpError::Denied)
+    );
+}
+
+#[tokio::test]
+async fn nak_clients() {
+    let mut p = pool::Pool::new_in_memory().expect("Failed to create pool");
+    let conf = crate::config::load_config_from_string_for_test(
+        "
+dhcp-policies:
+  - match-subnet: 192.0.2.0/24
+    apply-range: {start: 192.0.2.10, end: 192.0.2.20}
+    apply-nak: true
+",
+    )
+    .expect("Failed to parse test config");
+    let lockedconf = conf.read().await;
+    let serverids: dhcp::ServerIds = dhcp::ServerIds::new();
+
+    /* There's no such thing as a NAK for a DHCPDISCOVER */
+    let pkt = mk_dhcp_request();
+    assert_eq!(
+        dhcp::handle_discover(&mut p, &pkt, &serverids, &[], &lockedconf),
+        Err(dhcp::DhcpError::Denied)
+    );
+
+    let mut pkt = mk_dhcp_request();
+    pkt.pkt.ciaddr = "192.0.2.10".parse().unwrap();
+    let nak = dhcp::handle_request(&mut p, &pkt, &serverids, &[], &lockedconf)
+        .expect("Failed to handle request");
+    assert_eq!(nak.options.get_messagetype(), Some(dhcppkt::DHCPNAK));
+    assert_eq!(nak.yiaddr, net::Ipv4Addr::UNSPECIFIED);
+}
+
+#[tokio::test]
+async fn rapid_commit() {
+    let mut p = pool::Pool::new_in_memory().expect("Failed to create pool");
+    let conf = crate::config::load_config_from_string_for_test(
+        "
+dhcp-policies:
+  - match-subnet: 192.0.2.0/24
+    apply-range: {start: 192.0.2.10, end: 192.0.2.20}
+    apply-rapid-commit: true
+    policies:
+      - match-host-name: slow
+        apply-rapid-commit: false
+",
+    )
+    .expect("Failed to parse test config");
+    let lockedconf = conf.read().await;
+    let serverids: dhcp::ServerIds = dhcp::ServerIds::new();
+
+    /* A client that doesn't ask for rapid commit gets an offer */
+    let mut pkt = mk_dhcp_request();
+    pkt.pkt.options = pkt
+        .pkt
+        .options
+        .set_option(&dhcppkt::OPTION_MSGTYPE, &dhcppkt::DHCPDISCOVER);
+    let reply = dhcp::handle_discover(&mut p, &pkt, &serverids, &[], &lockedconf)
+        .expect("Failed to handle request");
+    assert_eq!(reply.options.get_messagetype(), Some(dhcppkt::DHCPOFFER));
+    assert!(!reply.options.has_rapid_commit());
+
+    /* One that does is ACK'd straight away, which commits the lease */
+    let changes = std::sync::Arc::new(std::sync::Mutex::new(vec![]));
+    let observed = changes.clone();
+    p.add_observer(Box::new(move |change: &pool::Event| {
+        observed.lock().unwrap().push(change.clone())
+    }));
+    pkt.pkt.options = pkt
+        .pkt
+        .options
+        .set_raw_option(&dhcppkt::OPTION_RAPID_COMMIT, &[]);
+    let reply = dhcp::handle_discover(&mut p, &pkt, &serverids, &[], &lockedconf)
+        .expect("Failed to handle request");
+    assert_eq!(reply.options.get_messagetype(), Some(dhcppkt::DHCPACK));
+    let change = changes.lock().unwrap().pop().expect("No lease change");
+    assert_eq!(change.lease.ip, reply.yiaddr);
+    assert_eq!(
+        dhcp::hooks::LeaseEvent::from_change(&change, String::new()).map(|event| event.event),
+        Some(dhcp::hooks::Event::Commit)
+    );
+    assert!(reply.options.has_rapid_commit());
+    assert!(reply
+        .options
+        .get_option::<u32>(&dhcppkt::OPTION_LEASETIME)
+        .is_some());
+    /* The option survives being sent on the wire */
+    let parsed = dhcppkt::parse(&reply.serialise()).expect("Failed to parse reply");
+    assert!(parsed.options.has_rapid_commit());
+
+    /* Unless the policy doesn't allow it */
+    pkt.pkt.options = pkt
+        .pkt
+        .options
+        .set_option(&dhcppkt::OPTION_HOSTNAME, &"slow".to_string());
+    let reply = dhcp::handle_discover(&mut p, &pkt, &serverids, &[], &lockedconf)
+        .expect("Failed to handle request");
+    assert_eq!(reply.options.get_messagetype(), Some(dhcppkt::DHCPOFFER));
+}
+
+#[tokio::test]
+async fn ipv6_only_preferred() {
+    let mut p = pool::Pool::new_in_memory().expect("Failed to create pool");
+    let conf = crate::config::load_config_from_string_for_test(
+        "
+dhcp-policies:
+  - match-subnet: 192.0.2.0/24
+    apply-range: {start: 192.0.2.10, end: 192.0.2.20}
+    apply-ipv6-only-preferred: 30m
+",
+    )
+    .expect("Failed to parse test config");
+    let lockedconf = conf.read().await;
+    let serverids: dhcp::ServerIds = dhcp::ServerIds::new();
+
+    /* A client that doesn't understand the option gets an address as usual */
+    let mut pkt = mk_dhcp_request();
+    pkt.pkt.options = pkt
+        .pkt
+        .options
+        .set_option(&dhcppkt::OPTION_MSGTYPE, &dhcppkt::DHCPDISCOVER);
+    let reply = dhcp::handle_discover(&mut p, &pkt, &serverids, &[], &lockedconf)
+        .expect("Failed to handle request");
+    assert_ne!(reply.yiaddr, net::Ipv4Addr::UNSPECIFIED);
+    assert_eq!(
+        reply
+            .options
+            .get_option::<u32>(&dhcppkt::OPTION_IPV6_ONLY_PREFERRED),
+        None
+    );
+
+    /* One that asks for it is told to go IPv6-only, and doesn't get an address.  Lease hooks and
+     * DNS updates are driven by changes to the pool, so they don't hear about it either.
+     */
+    let changes = std::sync::Arc::new(std::sync::Mutex::new(vec![]));
+    let observed = changes.clone();
+    p.add_observer(Box::new(move |change: &pool::Event| {
+        observed.lock().unwrap().push(change.clone())
+    }));
+    let mut pkt = mk_dhcp_request();
+    pkt.pkt.chaddr = vec![0x00, 0x00, 0x5E, 0x00, 0x53, 0x01];
+    pkt.pkt.options = pkt
+        .pkt
+        .options
+        .set_option(&dhcppkt::OPTION_MSGTYPE, &dhcppkt::DHCPDISCOVER)
+        .set_option(&dhcppkt::OPTION_PARAMLIST, &vec![1u8, 3u8, 6u8, 108u8]);
+    for msgtype in [dhcppkt::DHCPDISCOVER, dhcppkt::DHCPREQUEST] {
+        pkt.pkt.options = pkt
+            .pkt
+            .options
+            .set_option(&dhcppkt::OPTION_MSGTYPE, &msgtype);
+        let reply = if msgtype == dhcppkt::DHCPDISCOVER {
+            dhcp::handle_discover(&mut p, &pkt, &serverids, &[], &lockedconf)
+        } else {
+            dhcp::handle_request(&mut p, &pkt, &serverids, &[], &lockedconf)
+        }
+        .expect("Failed to handle request");
+        assert_eq!(reply.yiaddr, net::Ipv4Addr::UNSPECIFIED);
+        assert_eq!(
+            reply
+                .options
+                .get_option::<u32>(&dhcppkt::OPTION_IPV6_ONLY_PREFERRED),
+            Some(1800)
+        );
+    }
+    assert_eq!(*changes.lock().unwrap(), vec![]);
+    assert!(p
+        .get_client_leases(&pkt.pkt.get_client_id())
+        .expect("Failed to query pool")
+        .is_empty());
+}
+
+#[test]
+fn reply_destination() {
+    use erbium_net::addr::WithPort as _;
+    let mut request = mk_dhcp_request().pkt;
+    let mut reply = mk_dhcp_request().pkt;
+    reply.op = dhcppkt::OP_BOOTREPLY;
+    reply.options = reply
+        .options
+        .set_option(&dhcppkt::OPTION_MSGTYPE, &dhcppkt::DHCPACK);
+    reply.yiaddr = EXAMPLE_IP4;
+
+    /* A client without an address is sent the reply at its hardware address */
+    assert_eq!(
+        dhcp::reply_destination(&request, &reply, None),
+        dhcp::ReplyDestination::LinkLayer(EXAMPLE_IP4)
+    );
+
+    /* Unless it asked for a broadcast */
+    request.flags = dhcppkt::FLAG_BROADCAST;
+    assert_eq!(
+        dhcp::reply_destination(&request, &reply, None),
+        dhcp::ReplyDestination::Broadcast
+    );
+
+    /* A renewing client can be sent a normal unicast reply */
+    request.ciaddr = EXAMPLE_IP4;
+    assert_eq!(
+        dhcp::reply_destination(&request, &reply, None),
+        dhcp::ReplyDestination::Unicast(EXAMPLE_IP4.with_port(68))
+    );
+
+    /* But NAKs are always broadcast */
+    let mut nak = mk_dhcp_request().pkt;
+    nak.options = nak
+        .options
+        .set_option(&dhcppkt::OPTION_MSGTYPE, &dhcppkt::DHCPNAK);
+    assert_eq!(
+        dhcp::reply_destination(&request, &nak, None),
+        dhcp::ReplyDestination::Broadcast
+    );
+
+    /* Leasequery replies go back to whoever asked */
+    let requester = SERVER_IP.with_port(1067);
+    assert_eq!(
+        dhcp::reply_destination(&request, &reply, Some(requester)),
+        dhcp::ReplyDestination::Unicast(requester)
+    );
+
+    /* And anything that came via a relay goes back to the relay */
+    request.giaddr = SERVER_IP;
+    assert_eq!(
+        dhcp::reply_destination(&request, &nak, None),
+        dhcp::ReplyDestination::Unicast(SERVER_IP.with_port(67))
+    );
+}
+
+#[test]
+fn shortened_lease_time() {
+    let options = dhcp::ResponseOptions::default()
+        .set_option(&dhcppkt::OPTION_RENEWALTIME, &(3000_u16))
+        .set_option(&dhcppkt::OPTION_REBINDTIME, &(5000_u32))
+        .set_lease_time(std::time::Duration::from_secs(4000))
+        .to_options();
+    assert_eq!(
+        options.get_option::<u32>(&dhcppkt::OPTION_LEASETIME),
+        Some(4000)
+    );
+    /* Renewal times within the lease are left alone, ones past the end are reset */
+    assert_eq!(
+        options.get_option::<u16>(&dhcppkt::OPTION_RENEWALTIME),
+        Some(3000)
+    );
+    assert_eq!(
+        options.get_option::<u32>(&dhcppkt::OPTION_REBINDTIME),
+        Some(3500)
+    );
+}
+
+#[test]
+fn forcerenew() {
+    let lease = pool::LeaseInfo {
+        ip: EXAMPLE_IP4,
+        /* A DUID (RFC4361), which doesn't contain the hardware address */
+        client_id: vec![
+            0xff, 0x00, 0x00, 0x00, 0x01, 0x00, 0x04, 0x12, 0x34, 0x56, 0x78,
+        ],
+        chaddr: vec![0x00, 0x00, 0x5E, 0x00, 0x53, 0x00],
+        start: 0,
+        expire: u32::MAX,
+        options: vec![],
+    };
+    let pkt = dhcp::build_forcerenew(&lease, SERVER_IP).expect("No DHCPFORCERENEW built");
+    assert_eq!(pkt.op, dhcppkt::OP_BOOTREPLY);
+    assert_eq!(pkt.ciaddr, EXAMPLE_IP4);
+    assert_eq!(pkt.hlen, 6);
+    assert_eq!(pkt.chaddr, vec![0x00, 0x00, 0x5E, 0x00, 0x53, 0x00]);
+    assert_eq!(pkt.options.get_messagetype(), Some(dhcppkt::DHCPFORCERENEW));
+    assert_eq!(pkt.options.get_serverid(), Some(SERVER_IP));
+
+    /* We can't address clients whose hardware address we never recorded */
+    let lease = pool::LeaseInfo {
+        chaddr: vec![],
+        ..lease
+    };
+    assert!(dhcp::build_forcerenew(&lease, SERVER_IP).is_none());
+}
+
+#[tokio::test]
+async fn forcerenew_nonce() {
+    let mut p = pool::Pool::new_in_memory().expect("Failed to create pool");
+    let mut serverids: dhcp::ServerIds = dhcp::ServerIds::new();
+    serverids.insert(SERVER_IP);
+    let mut cfg = mk_default_config();
+    cfg.dhcp.forcerenew_secret = Some(b"secret".to_vec());
+
+    /* Clients that don't say they can authenticate a DHCPFORCERENEW aren't given a nonce */
+    let mut pkt = mk_dhcp_request();
+    let reply = dhcp::handle_request(&mut p, &pkt, &serverids, &[], &cfg)
+        .expect("Failed to handle request");
+    assert_eq!(reply.options.get_messagetype(), Some(dhcppkt::DHCPACK));
+    assert!(reply
+        .options
+        .get_raw_option(&dhcppkt::OPTION_AUTHENTICATION)
+        .is_none());
+
+    /* Ones that do are */
+    pkt.pkt.options = pkt
+        .pkt
+        .options
+        .set_raw_option(&dhcppkt::OPTION_FORCERENEW_NONCE_CAPABLE, &[1]);
+    let reply = dhcp::handle_request(&mut p, &pkt, &serverids, &[], &cfg)
+        .expect("Failed to handle request");
+    let lease = p
+        .get_lease(reply.yiaddr)
+        .expect("Failed to get lease")
+        .expect("No lease stored");
+    assert_eq!(
+        reply
+            .options
+            .get_raw_option(&dhcppkt::OPTION_AUTHENTICATION)
+            .map(|auth| auth[12..].to_vec()),
+        Some(dhcp::forcerenew::build_nonce_auth(b"secret", &lease)[12..].to_vec())
+    );
+
+    /* Unless there's no secret to derive it from */
+    cfg.dhcp.forcerenew_secret = None;
+    let reply = dhcp::handle_request(&mut p, &pkt, &serverids, &[], &cfg)
+        .expect("Failed to handle request");
+    assert!(reply
+        .options
+        .get_raw_option(&dhcppkt::OPTION_AUTHENTICATION)
+        .is_none());
+}
+
+#[tokio::test]
+async fn failover_split_pool() {
+    let mut p = pool::Pool::new_in_memory().expect("Failed to create pool");
+    let conf = crate::config::load_config_from_string_for_test(
+        "
+dhcp-policies:
+  - match-subnet: 192.0.2.0/24
+    apply-range: {start: 192.0.2.10, end: 192.0.2.20}
+dhcp-failover:
+  role: secondary
+  mode: split
+  peer: 192.0.2.2
+  secret: hunter2
+",
+    )
+    .expect("Failed to parse test config");
+    let lockedconf = conf.read().await;
+    let serverids: dhcp::ServerIds = dhcp::ServerIds::new();
+
+    /* The secondary only offers new clients its odd half of the range */
+    for i in 0..5 {
+        let mut pkt = mk_dhcp_request();
+        pkt.pkt.chaddr = vec![0x00, 0x00, 0x5E, 0x00, 0x53, i];
+        let reply = dhcp::handle_discover(&mut p, &pkt, &serverids, &[], &lockedconf)
+            .expect("Failed to handle request");
+        assert_eq!(u32::from(reply.yiaddr) % 2, 1, "{}", reply.yiaddr);
+    }
+
+    /* But a client keeps an address it was given by the primary */
+    let mut pkt = mk_dhcp_request();
+    pkt.pkt.chaddr = vec![0x00, 0x00, 0x5E, 0x00, 0x53, 0x10];
+    p.restore_lease(pool::LeaseInfo {
+        ip: "192.0.2.12".parse().unwrap(),
+        client_id: pkt.pkt.get_client_id(),
+        chaddr: pkt.pkt.chaddr.clone(),
+        start: 0,
+        expire: u32::MAX,
+        options: vec![],
+    })
+    .expect("Failed to restore lease");
+    let reply = dhcp::handle_discover(&mut p, &pkt, &serverids, &[], &lockedconf)
+        .expect("Failed to handle request");
+    assert_eq!(reply.yiaddr, "192.0.2.12".parse::<net::Ipv4Addr>().unwrap());
+}
+
 /* TODO:
  * 4. The servers receive the DHCPREQUEST broadcast from the client.  Those servers not selected by
  *    the DHCPREQUEST message use the message as notification that the client has declined that
@@ -0,0 +1,396 @@
+/*   Copyright 2023 Perry Lorier
+ *
+ *  Licensed under the Apache License, Version 2.0 (the "License");
+ *  you may not use this file except in compliance with the License.
+ *  You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ *  Unless required by applicable law or agreed to in writing, software
+ *  distributed under the License is distributed on an "AS IS" BASIS,
+ *  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ *  See the License for the specific language governing permissions and
+ *  limitations under the License.
+ *
+ *  SPDX-License-Identifier: Apache-2.0
+ *
+ *  DHCPv6 Configuration parsing.
+ */
+use super::pool::{PoolAddresses, PrefixPool};
+use yaml_rust::yaml;
+
+pub use crate::config::*;
+
+#[derive(Debug, Default, Clone)]
+pub struct Policy {
+    pub match_all: bool,
+    pub match_interface: Option<String>,
+    pub match_duid: Option<Vec<u8>>,
+    pub apply_address: Option<PoolAddresses>,
+    pub apply_preferred_lifetime: Option<std::time::Duration>,
+    pub apply_valid_lifetime: Option<std::time::Duration>,
+    pub apply_delegated_prefixes: Option<PrefixPool>,
+    pub apply_dns_servers: Option<Vec<std::net::Ipv6Addr>>,
+    pub apply_dns_search: Option<Vec<String>>,
+    pub apply_ntp_servers: Option<Vec<std::net::Ipv6Addr>>,
+    pub policies: Vec<Policy>,
+}
+
+impl Policy {
+    fn get_all_used_addresses(&self) -> Vec<std::ops::RangeInclusive<std::net::Ipv6Addr>> {
+        let mut ranges = Vec::new();
+        if let Some(address) = &self.apply_address {
+            ranges.extend(address.ranges.iter().cloned());
+        }
+        for p in &self.policies {
+            ranges.extend(p.get_all_used_addresses());
+        }
+        ranges
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Config {
+    pub policies: Vec<Policy>,
+}
+
+impl Config {
+    /// Returns every address range that is explicitly handed out by some policy.
+    pub fn get_all_used_addresses(&self) -> Vec<std::ops::RangeInclusive<std::net::Ipv6Addr>> {
+        self.policies
+            .iter()
+            .flat_map(Policy::get_all_used_addresses)
+            .collect()
+    }
+
+    fn parse_range(v: &yaml::Yaml) -> Result<std::ops::RangeInclusive<std::net::Ipv6Addr>, Error> {
+        if let Some(range) = v.as_hash() {
+            let mut start = None;
+            let mut end = None;
+            for (rangek, rangev) in range {
+                match rangek.as_str() {
+                    Some("start") => {
+                        start = Some(
+                            parse_string_ip6("start", rangev)
+                                .map_err(|x| x.annotate("Failed to parse range start"))?
+                                .ok_or_else(|| {
+                                    Error::InvalidConfig("range start cannot be nil".into())
+                                })?,
+                        )
+                    }
+                    Some("end") => {
+                        end = Some(
+                            parse_string_ip6("end", rangev)
+                                .map_err(|x| x.annotate("Failed to parse range end"))?
+                                .ok_or_else(|| {
+                                    Error::InvalidConfig("range end cannot be nil".into())
+                                })?,
+                        )
+                    }
+                    Some(e) => {
+                        return Err(Error::InvalidConfig(format!(
+                            "Unexpected key in range: {}",
+                            e
+                        )))
+                    }
+                    None => {
+                        return Err(Error::InvalidConfig(format!(
+                            "range key is not a string, instead: '{:?}'",
+                            rangek
+                        )))
+                    }
+                }
+            }
+            let start =
+                start.ok_or_else(|| Error::InvalidConfig("Missing start in range".into()))?;
+            let end = end.ok_or_else(|| Error::InvalidConfig("Missing end in range".into()))?;
+            if start > end {
+                return Err(Error::InvalidConfig(format!(
+                    "Range start {} is after range end {}",
+                    start, end
+                )));
+            }
+            Ok(start..=end)
+        } else {
+            Err(Error::InvalidConfig(format!(
+                "Range should be a hash, not '{:?}'",
+                v
+            )))
+        }
+    }
+
+    fn parse_prefix_pool(v: &yaml::Yaml) -> Result<PrefixPool, Error> {
+        if let Some(h) = v.as_hash() {
+            let mut prefix = None;
+            let mut length = None;
+            for (k, v) in h {
+                match k.as_str() {
+                    Some("prefix") => {
+                        prefix =
+                            Some(parse_string_prefix6("prefix", v)?.ok_or_else(|| {
+                                Error::InvalidConfig("prefix cannot be nil".into())
+                            })?)
+                    }
+                    Some("length") => {
+                        length =
+                            Some(parse_num::<i64>("length", v)?.ok_or_else(|| {
+                                Error::InvalidConfig("length cannot be nil".into())
+                            })?)
+                    }
+                    Some(e) => {
+                        return Err(Error::InvalidConfig(format!(
+                            "Unexpected key in delegated prefixes: {}",
+                            e
+                        )))
+                    }
+                    None => {
+                        return Err(Error::InvalidConfig(format!(
+                            "delegated prefixes key is not a string, instead: '{:?}'",
+                            k
+                        )))
+                    }
+                }
+            }
+            let prefix = prefix.ok_or_else(|| {
+                Error::InvalidConfig("Missing prefix in delegated prefixes".into())
+            })?;
+            let length = length.ok_or_else(|| {
+                Error::InvalidConfig("Missing length in delegated prefixes".into())
+            })?;
+            if length < i64::from(prefix.prefixlen) || length > 128 {
+                return Err(Error::InvalidConfig(format!(
+                    "Cannot delegate /{} prefixes from {}/{}",
+                    length, prefix.addr, prefix.prefixlen
+                )));
+            }
+            Ok(PrefixPool {
+                prefix,
+                delegated_length: length as u8,
+            })
+        } else {
+            Err(Error::InvalidConfig(format!(
+                "Delegated prefixes should be a hash, not '{:?}'",
+                v
+            )))
+        }
+    }
+
+    fn parse_policy(fragment: &yaml::Yaml) -> Result<Policy, Error> {
+        if let Some(h) = fragment.as_hash() {
+            let mut policy: Policy = Default::default();
+            let mut addresses: Option<Vec<std::ops::RangeInclusive<std::net::Ipv6Addr>>> = None;
+            for (k, v) in h {
+                match k.as_str() {
+                    Some("match-interface") => {
+                        policy.match_interface = Some(
+                            parse_string("match-interface", v)
+                                .map_err(|x| x.annotate("Failed to parse match-interface"))?
+                                .ok_or_else(|| {
+                                    Error::InvalidConfig("match-interface cannot be nil".into())
+                                })?,
+                        );
+                    }
+                    Some("match-duid") => {
+                        if policy.match_duid.is_some() {
+                            return Err(Error::InvalidConfig("match-duid specified twice".into()));
+                        }
+                        policy.match_duid = Some(
+                            parse_string_hwaddr("match-duid", v)
+                                .map_err(|x| x.annotate("Failed to parse match-duid"))?
+                                .ok_or_else(|| {
+                                    Error::InvalidConfig("match-duid cannot be nil".into())
+                                })?,
+                        );
+                    }
+                    Some("apply-address") => {
+                        let addr = parse_string_ip6("apply-address", v)
+                            .map_err(|x| x.annotate("Failed to parse apply-address"))?
+                            .ok_or_else(|| {
+                                Error::InvalidConfig("apply-address cannot be nil".into())
+                            })?;
+                        addresses.get_or_insert_with(Vec::new).push(addr..=addr);
+                    }
+                    Some("apply-range") => {
+                        let range = Config::parse_range(v)
+                            .map_err(|x| x.annotate("Failed to parse apply-range"))?;
+                        addresses.get_or_insert_with(Vec::new).push(range);
+                    }
+                    Some("apply-subnet") => {
+                        let subnet = parse_string_prefix6("apply-subnet", v)
+                            .map_err(|x| x.annotate("Failed to parse apply-subnet"))?
+                            .ok_or_else(|| {
+                                Error::InvalidConfig("apply-subnet cannot be nil".into())
+                            })?;
+                        addresses
+                            .get_or_insert_with(Vec::new)
+                            .push(PoolAddresses::subnet_range(&subnet));
+                    }
+                    Some("apply-preferred-lifetime") => {
+                        policy.apply_preferred_lifetime = Some(
+                            parse_duration("apply-preferred-lifetime", v)
+                                .map_err(|x| {
+                                    x.annotate("Failed to parse apply-preferred-lifetime")
+                                })?
+                                .ok_or_else(|| {
+                                    Error::InvalidConfig(
+                                        "apply-preferred-lifetime cannot be nil".into(),
+                                    )
+                                })?,
+                        );
+                    }
+                    Some("apply-valid-lifetime") => {
+                        policy.apply_valid_lifetime = Some(
+                            parse_duration("apply-valid-lifetime", v)
+                                .map_err(|x| x.annotate("Failed to parse apply-valid-lifetime"))?
+                                .ok_or_else(|| {
+                                    Error::InvalidConfig(
+                                        "apply-valid-lifetime cannot be nil".into(),
+                                    )
+                                })?,
+                        );
+                    }
+                    Some("apply-delegated-prefixes") => {
+                        policy.apply_delegated_prefixes =
+                            Some(Config::parse_prefix_pool(v).map_err(|x| {
+                                x.annotate("Failed to parse apply-delegated-prefixes")
+                            })?);
+                    }
+                    Some("apply-dns-servers") => {
+                        policy.apply_dns_servers = Some(
+                            parse_array("apply-dns-servers", v, parse_string_ip6)?
+                                .unwrap_or_default(),
+                        );
+                    }
+                    Some("apply-dns-search") => {
+                        policy.apply_dns_search = Some(
+                            parse_array("apply-dns-search", v, parse_string)?.unwrap_or_default(),
+                        );
+                    }
+                    Some("apply-ntp-servers") => {
+                        policy.apply_ntp_servers = Some(
+                            parse_array("apply-ntp-servers", v, parse_string_ip6)?
+                                .unwrap_or_default(),
+                        );
+                    }
+                    Some("policies") => {
+                        if !policy.policies.is_empty() {
+                            return Err(Error::InvalidConfig(
+                                "Can't specify policies twice in one policy".into(),
+                            ));
+                        }
+                        policy.policies = Config::parse_policies(v)?;
+                    }
+                    Some(x) => {
+                        return Err(Error::InvalidConfig(format!(
+                            "Policy contains unknown field '{}'",
+                            x
+                        )))
+                    }
+                    None => return Err(Error::InvalidConfig("Policy is not hash".into())),
+                }
+            }
+            /* If this Policy overrides addresses, then exclude any addresses that are reserved for
+             * sub policies */
+            if let Some(ranges) = addresses {
+                policy.apply_address = Some(PoolAddresses {
+                    ranges,
+                    excluded: policy
+                        .policies
+                        .iter()
+                        .flat_map(Policy::get_all_used_addresses)
+                        .collect(),
+                });
+            }
+            Ok(policy)
+        } else {
+            Err(Error::InvalidConfig("Policy should be a hash".into()))
+        }
+    }
+
+    fn parse_policies(fragment: &yaml::Yaml) -> Result<Vec<Policy>, Error> {
+        if let Some(l) = fragment.as_vec() {
+            let mut policies = Vec::new();
+            for i in l {
+                policies.push(Config::parse_policy(i)?);
+            }
+            Ok(policies)
+        } else {
+            Err(Error::InvalidConfig(
+                "policies should be a list of policies".into(),
+            ))
+        }
+    }
+
+    pub fn new(y: &yaml::Yaml) -> Result<Option<Self>, Error> {
+        Ok(Some(Config {
+            policies: Config::parse_policies(y)?,
+        }))
+    }
+}
+
+#[test]
+fn test_parse_policies() {
+    let cfg = crate::config::load_config_from_string_for_test(
+        "---
+dhcp6-policies:
+  - match-interface: eth0
+    apply-range: {start: 2001:db8::100, end: 2001:db8::1ff}
+    apply-preferred-lifetime: 1h
+    apply-ntp-servers: [2001:db8::123]
+    apply-dns-search: ~
+    policies:
+      - match-duid: 00:03:00:01:00:00:5e:00:53:01
+        apply-address: 2001:db8::180
+",
+    )
+    .expect("Failed to parse config");
+    let lockedcfg = cfg.try_read().unwrap();
+    let policy = &lockedcfg.dhcp6.policies[0];
+    assert_eq!(policy.match_interface, Some("eth0".into()));
+    assert_eq!(
+        policy.apply_preferred_lifetime,
+        Some(std::time::Duration::from_secs(3600))
+    );
+    let addresses = policy.apply_address.as_ref().unwrap();
+    assert!(addresses.contains("2001:db8::100".parse().unwrap()));
+    assert!(addresses.contains("2001:db8::1ff".parse().unwrap()));
+    assert!(!addresses.contains("2001:db8::200".parse().unwrap()));
+    /* Reserved for the sub policy */
+    assert!(!addresses.contains("2001:db8::180".parse().unwrap()));
+    assert_eq!(
+        policy.apply_ntp_servers,
+        Some(vec!["2001:db8::123".parse().unwrap()])
+    );
+    assert_eq!(policy.apply_dns_search, Some(vec![]));
+    assert_eq!(
+        policy.policies[0].match_duid,
+        Some(vec![0, 3, 0, 1, 0, 0, 0x5e, 0, 0x53, 1])
+    );
+}
+
+#[test]
+fn test_parse_delegated_prefixes() {
+    let cfg = crate::config::load_config_from_string_for_test(
+        "---
+dhcp6-policies:
+  - match-interface: eth0
+    apply-delegated-prefixes: {prefix: 2001:db8:100::/56, length: 60}
+",
+    )
+    .expect("Failed to parse config");
+    let lockedcfg = cfg.try_read().unwrap();
+    assert_eq!(
+        lockedcfg.dhcp6.policies[0].apply_delegated_prefixes,
+        Some(PrefixPool {
+            prefix: Prefix6::new("2001:db8:100::".parse().unwrap(), 56),
+            delegated_length: 60,
+        })
+    );
+    assert!(crate::config::load_config_from_string_for_test(
+        "---
+dhcp6-policies:
+  - apply-delegated-prefixes: {prefix: 2001:db8:100::/56, length: 48}
+",
+    )
+    .is_err());
+}
@@ -0,0 +1,802 @@
+/*   Copyright 2023 Perry Lorier
+ *
+ *  Licensed under the Apache License, Version 2.0 (the "License");
+ *  you may not use this file except in compliance with the License.
+ *  You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ *  Unless required by applicable law or agreed to in writing, software
+ *  distributed under the License is distributed on an "AS IS" BASIS,
+ *  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ *  See the License for the specific language governing permissions and
+ *  limitations under the License.
+ *
+ *  SPDX-License-Identifier: Apache-2.0
+ *
+ *  Parsing/Serialisation for a DHCPv6 Packet.
+ */
+
+use crate::pktparser;
+use std::fmt;
+use std::net;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ParseError {
+    UnexpectedEndOfInput,
+    InvalidPacket,
+}
+
+impl std::error::Error for ParseError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        None
+    }
+}
+
+impl std::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ParseError::UnexpectedEndOfInput => write!(f, "Unexpected End Of Input"),
+            ParseError::InvalidPacket => write!(f, "Invalid Packet"),
+        }
+    }
+}
+
+impl ParseError {
+    pub const fn get_variant_name(&self) -> &'static str {
+        use ParseError::*;
+        match self {
+            UnexpectedEndOfInput => "TRUNCATED_PACKET",
+            InvalidPacket => "INVALID_PACKET",
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub struct MessageType(u8);
+pub const SOLICIT: MessageType = MessageType(1);
+pub const ADVERTISE: MessageType = MessageType(2);
+pub const REQUEST: MessageType = MessageType(3);
+pub const CONFIRM: MessageType = MessageType(4);
+pub const RENEW: MessageType = MessageType(5);
+pub const REBIND: MessageType = MessageType(6);
+pub const REPLY: MessageType = MessageType(7);
+pub const RELEASE: MessageType = MessageType(8);
+pub const DECLINE: MessageType = MessageType(9);
+pub const RECONFIGURE: MessageType = MessageType(10);
+pub const INFORMATION_REQUEST: MessageType = MessageType(11);
+pub const RELAY_FORW: MessageType = MessageType(12);
+pub const RELAY_REPL: MessageType = MessageType(13);
+
+impl fmt::Display for MessageType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &SOLICIT => write!(f, "SOLICIT"),
+            &ADVERTISE => write!(f, "ADVERTISE"),
+            &REQUEST => write!(f, "REQUEST"),
+            &CONFIRM => write!(f, "CONFIRM"),
+            &RENEW => write!(f, "RENEW"),
+            &REBIND => write!(f, "REBIND"),
+            &REPLY => write!(f, "REPLY"),
+            &RELEASE => write!(f, "RELEASE"),
+            &DECLINE => write!(f, "DECLINE"),
+            &RECONFIGURE => write!(f, "RECONFIGURE"),
+            &INFORMATION_REQUEST => write!(f, "INFORMATION-REQUEST"),
+            &RELAY_FORW => write!(f, "RELAY-FORW"),
+            &RELAY_REPL => write!(f, "RELAY-REPL"),
+            MessageType(x) => write!(f, "#{}", x),
+        }
+    }
+}
+
+impl fmt::Debug for MessageType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self)
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Dhcp6Option(u16);
+pub const OPTION_CLIENTID: Dhcp6Option = Dhcp6Option(1);
+pub const OPTION_SERVERID: Dhcp6Option = Dhcp6Option(2);
+pub const OPTION_IA_NA: Dhcp6Option = Dhcp6Option(3);
+pub const OPTION_IA_TA: Dhcp6Option = Dhcp6Option(4);
+pub const OPTION_IAADDR: Dhcp6Option = Dhcp6Option(5);
+pub const OPTION_ORO: Dhcp6Option = Dhcp6Option(6);
+pub const OPTION_PREFERENCE: Dhcp6Option = Dhcp6Option(7);
+pub const OPTION_ELAPSED_TIME: Dhcp6Option = Dhcp6Option(8);
+pub const OPTION_RELAY_MSG: Dhcp6Option = Dhcp6Option(9);
+pub const OPTION_AUTH: Dhcp6Option = Dhcp6Option(11);
+pub const OPTION_UNICAST: Dhcp6Option = Dhcp6Option(12);
+pub const OPTION_STATUS_CODE: Dhcp6Option = Dhcp6Option(13);
+pub const OPTION_RAPID_COMMIT: Dhcp6Option = Dhcp6Option(14);
+pub const OPTION_USER_CLASS: Dhcp6Option = Dhcp6Option(15);
+pub const OPTION_VENDOR_CLASS: Dhcp6Option = Dhcp6Option(16);
+pub const OPTION_VENDOR_OPTS: Dhcp6Option = Dhcp6Option(17);
+pub const OPTION_INTERFACE_ID: Dhcp6Option = Dhcp6Option(18);
+pub const OPTION_RECONF_MSG: Dhcp6Option = Dhcp6Option(19);
+pub const OPTION_RECONF_ACCEPT: Dhcp6Option = Dhcp6Option(20);
+pub const OPTION_DNS_SERVERS: Dhcp6Option = Dhcp6Option(23); /* RFC3646 */
+pub const OPTION_DOMAIN_LIST: Dhcp6Option = Dhcp6Option(24); /* RFC3646 */
+pub const OPTION_IA_PD: Dhcp6Option = Dhcp6Option(25); /* RFC8415 */
+pub const OPTION_IAPREFIX: Dhcp6Option = Dhcp6Option(26); /* RFC8415 */
+pub const OPTION_SNTP_SERVERS: Dhcp6Option = Dhcp6Option(31); /* RFC4075 */
+pub const OPTION_NTP_SERVER: Dhcp6Option = Dhcp6Option(56); /* RFC5908 */
+
+impl Dhcp6Option {
+    pub const fn new(option: u16) -> Self {
+        Self(option)
+    }
+}
+
+impl From<Dhcp6Option> for u16 {
+    fn from(option: Dhcp6Option) -> Self {
+        option.0
+    }
+}
+
+impl fmt::Display for Dhcp6Option {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &OPTION_CLIENTID => write!(f, "client-id"),
+            &OPTION_SERVERID => write!(f, "server-id"),
+            &OPTION_IA_NA => write!(f, "ia-na"),
+            &OPTION_IA_TA => write!(f, "ia-ta"),
+            &OPTION_IAADDR => write!(f, "iaaddr"),
+            &OPTION_ORO => write!(f, "option-request"),
+            &OPTION_PREFERENCE => write!(f, "preference"),
+            &OPTION_ELAPSED_TIME => write!(f, "elapsed-time"),
+            &OPTION_RELAY_MSG => write!(f, "relay-message"),
+            &OPTION_AUTH => write!(f, "authentication"),
+            &OPTION_UNICAST => write!(f, "server-unicast"),
+            &OPTION_STATUS_CODE => write!(f, "status-code"),
+            &OPTION_RAPID_COMMIT => write!(f, "rapid-commit"),
+            &OPTION_USER_CLASS => write!(f, "user-class"),
+            &OPTION_VENDOR_CLASS => write!(f, "vendor-class"),
+            &OPTION_VENDOR_OPTS => write!(f, "vendor-options"),
+            &OPTION_INTERFACE_ID => write!(f, "interface-id"),
+            &OPTION_RECONF_MSG => write!(f, "reconfigure-message"),
+            &OPTION_RECONF_ACCEPT => write!(f, "reconfigure-accept"),
+            &OPTION_DNS_SERVERS => write!(f, "dns-servers"),
+            &OPTION_DOMAIN_LIST => write!(f, "domain-list"),
+            &OPTION_IA_PD => write!(f, "ia-pd"),
+            &OPTION_IAPREFIX => write!(f, "iaprefix"),
+            &OPTION_SNTP_SERVERS => write!(f, "sntp-servers"),
+            &OPTION_NTP_SERVER => write!(f, "ntp-server"),
+            Dhcp6Option(x) => write!(f, "#{}", x),
+        }
+    }
+}
+
+impl fmt::Debug for Dhcp6Option {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self)
+    }
+}
+
+/* RFC8415 Section 21.13 */
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub struct StatusCode(u16);
+pub const STATUS_SUCCESS: StatusCode = StatusCode(0);
+pub const STATUS_UNSPEC_FAIL: StatusCode = StatusCode(1);
+pub const STATUS_NO_ADDRS_AVAIL: StatusCode = StatusCode(2);
+pub const STATUS_NO_BINDING: StatusCode = StatusCode(3);
+pub const STATUS_NOT_ON_LINK: StatusCode = StatusCode(4);
+pub const STATUS_USE_MULTICAST: StatusCode = StatusCode(5);
+pub const STATUS_NO_PREFIX_AVAIL: StatusCode = StatusCode(6);
+
+impl fmt::Display for StatusCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &STATUS_SUCCESS => write!(f, "Success"),
+            &STATUS_UNSPEC_FAIL => write!(f, "UnspecFail"),
+            &STATUS_NO_ADDRS_AVAIL => write!(f, "NoAddrsAvail"),
+            &STATUS_NO_BINDING => write!(f, "NoBinding"),
+            &STATUS_NOT_ON_LINK => write!(f, "NotOnLink"),
+            &STATUS_USE_MULTICAST => write!(f, "UseMulticast"),
+            &STATUS_NO_PREFIX_AVAIL => write!(f, "NoPrefixAvail"),
+            StatusCode(x) => write!(f, "#{}", x),
+        }
+    }
+}
+
+impl fmt::Debug for StatusCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self)
+    }
+}
+
+/// The options in a DHCPv6 message, or encapsulated within another option.
+///
+/// Unlike DHCPv4, options may legitimately be repeated (eg, multiple IA_NA's), so the order and
+/// repeats are preserved.
+#[derive(Debug, Clone, PartialEq, Default, Eq)]
+pub struct Dhcp6Options {
+    pub other: Vec<(Dhcp6Option, Vec<u8>)>,
+}
+
+impl Dhcp6Options {
+    pub fn get_raw_option(&self, option: &Dhcp6Option) -> Option<&[u8]> {
+        self.other
+            .iter()
+            .find(|(o, _)| o == option)
+            .map(|(_, v)| v.as_slice())
+    }
+
+    pub fn get_raw_options<'a>(
+        &'a self,
+        option: &'a Dhcp6Option,
+    ) -> impl Iterator<Item = &'a [u8]> + 'a {
+        self.other
+            .iter()
+            .filter(move |(o, _)| o == option)
+            .map(|(_, v)| v.as_slice())
+    }
+
+    pub fn get_option<T: Dhcp6Parse>(&self, option: &Dhcp6Option) -> Option<T> {
+        self.get_raw_option(option).and_then(T::parse_into)
+    }
+
+    pub fn get_options<'a, T: Dhcp6Parse + 'a>(
+        &'a self,
+        option: &'a Dhcp6Option,
+    ) -> impl Iterator<Item = T> + 'a {
+        self.get_raw_options(option).filter_map(T::parse_into)
+    }
+
+    pub fn get_clientid(&self) -> Option<Vec<u8>> {
+        self.get_option::<Vec<u8>>(&OPTION_CLIENTID)
+    }
+
+    pub fn get_serverid(&self) -> Option<Vec<u8>> {
+        self.get_option::<Vec<u8>>(&OPTION_SERVERID)
+    }
+
+    /// Returns the list of options the client asked for in the Option Request Option.
+    pub fn get_option_request(&self) -> Vec<Dhcp6Option> {
+        self.get_option::<Vec<u16>>(&OPTION_ORO)
+            .unwrap_or_default()
+            .into_iter()
+            .map(Dhcp6Option)
+            .collect()
+    }
+
+    #[must_use]
+    pub fn add_raw_option(mut self, option: &Dhcp6Option, value: &[u8]) -> Self {
+        self.other.push((*option, value.to_vec()));
+        self
+    }
+
+    #[must_use]
+    pub fn add_option<T: Serialise>(self, option: &Dhcp6Option, value: &T) -> Self {
+        let mut v = Vec::new();
+        value.serialise(&mut v);
+        self.add_raw_option(option, &v)
+    }
+
+    #[must_use]
+    pub fn maybe_add_option<T: Serialise>(self, option: &Dhcp6Option, value: Option<&T>) -> Self {
+        if let Some(v) = value {
+            self.add_option(option, v)
+        } else {
+            self
+        }
+    }
+
+    #[must_use]
+    pub fn remove_option(mut self, option: &Dhcp6Option) -> Self {
+        self.other.retain(|(o, _)| o != option);
+        self
+    }
+}
+
+pub trait Dhcp6Parse {
+    fn parse_into(v: &[u8]) -> Option<Self>
+    where
+        Self: Sized;
+}
+
+impl Dhcp6Parse for Vec<u8> {
+    fn parse_into(v: &[u8]) -> Option<Self> {
+        Some(v.to_vec())
+    }
+}
+
+impl Dhcp6Parse for Vec<u16> {
+    fn parse_into(v: &[u8]) -> Option<Self> {
+        let chunks = v.chunks_exact(2);
+        if !chunks.remainder().is_empty() {
+            return None;
+        }
+        Some(chunks.map(|c| u16::from_be_bytes([c[0], c[1]])).collect())
+    }
+}
+
+impl Dhcp6Parse for Vec<net::Ipv6Addr> {
+    fn parse_into(v: &[u8]) -> Option<Self> {
+        let chunks = v.chunks_exact(16);
+        if !chunks.remainder().is_empty() {
+            return None;
+        }
+        Some(
+            chunks
+                .map(|c| <[u8; 16]>::try_from(c).unwrap().into())
+                .collect(),
+        )
+    }
+}
+
+impl Dhcp6Parse for u16 {
+    fn parse_into(v: &[u8]) -> Option<Self> {
+        Some(u16::from_be_bytes(v.try_into().ok()?))
+    }
+}
+
+impl Dhcp6Parse for u32 {
+    fn parse_into(v: &[u8]) -> Option<Self> {
+        Some(u32::from_be_bytes(v.try_into().ok()?))
+    }
+}
+
+/// Identity Association for Non-temporary Addresses (RFC8415 Section 21.4)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IaNa {
+    pub iaid: u32,
+    pub t1: u32,
+    pub t2: u32,
+    pub options: Dhcp6Options,
+}
+
+impl IaNa {
+    pub fn get_addresses(&self) -> impl Iterator<Item = IaAddr> + '_ {
+        self.options.get_options::<IaAddr>(&OPTION_IAADDR)
+    }
+}
+
+impl Dhcp6Parse for IaNa {
+    fn parse_into(v: &[u8]) -> Option<Self> {
+        let mut buf = pktparser::Buffer::new(v);
+        Some(IaNa {
+            iaid: buf.get_be32()?,
+            t1: buf.get_be32()?,
+            t2: buf.get_be32()?,
+            options: parse_options(buf).ok()?,
+        })
+    }
+}
+
+impl Serialise for IaNa {
+    fn serialise(&self, v: &mut Vec<u8>) {
+        self.iaid.serialise(v);
+        self.t1.serialise(v);
+        self.t2.serialise(v);
+        self.options.serialise(v);
+    }
+}
+
+/// IA Address (RFC8415 Section 21.6)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IaAddr {
+    pub addr: net::Ipv6Addr,
+    pub preferred: u32,
+    pub valid: u32,
+    pub options: Dhcp6Options,
+}
+
+impl Dhcp6Parse for IaAddr {
+    fn parse_into(v: &[u8]) -> Option<Self> {
+        let mut buf = pktparser::Buffer::new(v);
+        let addr: [u8; 16] = buf.get_bytes(16)?.try_into().ok()?;
+        Some(IaAddr {
+            addr: addr.into(),
+            preferred: buf.get_be32()?,
+            valid: buf.get_be32()?,
+            options: parse_options(buf).ok()?,
+        })
+    }
+}
+
+impl Serialise for IaAddr {
+    fn serialise(&self, v: &mut Vec<u8>) {
+        self.addr.serialise(v);
+        self.preferred.serialise(v);
+        self.valid.serialise(v);
+        self.options.serialise(v);
+    }
+}
+
+/// Identity Association for Prefix Delegation (RFC8415 Section 21.21)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IaPd {
+    pub iaid: u32,
+    pub t1: u32,
+    pub t2: u32,
+    pub options: Dhcp6Options,
+}
+
+impl IaPd {
+    pub fn get_prefixes(&self) -> impl Iterator<Item = IaPrefix> + '_ {
+        self.options.get_options::<IaPrefix>(&OPTION_IAPREFIX)
+    }
+}
+
+impl Dhcp6Parse for IaPd {
+    fn parse_into(v: &[u8]) -> Option<Self> {
+        let mut buf = pktparser::Buffer::new(v);
+        Some(IaPd {
+            iaid: buf.get_be32()?,
+            t1: buf.get_be32()?,
+            t2: buf.get_be32()?,
+            options: parse_options(buf).ok()?,
+        })
+    }
+}
+
+impl Serialise for IaPd {
+    fn serialise(&self, v: &mut Vec<u8>) {
+        self.iaid.serialise(v);
+        self.t1.serialise(v);
+        self.t2.serialise(v);
+        self.options.serialise(v);
+    }
+}
+
+/// IA Prefix (RFC8415 Section 21.22)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IaPrefix {
+    pub preferred: u32,
+    pub valid: u32,
+    pub prefixlen: u8,
+    pub prefix: net::Ipv6Addr,
+    pub options: Dhcp6Options,
+}
+
+impl Dhcp6Parse for IaPrefix {
+    fn parse_into(v: &[u8]) -> Option<Self> {
+        let mut buf = pktparser::Buffer::new(v);
+        let preferred = buf.get_be32()?;
+        let valid = buf.get_be32()?;
+        let prefixlen = buf.get_u8()?;
+        let prefix: [u8; 16] = buf.get_bytes(16)?.try_into().ok()?;
+        Some(IaPrefix {
+            preferred,
+            valid,
+            prefixlen,
+            prefix: prefix.into(),
+            options: parse_options(buf).ok()?,
+        })
+    }
+}
+
+impl Serialise for IaPrefix {
+    fn serialise(&self, v: &mut Vec<u8>) {
+        self.preferred.serialise(v);
+        self.valid.serialise(v);
+        self.prefixlen.serialise(v);
+        self.prefix.serialise(v);
+        self.options.serialise(v);
+    }
+}
+
+/// Status Code (RFC8415 Section 21.13)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Status {
+    pub code: StatusCode,
+    pub message: String,
+}
+
+impl Status {
+    pub fn new(code: StatusCode, message: &str) -> Self {
+        Self {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+impl Dhcp6Parse for Status {
+    fn parse_into(v: &[u8]) -> Option<Self> {
+        let mut buf = pktparser::Buffer::new(v);
+        Some(Status {
+            code: StatusCode(buf.get_be16()?),
+            message: String::from_utf8_lossy(buf.get_bytes(buf.remaining())?).into(),
+        })
+    }
+}
+
+impl Serialise for Status {
+    fn serialise(&self, v: &mut Vec<u8>) {
+        self.code.0.serialise(v);
+        v.extend(self.message.as_bytes());
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct Dhcp6 {
+    pub msgtype: MessageType,
+    /// Only the bottom 24 bits are used.
+    pub xid: u32,
+    pub options: Dhcp6Options,
+}
+
+/// A list of domain names, uncompressed (RFC8415 Section 10).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DomainList(pub Vec<String>);
+
+impl Dhcp6Parse for DomainList {
+    fn parse_into(v: &[u8]) -> Option<Self> {
+        let mut buf = pktparser::Buffer::new(v);
+        let mut domains = vec![];
+        let mut labels = vec![];
+        while !buf.empty() {
+            match buf.get_u8()? {
+                0 => domains.push(std::mem::take(&mut labels).join(".")),
+                len => labels.push(String::from_utf8(buf.get_bytes(len.into())?.to_vec()).ok()?),
+            }
+        }
+        /* The last domain must be terminated */
+        if !labels.is_empty() {
+            return None;
+        }
+        Some(DomainList(domains))
+    }
+}
+
+impl Serialise for DomainList {
+    fn serialise(&self, v: &mut Vec<u8>) {
+        for domain in &self.0 {
+            for label in domain.split('.').filter(|l| !l.is_empty()) {
+                (label.len() as u8).serialise(v);
+                v.extend(label.as_bytes());
+            }
+            0u8.serialise(v);
+        }
+    }
+}
+
+/* RFC5908 Section 4 */
+const NTP_SUBOPTION_SRV_ADDR: u16 = 1;
+
+/// The NTP Server option, only supporting server addresses (RFC5908).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NtpServers(pub Vec<net::Ipv6Addr>);
+
+impl Dhcp6Parse for NtpServers {
+    fn parse_into(v: &[u8]) -> Option<Self> {
+        let mut buf = pktparser::Buffer::new(v);
+        let mut servers = vec![];
+        while !buf.empty() {
+            let suboption = buf.get_be16()?;
+            let len = buf.get_be16()?;
+            let data = buf.get_bytes(len.into())?;
+            if suboption == NTP_SUBOPTION_SRV_ADDR {
+                servers.push(<[u8; 16]>::try_from(data).ok()?.into());
+            }
+        }
+        Some(NtpServers(servers))
+    }
+}
+
+impl Serialise for NtpServers {
+    fn serialise(&self, v: &mut Vec<u8>) {
+        for server in &self.0 {
+            NTP_SUBOPTION_SRV_ADDR.serialise(v);
+            16u16.serialise(v);
+            server.serialise(v);
+        }
+    }
+}
+
+pub fn parse_options(mut buf: pktparser::Buffer) -> Result<Dhcp6Options, ParseError> {
+    let mut options = Dhcp6Options::default();
+    while !buf.empty() {
+        let option = buf.get_be16().ok_or(ParseError::UnexpectedEndOfInput)?;
+        let len = buf.get_be16().ok_or(ParseError::UnexpectedEndOfInput)?;
+        let value = buf
+            .get_bytes(len as usize)
+            .ok_or(ParseError::UnexpectedEndOfInput)?;
+        options.other.push((Dhcp6Option(option), value.to_vec()));
+    }
+    Ok(options)
+}
+
+pub fn parse(pkt: &[u8]) -> Result<Dhcp6, ParseError> {
+    let mut buf = pktparser::Buffer::new(pkt);
+    let msgtype = buf.get_u8().ok_or(ParseError::UnexpectedEndOfInput)?;
+    if msgtype == RELAY_FORW.0 || msgtype == RELAY_REPL.0 {
+        /* Relay messages have an entirely different format */
+        return Err(ParseError::InvalidPacket);
+    }
+    let xid = buf.get_bytes(3).ok_or(ParseError::UnexpectedEndOfInput)?;
+    let options = parse_options(buf)?;
+    Ok(Dhcp6 {
+        msgtype: MessageType(msgtype),
+        xid: u32::from_be_bytes([0, xid[0], xid[1], xid[2]]),
+        options,
+    })
+}
+
+pub trait Serialise {
+    fn serialise(&self, v: &mut Vec<u8>);
+}
+
+impl Serialise for u8 {
+    fn serialise(&self, v: &mut Vec<u8>) {
+        v.push(*self);
+    }
+}
+
+impl Serialise for u16 {
+    fn serialise(&self, v: &mut Vec<u8>) {
+        v.extend(self.to_be_bytes().iter());
+    }
+}
+
+impl Serialise for u32 {
+    fn serialise(&self, v: &mut Vec<u8>) {
+        v.extend(self.to_be_bytes().iter());
+    }
+}
+
+impl Serialise for net::Ipv6Addr {
+    fn serialise(&self, v: &mut Vec<u8>) {
+        v.extend(self.octets().iter());
+    }
+}
+
+impl Serialise for &[u8] {
+    fn serialise(&self, v: &mut Vec<u8>) {
+        v.extend(*self);
+    }
+}
+
+impl<T: Serialise> Serialise for Vec<T> {
+    fn serialise(&self, v: &mut Vec<u8>) {
+        for i in self {
+            i.serialise(v);
+        }
+    }
+}
+
+impl Serialise for Dhcp6Options {
+    fn serialise(&self, v: &mut Vec<u8>) {
+        for (o, p) in &self.other {
+            o.0.serialise(v);
+            (p.len() as u16).serialise(v);
+            v.extend(p);
+        }
+    }
+}
+
+impl Dhcp6 {
+    pub fn serialise(&self) -> Vec<u8> {
+        let mut v: Vec<u8> = Vec::new();
+        self.msgtype.0.serialise(&mut v);
+        v.extend(&self.xid.to_be_bytes()[1..]);
+        self.options.serialise(&mut v);
+        v
+    }
+}
+
+#[test]
+fn test_parse_inverse_serialise() {
+    let pkt = Dhcp6 {
+        msgtype: SOLICIT,
+        xid: 0x123456,
+        options: Dhcp6Options::default()
+            .add_option(
+                &OPTION_CLIENTID,
+                &vec![0u8, 3, 0, 1, 0, 0, 0x5e, 0, 0x53, 0],
+            )
+            .add_option(&OPTION_ELAPSED_TIME, &0u16)
+            .add_option(
+                &OPTION_IA_NA,
+                &IaNa {
+                    iaid: 1,
+                    t1: 0,
+                    t2: 0,
+                    options: Dhcp6Options::default(),
+                },
+            )
+            .add_option(
+                &OPTION_IA_NA,
+                &IaNa {
+                    iaid: 2,
+                    t1: 3600,
+                    t2: 5400,
+                    options: Dhcp6Options::default().add_option(
+                        &OPTION_IAADDR,
+                        &IaAddr {
+                            addr: "2001:db8::1".parse().unwrap(),
+                            preferred: 3600,
+                            valid: 7200,
+                            options: Dhcp6Options::default(),
+                        },
+                    ),
+                },
+            ),
+    };
+    let bytes = pkt.serialise();
+    let new_pkt = parse(&bytes).expect("Failed to parse DHCPv6 packet");
+    assert_eq!(pkt, new_pkt);
+    let ias = new_pkt
+        .options
+        .get_options::<IaNa>(&OPTION_IA_NA)
+        .collect::<Vec<_>>();
+    assert_eq!(ias.len(), 2);
+    assert_eq!(
+        ias[1].get_addresses().map(|a| a.addr).collect::<Vec<_>>(),
+        vec!["2001:db8::1".parse::<net::Ipv6Addr>().unwrap()]
+    );
+}
+
+#[test]
+fn test_ia_pd() {
+    let ia = IaPd {
+        iaid: 7,
+        t1: 100,
+        t2: 200,
+        options: Dhcp6Options::default().add_option(
+            &OPTION_IAPREFIX,
+            &IaPrefix {
+                preferred: 300,
+                valid: 400,
+                prefixlen: 60,
+                prefix: "2001:db8:0:10::".parse().unwrap(),
+                options: Dhcp6Options::default(),
+            },
+        ),
+    };
+    let mut v = vec![];
+    ia.serialise(&mut v);
+    /* iaid + t1 + t2 + option header + IAPREFIX */
+    assert_eq!(v.len(), 12 + 4 + 25);
+    let parsed = IaPd::parse_into(&v).expect("Failed to parse IA_PD");
+    assert_eq!(parsed, ia);
+    assert_eq!(parsed.get_prefixes().next().unwrap().prefixlen, 60);
+}
+
+#[test]
+fn test_config_options() {
+    let options = Dhcp6Options::default()
+        .add_option(
+            &OPTION_DOMAIN_LIST,
+            &DomainList(vec!["example.com".into(), "example.org.".into()]),
+        )
+        .add_option(
+            &OPTION_NTP_SERVER,
+            &NtpServers(vec!["2001:db8::123".parse().unwrap()]),
+        );
+    let pkt = Dhcp6 {
+        msgtype: REPLY,
+        xid: 1,
+        options,
+    };
+    let parsed = parse(&pkt.serialise()).expect("Failed to parse");
+    assert_eq!(
+        parsed.options.get_option::<DomainList>(&OPTION_DOMAIN_LIST),
+        Some(DomainList(vec!["example.com".into(), "example.org".into()]))
+    );
+    assert_eq!(
+        parsed.options.get_option::<NtpServers>(&OPTION_NTP_SERVER),
+        Some(NtpServers(vec!["2001:db8::123".parse().unwrap()]))
+    );
+    /* An unterminated domain name is invalid */
+    assert_eq!(DomainList::parse_into(b"\x07example"), None);
+}
+
+#[test]
+fn test_truncated() {
+    let pkt = Dhcp6 {
+        msgtype: REQUEST,
+        xid: 1,
+        options: Dhcp6Options::default().add_option(&OPTION_CLIENTID, &vec![1u8, 2, 3]),
+    }
+    .serialise();
+    for i in 0..pkt.len() {
+        /* Truncating in the middle of an option must fail, but not panic */
+        if i != 4 {
+            assert!(parse(&pkt[..i]).is_err(), "Parsed truncated packet {}", i);
+        }
+    }
+}
@@ -0,0 +1,1349 @@
+/*   Copyright 2023 Perry Lorier
+ *
+ *  Licensed under the Apache License, Version 2.0 (the "License");
+ *  you may not use this file except in compliance with the License.
+ *  You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ *  Unless required by applicable law or agreed to in writing, software
+ *  distributed under the License is distributed on an "AS IS" BASIS,
+ *  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ *  See the License for the specific language governing permissions and
+ *  limitations under the License.
+ *
+ *  SPDX-License-Identifier: Apache-2.0
+ *
+ *  Main DHCPv6 Code.
+ */
+use std::convert::TryInto as _;
+use std::net;
+use std::sync::Arc;
+use tokio::sync;
+
+use erbium_net::addr::{
+    NetAddrExt as _, WithPort as _, ALL_DHCP_RELAY_AGENTS_AND_SERVERS, UNSPECIFIED6,
+};
+use erbium_net::udp;
+
+pub mod config;
+pub mod dhcp6pkt;
+pub mod pool;
+#[cfg(test)]
+mod test;
+
+type UdpSocket = udp::UdpSocket;
+
+lazy_static::lazy_static! {
+    static ref DHCP6_RX_PACKETS: prometheus::IntCounter =
+        prometheus::register_int_counter!("dhcp6_received_packets", "Number of DHCPv6 packets received")
+            .unwrap();
+    static ref DHCP6_TX_PACKETS: prometheus::IntCounter =
+        prometheus::register_int_counter!("dhcp6_sent_packets", "Number of DHCPv6 packets sent")
+            .unwrap();
+    static ref DHCP6_ERRORS: prometheus::IntCounterVec = prometheus::register_int_counter_vec!(
+        "dhcp6_errors",
+        "Counts of reasons that DHCPv6 replies cannot be sent",
+        &["reason"]
+    )
+    .unwrap();
+    static ref DHCP6_ALLOCATIONS: prometheus::IntCounterVec = prometheus::register_int_counter_vec!(
+        "dhcp6_allocations",
+        "Counts of DHCPv6 address allocation types",
+        &["reason"]
+    )
+    .unwrap();
+    static ref DHCP6_DELEGATIONS: prometheus::IntCounterVec = prometheus::register_int_counter_vec!(
+        "dhcp6_delegations",
+        "Counts of DHCPv6 prefix delegation types",
+        &["reason"]
+    )
+    .unwrap();
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Dhcp6Error {
+    UnknownMessageType(dhcp6pkt::MessageType),
+    ParseError(dhcp6pkt::ParseError),
+    PoolError(pool::Error),
+    MissingClientId,
+    OtherServer,
+    NoPolicyConfigured,
+    UnexpectedIa,
+}
+
+impl std::error::Error for Dhcp6Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        None
+    }
+}
+
+impl std::fmt::Display for Dhcp6Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Dhcp6Error::UnknownMessageType(m) => write!(f, "Unknown Message Type: {}", m),
+            Dhcp6Error::ParseError(e) => write!(f, "Parse Error: {}", e),
+            Dhcp6Error::PoolError(p) => write!(f, "Pool Error: {}", p),
+            Dhcp6Error::MissingClientId => write!(f, "Message has no Client Identifier"),
+            Dhcp6Error::OtherServer => write!(f, "Packet for a different DHCPv6 server"),
+            Dhcp6Error::NoPolicyConfigured => write!(f, "No policy configured for client"),
+            Dhcp6Error::UnexpectedIa => write!(f, "Information-Request contains an IA"),
+        }
+    }
+}
+
+impl Dhcp6Error {
+    const fn get_variant_name(&self) -> &'static str {
+        use Dhcp6Error::*;
+        match self {
+            UnknownMessageType(_) => "UNKNOWN_MESSAGE_TYPE",
+            ParseError(_) => "PARSE_ERROR",
+            MissingClientId => "MISSING_CLIENT_ID",
+            OtherServer => "OTHER_SERVER",
+            NoPolicyConfigured => "NO_POLICY",
+            UnexpectedIa => "UNEXPECTED_IA",
+            PoolError(pool::Error::NoAssignableAddress) => "NO_ADDRESS",
+            PoolError(pool::Error::NoAssignablePrefix) => "NO_PREFIX",
+            PoolError(pool::Error::UnknownLease) => "UNKNOWN_LEASE",
+            PoolError(_) => "INTERNAL_POOL_ERROR",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Dhcp6Request {
+    /// The DHCPv6 packet itself.
+    pub pkt: dhcp6pkt::Dhcp6,
+    /// The interface the packet arrived on.
+    pub ifindex: u32,
+    pub ifname: Option<String>,
+    /// Addresses (and prefix lengths) configured on the interface the packet arrived on.
+    pub if_addresses: Vec<(net::Ipv6Addr, u8)>,
+    /// The (link local) address the packet came from, used as the nexthop for delegated prefixes.
+    pub peer: Option<net::Ipv6Addr>,
+}
+
+impl std::default::Default for Dhcp6Request {
+    fn default() -> Self {
+        Dhcp6Request {
+            pkt: dhcp6pkt::Dhcp6 {
+                msgtype: dhcp6pkt::SOLICIT,
+                xid: 0,
+                options: dhcp6pkt::Dhcp6Options::default().add_option(
+                    &dhcp6pkt::OPTION_CLIENTID,
+                    &vec![
+                        0x00u8, 0x03, 0x00, 0x01, /* DUID-LL, Ethernet */
+                        0x00, 0x00, 0x5E, 0x00, 0x53,
+                        0x00, /* Reserved for documentation, per RFC7042 */
+                    ],
+                ),
+            },
+            ifindex: 0,
+            ifname: None,
+            if_addresses: vec![],
+            peer: None,
+        }
+    }
+}
+
+#[derive(Eq, PartialEq, Debug)]
+enum PolicyMatch {
+    NoMatch,
+    MatchFailed,
+    MatchSucceeded,
+}
+
+fn check_policy(req: &Dhcp6Request, policy: &config::Policy) -> PolicyMatch {
+    let mut outcome = PolicyMatch::NoMatch;
+    if policy.match_all {
+        outcome = PolicyMatch::MatchSucceeded;
+    }
+    if let Some(match_interface) = &policy.match_interface {
+        outcome = PolicyMatch::MatchSucceeded;
+        if req.ifname.as_ref() != Some(match_interface) {
+            return PolicyMatch::MatchFailed;
+        }
+    }
+    if let Some(match_duid) = &policy.match_duid {
+        outcome = PolicyMatch::MatchSucceeded;
+        if req.pkt.options.get_clientid().as_ref() != Some(match_duid) {
+            return PolicyMatch::MatchFailed;
+        }
+    }
+    outcome
+}
+
+#[derive(Default)]
+struct Response {
+    address: Option<pool::PoolAddresses>,
+    preferred_lifetime: Option<std::time::Duration>,
+    valid_lifetime: Option<std::time::Duration>,
+    delegated_prefixes: Option<pool::PrefixPool>,
+    dns_servers: Option<Vec<net::Ipv6Addr>>,
+    dns_search: Option<Vec<String>>,
+    ntp_servers: Option<Vec<net::Ipv6Addr>>,
+}
+
+fn apply_policy(req: &Dhcp6Request, policy: &config::Policy, response: &mut Response) -> bool {
+    match check_policy(req, policy) {
+        PolicyMatch::MatchFailed => return false,
+        PolicyMatch::NoMatch => {
+            if !check_policies(req, &policy.policies) {
+                return false;
+            }
+        }
+        PolicyMatch::MatchSucceeded => (),
+    }
+
+    if let Some(address) = &policy.apply_address {
+        response.address = Some(address.clone());
+    }
+    if let Some(preferred) = policy.apply_preferred_lifetime {
+        response.preferred_lifetime = Some(preferred);
+    }
+    if let Some(valid) = policy.apply_valid_lifetime {
+        response.valid_lifetime = Some(valid);
+    }
+    if let Some(prefixes) = &policy.apply_delegated_prefixes {
+        response.delegated_prefixes = Some(prefixes.clone());
+    }
+    if let Some(servers) = &policy.apply_dns_servers {
+        response.dns_servers = Some(servers.clone());
+    }
+    if let Some(search) = &policy.apply_dns_search {
+        response.dns_search = Some(search.clone());
+    }
+    if let Some(servers) = &policy.apply_ntp_servers {
+        response.ntp_servers = Some(servers.clone());
+    }
+
+    apply_policies(req, &policy.policies, response);
+
+    true
+}
+
+fn check_policies(req: &Dhcp6Request, policies: &[config::Policy]) -> bool {
+    for policy in policies {
+        match check_policy(req, policy) {
+            PolicyMatch::MatchSucceeded => return true,
+            PolicyMatch::MatchFailed => continue,
+            PolicyMatch::NoMatch => {
+                if check_policies(req, &policy.policies) {
+                    return true;
+                } else {
+                    continue;
+                }
+            }
+        }
+    }
+    false
+}
+
+fn apply_policies(
+    req: &Dhcp6Request,
+    policies: &[config::Policy],
+    response: &mut Response,
+) -> bool {
+    for policy in policies {
+        if apply_policy(req, policy, response) {
+            return true;
+        }
+    }
+    false
+}
+
+/// ```yaml
+/// # Always match the base configuration
+/// match-all: true
+/// # For top level settings, apply them.
+/// apply-dns-servers: [ip6s]
+/// apply-dns-search: [domains]
+/// policies:
+///  # For each IPv6 prefix provided in the addresses top level config that is on the interface
+///  # the request arrived on:
+///  - apply-subnet: prefix6 # with addresses used by dhcp6-policies and the interface removed.
+/// ```
+pub fn build_default_config(
+    conf: &crate::config::Config,
+    request: &Dhcp6Request,
+) -> config::Policy {
+    use crate::config::Match as _;
+    let mut excluded = conf.dhcp6.get_all_used_addresses();
+    excluded.extend(request.if_addresses.iter().map(|(ip6, _)| *ip6..=*ip6));
+    /* Link local addresses are useless to hand out without a scope, so if the configuration asks
+     * for "this server", use a global address from the interface the request arrived on.
+     */
+    let selfip = request
+        .if_addresses
+        .iter()
+        .map(|(ip6, _)| *ip6)
+        .find(|ip6| ip6.segments()[0] & 0xffc0 != 0xfe80);
+    config::Policy {
+        match_all: true, /* We always want this policy to match. */
+        apply_dns_servers: Some(
+            conf.dns_servers
+                .iter()
+                .filter_map(|ip| match ip {
+                    ip if *ip == config::INTERFACE6 => selfip,
+                    net::IpAddr::V6(ip6) => Some(*ip6),
+                    _ => None,
+                })
+                .collect(),
+        ),
+        apply_dns_search: Some(conf.dns_search.clone()),
+        policies: conf
+            .addresses
+            .iter()
+            .filter_map(|prefix| match prefix {
+                config::Prefix::V6(p6)
+                    if request
+                        .if_addresses
+                        .iter()
+                        .any(|(ip6, _)| p6.contains(*ip6)) =>
+                {
+                    Some(config::Policy {
+                        match_all: true,
+                        apply_address: Some(pool::PoolAddresses {
+                            ranges: vec![pool::PoolAddresses::subnet_range(p6)],
+                            excluded: excluded.clone(),
+                        }),
+                        ..Default::default()
+                    })
+                }
+                _ => None,
+            })
+            .collect(),
+        ..Default::default()
+    }
+}
+
+fn build_reply(
+    req: &Dhcp6Request,
+    msgtype: dhcp6pkt::MessageType,
+    serverid: &[u8],
+    clientid: &[u8],
+) -> dhcp6pkt::Dhcp6 {
+    dhcp6pkt::Dhcp6 {
+        msgtype,
+        xid: req.pkt.xid,
+        options: dhcp6pkt::Dhcp6Options::default()
+            .add_raw_option(&dhcp6pkt::OPTION_SERVERID, serverid)
+            .add_raw_option(&dhcp6pkt::OPTION_CLIENTID, clientid),
+    }
+}
+
+fn check_serverid(req: &Dhcp6Request, serverid: &[u8]) -> Result<(), Dhcp6Error> {
+    if req.pkt.options.get_serverid().as_deref() == Some(serverid) {
+        Ok(())
+    } else {
+        Err(Dhcp6Error::OtherServer)
+    }
+}
+
+fn ia_status(iaid: u32, code: dhcp6pkt::StatusCode, message: &str) -> dhcp6pkt::IaNa {
+    dhcp6pkt::IaNa {
+        iaid,
+        t1: 0,
+        t2: 0,
+        options: dhcp6pkt::Dhcp6Options::default().add_option(
+            &dhcp6pkt::OPTION_STATUS_CODE,
+            &dhcp6pkt::Status::new(code, message),
+        ),
+    }
+}
+
+fn ia_pd_status(iaid: u32, code: dhcp6pkt::StatusCode, message: &str) -> dhcp6pkt::IaPd {
+    dhcp6pkt::IaPd {
+        iaid,
+        t1: 0,
+        t2: 0,
+        options: dhcp6pkt::Dhcp6Options::default().add_option(
+            &dhcp6pkt::OPTION_STATUS_CODE,
+            &dhcp6pkt::Status::new(code, message),
+        ),
+    }
+}
+
+/// Returns the (preferred, valid) lifetimes to hand out.
+fn get_lifetimes(response: &Response) -> (std::time::Duration, std::time::Duration) {
+    let valid = response
+        .valid_lifetime
+        .unwrap_or(pool::DEFAULT_VALID_LIFETIME);
+    let preferred = std::cmp::min(
+        response
+            .preferred_lifetime
+            .unwrap_or(pool::DEFAULT_PREFERRED_LIFETIME),
+        valid,
+    );
+    (preferred, valid)
+}
+
+/// Adds the configuration options the client asked for.
+fn add_config_options(
+    req: &Dhcp6Request,
+    response: &Response,
+    mut options: dhcp6pkt::Dhcp6Options,
+) -> dhcp6pkt::Dhcp6Options {
+    let requested = req.pkt.options.get_option_request();
+    let wanted = |option| requested.contains(option);
+    if let Some(servers) = response.dns_servers.as_ref().filter(|s| !s.is_empty()) {
+        if wanted(&dhcp6pkt::OPTION_DNS_SERVERS) {
+            options = options.add_option(&dhcp6pkt::OPTION_DNS_SERVERS, servers);
+        }
+    }
+    if let Some(search) = response.dns_search.as_ref().filter(|s| !s.is_empty()) {
+        if wanted(&dhcp6pkt::OPTION_DOMAIN_LIST) {
+            options = options.add_option(
+                &dhcp6pkt::OPTION_DOMAIN_LIST,
+                &dhcp6pkt::DomainList(search.clone()),
+            );
+        }
+    }
+    if let Some(servers) = response.ntp_servers.as_ref().filter(|s| !s.is_empty()) {
+        if wanted(&dhcp6pkt::OPTION_NTP_SERVER) {
+            options = options.add_option(
+                &dhcp6pkt::OPTION_NTP_SERVER,
+                &dhcp6pkt::NtpServers(servers.clone()),
+            );
+        }
+        /* Older clients only know about SNTP (RFC4075) */
+        if wanted(&dhcp6pkt::OPTION_SNTP_SERVERS) {
+            options = options.add_option(&dhcp6pkt::OPTION_SNTP_SERVERS, servers);
+        }
+    }
+    options
+}
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum IaMode {
+    Solicit,
+    Request,
+    Renew,
+    Rebind,
+}
+
+/// Handles the IA_NA's in a SOLICIT/REQUEST/RENEW/REBIND, returning the IA_NAs to reply with, and
+/// whether any addresses were assigned at all.
+fn handle_ias(
+    pools: &mut pool::Pool,
+    req: &Dhcp6Request,
+    clientid: &[u8],
+    response: &Response,
+    mode: IaMode,
+) -> Result<(Vec<dhcp6pkt::IaNa>, bool), Dhcp6Error> {
+    let (preferred, valid) = get_lifetimes(response);
+    let mut assigned = false;
+    let mut ias = vec![];
+    for ia in req
+        .pkt
+        .options
+        .get_options::<dhcp6pkt::IaNa>(&dhcp6pkt::OPTION_IA_NA)
+    {
+        let client_addrs = ia.get_addresses().map(|a| a.addr).collect::<Vec<_>>();
+        /* RFC8415 Section 18.3.4: If we can't find a binding for a RENEW, say so, and the client
+         * will go back to SOLICIT.
+         */
+        if mode == IaMode::Renew
+            && pools
+                .get_binding(clientid, ia.iaid)
+                .map_err(Dhcp6Error::PoolError)?
+                .is_none()
+        {
+            ias.push(ia_status(
+                ia.iaid,
+                dhcp6pkt::STATUS_NO_BINDING,
+                "No binding for this IA",
+            ));
+            continue;
+        }
+        let addresses = match &response.address {
+            Some(addresses) => addresses,
+            None => {
+                ias.push(ia_status(
+                    ia.iaid,
+                    dhcp6pkt::STATUS_NO_ADDRS_AVAIL,
+                    "No addresses configured",
+                ));
+                continue;
+            }
+        };
+        /* RFC8415 Section 18.3.9: Only a REQUEST/RENEW/REBIND binds an address to the IA */
+        let allocate = if mode == IaMode::Solicit {
+            pool::Pool::offer_address
+        } else {
+            pool::Pool::allocate_address
+        };
+        match allocate(
+            pools,
+            clientid,
+            ia.iaid,
+            client_addrs.first().copied(),
+            addresses,
+            valid,
+        ) {
+            Ok(lease) => {
+                DHCP6_ALLOCATIONS
+                    .with_label_values(&[&format!("{:?}", lease.lease_type)])
+                    .inc();
+                log::info!(
+                    "Allocated Lease: {} for {:?} ({:?})",
+                    lease.ip,
+                    lease.expire,
+                    lease.lease_type
+                );
+                assigned = true;
+                let mut options = dhcp6pkt::Dhcp6Options::default().add_option(
+                    &dhcp6pkt::OPTION_IAADDR,
+                    &dhcp6pkt::IaAddr {
+                        addr: lease.ip,
+                        preferred: preferred.as_secs() as u32,
+                        valid: lease.expire.as_secs() as u32,
+                        options: Default::default(),
+                    },
+                );
+                /* RFC8415 Section 18.3.4/18.3.5: Tell the client to stop using any addresses it
+                 * had that it should no longer have.
+                 */
+                if mode == IaMode::Renew || mode == IaMode::Rebind {
+                    for addr in client_addrs.iter().filter(|addr| **addr != lease.ip) {
+                        options = options.add_option(
+                            &dhcp6pkt::OPTION_IAADDR,
+                            &dhcp6pkt::IaAddr {
+                                addr: *addr,
+                                preferred: 0,
+                                valid: 0,
+                                options: Default::default(),
+                            },
+                        );
+                    }
+                }
+                /* RFC8415 Section 21.4: Recommended values for T1 and T2 are .5 and .8 times the
+                 * shortest preferred lifetime.
+                 */
+                ias.push(dhcp6pkt::IaNa {
+                    iaid: ia.iaid,
+                    t1: (preferred.as_secs() / 2) as u32,
+                    t2: (preferred.as_secs() * 4 / 5) as u32,
+                    options,
+                });
+            }
+            Err(pool::Error::NoAssignableAddress) => ias.push(ia_status(
+                ia.iaid,
+                dhcp6pkt::STATUS_NO_ADDRS_AVAIL,
+                "No addresses available",
+            )),
+            Err(e) => return Err(Dhcp6Error::PoolError(e)),
+        }
+    }
+    Ok((ias, assigned))
+}
+
+/// Handles the IA_PD's in a SOLICIT/REQUEST/RENEW/REBIND, returning the IA_PDs to reply with.
+fn handle_ia_pds(
+    pools: &mut pool::Pool,
+    req: &Dhcp6Request,
+    clientid: &[u8],
+    response: &Response,
+    mode: IaMode,
+) -> Result<Vec<dhcp6pkt::IaPd>, Dhcp6Error> {
+    let (preferred, valid) = get_lifetimes(response);
+    let mut pds = vec![];
+    for pd in req
+        .pkt
+        .options
+        .get_options::<dhcp6pkt::IaPd>(&dhcp6pkt::OPTION_IA_PD)
+    {
+        let client_prefixes = pd
+            .get_prefixes()
+            .map(|p| (p.prefix, p.prefixlen))
+            .collect::<Vec<_>>();
+        if mode == IaMode::Renew
+            && !pools
+                .has_prefix_binding(clientid, pd.iaid)
+                .map_err(Dhcp6Error::PoolError)?
+        {
+            pds.push(ia_pd_status(
+                pd.iaid,
+                dhcp6pkt::STATUS_NO_BINDING,
+                "No binding for this IA",
+            ));
+            continue;
+        }
+        let (prefixes, nexthop) = match (&response.delegated_prefixes, req.peer) {
+            (Some(prefixes), Some(nexthop)) => (prefixes, nexthop),
+            _ => {
+                pds.push(ia_pd_status(
+                    pd.iaid,
+                    dhcp6pkt::STATUS_NO_PREFIX_AVAIL,
+                    "No prefixes configured",
+                ));
+                continue;
+            }
+        };
+        let delegation = if mode == IaMode::Solicit {
+            pools.offer_prefix(
+                clientid,
+                pd.iaid,
+                client_prefixes.first().copied(),
+                prefixes,
+                valid,
+            )
+        } else {
+            pools.allocate_prefix(
+                clientid,
+                pd.iaid,
+                client_prefixes.first().copied(),
+                prefixes,
+                valid,
+                nexthop,
+                req.ifindex,
+            )
+        };
+        match delegation {
+            Ok(delegation) => {
+                DHCP6_DELEGATIONS
+                    .with_label_values(&[&format!("{:?}", delegation.lease_type)])
+                    .inc();
+                log::info!(
+                    "Delegated Prefix: {}/{} to {} for {:?} ({:?})",
+                    delegation.prefix,
+                    delegation.prefixlen,
+                    nexthop,
+                    delegation.expire,
+                    delegation.lease_type
+                );
+                let mut options = dhcp6pkt::Dhcp6Options::default().add_option(
+                    &dhcp6pkt::OPTION_IAPREFIX,
+                    &dhcp6pkt::IaPrefix {
+                        preferred: preferred.as_secs() as u32,
+                        valid: delegation.expire.as_secs() as u32,
+                        prefixlen: delegation.prefixlen,
+                        prefix: delegation.prefix,
+                        options: Default::default(),
+                    },
+                );
+                /* RFC8415 Section 18.3.4/18.3.5: As with addresses, tell the router to stop using
+                 * any prefixes it should no longer have.
+                 */
+                if mode == IaMode::Renew || mode == IaMode::Rebind {
+                    for (prefix, prefixlen) in client_prefixes
+                        .iter()
+                        .filter(|p| **p != (delegation.prefix, delegation.prefixlen))
+                    {
+                        options = options.add_option(
+                            &dhcp6pkt::OPTION_IAPREFIX,
+                            &dhcp6pkt::IaPrefix {
+                                preferred: 0,
+                                valid: 0,
+                                prefixlen: *prefixlen,
+                                prefix: *prefix,
+                                options: Default::default(),
+                            },
+                        );
+                    }
+                }
+                pds.push(dhcp6pkt::IaPd {
+                    iaid: pd.iaid,
+                    t1: (preferred.as_secs() / 2) as u32,
+                    t2: (preferred.as_secs() * 4 / 5) as u32,
+                    options,
+                });
+            }
+            Err(pool::Error::NoAssignablePrefix) => pds.push(ia_pd_status(
+                pd.iaid,
+                dhcp6pkt::STATUS_NO_PREFIX_AVAIL,
+                "No prefixes available",
+            )),
+            Err(e) => return Err(Dhcp6Error::PoolError(e)),
+        }
+    }
+    Ok(pds)
+}
+
+fn handle_assign(
+    pools: &mut pool::Pool,
+    req: &Dhcp6Request,
+    serverid: &[u8],
+    clientid: &[u8],
+    base: &[config::Policy],
+    conf: &crate::config::Config,
+    mode: IaMode,
+) -> Result<dhcp6pkt::Dhcp6, Dhcp6Error> {
+    let mut response = Response::default();
+    let base_policy = apply_policies(req, base, &mut response);
+    let conf_policy = apply_policies(req, &conf.dhcp6.policies, &mut response);
+    if !base_policy && !conf_policy {
+        return Err(Dhcp6Error::NoPolicyConfigured);
+    }
+
+    let (ias, assigned) = handle_ias(pools, req, clientid, &response, mode)?;
+    let pds = handle_ia_pds(pools, req, clientid, &response, mode)?;
+    let delegated = pds.iter().any(|pd| pd.get_prefixes().next().is_some());
+
+    let msgtype = if mode == IaMode::Solicit {
+        dhcp6pkt::ADVERTISE
+    } else {
+        dhcp6pkt::REPLY
+    };
+    let mut reply = build_reply(req, msgtype, serverid, clientid);
+    if mode == IaMode::Solicit && !assigned && !delegated {
+        /* RFC8415 Section 18.3.9: If we have nothing to offer for any IA, the Advertise just
+         * contains a status code (and whatever we have to say about any IA_PDs).  Otherwise each
+         * IA_NA we can't satisfy carries its own status, so the client can still take up the
+         * delegated prefixes.
+         */
+        reply.options = reply.options.add_option(
+            &dhcp6pkt::OPTION_STATUS_CODE,
+            &dhcp6pkt::Status::new(dhcp6pkt::STATUS_NO_ADDRS_AVAIL, "No addresses available"),
+        );
+    } else {
+        for ia in ias {
+            reply.options = reply.options.add_option(&dhcp6pkt::OPTION_IA_NA, &ia);
+        }
+    }
+    for pd in pds {
+        reply.options = reply.options.add_option(&dhcp6pkt::OPTION_IA_PD, &pd);
+    }
+    reply.options = add_config_options(req, &response, reply.options);
+    Ok(reply)
+}
+
+/// Handles RELEASE and DECLINE, which both give addresses back to the server.
+///
+/// Returns the reply, and the routes to the delegated prefixes that were released.
+fn handle_return(
+    pools: &mut pool::Pool,
+    req: &Dhcp6Request,
+    serverid: &[u8],
+    clientid: &[u8],
+    decline: bool,
+) -> Result<Reply, Dhcp6Error> {
+    let mut reply = build_reply(req, dhcp6pkt::REPLY, serverid, clientid);
+    let mut released = vec![];
+    for ia in req
+        .pkt
+        .options
+        .get_options::<dhcp6pkt::IaNa>(&dhcp6pkt::OPTION_IA_NA)
+    {
+        let mut found = true;
+        for addr in ia.get_addresses() {
+            let ret = if decline {
+                log::warn!("Address {} has been declined, quarantining", addr.addr);
+                pools.decline_address(
+                    clientid,
+                    ia.iaid,
+                    addr.addr,
+                    pool::DEFAULT_DECLINE_QUARANTINE,
+                )
+            } else {
+                pools.release_address(clientid, ia.iaid, addr.addr)
+            };
+            match ret {
+                Ok(()) => (),
+                Err(pool::Error::UnknownLease) => found = false,
+                Err(e) => return Err(Dhcp6Error::PoolError(e)),
+            }
+        }
+        /* RFC8415 Section 18.3.7: Only IAs we have no binding for are included in the reply */
+        if !found {
+            reply.options = reply.options.add_option(
+                &dhcp6pkt::OPTION_IA_NA,
+                &ia_status(
+                    ia.iaid,
+                    dhcp6pkt::STATUS_NO_BINDING,
+                    "No binding for this IA",
+                ),
+            );
+        }
+    }
+    /* RFC8415 Section 18.3.8: Declines are only for addresses, routers don't check delegated
+     * prefixes for duplicates.
+     */
+    if !decline {
+        for pd in req
+            .pkt
+            .options
+            .get_options::<dhcp6pkt::IaPd>(&dhcp6pkt::OPTION_IA_PD)
+        {
+            let mut found = true;
+            for prefix in pd.get_prefixes() {
+                match pools.release_prefix(clientid, pd.iaid, prefix.prefix, prefix.prefixlen) {
+                    Ok((nexthop, ifindex)) => released.push(erbium_net::route::Route {
+                        destination: prefix.prefix.into(),
+                        prefixlen: prefix.prefixlen,
+                        nexthop: nexthop.into(),
+                        oifidx: Some(ifindex),
+                    }),
+                    Err(pool::Error::UnknownLease) => found = false,
+                    Err(e) => return Err(Dhcp6Error::PoolError(e)),
+                }
+            }
+            if !found {
+                reply.options = reply.options.add_option(
+                    &dhcp6pkt::OPTION_IA_PD,
+                    &ia_pd_status(
+                        pd.iaid,
+                        dhcp6pkt::STATUS_NO_BINDING,
+                        "No binding for this IA",
+                    ),
+                );
+            }
+        }
+    }
+    reply.options = reply.options.add_option(
+        &dhcp6pkt::OPTION_STATUS_CODE,
+        &dhcp6pkt::Status::new(dhcp6pkt::STATUS_SUCCESS, ""),
+    );
+    Ok(Reply {
+        pkt: reply,
+        released,
+    })
+}
+
+/// Handles stateless configuration requests (RFC8415 Section 18.3.6).
+fn handle_information_request(
+    req: &Dhcp6Request,
+    serverid: &[u8],
+    base: &[config::Policy],
+    conf: &crate::config::Config,
+) -> Result<dhcp6pkt::Dhcp6, Dhcp6Error> {
+    /* RFC8415 Section 16.12: The server identifier is optional, but if present must be ours, and
+     * there must be no IAs.
+     */
+    if req.pkt.options.get_serverid().is_some() {
+        check_serverid(req, serverid)?;
+    }
+    if [
+        dhcp6pkt::OPTION_IA_NA,
+        dhcp6pkt::OPTION_IA_TA,
+        dhcp6pkt::OPTION_IA_PD,
+    ]
+    .iter()
+    .any(|ia| req.pkt.options.get_raw_option(ia).is_some())
+    {
+        return Err(Dhcp6Error::UnexpectedIa);
+    }
+
+    let mut response = Response::default();
+    let base_policy = apply_policies(req, base, &mut response);
+    let conf_policy = apply_policies(req, &conf.dhcp6.policies, &mut response);
+    if !base_policy && !conf_policy {
+        return Err(Dhcp6Error::NoPolicyConfigured);
+    }
+
+    /* The client identifier is also optional, but if present is echoed back. */
+    let options = dhcp6pkt::Dhcp6Options::default()
+        .add_raw_option(&dhcp6pkt::OPTION_SERVERID, serverid)
+        .maybe_add_option(
+            &dhcp6pkt::OPTION_CLIENTID,
+            req.pkt.options.get_clientid().as_ref(),
+        );
+    Ok(dhcp6pkt::Dhcp6 {
+        msgtype: dhcp6pkt::REPLY,
+        xid: req.pkt.xid,
+        options: add_config_options(req, &response, options),
+    })
+}
+
+/// What to send back to a client, and what changed as a result of its request.
+#[derive(Debug)]
+pub struct Reply {
+    pub pkt: dhcp6pkt::Dhcp6,
+    /// Routes to delegated prefixes that the client gave back.
+    pub released: Vec<erbium_net::route::Route>,
+}
+
+impl From<dhcp6pkt::Dhcp6> for Reply {
+    fn from(pkt: dhcp6pkt::Dhcp6) -> Self {
+        Reply {
+            pkt,
+            released: vec![],
+        }
+    }
+}
+
+pub async fn handle_pkt(
+    pools: &mut pool::Pool,
+    request: &Dhcp6Request,
+    serverid: &[u8],
+    conf: &crate::config::Config,
+) -> Result<Option<Reply>, Dhcp6Error> {
+    let base = [build_default_config(conf, request)];
+    if request.pkt.msgtype == dhcp6pkt::INFORMATION_REQUEST {
+        return handle_information_request(request, serverid, &base, conf)
+            .map(|pkt| Some(pkt.into()));
+    }
+    let clientid = request
+        .pkt
+        .options
+        .get_clientid()
+        .ok_or(Dhcp6Error::MissingClientId)?;
+    match request.pkt.msgtype {
+        dhcp6pkt::SOLICIT => {
+            /* RFC8415 Section 16.2: Solicits must not contain a server identifier */
+            if request.pkt.options.get_serverid().is_some() {
+                return Err(Dhcp6Error::OtherServer);
+            }
+            handle_assign(
+                pools,
+                request,
+                serverid,
+                &clientid,
+                &base,
+                conf,
+                IaMode::Solicit,
+            )
+            .map(|pkt| Some(pkt.into()))
+        }
+        dhcp6pkt::REQUEST => {
+            check_serverid(request, serverid)?;
+            handle_assign(
+                pools,
+                request,
+                serverid,
+                &clientid,
+                &base,
+                conf,
+                IaMode::Request,
+            )
+            .map(|pkt| Some(pkt.into()))
+        }
+        dhcp6pkt::RENEW => {
+            check_serverid(request, serverid)?;
+            handle_assign(
+                pools,
+                request,
+                serverid,
+                &clientid,
+                &base,
+                conf,
+                IaMode::Renew,
+            )
+            .map(|pkt| Some(pkt.into()))
+        }
+        dhcp6pkt::REBIND => handle_assign(
+            pools,
+            request,
+            serverid,
+            &clientid,
+            &base,
+            conf,
+            IaMode::Rebind,
+        )
+        .map(|pkt| Some(pkt.into())),
+        dhcp6pkt::RELEASE => {
+            check_serverid(request, serverid)?;
+            handle_return(pools, request, serverid, &clientid, false).map(Some)
+        }
+        dhcp6pkt::DECLINE => {
+            check_serverid(request, serverid)?;
+            handle_return(pools, request, serverid, &clientid, true).map(Some)
+        }
+        x => Err(Dhcp6Error::UnknownMessageType(x)),
+    }
+}
+
+/// Routes that need to be installed or removed after replying to a request.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct RouteChanges {
+    pub add: Vec<erbium_net::route::Route>,
+    pub del: Vec<erbium_net::route::Route>,
+}
+
+fn delegated_route(
+    req: &Dhcp6Request,
+    nexthop: net::Ipv6Addr,
+    prefix: net::Ipv6Addr,
+    prefixlen: u8,
+) -> erbium_net::route::Route {
+    erbium_net::route::Route {
+        destination: prefix.into(),
+        prefixlen,
+        nexthop: nexthop.into(),
+        oifidx: Some(req.ifindex),
+    }
+}
+
+/// Works out which routes to delegated prefixes need to change given a request and our reply to it.
+pub fn get_route_changes(req: &Dhcp6Request, reply: &Reply) -> RouteChanges {
+    let mut changes = RouteChanges {
+        add: vec![],
+        del: reply.released.clone(),
+    };
+    let nexthop = match req.peer {
+        Some(peer) => peer,
+        None => return changes,
+    };
+    if reply.pkt.msgtype != dhcp6pkt::REPLY {
+        return changes;
+    }
+    for pd in reply
+        .pkt
+        .options
+        .get_options::<dhcp6pkt::IaPd>(&dhcp6pkt::OPTION_IA_PD)
+    {
+        for p in pd.get_prefixes() {
+            let route = delegated_route(req, nexthop, p.prefix, p.prefixlen);
+            if p.valid > 0 {
+                changes.add.push(route);
+            } else {
+                changes.del.push(route);
+            }
+        }
+    }
+    changes
+}
+
+fn format_duid(duid: &[u8]) -> String {
+    duid.iter()
+        .map(|b| format!("{:0>2x}", b))
+        .collect::<Vec<String>>()
+        .join(":")
+}
+
+enum RunError {
+    ListenError(std::io::Error),
+    RecvError(std::io::Error),
+    Io(std::io::Error),
+    PoolError(pool::Error),
+}
+
+impl std::fmt::Display for RunError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RunError::Io(e) => write!(f, "I/O Error in DHCPv6: {}", e),
+            RunError::PoolError(e) => write!(f, "DHCPv6 Pool Error: {}", e),
+            RunError::ListenError(e) => write!(f, "Failed to listen on DHCPv6: {}", e),
+            RunError::RecvError(e) => {
+                write!(f, "Failed to receive a packet for DHCPv6: {}", e)
+            }
+        }
+    }
+}
+
+pub struct Dhcp6Service {
+    netinfo: erbium_net::netinfo::SharedNetInfo,
+    conf: crate::config::SharedConfig,
+    pool: Arc<sync::Mutex<pool::Pool>>,
+    serverid: Vec<u8>,
+    listener: UdpSocket,
+    /// Routes we've installed towards delegated prefixes, and when their delegation expires.
+    routes: sync::Mutex<
+        std::collections::HashMap<(net::Ipv6Addr, u8), (erbium_net::route::Route, u32)>,
+    >,
+}
+
+/// How often to look for expired delegations to remove the routes for.
+const ROUTE_SWEEP_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60);
+
+impl Dhcp6Service {
+    async fn recvdhcp6(&self, rm: udp::RecvMsg) {
+        let intf: u32 = match rm.local_intf().and_then(|i| i.try_into().ok()) {
+            Some(intf) => intf,
+            None => {
+                DHCP6_ERRORS.with_label_values(&["NO_INTERFACE"]).inc();
+                return;
+            }
+        };
+        let src = match rm.address {
+            Some(src) => src,
+            None => return,
+        };
+
+        let pkt = match dhcp6pkt::parse(&rm.buffer) {
+            Err(e) => {
+                log::warn!("Failed to parse DHCPv6 packet: {}", e);
+                DHCP6_ERRORS
+                    .with_label_values(&[e.get_variant_name()])
+                    .inc();
+                return;
+            }
+            Ok(pkt) => pkt,
+        };
+
+        let request = Dhcp6Request {
+            pkt,
+            ifindex: intf,
+            ifname: self.netinfo.get_name_by_ifidx(intf).await,
+            if_addresses: self
+                .netinfo
+                .get_prefixes_by_ifidx(intf)
+                .await
+                .unwrap_or_default()
+                .into_iter()
+                .filter_map(|(ip, prefixlen)| match ip {
+                    net::IpAddr::V6(ip6) => Some((ip6, prefixlen)),
+                    _ => None,
+                })
+                .collect(),
+            peer: match src.ip() {
+                Some(net::IpAddr::V6(ip6)) => Some(ip6),
+                _ => None,
+            },
+        };
+        let client = format_duid(&request.pkt.options.get_clientid().unwrap_or_default());
+        log::info!(
+            "{}: Received {} on {}",
+            client,
+            request.pkt.msgtype,
+            self.netinfo.get_safe_name_by_ifidx(intf).await
+        );
+
+        let reply;
+        {
+            /* Limit the amount of time we have these locked to just handling the packet */
+            let mut pool = self.pool.lock().await;
+            let lockedconf = self.conf.read().await;
+
+            reply = match handle_pkt(&mut pool, &request, &self.serverid, &lockedconf).await {
+                Err(e) => {
+                    log::warn!(
+                        "{}: Failed to handle {}: {}",
+                        client,
+                        request.pkt.msgtype,
+                        e
+                    );
+                    DHCP6_ERRORS
+                        .with_label_values(&[e.get_variant_name()])
+                        .inc();
+                    return;
+                }
+                Ok(None) => return,
+                Ok(Some(r)) => r,
+            };
+        }
+
+        self.update_routes(get_route_changes(&request, &reply), &reply.pkt)
+            .await;
+        let reply = reply.pkt;
+
+        log::info!(
+            "{}: Sending {} on {}",
+            client,
+            reply.msgtype,
+            self.netinfo.get_safe_name_by_ifidx(intf).await
+        );
+
+        /* If the client unicast to us, then reply from the same address.  Otherwise the reply goes
+         * back to the (link local, scoped) address the client sent from, and the kernel chooses.
+         */
+        let send_from = match rm.local_ip() {
+            Some(net::IpAddr::V6(ip6)) if !ip6.is_multicast() => Some(net::IpAddr::V6(ip6)),
+            _ => None,
+        };
+        if let Err(e) = self
+            .listener
+            .send_msg(
+                &reply.serialise(),
+                &udp::ControlMessage::new()
+                    .set_send_from(send_from)
+                    .set_src6_intf(intf),
+                udp::MsgFlags::empty(),
+                Some(&src),
+            )
+            .await
+        {
+            log::warn!("{}: Failed to send reply: {}", client, e);
+            DHCP6_ERRORS.with_label_values(&["SEND_FAILED"]).inc();
+        } else {
+            DHCP6_TX_PACKETS.inc();
+        }
+    }
+
+    async fn update_routes(&self, changes: RouteChanges, reply: &dhcp6pkt::Dhcp6) {
+        let mut routes = self.routes.lock().await;
+        for route in changes.del {
+            if let Err(e) = erbium_net::route::del_route(&route).await {
+                log::debug!(
+                    "Failed to remove route to {}/{}: {}",
+                    route.destination,
+                    route.prefixlen,
+                    e
+                );
+            }
+            if let net::IpAddr::V6(prefix) = route.destination {
+                routes.remove(&(prefix, route.prefixlen));
+            }
+        }
+        if changes.add.is_empty() {
+            return;
+        }
+        let expiries = reply
+            .options
+            .get_options::<dhcp6pkt::IaPd>(&dhcp6pkt::OPTION_IA_PD)
+            .flat_map(|pd| pd.get_prefixes().collect::<Vec<_>>())
+            .map(|p| ((p.prefix, p.prefixlen), p.valid))
+            .collect::<std::collections::HashMap<_, _>>();
+        for route in changes.add {
+            if let Err(e) = erbium_net::route::add_route(&route).await {
+                log::warn!(
+                    "Failed to install route to {}/{} via {}: {}",
+                    route.destination,
+                    route.prefixlen,
+                    route.nexthop,
+                    e
+                );
+                DHCP6_ERRORS.with_label_values(&["ROUTE_FAILED"]).inc();
+                continue;
+            }
+            if let net::IpAddr::V6(prefix) = route.destination {
+                let key = (prefix, route.prefixlen);
+                let expiry = pool::now() as u32 + expiries.get(&key).copied().unwrap_or(0);
+                routes.insert(key, (route, expiry));
+            }
+        }
+    }
+
+    /// Installs routes for delegations that are still valid, eg after a restart.
+    async fn restore_routes(&self) {
+        let delegations = match self.pool.lock().await.get_delegations() {
+            Ok(d) => d,
+            Err(e) => {
+                log::warn!("Failed to load DHCPv6 delegations: {}", e);
+                return;
+            }
+        };
+        let now = pool::now() as u32;
+        let mut routes = self.routes.lock().await;
+        for d in delegations.into_iter().filter(|d| d.expire >= now) {
+            let route = erbium_net::route::Route {
+                destination: d.prefix.into(),
+                prefixlen: d.prefixlen,
+                nexthop: d.nexthop.into(),
+                oifidx: Some(d.ifindex),
+            };
+            match erbium_net::route::add_route(&route).await {
+                Ok(()) => {
+                    routes.insert((d.prefix, d.prefixlen), (route, d.expire));
+                }
+                Err(e) => {
+                    log::warn!(
+                        "Failed to restore route to {}/{} via {}: {}",
+                        d.prefix,
+                        d.prefixlen,
+                        d.nexthop,
+                        e
+                    );
+                    DHCP6_ERRORS.with_label_values(&["ROUTE_FAILED"]).inc();
+                }
+            }
+        }
+    }
+
+    /// Removes routes for delegations that have expired without being renewed.
+    async fn expire_routes(&self) {
+        let now = pool::now() as u32;
+        let mut routes = self.routes.lock().await;
+        let expired = routes
+            .iter()
+            .filter(|(_, (_, expiry))| *expiry < now)
+            .map(|(k, _)| *k)
+            .collect::<Vec<_>>();
+        for key in expired {
+            if let Some((route, _)) = routes.remove(&key) {
+                log::info!(
+                    "Delegation of {}/{} to {} expired, removing route",
+                    key.0,
+                    key.1,
+                    route.nexthop
+                );
+                if let Err(e) = erbium_net::route::del_route(&route).await {
+                    log::warn!("Failed to remove route to {}/{}: {}", key.0, key.1, e);
+                }
+            }
+        }
+    }
+
+    async fn new_internal(
+        netinfo: erbium_net::netinfo::SharedNetInfo,
+        conf: crate::config::SharedConfig,
+    ) -> Result<Self, RunError> {
+        let mut pool = pool::Pool::new(&*conf.read().await).map_err(RunError::PoolError)?;
+        let serverid = pool.get_server_duid().map_err(RunError::PoolError)?;
+        let listener = UdpSocket::bind(&[UNSPECIFIED6.with_port(547)])
+            .await
+            .map_err(RunError::ListenError)?;
+        listener
+            .set_opt_ipv6_packet_info(true)
+            .map_err(RunError::ListenError)?;
+        listener
+            .set_opt_reuse_port(true)
+            .map_err(RunError::ListenError)?;
+        /* RFC8415 Section 7.1: Clients send to All_DHCP_Relay_Agents_and_Servers */
+        for idx in netinfo.get_ifindexes().await {
+            if let Some(ifflags) = netinfo.get_flags_by_ifidx(idx).await {
+                if ifflags.has_multicast() {
+                    if let Err(e) =
+                        listener.join_multicast_v6(&ALL_DHCP_RELAY_AGENTS_AND_SERVERS, idx)
+                    {
+                        log::warn!(
+                            "Failed to join DHCPv6 multicast group on {}: {}",
+                            netinfo.get_safe_name_by_ifidx(idx).await,
+                            e
+                        );
+                    }
+                }
+            }
+        }
+        log::info!(
+            "Listening for DHCPv6 on {} as {}",
+            listener.local_addr().map_err(RunError::Io)?,
+            format_duid(&serverid)
+        );
+        Ok(Self {
+            netinfo,
+            conf,
+            pool: Arc::new(sync::Mutex::new(pool)),
+            serverid,
+            listener,
+            routes: Default::default(),
+        })
+    }
+
+    pub async fn new(
+        netinfo: erbium_net::netinfo::SharedNetInfo,
+        conf: crate::config::SharedConfig,
+    ) -> Result<Self, String> {
+        Self::new_internal(netinfo, conf)
+            .await
+            .map_err(|e| e.to_string())
+    }
+
+    async fn run_internal(self: &Arc<Self>) -> Result<(), RunError> {
+        self.restore_routes().await;
+        let self2 = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(ROUTE_SWEEP_INTERVAL);
+            loop {
+                interval.tick().await;
+                self2.expire_routes().await;
+            }
+        });
+        loop {
+            let rm = match self.listener.recv_msg(65536, udp::MsgFlags::empty()).await {
+                Ok(m) => m,
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => continue,
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(RunError::RecvError(e)),
+            };
+            DHCP6_RX_PACKETS.inc();
+            let self2 = self.clone();
+            tokio::spawn(async move { self2.recvdhcp6(rm).await });
+        }
+    }
+
+    pub async fn run(self: Arc<Self>) -> Result<(), String> {
+        self.run_internal().await.map_err(|e| e.to_string())
+    }
+
+    pub async fn get_leases(self: &Arc<Self>) -> Vec<pool::LeaseInfo> {
+        let ret = self.pool.lock().await.get_leases();
+        match ret {
+            Ok(l) => l,
+            Err(e) => {
+                log::warn!("Failed to get DHCPv6 leases: {}", e);
+                Vec::new()
+            }
+        }
+    }
+}
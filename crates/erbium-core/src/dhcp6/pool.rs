@@ -0,0 +1,1101 @@
+/*   Copyright 2023 Perry Lorier
+ *
+ *  Licensed under the Apache License, Version 2.0 (the "License");
+ *  you may not use this file except in compliance with the License.
+ *  You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ *  Unless required by applicable law or agreed to in writing, software
+ *  distributed under the License is distributed on an "AS IS" BASIS,
+ *  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ *  See the License for the specific language governing permissions and
+ *  limitations under the License.
+ *
+ *  SPDX-License-Identifier: Apache-2.0
+ *
+ *  DHCPv6 Pool Management.
+ */
+
+use rusqlite::OptionalExtension;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::Hash;
+use std::hash::Hasher;
+
+pub const DEFAULT_PREFERRED_LIFETIME: std::time::Duration = std::time::Duration::from_secs(43200);
+pub const DEFAULT_VALID_LIFETIME: std::time::Duration = std::time::Duration::from_secs(86400);
+pub const DEFAULT_DECLINE_QUARANTINE: std::time::Duration = std::time::Duration::from_secs(3600);
+
+/* How many addresses to try after the hashed starting point before giving up.  IPv6 pools are
+ * usually very sparsely populated, so this is mostly to avoid walking enormous ranges when the
+ * pool is small and (nearly) full.
+ */
+const MAX_PROBES: u128 = 256;
+
+/// The set of addresses that a policy can hand out.
+///
+/// IPv6 pools are frequently far too large to enumerate (eg a /64), so these are kept as ranges.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct PoolAddresses {
+    pub ranges: Vec<std::ops::RangeInclusive<std::net::Ipv6Addr>>,
+    /// Addresses that fall within `ranges` but are reserved for some other policy.
+    pub excluded: Vec<std::ops::RangeInclusive<std::net::Ipv6Addr>>,
+}
+
+impl PoolAddresses {
+    /// Returns the usable range of a subnet, skipping the Subnet-Router anycast address
+    /// (RFC4291 Section 2.6.1).
+    pub fn subnet_range(
+        subnet: &crate::config::Prefix6,
+    ) -> std::ops::RangeInclusive<std::net::Ipv6Addr> {
+        use crate::config::PrefixOps as _;
+        let network = u128::from(subnet.network());
+        let broadcast = u128::from(subnet.broadcast());
+        std::net::Ipv6Addr::from(std::cmp::min(network + 1, broadcast))
+            ..=std::net::Ipv6Addr::from(broadcast)
+    }
+
+    pub fn contains(&self, addr: std::net::Ipv6Addr) -> bool {
+        self.ranges.iter().any(|r| r.contains(&addr))
+            && !self.excluded.iter().any(|r| r.contains(&addr))
+    }
+
+    fn len(&self) -> u128 {
+        self.ranges.iter().fold(0u128, |acc, r| {
+            acc.saturating_add(u128::from(*r.end()) - u128::from(*r.start()))
+                .saturating_add(1)
+        })
+    }
+
+    /// Returns the `offset`th address in the pool, treating the ranges as one contiguous list.
+    fn nth(&self, mut offset: u128) -> Option<std::net::Ipv6Addr> {
+        for r in &self.ranges {
+            let len = u128::from(*r.end()) - u128::from(*r.start());
+            if offset <= len {
+                return Some((u128::from(*r.start()) + offset).into());
+            }
+            offset -= len + 1;
+        }
+        None
+    }
+}
+
+/// A prefix that is carved up into smaller prefixes to delegate to downstream routers.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PrefixPool {
+    pub prefix: crate::config::Prefix6,
+    pub delegated_length: u8,
+}
+
+impl PrefixPool {
+    /// The number of prefixes available for delegation, saturating for absurdly large pools.
+    fn len(&self) -> u128 {
+        1u128
+            .checked_shl(u32::from(self.delegated_length - self.prefix.prefixlen))
+            .unwrap_or(u128::MAX)
+    }
+
+    /// Returns the `offset`th delegatable prefix in this pool.
+    fn nth(&self, offset: u128) -> std::net::Ipv6Addr {
+        use crate::config::PrefixOps as _;
+        let shift = 128 - u32::from(self.delegated_length);
+        (u128::from(self.prefix.network()) | offset.checked_shl(shift).unwrap_or(0)).into()
+    }
+
+    pub fn contains(&self, prefix: std::net::Ipv6Addr, prefixlen: u8) -> bool {
+        use crate::config::Match as _;
+        use crate::config::PrefixOps as _;
+        prefixlen == self.delegated_length
+            && self.prefix.contains(prefix)
+            && crate::config::Prefix6::new(prefix, prefixlen).network() == prefix
+    }
+}
+
+#[derive(Debug)]
+pub enum LeaseType {
+    NewAddress,
+    ReusingLease,
+    Requested,
+    Revived,
+}
+
+#[derive(Debug)]
+pub struct Lease {
+    pub ip: std::net::Ipv6Addr,
+    pub expire: std::time::Duration,
+    pub lease_type: LeaseType,
+}
+
+#[derive(Ord, PartialOrd, Eq, PartialEq, Debug)]
+pub struct LeaseInfo {
+    pub ip: std::net::Ipv6Addr,
+    pub duid: Vec<u8>,
+    pub iaid: u32,
+    pub start: u32,
+    pub expire: u32,
+}
+
+#[derive(Debug)]
+pub struct Delegation {
+    pub prefix: std::net::Ipv6Addr,
+    pub prefixlen: u8,
+    pub expire: std::time::Duration,
+    pub lease_type: LeaseType,
+}
+
+#[derive(Ord, PartialOrd, Eq, PartialEq, Debug)]
+pub struct DelegationInfo {
+    pub prefix: std::net::Ipv6Addr,
+    pub prefixlen: u8,
+    pub duid: Vec<u8>,
+    pub iaid: u32,
+    /// The router the prefix is delegated to, and the interface it's on.
+    pub nexthop: std::net::Ipv6Addr,
+    pub ifindex: u32,
+    pub start: u32,
+    pub expire: u32,
+}
+
+/// The (DUID, IAID) of the client holding an address, and when it expires.
+type Holder = (Option<Vec<u8>>, Option<u32>, u32);
+
+pub struct Pool {
+    conn: rusqlite::Connection,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Error {
+    DbError(String),
+    NoAssignableAddress,
+    NoAssignablePrefix,
+    UnknownLease,
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::DbError(reason) => write!(f, "{}", reason),
+            Error::NoAssignableAddress => write!(f, "No Assignable Address"),
+            Error::NoAssignablePrefix => write!(f, "No Assignable Prefix"),
+            Error::UnknownLease => write!(f, "No matching lease"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl Error {
+    fn emit(reason: &str, e: &rusqlite::Error) -> Error {
+        Error::DbError(format!("{} ({})", reason, e))
+    }
+}
+
+fn calculate_hash<S: Hash, T: Hash>(s: &S, t: &T) -> u64 {
+    let mut h = DefaultHasher::new();
+    s.hash(&mut h);
+    t.hash(&mut h);
+    h.finish()
+}
+
+pub(crate) fn now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::SystemTime::UNIX_EPOCH)
+        .expect("clock failure")
+        .as_secs()
+}
+
+impl Pool {
+    // As with the DHCPv4 pool:
+    // - upgrade_schema_from_no_version should install the latest schema
+    //   directly so that new installations need not go through the
+    //   upgrade chain.
+    // - upgrade_schema_from_version_* should perform upgrades one
+    //   version at a time since that is the only thing that is tested.
+
+    fn create_delegations_table(&self) -> Result<(), Error> {
+        self.conn
+            .execute(
+                "CREATE TABLE delegations6 (
+                prefix TEXT NOT NULL,
+                prefixlen INTEGER NOT NULL,
+                duid BLOB NOT NULL,
+                iaid INTEGER NOT NULL,
+                nexthop TEXT NOT NULL,
+                ifindex INTEGER NOT NULL,
+                start INTEGER NOT NULL,
+                expiry INTEGER NOT NULL,
+                PRIMARY KEY (prefix, prefixlen)
+              )",
+                rusqlite::params![],
+            )
+            .map_err(|e| Error::emit("Creating table delegations6", &e))?;
+        Ok(())
+    }
+
+    fn upgrade_schema_from_no_version(&self) -> Result<usize, Error> {
+        self.conn
+            .execute(
+                "CREATE TABLE leases6 (
+                address TEXT NOT NULL,
+                duid BLOB,
+                iaid INTEGER,
+                start INTEGER NOT NULL,
+                expiry INTEGER NOT NULL,
+                PRIMARY KEY (address)
+              )",
+                rusqlite::params![],
+            )
+            .map_err(|e| Error::emit("Creating table leases6", &e))?;
+        self.conn
+            .execute(
+                "CREATE TABLE serverid6 (
+                duid BLOB NOT NULL
+              )",
+                rusqlite::params![],
+            )
+            .map_err(|e| Error::emit("Creating table serverid6", &e))?;
+        self.create_delegations_table()?;
+        Ok(2)
+    }
+
+    fn upgrade_schema_from_version_1(&self) -> Result<usize, Error> {
+        self.create_delegations_table()?;
+        Ok(2)
+    }
+
+    fn setup_db(self) -> Result<Self, Error> {
+        // The schema_version table is shared with the DHCPv4 pool, which uses the "pool" key.
+        const DB_SCHEMA_KEY: &str = "dhcp6";
+
+        self.conn
+            .execute(
+                "CREATE TABLE IF NOT EXISTS schema_version (
+                    key TEXT NOT NULL,
+                    version INTEGER NOT NULL,
+                    PRIMARY KEY (key)
+                )",
+                rusqlite::params![],
+            )
+            .map_err(|e| Error::emit("Creating table schema_version", &e))?;
+
+        loop {
+            let upgraded_to_version = match self.conn
+                .query_row(
+                    "SELECT version FROM schema_version
+                        WHERE key = ?1",
+                    rusqlite::params![DB_SCHEMA_KEY],
+                    |row| row.get(0),
+                )
+                .optional()
+                .map_err(|e| Error::emit("Querying schema version", &e))?
+            {
+                None => self.upgrade_schema_from_no_version()?,
+                Some(1) => self.upgrade_schema_from_version_1()?,
+                Some(2) => break,  // up to date
+                Some(v) => return Err(Error::DbError(format!(
+                    "DHCPv6 lease database has version {} which is newer than 2, the newest supported version",
+                    v
+                ))),
+            };
+            self.conn
+                .execute(
+                    "INSERT OR REPLACE INTO schema_version (key, version)
+                 VALUES (?1, ?2)",
+                    rusqlite::params![DB_SCHEMA_KEY, upgraded_to_version],
+                )
+                .map_err(|e| Error::emit("Creating updating schema version", &e))?;
+        }
+        Ok(self)
+    }
+
+    fn new_with_conn(conn: rusqlite::Connection) -> Result<Self, Error> {
+        /* The DHCPv4 pool may have this database open at the same time */
+        conn.busy_timeout(std::time::Duration::from_secs(5))
+            .map_err(|e| Error::emit("Setting busy timeout", &e))?;
+        Pool { conn }.setup_db()
+    }
+
+    pub fn new_in_memory() -> Result<Pool, Error> {
+        let conn = rusqlite::Connection::open_in_memory()
+            .map_err(|e| Error::emit("Creating database in memory database", &e))?;
+
+        Self::new_with_conn(conn)
+    }
+
+    /// Opens the lease database for the configured lease store.
+    ///
+    /// DHCPv6 leases are kept in the same database as DHCPv4 leases, so this fails if the lease
+    /// store doesn't have one.
+    pub fn new(conf: &crate::config::Config) -> Result<Pool, Error> {
+        let conn = crate::dhcp::store::open_database(&conf.state_directory, conf.lease_store)
+            .map_err(|e| Error::DbError(e.to_string()))?;
+
+        Self::new_with_conn(conn)
+    }
+
+    /// Returns this server's DUID, creating and persisting one the first time it's needed.
+    ///
+    /// We use a DUID-UUID (RFC6355) as it doesn't depend on any particular interface being
+    /// present, and stays stable across restarts.
+    pub fn get_server_duid(&mut self) -> Result<Vec<u8>, Error> {
+        if let Some(duid) = self
+            .conn
+            .query_row("SELECT duid FROM serverid6 LIMIT 1", [], |row| row.get(0))
+            .optional()
+            .map_err(|e| Error::emit("Querying server DUID", &e))?
+        {
+            return Ok(duid);
+        }
+        let mut duid = vec![0x00, 0x04]; /* DUID-UUID */
+        duid.extend(rand::random::<[u8; 16]>());
+        self.conn
+            .execute(
+                "INSERT INTO serverid6 (duid) VALUES (?1)",
+                rusqlite::params![duid],
+            )
+            .map_err(|e| Error::emit("Storing server DUID", &e))?;
+        Ok(duid)
+    }
+
+    pub fn get_pool_metrics(&mut self) -> Result<(u32, u32), Error> {
+        self.conn
+            .query_row(
+                "SELECT
+             COALESCE(SUM(CASE WHEN expiry >= ?1 THEN 1 ELSE 0 END), 0) as active,
+             COALESCE(SUM(CASE WHEN expiry < ?1 THEN 1 ELSE 0 END), 0) as expired
+             FROM leases6",
+                rusqlite::params![now() as u32],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .map_err(|e| Error::DbError(e.to_string()))
+    }
+
+    pub fn get_leases(&mut self) -> Result<Vec<LeaseInfo>, Error> {
+        self.conn
+            .prepare_cached("SELECT address, duid, iaid, start, expiry FROM leases6")
+            .map_err(|e| Error::DbError(e.to_string()))?
+            .query_map([], |row| {
+                Ok(LeaseInfo {
+                    ip: row
+                        .get::<_, String>(0)?
+                        .parse::<std::net::Ipv6Addr>()
+                        .map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))?,
+                    /* Quarantined addresses have no client */
+                    duid: row.get::<usize, Option<Vec<u8>>>(1)?.unwrap_or_default(),
+                    iaid: row.get::<usize, Option<u32>>(2)?.unwrap_or_default(),
+                    start: row.get(3)?,
+                    expire: row.get(4)?,
+                })
+            })
+            .map_err(|e| Error::DbError(e.to_string()))?
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| Error::DbError(e.to_string()))
+    }
+
+    /// Returns who (if anyone) has a row for this address, and when it expires.
+    fn get_holder(&mut self, addr: std::net::Ipv6Addr) -> Result<Option<Holder>, Error> {
+        self.conn
+            .query_row(
+                "SELECT duid, iaid, expiry FROM leases6 WHERE address = ?1",
+                rusqlite::params![addr.to_string()],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+            )
+            .optional()
+            .map_err(|e| Error::emit("Querying address holder", &e))
+    }
+
+    /// Returns true if this address isn't being used by anyone other than this IA.
+    fn is_available(
+        &mut self,
+        duid: &[u8],
+        iaid: u32,
+        addr: std::net::Ipv6Addr,
+        allow_expired: bool,
+    ) -> Result<bool, Error> {
+        Ok(match self.get_holder(addr)? {
+            None => true,
+            Some((Some(d), Some(i), _)) if d == duid && i == iaid => true,
+            Some((_, _, expiry)) => allow_expired && u64::from(expiry) < now(),
+        })
+    }
+
+    /// Returns the address most recently bound to this IA, if any.
+    pub fn get_binding(
+        &mut self,
+        duid: &[u8],
+        iaid: u32,
+    ) -> Result<Option<(std::net::Ipv6Addr, u32)>, Error> {
+        self.conn
+            .query_row(
+                "SELECT address, expiry FROM leases6
+                 WHERE duid = ?1 AND iaid = ?2
+                 ORDER BY expiry DESC
+                 LIMIT 1",
+                rusqlite::params![duid, iaid],
+                |row| Ok((row.get::<_, String>(0)?, row.get(1)?)),
+            )
+            .optional()
+            .map_err(|e| Error::emit("Querying binding", &e))?
+            .map(|(addr, expiry)| {
+                addr.parse()
+                    .map(|addr| (addr, expiry))
+                    .map_err(|e| Error::DbError(format!("Corrupt address {}: {}", addr, e)))
+            })
+            .transpose()
+    }
+
+    fn select_address(
+        &mut self,
+        duid: &[u8],
+        iaid: u32,
+        requested: Option<std::net::Ipv6Addr>,
+        addresses: &PoolAddresses,
+    ) -> Result<(std::net::Ipv6Addr, LeaseType), Error> {
+        /* RFC8415 Section 18.3.2: Prefer giving a client back the address it already has */
+        if let Some((addr, expiry)) = self.get_binding(duid, iaid)? {
+            if addresses.contains(addr) {
+                if u64::from(expiry) >= now() {
+                    return Ok((addr, LeaseType::ReusingLease));
+                } else {
+                    return Ok((addr, LeaseType::Revived));
+                }
+            }
+        }
+
+        /* The client may be hinting at an address it would like */
+        if let Some(addr) = requested {
+            if addresses.contains(addr) && self.is_available(duid, iaid, addr, true)? {
+                return Ok((addr, LeaseType::Requested));
+            }
+        }
+
+        /* Otherwise pick an address deterministically based on the IA, so a client that lost its
+         * lease tends to end up with the same address again.
+         */
+        let len = addresses.len();
+        if len == 0 {
+            return Err(Error::NoAssignableAddress);
+        }
+        let start = u128::from(calculate_hash(&duid, &iaid)) % len;
+        for probe in 0..std::cmp::min(MAX_PROBES, len) {
+            if let Some(addr) = addresses.nth((start + probe) % len) {
+                if addresses.contains(addr) && self.is_available(duid, iaid, addr, false)? {
+                    return Ok((addr, LeaseType::NewAddress));
+                }
+            }
+        }
+
+        /* Finally, steal the oldest expired lease that's in this pool. */
+        let mut stmt = self
+            .conn
+            .prepare_cached(
+                "SELECT address FROM leases6
+                 WHERE expiry < ?1
+                 ORDER BY expiry ASC",
+            )
+            .map_err(|e| Error::emit("Preparing expired lease query", &e))?;
+        let expired = stmt
+            .query_map(rusqlite::params![now() as u32], |row| {
+                row.get::<_, String>(0)
+            })
+            .map_err(|e| Error::emit("Querying expired leases", &e))?;
+        for addr in expired {
+            let addr = addr.map_err(|e| Error::emit("Reading expired lease", &e))?;
+            if let Ok(addr) = addr.parse() {
+                if addresses.contains(addr) {
+                    return Ok((addr, LeaseType::Revived));
+                }
+            }
+        }
+
+        Err(Error::NoAssignableAddress)
+    }
+
+    /// Finds (or reuses) an address for the IA identified by (`duid`, `iaid`) and records it.
+    pub fn allocate_address(
+        &mut self,
+        duid: &[u8],
+        iaid: u32,
+        requested: Option<std::net::Ipv6Addr>,
+        addresses: &PoolAddresses,
+        valid: std::time::Duration,
+    ) -> Result<Lease, Error> {
+        let (ip, lease_type) = self.select_address(duid, iaid, requested, addresses)?;
+        let ts = now();
+
+        self.conn
+            .execute(
+                "INSERT OR REPLACE
+                 INTO leases6 (address, duid, iaid, start, expiry)
+                 VALUES (?1, ?2, ?3, ?4, ?5)",
+                rusqlite::params![
+                    ip.to_string(),
+                    duid,
+                    iaid,
+                    ts as u32,
+                    (ts + valid.as_secs()) as u32,
+                ],
+            )
+            .map_err(|e| Error::DbError(format!("Failed to update lease: {}", e)))?;
+
+        Ok(Lease {
+            ip,
+            expire: valid,
+            lease_type,
+        })
+    }
+
+    /// Picks the address allocate_address would give the IA, without recording it.
+    ///
+    /// This is used for SOLICITs (RFC8415 Section 18.3.9), so that clients that never send a
+    /// REQUEST, or pick some other server, don't hold on to addresses.
+    pub fn offer_address(
+        &mut self,
+        duid: &[u8],
+        iaid: u32,
+        requested: Option<std::net::Ipv6Addr>,
+        addresses: &PoolAddresses,
+        valid: std::time::Duration,
+    ) -> Result<Lease, Error> {
+        let (ip, lease_type) = self.select_address(duid, iaid, requested, addresses)?;
+        Ok(Lease {
+            ip,
+            expire: valid,
+            lease_type,
+        })
+    }
+
+    /// Expires the binding of `addr` to this IA immediately (RFC8415 Section 18.3.7).
+    pub fn release_address(
+        &mut self,
+        duid: &[u8],
+        iaid: u32,
+        addr: std::net::Ipv6Addr,
+    ) -> Result<(), Error> {
+        let ts = now();
+
+        let updated = self
+            .conn
+            .execute(
+                "UPDATE leases6
+                 SET expiry = ?1, start = MIN(start, ?1)
+                 WHERE address = ?2
+                 AND duid = ?3
+                 AND iaid = ?4
+                 AND expiry >= ?5",
+                rusqlite::params![(ts - 1) as u32, addr.to_string(), duid, iaid, ts as u32],
+            )
+            .map_err(|e| Error::DbError(format!("Failed to release lease: {}", e)))?;
+
+        if updated == 0 {
+            Err(Error::UnknownLease)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Withholds `addr` from allocation for `quarantine` as the client found it already in use
+    /// (RFC8415 Section 18.3.8).
+    pub fn decline_address(
+        &mut self,
+        duid: &[u8],
+        iaid: u32,
+        addr: std::net::Ipv6Addr,
+        quarantine: std::time::Duration,
+    ) -> Result<(), Error> {
+        let ts = now();
+
+        let updated = self
+            .conn
+            .execute(
+                "UPDATE leases6
+                 SET duid = NULL, iaid = NULL, start = ?1, expiry = ?2
+                 WHERE address = ?3
+                 AND duid = ?4
+                 AND iaid = ?5",
+                rusqlite::params![
+                    ts as u32,
+                    (ts + quarantine.as_secs()) as u32,
+                    addr.to_string(),
+                    duid,
+                    iaid
+                ],
+            )
+            .map_err(|e| Error::DbError(format!("Failed to quarantine address: {}", e)))?;
+
+        if updated == 0 {
+            Err(Error::UnknownLease)
+        } else {
+            Ok(())
+        }
+    }
+
+    pub fn get_delegations(&mut self) -> Result<Vec<DelegationInfo>, Error> {
+        self.conn
+            .prepare_cached(
+                "SELECT prefix, prefixlen, duid, iaid, nexthop, ifindex, start, expiry
+                 FROM delegations6",
+            )
+            .map_err(|e| Error::DbError(e.to_string()))?
+            .query_map([], |row| {
+                let parse = |s: String| {
+                    s.parse::<std::net::Ipv6Addr>()
+                        .map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))
+                };
+                Ok(DelegationInfo {
+                    prefix: parse(row.get(0)?)?,
+                    prefixlen: row.get(1)?,
+                    duid: row.get(2)?,
+                    iaid: row.get(3)?,
+                    nexthop: parse(row.get(4)?)?,
+                    ifindex: row.get(5)?,
+                    start: row.get(6)?,
+                    expire: row.get(7)?,
+                })
+            })
+            .map_err(|e| Error::DbError(e.to_string()))?
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| Error::DbError(e.to_string()))
+    }
+
+    /// Returns the prefixes most recently delegated to this IA_PD.
+    fn get_prefix_bindings(
+        &mut self,
+        duid: &[u8],
+        iaid: u32,
+    ) -> Result<Vec<(std::net::Ipv6Addr, u8, u32)>, Error> {
+        self.conn
+            .prepare_cached(
+                "SELECT prefix, prefixlen, expiry FROM delegations6
+                 WHERE duid = ?1 AND iaid = ?2
+                 ORDER BY expiry DESC",
+            )
+            .map_err(|e| Error::emit("Preparing delegation query", &e))?
+            .query_map(rusqlite::params![duid, iaid], |row| {
+                Ok((row.get::<_, String>(0)?, row.get(1)?, row.get(2)?))
+            })
+            .map_err(|e| Error::emit("Querying delegations", &e))?
+            .filter_map(|row| match row {
+                Ok((prefix, prefixlen, expiry)) => prefix
+                    .parse()
+                    .ok()
+                    .map(|prefix| Ok((prefix, prefixlen, expiry))),
+                Err(e) => Some(Err(Error::emit("Reading delegation", &e))),
+            })
+            .collect()
+    }
+
+    pub fn has_prefix_binding(&mut self, duid: &[u8], iaid: u32) -> Result<bool, Error> {
+        Ok(!self.get_prefix_bindings(duid, iaid)?.is_empty())
+    }
+
+    /// Returns true if this prefix isn't delegated to anyone other than this IA_PD.
+    fn is_prefix_available(
+        &mut self,
+        duid: &[u8],
+        iaid: u32,
+        prefix: std::net::Ipv6Addr,
+        prefixlen: u8,
+    ) -> Result<bool, Error> {
+        Ok(
+            match self
+                .conn
+                .query_row(
+                    "SELECT duid, iaid, expiry FROM delegations6
+                     WHERE prefix = ?1 AND prefixlen = ?2",
+                    rusqlite::params![prefix.to_string(), prefixlen],
+                    |row| {
+                        Ok((
+                            row.get::<_, Vec<u8>>(0)?,
+                            row.get::<_, u32>(1)?,
+                            row.get::<_, u32>(2)?,
+                        ))
+                    },
+                )
+                .optional()
+                .map_err(|e| Error::emit("Querying prefix holder", &e))?
+            {
+                None => true,
+                Some((d, i, _)) if d == duid && i == iaid => true,
+                Some((_, _, expiry)) => u64::from(expiry) < now(),
+            },
+        )
+    }
+
+    fn select_prefix(
+        &mut self,
+        duid: &[u8],
+        iaid: u32,
+        requested: Option<(std::net::Ipv6Addr, u8)>,
+        prefixes: &PrefixPool,
+    ) -> Result<(std::net::Ipv6Addr, LeaseType), Error> {
+        /* Prefer giving a router back the prefix it already has, renumbering downstream networks
+         * is painful.
+         */
+        for (prefix, prefixlen, expiry) in self.get_prefix_bindings(duid, iaid)? {
+            if prefixes.contains(prefix, prefixlen) {
+                if u64::from(expiry) >= now() {
+                    return Ok((prefix, LeaseType::ReusingLease));
+                } else {
+                    return Ok((prefix, LeaseType::Revived));
+                }
+            }
+        }
+
+        if let Some((prefix, prefixlen)) = requested {
+            if prefixes.contains(prefix, prefixlen)
+                && self.is_prefix_available(duid, iaid, prefix, prefixlen)?
+            {
+                return Ok((prefix, LeaseType::Requested));
+            }
+        }
+
+        let len = prefixes.len();
+        let start = u128::from(calculate_hash(&duid, &iaid)) % len;
+        for probe in 0..std::cmp::min(MAX_PROBES, len) {
+            let prefix = prefixes.nth((start + probe) % len);
+            if self.is_prefix_available(duid, iaid, prefix, prefixes.delegated_length)? {
+                return Ok((prefix, LeaseType::NewAddress));
+            }
+        }
+
+        Err(Error::NoAssignablePrefix)
+    }
+
+    /// Delegates a prefix from `prefixes` to the router identified by (`duid`, `iaid`), which can
+    /// be reached via `nexthop` on `ifindex`.
+    #[allow(clippy::too_many_arguments)]
+    pub fn allocate_prefix(
+        &mut self,
+        duid: &[u8],
+        iaid: u32,
+        requested: Option<(std::net::Ipv6Addr, u8)>,
+        prefixes: &PrefixPool,
+        valid: std::time::Duration,
+        nexthop: std::net::Ipv6Addr,
+        ifindex: u32,
+    ) -> Result<Delegation, Error> {
+        let (prefix, lease_type) = self.select_prefix(duid, iaid, requested, prefixes)?;
+        let ts = now();
+
+        self.conn
+            .execute(
+                "INSERT OR REPLACE
+                 INTO delegations6 (prefix, prefixlen, duid, iaid, nexthop, ifindex, start, expiry)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+                rusqlite::params![
+                    prefix.to_string(),
+                    prefixes.delegated_length,
+                    duid,
+                    iaid,
+                    nexthop.to_string(),
+                    ifindex,
+                    ts as u32,
+                    (ts + valid.as_secs()) as u32,
+                ],
+            )
+            .map_err(|e| Error::DbError(format!("Failed to update delegation: {}", e)))?;
+
+        Ok(Delegation {
+            prefix,
+            prefixlen: prefixes.delegated_length,
+            expire: valid,
+            lease_type,
+        })
+    }
+
+    /// Picks the prefix allocate_prefix would delegate to the IA_PD, without recording it.
+    ///
+    /// As with offer_address, this is for SOLICITs, so advertised prefixes are neither held nor
+    /// routed until the router REQUESTs them.
+    pub fn offer_prefix(
+        &mut self,
+        duid: &[u8],
+        iaid: u32,
+        requested: Option<(std::net::Ipv6Addr, u8)>,
+        prefixes: &PrefixPool,
+        valid: std::time::Duration,
+    ) -> Result<Delegation, Error> {
+        let (prefix, lease_type) = self.select_prefix(duid, iaid, requested, prefixes)?;
+        Ok(Delegation {
+            prefix,
+            prefixlen: prefixes.delegated_length,
+            expire: valid,
+            lease_type,
+        })
+    }
+
+    /// Expires a delegation immediately, returning where it was routed to.
+    pub fn release_prefix(
+        &mut self,
+        duid: &[u8],
+        iaid: u32,
+        prefix: std::net::Ipv6Addr,
+        prefixlen: u8,
+    ) -> Result<(std::net::Ipv6Addr, u32), Error> {
+        let ts = now();
+        let key = rusqlite::params![prefix.to_string(), prefixlen, duid, iaid, ts as u32];
+        let route = self
+            .conn
+            .query_row(
+                "SELECT nexthop, ifindex FROM delegations6
+                 WHERE prefix = ?1 AND prefixlen = ?2 AND duid = ?3 AND iaid = ?4
+                 AND expiry >= ?5",
+                key,
+                |row| Ok((row.get::<_, String>(0)?, row.get::<_, u32>(1)?)),
+            )
+            .optional()
+            .map_err(|e| Error::emit("Querying delegation", &e))?
+            .ok_or(Error::UnknownLease)?;
+
+        self.conn
+            .execute(
+                "UPDATE delegations6
+                 SET expiry = ?5 - 1, start = MIN(start, ?5 - 1)
+                 WHERE prefix = ?1 AND prefixlen = ?2 AND duid = ?3 AND iaid = ?4",
+                key,
+            )
+            .map_err(|e| Error::DbError(format!("Failed to release delegation: {}", e)))?;
+
+        route
+            .0
+            .parse()
+            .map(|nexthop| (nexthop, route.1))
+            .map_err(|e| Error::DbError(format!("Corrupt nexthop {}: {}", route.0, e)))
+    }
+}
+
+#[cfg(test)]
+fn test_addresses(start: &str, end: &str) -> PoolAddresses {
+    PoolAddresses {
+        ranges: vec![start.parse().unwrap()..=end.parse().unwrap()],
+        excluded: vec![],
+    }
+}
+
+#[test]
+fn server_duid_is_stable() {
+    let mut p = Pool::new_in_memory().expect("Failed to create pool");
+    let duid = p.get_server_duid().expect("Failed to create DUID");
+    assert_eq!(duid.len(), 18);
+    assert_eq!(p.get_server_duid().unwrap(), duid);
+}
+
+#[test]
+fn allocate_and_reacquire() {
+    let mut p = Pool::new_in_memory().expect("Failed to create pool");
+    let addrs = test_addresses("2001:db8::100", "2001:db8::1ff");
+    let lease = p
+        .allocate_address(&[1, 2, 3], 1, None, &addrs, DEFAULT_VALID_LIFETIME)
+        .expect("Failed to allocate");
+    assert!(addrs.contains(lease.ip));
+    let again = p
+        .allocate_address(&[1, 2, 3], 1, None, &addrs, DEFAULT_VALID_LIFETIME)
+        .expect("Failed to reallocate");
+    assert_eq!(lease.ip, again.ip);
+    /* A different IA from the same client gets a different address */
+    let other = p
+        .allocate_address(&[1, 2, 3], 2, None, &addrs, DEFAULT_VALID_LIFETIME)
+        .expect("Failed to allocate second IA");
+    assert_ne!(lease.ip, other.ip);
+    assert_eq!(p.get_pool_metrics().unwrap(), (2, 0));
+}
+
+#[test]
+fn requested_address() {
+    let mut p = Pool::new_in_memory().expect("Failed to create pool");
+    let addrs = test_addresses("2001:db8::100", "2001:db8::1ff");
+    let want = "2001:db8::123".parse().unwrap();
+    let lease = p
+        .allocate_address(&[1], 1, Some(want), &addrs, DEFAULT_VALID_LIFETIME)
+        .expect("Failed to allocate");
+    assert_eq!(lease.ip, want);
+    /* Someone else asking for the same address doesn't get it */
+    let lease = p
+        .allocate_address(&[2], 1, Some(want), &addrs, DEFAULT_VALID_LIFETIME)
+        .expect("Failed to allocate");
+    assert_ne!(lease.ip, want);
+}
+
+#[test]
+fn exhausted_pool() {
+    let mut p = Pool::new_in_memory().expect("Failed to create pool");
+    let addrs = test_addresses("2001:db8::1", "2001:db8::2");
+    p.allocate_address(&[1], 1, None, &addrs, DEFAULT_VALID_LIFETIME)
+        .expect("Failed to allocate");
+    p.allocate_address(&[2], 1, None, &addrs, DEFAULT_VALID_LIFETIME)
+        .expect("Failed to allocate");
+    assert_eq!(
+        p.allocate_address(&[3], 1, None, &addrs, DEFAULT_VALID_LIFETIME)
+            .map(|l| l.ip),
+        Err(Error::NoAssignableAddress)
+    );
+}
+
+#[test]
+fn release_and_decline() {
+    let mut p = Pool::new_in_memory().expect("Failed to create pool");
+    let addrs = test_addresses("2001:db8::1", "2001:db8::1");
+    let lease = p
+        .allocate_address(&[1], 1, None, &addrs, DEFAULT_VALID_LIFETIME)
+        .expect("Failed to allocate");
+    p.release_address(&[1], 1, lease.ip)
+        .expect("Failed to release");
+    assert_eq!(
+        p.release_address(&[1], 1, lease.ip),
+        Err(Error::UnknownLease)
+    );
+    /* Released addresses can be reused by another client */
+    let lease = p
+        .allocate_address(&[2], 1, None, &addrs, DEFAULT_VALID_LIFETIME)
+        .expect("Failed to allocate released address");
+    p.decline_address(&[2], 1, lease.ip, DEFAULT_DECLINE_QUARANTINE)
+        .expect("Failed to decline");
+    /* But declined addresses can't */
+    assert_eq!(
+        p.allocate_address(&[3], 1, None, &addrs, DEFAULT_VALID_LIFETIME)
+            .map(|l| l.ip),
+        Err(Error::NoAssignableAddress)
+    );
+}
+
+#[test]
+fn shares_database_with_dhcp4() {
+    /* Both pools share the same schema_version table, and must not trample each other */
+    let conn = rusqlite::Connection::open_in_memory().unwrap();
+    let p = Pool::new_with_conn(conn).expect("Failed to create pool");
+    p.conn
+        .execute(
+            "INSERT INTO schema_version (key, version) VALUES ('pool', 1)",
+            [],
+        )
+        .unwrap();
+    let p = p.setup_db().expect("Failed to reopen pool");
+    let version: u32 = p
+        .conn
+        .query_row(
+            "SELECT version FROM schema_version WHERE key = 'dhcp6'",
+            [],
+            |row| row.get(0),
+        )
+        .unwrap();
+    assert_eq!(version, 2);
+}
+
+#[test]
+fn schema_upgrade_from_version_1() {
+    let conn = rusqlite::Connection::open_in_memory().unwrap();
+    conn.execute_batch(
+        "CREATE TABLE schema_version (key TEXT NOT NULL, version INTEGER NOT NULL, PRIMARY KEY (key));
+         INSERT INTO schema_version (key, version) VALUES ('dhcp6', 1);
+         CREATE TABLE leases6 (address TEXT NOT NULL, duid BLOB, iaid INTEGER,
+            start INTEGER NOT NULL, expiry INTEGER NOT NULL, PRIMARY KEY (address));
+         CREATE TABLE serverid6 (duid BLOB NOT NULL);",
+    )
+    .expect("Failed to set up test database with old schema");
+    let mut p = Pool::new_with_conn(conn).expect("Failed to upgrade pool");
+    assert_eq!(p.get_delegations().unwrap(), vec![]);
+}
+
+#[cfg(test)]
+fn test_prefix_pool(prefix: &str, prefixlen: u8, delegated_length: u8) -> PrefixPool {
+    PrefixPool {
+        prefix: crate::config::Prefix6::new(prefix.parse().unwrap(), prefixlen),
+        delegated_length,
+    }
+}
+
+#[test]
+fn delegate_prefix() {
+    let mut p = Pool::new_in_memory().expect("Failed to create pool");
+    let pool = test_prefix_pool("2001:db8:100::", 56, 60);
+    let router = "fe80::1".parse().unwrap();
+    let d = p
+        .allocate_prefix(&[1], 1, None, &pool, DEFAULT_VALID_LIFETIME, router, 2)
+        .expect("Failed to delegate prefix");
+    assert_eq!(d.prefixlen, 60);
+    assert!(pool.contains(d.prefix, d.prefixlen));
+    /* Asking again gets the same prefix back */
+    let again = p
+        .allocate_prefix(&[1], 1, None, &pool, DEFAULT_VALID_LIFETIME, router, 2)
+        .expect("Failed to delegate prefix");
+    assert_eq!(again.prefix, d.prefix);
+    /* The pool has room for exactly 16 /60s */
+    for i in 2..=16 {
+        p.allocate_prefix(&[i], 1, None, &pool, DEFAULT_VALID_LIFETIME, router, 2)
+            .expect("Failed to delegate prefix");
+    }
+    assert_eq!(
+        p.allocate_prefix(&[17], 1, None, &pool, DEFAULT_VALID_LIFETIME, router, 2)
+            .map(|d| d.prefix),
+        Err(Error::NoAssignablePrefix)
+    );
+    let delegations = p.get_delegations().unwrap();
+    assert_eq!(delegations.len(), 16);
+    assert!(delegations
+        .iter()
+        .all(|d| d.nexthop == router && d.ifindex == 2));
+    /* Once released, it can be delegated to someone else */
+    assert_eq!(
+        p.release_prefix(&[1], 1, d.prefix, d.prefixlen),
+        Ok((router, 2))
+    );
+    assert_eq!(
+        p.release_prefix(&[1], 1, d.prefix, d.prefixlen),
+        Err(Error::UnknownLease)
+    );
+    assert_eq!(
+        p.allocate_prefix(&[17], 1, None, &pool, DEFAULT_VALID_LIFETIME, router, 2)
+            .map(|d| d.prefix),
+        Ok(d.prefix)
+    );
+}
+
+#[test]
+fn delegate_requested_prefix() {
+    let mut p = Pool::new_in_memory().expect("Failed to create pool");
+    let pool = test_prefix_pool("2001:db8:100::", 56, 64);
+    let want = "2001:db8:100:42::".parse().unwrap();
+    let router = "fe80::1".parse().unwrap();
+    let d = p
+        .allocate_prefix(
+            &[1],
+            1,
+            Some((want, 64)),
+            &pool,
+            DEFAULT_VALID_LIFETIME,
+            router,
+            2,
+        )
+        .expect("Failed to delegate prefix");
+    assert_eq!(d.prefix, want);
+    /* A misaligned or wrongly sized hint is ignored */
+    let d = p
+        .allocate_prefix(
+            &[2],
+            1,
+            Some(("2001:db8:100:43::1".parse().unwrap(), 64)),
+            &pool,
+            DEFAULT_VALID_LIFETIME,
+            router,
+            2,
+        )
+        .expect("Failed to delegate prefix");
+    assert_ne!(
+        d.prefix,
+        "2001:db8:100:43::1".parse::<std::net::Ipv6Addr>().unwrap()
+    );
+    assert!(pool.contains(d.prefix, 64));
+}
+
+#[test]
+fn subnet_range() {
+    let range = PoolAddresses::subnet_range(&crate::config::Prefix6::new(
+        "2001:db8::".parse().unwrap(),
+        64,
+    ));
+    assert_eq!(
+        range,
+        "2001:db8::1".parse().unwrap()..="2001:db8::ffff:ffff:ffff:ffff".parse().unwrap()
+    );
+}
@@ -0,0 +1,735 @@
+/*   Copyright 2023 Perry Lorier
+ *
+ *  Licensed under the Apache License, Version 2.0 (the "License");
+ *  you may not use this file except in compliance with the License.
+ *  You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ *  Unless required by applicable law or agreed to in writing, software
+ *  distributed under the License is distributed on an "AS IS" BASIS,
+ *  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ *  See the License for the specific language governing permissions and
+ *  limitations under the License.
+ *
+ *  SPDX-License-Identifier: Apache-2.0
+ *
+ *  Tests for DHCPv6 functionality.
+ */
+
+use crate::dhcp6;
+use crate::dhcp6::dhcp6pkt;
+use crate::dhcp6::pool;
+use std::net;
+
+const SERVERID: &[u8] = &[
+    0x00, 0x04, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16,
+];
+const OTHER_SERVERID: &[u8] = &[0x00, 0x04, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1];
+const CLIENTID: &[u8] = &[0x00, 0x03, 0x00, 0x01, 0x00, 0x00, 0x5E, 0x00, 0x53, 0x00];
+const IAID: u32 = 1;
+const ROUTER: net::Ipv6Addr = net::Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 1);
+
+fn mk_config() -> crate::config::SharedConfig {
+    crate::config::load_config_from_string_for_test(
+        "---
+dhcp6-policies:
+  - match-interface: eth0
+    apply-range: {start: 2001:db8::100, end: 2001:db8::1ff}
+    apply-preferred-lifetime: 1h
+    apply-valid-lifetime: 2h
+",
+    )
+    .expect("Failed to parse test config")
+}
+
+fn mk_request(msgtype: dhcp6pkt::MessageType, addr: Option<net::Ipv6Addr>) -> dhcp6::Dhcp6Request {
+    let mut options = dhcp6pkt::Dhcp6Options::default()
+        .add_option(&dhcp6pkt::OPTION_CLIENTID, &CLIENTID)
+        .add_option(
+            &dhcp6pkt::OPTION_IA_NA,
+            &dhcp6pkt::IaNa {
+                iaid: IAID,
+                t1: 0,
+                t2: 0,
+                options: dhcp6pkt::Dhcp6Options::default().maybe_add_option(
+                    &dhcp6pkt::OPTION_IAADDR,
+                    addr.map(|addr| dhcp6pkt::IaAddr {
+                        addr,
+                        preferred: 0,
+                        valid: 0,
+                        options: Default::default(),
+                    })
+                    .as_ref(),
+                ),
+            },
+        );
+    if msgtype != dhcp6pkt::SOLICIT && msgtype != dhcp6pkt::REBIND {
+        options = options.add_option(&dhcp6pkt::OPTION_SERVERID, &SERVERID);
+    }
+    dhcp6::Dhcp6Request {
+        pkt: dhcp6pkt::Dhcp6 {
+            msgtype,
+            xid: 0x123456,
+            options,
+        },
+        ifindex: 1,
+        ifname: Some("eth0".into()),
+        if_addresses: vec![],
+        peer: Some(ROUTER),
+    }
+}
+
+fn get_ia(reply: &dhcp6pkt::Dhcp6) -> dhcp6pkt::IaNa {
+    reply
+        .options
+        .get_option::<dhcp6pkt::IaNa>(&dhcp6pkt::OPTION_IA_NA)
+        .expect("Missing IA_NA")
+}
+
+fn get_status(options: &dhcp6pkt::Dhcp6Options) -> Option<dhcp6pkt::StatusCode> {
+    options
+        .get_option::<dhcp6pkt::Status>(&dhcp6pkt::OPTION_STATUS_CODE)
+        .map(|s| s.code)
+}
+
+async fn handle_reply(
+    p: &mut pool::Pool,
+    req: &dhcp6::Dhcp6Request,
+    conf: &crate::config::SharedConfig,
+) -> Result<Option<dhcp6::Reply>, dhcp6::Dhcp6Error> {
+    dhcp6::handle_pkt(p, req, SERVERID, &*conf.read().await).await
+}
+
+async fn handle(
+    p: &mut pool::Pool,
+    req: &dhcp6::Dhcp6Request,
+    conf: &crate::config::SharedConfig,
+) -> Result<Option<dhcp6pkt::Dhcp6>, dhcp6::Dhcp6Error> {
+    handle_reply(p, req, conf)
+        .await
+        .map(|reply| reply.map(|r| r.pkt))
+}
+
+#[tokio::test]
+async fn solicit_request_renew_release() {
+    let conf = mk_config();
+    let mut p = pool::Pool::new_in_memory().expect("Failed to create pool");
+
+    /* SOLICIT -> ADVERTISE */
+    let advertise = handle(&mut p, &mk_request(dhcp6pkt::SOLICIT, None), &conf)
+        .await
+        .expect("Failed to handle SOLICIT")
+        .expect("Missing ADVERTISE");
+    assert_eq!(advertise.msgtype, dhcp6pkt::ADVERTISE);
+    assert_eq!(advertise.xid, 0x123456);
+    assert_eq!(advertise.options.get_serverid().as_deref(), Some(SERVERID));
+    assert_eq!(advertise.options.get_clientid().as_deref(), Some(CLIENTID));
+    let ia = get_ia(&advertise);
+    assert_eq!(ia.iaid, IAID);
+    assert_eq!(ia.t1, 1800);
+    assert_eq!(ia.t2, 2880);
+    let addr = ia.get_addresses().next().expect("Missing IAADDR");
+    assert_eq!(addr.preferred, 3600);
+    assert_eq!(addr.valid, 7200);
+    let offered = addr.addr;
+    assert!(offered >= "2001:db8::100".parse::<net::Ipv6Addr>().unwrap());
+    assert!(offered <= "2001:db8::1ff".parse::<net::Ipv6Addr>().unwrap());
+
+    /* REQUEST -> REPLY with the same address */
+    let reply = handle(&mut p, &mk_request(dhcp6pkt::REQUEST, Some(offered)), &conf)
+        .await
+        .expect("Failed to handle REQUEST")
+        .expect("Missing REPLY");
+    assert_eq!(reply.msgtype, dhcp6pkt::REPLY);
+    assert_eq!(
+        get_ia(&reply).get_addresses().next().map(|a| a.addr),
+        Some(offered)
+    );
+
+    /* RENEW -> REPLY with the same address */
+    let reply = handle(&mut p, &mk_request(dhcp6pkt::RENEW, Some(offered)), &conf)
+        .await
+        .expect("Failed to handle RENEW")
+        .expect("Missing REPLY");
+    assert_eq!(
+        get_ia(&reply).get_addresses().next().map(|a| a.addr),
+        Some(offered)
+    );
+
+    /* RELEASE -> REPLY with success */
+    let reply = handle(&mut p, &mk_request(dhcp6pkt::RELEASE, Some(offered)), &conf)
+        .await
+        .expect("Failed to handle RELEASE")
+        .expect("Missing REPLY");
+    assert_eq!(get_status(&reply.options), Some(dhcp6pkt::STATUS_SUCCESS));
+    assert!(reply
+        .options
+        .get_option::<dhcp6pkt::IaNa>(&dhcp6pkt::OPTION_IA_NA)
+        .is_none());
+
+    /* Releasing it again means we have no binding */
+    let reply = handle(&mut p, &mk_request(dhcp6pkt::RELEASE, Some(offered)), &conf)
+        .await
+        .expect("Failed to handle RELEASE")
+        .expect("Missing REPLY");
+    assert_eq!(
+        get_status(&get_ia(&reply).options),
+        Some(dhcp6pkt::STATUS_NO_BINDING)
+    );
+}
+
+#[tokio::test]
+async fn solicit_doesnt_bind() {
+    let conf = mk_config();
+    let mut p = pool::Pool::new_in_memory().expect("Failed to create pool");
+    let advertise = handle(&mut p, &mk_request(dhcp6pkt::SOLICIT, None), &conf)
+        .await
+        .expect("Failed to handle SOLICIT")
+        .expect("Missing ADVERTISE");
+    let offered = get_ia(&advertise).get_addresses().next().unwrap().addr;
+
+    /* Another client (or the same one with a spoofed DUID) solicits too */
+    let mut req = mk_request(dhcp6pkt::SOLICIT, None);
+    req.pkt.options = req
+        .pkt
+        .options
+        .remove_option(&dhcp6pkt::OPTION_CLIENTID)
+        .add_option(&dhcp6pkt::OPTION_CLIENTID, &OTHER_SERVERID);
+    handle(&mut p, &req, &conf)
+        .await
+        .expect("Failed to handle SOLICIT")
+        .expect("Missing ADVERTISE");
+
+    /* Neither is holding an address for the valid lifetime */
+    assert!(p
+        .get_leases()
+        .unwrap()
+        .iter()
+        .all(|lease| lease.expire < lease.start + 7200));
+
+    /* So the other client can have the address we offered the first one */
+    let mut req = mk_request(dhcp6pkt::REQUEST, Some(offered));
+    req.pkt.options = req
+        .pkt
+        .options
+        .remove_option(&dhcp6pkt::OPTION_CLIENTID)
+        .add_option(&dhcp6pkt::OPTION_CLIENTID, &OTHER_SERVERID);
+    let reply = handle(&mut p, &req, &conf)
+        .await
+        .expect("Failed to handle REQUEST")
+        .expect("Missing REPLY");
+    assert_eq!(
+        get_ia(&reply).get_addresses().next().map(|a| a.addr),
+        Some(offered)
+    );
+    assert_eq!(p.get_leases().unwrap().len(), 1);
+}
+
+#[tokio::test]
+async fn renew_without_binding() {
+    let conf = mk_config();
+    let mut p = pool::Pool::new_in_memory().expect("Failed to create pool");
+    let reply = handle(
+        &mut p,
+        &mk_request(dhcp6pkt::RENEW, Some("2001:db8::123".parse().unwrap())),
+        &conf,
+    )
+    .await
+    .expect("Failed to handle RENEW")
+    .expect("Missing REPLY");
+    assert_eq!(
+        get_status(&get_ia(&reply).options),
+        Some(dhcp6pkt::STATUS_NO_BINDING)
+    );
+}
+
+#[tokio::test]
+async fn rebind_with_wrong_address() {
+    let conf = mk_config();
+    let mut p = pool::Pool::new_in_memory().expect("Failed to create pool");
+    let stale: net::Ipv6Addr = "2001:db8:1::1".parse().unwrap();
+    let reply = handle(&mut p, &mk_request(dhcp6pkt::REBIND, Some(stale)), &conf)
+        .await
+        .expect("Failed to handle REBIND")
+        .expect("Missing REPLY");
+    let addrs = get_ia(&reply).get_addresses().collect::<Vec<_>>();
+    assert_eq!(addrs.len(), 2);
+    assert_ne!(addrs[0].addr, stale);
+    /* The address that isn't appropriate for the link is returned with 0 lifetimes */
+    assert_eq!(addrs[1].addr, stale);
+    assert_eq!(addrs[1].preferred, 0);
+    assert_eq!(addrs[1].valid, 0);
+}
+
+#[tokio::test]
+async fn decline() {
+    let conf = mk_config();
+    let mut p = pool::Pool::new_in_memory().expect("Failed to create pool");
+    let reply = handle(&mut p, &mk_request(dhcp6pkt::REQUEST, None), &conf)
+        .await
+        .expect("Failed to handle REQUEST")
+        .expect("Missing REPLY");
+    let addr = get_ia(&reply).get_addresses().next().unwrap().addr;
+    let reply = handle(&mut p, &mk_request(dhcp6pkt::DECLINE, Some(addr)), &conf)
+        .await
+        .expect("Failed to handle DECLINE")
+        .expect("Missing REPLY");
+    assert_eq!(get_status(&reply.options), Some(dhcp6pkt::STATUS_SUCCESS));
+    /* We shouldn't get given the declined address again */
+    let reply = handle(&mut p, &mk_request(dhcp6pkt::REQUEST, None), &conf)
+        .await
+        .expect("Failed to handle REQUEST")
+        .expect("Missing REPLY");
+    assert_ne!(get_ia(&reply).get_addresses().next().unwrap().addr, addr);
+}
+
+#[tokio::test]
+async fn other_server() {
+    let conf = mk_config();
+    let mut p = pool::Pool::new_in_memory().expect("Failed to create pool");
+    let mut req = mk_request(dhcp6pkt::REQUEST, None);
+    req.pkt.options = req
+        .pkt
+        .options
+        .remove_option(&dhcp6pkt::OPTION_SERVERID)
+        .add_option(&dhcp6pkt::OPTION_SERVERID, &OTHER_SERVERID);
+    assert_eq!(
+        handle(&mut p, &req, &conf).await,
+        Err(dhcp6::Dhcp6Error::OtherServer)
+    );
+}
+
+#[tokio::test]
+async fn no_policy_for_interface() {
+    let conf = mk_config();
+    let mut p = pool::Pool::new_in_memory().expect("Failed to create pool");
+    let mut req = mk_request(dhcp6pkt::SOLICIT, None);
+    req.ifname = Some("eth1".into());
+    /* The base policy always matches, but has no addresses for this interface */
+    let advertise = handle(&mut p, &req, &conf)
+        .await
+        .expect("Failed to handle SOLICIT")
+        .expect("Missing ADVERTISE");
+    assert_eq!(
+        get_status(&advertise.options),
+        Some(dhcp6pkt::STATUS_NO_ADDRS_AVAIL)
+    );
+    assert!(advertise
+        .options
+        .get_option::<dhcp6pkt::IaNa>(&dhcp6pkt::OPTION_IA_NA)
+        .is_none());
+}
+
+#[tokio::test]
+async fn addresses_from_interface_prefix() {
+    let conf = crate::config::load_config_from_string_for_test(
+        "---
+addresses: [2001:db8:1::/64]
+",
+    )
+    .expect("Failed to parse test config");
+    let mut p = pool::Pool::new_in_memory().expect("Failed to create pool");
+    let mut req = mk_request(dhcp6pkt::SOLICIT, None);
+    req.if_addresses = vec![("2001:db8:1::1".parse().unwrap(), 64)];
+    let advertise = handle(&mut p, &req, &conf)
+        .await
+        .expect("Failed to handle SOLICIT")
+        .expect("Missing ADVERTISE");
+    let addr = get_ia(&advertise).get_addresses().next().unwrap().addr;
+    assert_eq!(addr.segments()[..4], [0x2001, 0xdb8, 1, 0]);
+    assert_ne!(addr, "2001:db8:1::1".parse::<net::Ipv6Addr>().unwrap());
+}
+
+fn mk_pd_config() -> crate::config::SharedConfig {
+    crate::config::load_config_from_string_for_test(
+        "---
+dhcp6-policies:
+  - match-interface: eth0
+    apply-delegated-prefixes: {prefix: 2001:db8:100::/56, length: 60}
+    apply-preferred-lifetime: 1h
+    apply-valid-lifetime: 2h
+",
+    )
+    .expect("Failed to parse test config")
+}
+
+fn mk_pd_request(
+    msgtype: dhcp6pkt::MessageType,
+    prefix: Option<(net::Ipv6Addr, u8)>,
+) -> dhcp6::Dhcp6Request {
+    let mut req = mk_request(msgtype, None);
+    req.pkt.options = req
+        .pkt
+        .options
+        .remove_option(&dhcp6pkt::OPTION_IA_NA)
+        .add_option(
+            &dhcp6pkt::OPTION_IA_PD,
+            &dhcp6pkt::IaPd {
+                iaid: IAID,
+                t1: 0,
+                t2: 0,
+                options: dhcp6pkt::Dhcp6Options::default().maybe_add_option(
+                    &dhcp6pkt::OPTION_IAPREFIX,
+                    prefix
+                        .map(|(prefix, prefixlen)| dhcp6pkt::IaPrefix {
+                            preferred: 0,
+                            valid: 0,
+                            prefixlen,
+                            prefix,
+                            options: Default::default(),
+                        })
+                        .as_ref(),
+                ),
+            },
+        );
+    req
+}
+
+fn get_pd(reply: &dhcp6pkt::Dhcp6) -> dhcp6pkt::IaPd {
+    reply
+        .options
+        .get_option::<dhcp6pkt::IaPd>(&dhcp6pkt::OPTION_IA_PD)
+        .expect("Missing IA_PD")
+}
+
+#[tokio::test]
+async fn delegate_prefix() {
+    let conf = mk_pd_config();
+    let mut p = pool::Pool::new_in_memory().expect("Failed to create pool");
+
+    /* SOLICIT -> ADVERTISE, with no routes changed */
+    let req = mk_pd_request(dhcp6pkt::SOLICIT, None);
+    let advertise = handle_reply(&mut p, &req, &conf)
+        .await
+        .expect("Failed to handle SOLICIT")
+        .expect("Missing ADVERTISE");
+    /* Only IA_PDs were asked for, so there's no top level NoAddrsAvail */
+    assert_eq!(get_status(&advertise.pkt.options), None);
+    let pd = get_pd(&advertise.pkt);
+    assert_eq!(pd.iaid, IAID);
+    assert_eq!(pd.t1, 1800);
+    let prefix = pd.get_prefixes().next().expect("Missing IAPREFIX");
+    assert_eq!(prefix.prefixlen, 60);
+    assert_eq!(prefix.preferred, 3600);
+    assert_eq!(prefix.valid, 7200);
+    assert_eq!(prefix.prefix.segments()[..3], [0x2001, 0xdb8, 0x100]);
+    assert_eq!(
+        dhcp6::get_route_changes(&req, &advertise),
+        Default::default()
+    );
+
+    /* REQUEST -> REPLY, and a route via the requesting router */
+    let req = mk_pd_request(dhcp6pkt::REQUEST, Some((prefix.prefix, 60)));
+    let reply = handle_reply(&mut p, &req, &conf)
+        .await
+        .expect("Failed to handle REQUEST")
+        .expect("Missing REPLY");
+    assert_eq!(
+        get_pd(&reply.pkt).get_prefixes().next().map(|p| p.prefix),
+        Some(prefix.prefix)
+    );
+    let route = erbium_net::route::Route {
+        destination: prefix.prefix.into(),
+        prefixlen: 60,
+        nexthop: ROUTER.into(),
+        oifidx: Some(1),
+    };
+    assert_eq!(
+        dhcp6::get_route_changes(&req, &reply),
+        dhcp6::RouteChanges {
+            add: vec![route.clone()],
+            del: vec![],
+        }
+    );
+    assert_eq!(p.get_delegations().unwrap().len(), 1);
+
+    /* RELEASE -> REPLY, and the route is removed */
+    let req = mk_pd_request(dhcp6pkt::RELEASE, Some((prefix.prefix, 60)));
+    let reply = handle_reply(&mut p, &req, &conf)
+        .await
+        .expect("Failed to handle RELEASE")
+        .expect("Missing REPLY");
+    assert_eq!(
+        get_status(&reply.pkt.options),
+        Some(dhcp6pkt::STATUS_SUCCESS)
+    );
+    assert_eq!(
+        dhcp6::get_route_changes(&req, &reply),
+        dhcp6::RouteChanges {
+            add: vec![],
+            del: vec![route],
+        }
+    );
+
+    /* Releasing again doesn't remove anything */
+    let reply = handle_reply(&mut p, &req, &conf)
+        .await
+        .expect("Failed to handle RELEASE")
+        .expect("Missing REPLY");
+    assert_eq!(
+        get_status(&get_pd(&reply.pkt).options),
+        Some(dhcp6pkt::STATUS_NO_BINDING)
+    );
+    assert_eq!(dhcp6::get_route_changes(&req, &reply), Default::default());
+}
+
+#[tokio::test]
+async fn delegate_prefix_without_addresses() {
+    let conf = mk_pd_config();
+    let mut p = pool::Pool::new_in_memory().expect("Failed to create pool");
+    /* The router asks for an address as well, but there are none to give */
+    let mut req = mk_pd_request(dhcp6pkt::SOLICIT, None);
+    req.pkt.options = req.pkt.options.add_option(
+        &dhcp6pkt::OPTION_IA_NA,
+        &dhcp6pkt::IaNa {
+            iaid: IAID,
+            t1: 0,
+            t2: 0,
+            options: Default::default(),
+        },
+    );
+    let advertise = handle(&mut p, &req, &conf)
+        .await
+        .expect("Failed to handle SOLICIT")
+        .expect("Missing ADVERTISE");
+    /* The NoAddrsAvail is only for the IA_NA, so the delegation is still usable */
+    assert_eq!(get_status(&advertise.options), None);
+    assert_eq!(
+        get_status(&get_ia(&advertise).options),
+        Some(dhcp6pkt::STATUS_NO_ADDRS_AVAIL)
+    );
+    assert!(get_pd(&advertise).get_prefixes().next().is_some());
+}
+
+#[tokio::test]
+async fn advertised_prefix_isnt_delegated() {
+    let conf = mk_pd_config();
+    let mut p = pool::Pool::new_in_memory().expect("Failed to create pool");
+    let advertise = handle(&mut p, &mk_pd_request(dhcp6pkt::SOLICIT, None), &conf)
+        .await
+        .expect("Failed to handle SOLICIT")
+        .expect("Missing ADVERTISE");
+    let prefix = get_pd(&advertise).get_prefixes().next().unwrap().prefix;
+
+    /* Nothing is recorded, so there's no route to restore after a restart */
+    assert_eq!(p.get_delegations().unwrap(), vec![]);
+
+    /* And the prefix isn't held for the router it was advertised to */
+    let mut req = mk_pd_request(dhcp6pkt::REQUEST, Some((prefix, 60)));
+    req.pkt.options = req
+        .pkt
+        .options
+        .remove_option(&dhcp6pkt::OPTION_CLIENTID)
+        .add_option(&dhcp6pkt::OPTION_CLIENTID, &OTHER_SERVERID);
+    let reply = handle(&mut p, &req, &conf)
+        .await
+        .expect("Failed to handle REQUEST")
+        .expect("Missing REPLY");
+    assert_eq!(
+        get_pd(&reply).get_prefixes().next().map(|p| p.prefix),
+        Some(prefix)
+    );
+}
+
+#[tokio::test]
+async fn rebind_with_wrong_prefix() {
+    let conf = mk_pd_config();
+    let mut p = pool::Pool::new_in_memory().expect("Failed to create pool");
+    let stale: net::Ipv6Addr = "2001:db8:200::".parse().unwrap();
+    let req = mk_pd_request(dhcp6pkt::REBIND, Some((stale, 60)));
+    let reply = handle_reply(&mut p, &req, &conf)
+        .await
+        .expect("Failed to handle REBIND")
+        .expect("Missing REPLY");
+    let prefixes = get_pd(&reply.pkt).get_prefixes().collect::<Vec<_>>();
+    assert_eq!(prefixes.len(), 2);
+    assert_eq!(prefixes[1].prefix, stale);
+    assert_eq!(prefixes[1].valid, 0);
+    let changes = dhcp6::get_route_changes(&req, &reply);
+    assert_eq!(changes.add.len(), 1);
+    assert_eq!(changes.del.len(), 1);
+    assert_eq!(changes.del[0].destination, net::IpAddr::V6(stale));
+}
+
+#[tokio::test]
+async fn release_some_prefixes() {
+    let conf = mk_pd_config();
+    let mut p = pool::Pool::new_in_memory().expect("Failed to create pool");
+    let req = mk_pd_request(dhcp6pkt::SOLICIT, None);
+    let advertise = handle(&mut p, &req, &conf)
+        .await
+        .expect("Failed to handle SOLICIT")
+        .expect("Missing ADVERTISE");
+    let prefix = get_pd(&advertise).get_prefixes().next().unwrap().prefix;
+    let req = mk_pd_request(dhcp6pkt::REQUEST, Some((prefix, 60)));
+    handle(&mut p, &req, &conf)
+        .await
+        .expect("Failed to handle REQUEST")
+        .expect("Missing REPLY");
+
+    /* Release the delegated prefix along with one we never delegated, in the same IA_PD */
+    let unknown: net::Ipv6Addr = "2001:db8:200::".parse().unwrap();
+    let mut req = mk_pd_request(dhcp6pkt::RELEASE, Some((prefix, 60)));
+    let mut pd = get_pd(&req.pkt);
+    pd.options = pd.options.add_option(
+        &dhcp6pkt::OPTION_IAPREFIX,
+        &dhcp6pkt::IaPrefix {
+            preferred: 0,
+            valid: 0,
+            prefixlen: 60,
+            prefix: unknown,
+            options: Default::default(),
+        },
+    );
+    req.pkt.options = req
+        .pkt
+        .options
+        .remove_option(&dhcp6pkt::OPTION_IA_PD)
+        .add_option(&dhcp6pkt::OPTION_IA_PD, &pd);
+    let reply = handle_reply(&mut p, &req, &conf)
+        .await
+        .expect("Failed to handle RELEASE")
+        .expect("Missing REPLY");
+    assert_eq!(
+        get_status(&get_pd(&reply.pkt).options),
+        Some(dhcp6pkt::STATUS_NO_BINDING)
+    );
+    /* The prefix we did know about is still released, and its route removed */
+    let changes = dhcp6::get_route_changes(&req, &reply);
+    assert!(changes.add.is_empty());
+    assert_eq!(
+        changes.del,
+        vec![erbium_net::route::Route {
+            destination: prefix.into(),
+            prefixlen: 60,
+            nexthop: ROUTER.into(),
+            oifidx: Some(1),
+        }]
+    );
+}
+
+#[tokio::test]
+async fn no_prefixes_configured() {
+    let conf = mk_config();
+    let mut p = pool::Pool::new_in_memory().expect("Failed to create pool");
+    let advertise = handle(&mut p, &mk_pd_request(dhcp6pkt::SOLICIT, None), &conf)
+        .await
+        .expect("Failed to handle SOLICIT")
+        .expect("Missing ADVERTISE");
+    assert_eq!(
+        get_status(&get_pd(&advertise).options),
+        Some(dhcp6pkt::STATUS_NO_PREFIX_AVAIL)
+    );
+}
+
+fn mk_information_request() -> dhcp6::Dhcp6Request {
+    dhcp6::Dhcp6Request {
+        pkt: dhcp6pkt::Dhcp6 {
+            msgtype: dhcp6pkt::INFORMATION_REQUEST,
+            xid: 0x123456,
+            options: dhcp6pkt::Dhcp6Options::default().add_option(
+                &dhcp6pkt::OPTION_ORO,
+                &vec![
+                    u16::from(dhcp6pkt::OPTION_DNS_SERVERS),
+                    u16::from(dhcp6pkt::OPTION_DOMAIN_LIST),
+                    u16::from(dhcp6pkt::OPTION_NTP_SERVER),
+                    u16::from(dhcp6pkt::OPTION_SNTP_SERVERS),
+                ],
+            ),
+        },
+        ifindex: 1,
+        ifname: Some("eth0".into()),
+        if_addresses: vec![
+            ("fe80::1".parse().unwrap(), 64),
+            ("2001:db8::1".parse().unwrap(), 64),
+        ],
+        peer: Some(ROUTER),
+    }
+}
+
+#[tokio::test]
+async fn information_request() {
+    let conf = crate::config::load_config_from_string_for_test(
+        "---
+dns-servers: [192.0.2.53, 2001:db8::53, \"::\"]
+dns-search: [example.com]
+dhcp6-policies:
+  - match-interface: eth0
+    apply-ntp-servers: [2001:db8::123]
+",
+    )
+    .expect("Failed to parse test config");
+    let mut p = pool::Pool::new_in_memory().expect("Failed to create pool");
+    let reply = handle(&mut p, &mk_information_request(), &conf)
+        .await
+        .expect("Failed to handle INFORMATION-REQUEST")
+        .expect("Missing REPLY");
+    assert_eq!(reply.msgtype, dhcp6pkt::REPLY);
+    assert_eq!(reply.options.get_serverid().as_deref(), Some(SERVERID));
+    assert_eq!(reply.options.get_clientid(), None);
+    assert_eq!(
+        reply
+            .options
+            .get_option::<Vec<net::Ipv6Addr>>(&dhcp6pkt::OPTION_DNS_SERVERS),
+        /* :: is replaced with our global address on the interface */
+        Some(vec![
+            "2001:db8::53".parse().unwrap(),
+            "2001:db8::1".parse().unwrap()
+        ])
+    );
+    assert_eq!(
+        reply
+            .options
+            .get_option::<dhcp6pkt::DomainList>(&dhcp6pkt::OPTION_DOMAIN_LIST),
+        Some(dhcp6pkt::DomainList(vec!["example.com".into()]))
+    );
+    assert_eq!(
+        reply
+            .options
+            .get_option::<dhcp6pkt::NtpServers>(&dhcp6pkt::OPTION_NTP_SERVER),
+        Some(dhcp6pkt::NtpServers(vec!["2001:db8::123".parse().unwrap()]))
+    );
+    assert_eq!(
+        reply
+            .options
+            .get_option::<Vec<net::Ipv6Addr>>(&dhcp6pkt::OPTION_SNTP_SERVERS),
+        Some(vec!["2001:db8::123".parse().unwrap()])
+    );
+    /* Nothing was allocated */
+    assert_eq!(p.get_leases().unwrap(), vec![]);
+}
+
+#[tokio::test]
+async fn information_request_options_are_requested() {
+    let conf = mk_config();
+    let mut p = pool::Pool::new_in_memory().expect("Failed to create pool");
+    let mut req = mk_information_request();
+    req.pkt.options = req
+        .pkt
+        .options
+        .remove_option(&dhcp6pkt::OPTION_ORO)
+        .add_option(&dhcp6pkt::OPTION_CLIENTID, &CLIENTID);
+    let reply = handle(&mut p, &req, &conf)
+        .await
+        .expect("Failed to handle INFORMATION-REQUEST")
+        .expect("Missing REPLY");
+    assert_eq!(reply.options.get_clientid().as_deref(), Some(CLIENTID));
+    /* We only send what was asked for */
+    assert_eq!(
+        reply.options.get_raw_option(&dhcp6pkt::OPTION_DNS_SERVERS),
+        None
+    );
+}
+
+#[tokio::test]
+async fn information_request_with_ia() {
+    let conf = mk_config();
+    let mut p = pool::Pool::new_in_memory().expect("Failed to create pool");
+    let mut req = mk_request(dhcp6pkt::SOLICIT, None);
+    req.pkt.msgtype = dhcp6pkt::INFORMATION_REQUEST;
+    assert_eq!(
+        handle(&mut p, &req, &conf).await,
+        Err(dhcp6::Dhcp6Error::UnexpectedIa)
+    );
+}
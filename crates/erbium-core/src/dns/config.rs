@@ -24,11 +24,14 @@ use yaml_rust::yaml;
 pub enum Handler {
     Forward(Vec<std::net::SocketAddr>),
     ForgeNxDomain,
+    /// Answer from DHCP leases, registering hosts under this domain.
+    DhcpLeases(super::dnspkt::Domain),
 }
 
 enum HandlerType {
     Forward,
     ForgeNxDomain,
+    DhcpLeases,
 }
 
 #[derive(Debug)]
@@ -51,6 +54,7 @@ pub fn parse_dns_route(name: &str, fragment: &yaml::Yaml) -> Result<Option<Route
                 Some("type") => match parse_string("type", v)? {
                     Some(t) if t == "forward" => handler = Some(HandlerType::Forward),
                     Some(t) if t == "forge-nxdomain" => handler = Some(HandlerType::ForgeNxDomain),
+                    Some(t) if t == "dhcp-leases" => handler = Some(HandlerType::DhcpLeases),
                     Some(kw) => {
                         return Err(Error::InvalidConfig(format!(
                             "{} type {} not supported",
@@ -103,6 +107,29 @@ pub fn parse_dns_route(name: &str, fragment: &yaml::Yaml) -> Result<Option<Route
                     dest: Handler::ForgeNxDomain,
                 }))
             }
+            Some(HandlerType::DhcpLeases) => {
+                if !cfg!(feature = "dhcp") {
+                    return Err(Error::InvalidConfig(format!(
+                        "{} type dhcp-leases requires DHCP support",
+                        name
+                    )));
+                }
+                /* Hosts are registered under the first suffix that isn't for reverse lookups */
+                let domain = suffix_domains
+                    .iter()
+                    .find(|d| !d.to_string().to_ascii_lowercase().ends_with("in-addr.arpa"))
+                    .cloned()
+                    .ok_or_else(|| {
+                        Error::InvalidConfig(format!(
+                            "{} type dhcp-leases needs a domain suffix to register hosts under",
+                            name
+                        ))
+                    })?;
+                return Ok(Some(Route {
+                    suffixes: suffix_domains,
+                    dest: Handler::DhcpLeases(domain),
+                }));
+            }
         }
     }
     Ok(None)
@@ -120,10 +147,20 @@ fn test_dns_config() -> Result<(), Error> {
 dns-routes:
   - domain-suffixes: ['invalid']
     type: forge-nxdomain
+  - domain-suffixes: ['home.arpa', '168.192.in-addr.arpa']
+    type: dhcp-leases
   - domain-suffixes: ['']
     type: forward
     dns-servers: [2001:4860:4860::8888]
 ",
     )?;
+    assert!(config::load_config_from_string_for_test(
+        "---
+dns-routes:
+  - domain-suffixes: ['168.192.in-addr.arpa']
+    type: dhcp-leases
+",
+    )
+    .is_err());
     Ok(())
 }
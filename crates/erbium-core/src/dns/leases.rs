@@ -0,0 +1,507 @@
+/*   Copyright 2023 Perry Lorier
+ *
+ *  Licensed under the Apache License, Version 2.0 (the "License");
+ *  you may not use this file except in compliance with the License.
+ *  You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ *  Unless required by applicable law or agreed to in writing, software
+ *  distributed under the License is distributed on an "AS IS" BASIS,
+ *  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ *  See the License for the specific language governing permissions and
+ *  limitations under the License.
+ *
+ *  SPDX-License-Identifier: Apache-2.0
+ *
+ *  Answers DNS queries for hosts that have DHCP leases.
+ */
+
+use super::dnspkt;
+use super::Error;
+use crate::dhcp::pool;
+use std::collections::HashMap;
+use std::net::Ipv4Addr;
+use std::sync::Arc;
+use tokio::sync::RwLock;
+
+/// How often to reread the lease database in the background.
+const REFRESH_INTERVAL: std::time::Duration = std::time::Duration::from_secs(5);
+/// The longest TTL we hand out, so clients notice hosts going away reasonably quickly.
+const MAX_TTL: u32 = 300;
+
+lazy_static::lazy_static! {
+    static ref DNS_LEASE_NAMES: prometheus::IntGauge =
+        prometheus::register_int_gauge!("dns_lease_names", "Number of hostnames registered from DHCP leases")
+            .unwrap();
+    static ref DNS_LEASE_NAME_CONFLICTS: prometheus::IntGauge =
+        prometheus::register_int_gauge!("dns_lease_name_conflicts", "Number of DHCP leases whose hostname is already in use by another client")
+            .unwrap();
+}
+
+/// Turns a hostname a client gave us into a single DNS label that's safe to publish.
+///
+/// Only the first label is used, it is lowercased, and anything other than letters, digits and
+/// hyphens is replaced with a hyphen.  Returns None if there's nothing useful left.
+pub fn sanitise_hostname(name: &str) -> Option<String> {
+    let label = name.split('.').next().unwrap_or_default();
+    let mapped = label
+        .chars()
+        .map(|c| match c.to_ascii_lowercase() {
+            c @ ('a'..='z' | '0'..='9' | '-') => c,
+            _ => '-',
+        })
+        .take(63)
+        .collect::<String>();
+    let trimmed = mapped.trim_matches('-');
+    if trimmed.is_empty() {
+        None
+    } else {
+        Some(trimmed.to_string())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Owner {
+    ip: Ipv4Addr,
+    client_id: Vec<u8>,
+    expire: u32,
+}
+
+/// The names (and reverse names) of every host with an active lease.
+#[derive(Debug, Default)]
+pub struct LeaseNames {
+    names: HashMap<String, Owner>,
+    addresses: HashMap<Ipv4Addr, String>,
+}
+
+impl LeaseNames {
+    /// Builds the table of names from the current leases.
+    ///
+    /// If more than one client claims the same name, whoever had the name in `previous` keeps it,
+    /// otherwise the name goes to the longest standing lease.  Other clients don't get a name.
+    pub fn new(leases: &[pool::LeaseInfo], now: u32, previous: &LeaseNames) -> Self {
+        let mut claims = leases
+            .iter()
+            .filter(|li| li.expire >= now && !li.client_id.is_empty())
+            .filter_map(|li| {
+                crate::dhcp::dhcppkt::parse_options(crate::pktparser::Buffer::new(&li.options))
+                    .ok()
+                    .and_then(|o| o.get_hostname())
+                    .and_then(|h| sanitise_hostname(&h))
+                    .map(|name| (name, li))
+            })
+            .collect::<Vec<_>>();
+        claims.sort_by_key(|(name, li)| {
+            let incumbent = previous
+                .names
+                .get(name)
+                .map(|owner| owner.client_id == li.client_id)
+                .unwrap_or(false);
+            (!incumbent, li.start, li.ip)
+        });
+
+        let mut ret = LeaseNames::default();
+        let mut conflicts = 0;
+        for (name, li) in claims {
+            if let Some(owner) = ret.names.get(&name) {
+                log::debug!(
+                    "{} ({}) wants the name {}, but it belongs to {}",
+                    li.ip,
+                    crate::dhcp::format_mac(&li.client_id),
+                    name,
+                    owner.ip
+                );
+                conflicts += 1;
+                continue;
+            }
+            ret.addresses.insert(li.ip, name.clone());
+            ret.names.insert(
+                name,
+                Owner {
+                    ip: li.ip,
+                    client_id: li.client_id.clone(),
+                    expire: li.expire,
+                },
+            );
+        }
+        DNS_LEASE_NAMES.set(ret.names.len() as i64);
+        DNS_LEASE_NAME_CONFLICTS.set(conflicts);
+        ret
+    }
+
+    pub fn lookup_name(&self, name: &str) -> Option<Ipv4Addr> {
+        self.names.get(name).map(|owner| owner.ip)
+    }
+
+    pub fn lookup_address(&self, ip: Ipv4Addr) -> Option<&str> {
+        self.addresses.get(&ip).map(String::as_str)
+    }
+
+    fn ttl(&self, name: &str, now: u32) -> u32 {
+        self.names
+            .get(name)
+            .map(|owner| owner.expire.saturating_sub(now))
+            .unwrap_or(0)
+            .min(MAX_TTL)
+    }
+}
+
+fn authoritative_reply(
+    question: &dnspkt::Question,
+    rcode: dnspkt::RCode,
+    answer: Vec<dnspkt::RR>,
+) -> dnspkt::DNSPkt {
+    dnspkt::DNSPkt {
+        qid: 0,
+        rd: false,
+        tc: false,
+        aa: true,
+        qr: true,
+        opcode: dnspkt::OPCODE_QUERY,
+        cd: false,
+        ad: false,
+        ra: true,
+        rcode,
+        bufsize: 4096,
+        edns_ver: None,
+        edns_do: false,
+        question: question.clone(),
+        answer,
+        nameserver: vec![],
+        additional: vec![],
+        edns: None,
+    }
+}
+
+/// Parses a name under in-addr.arpa back into an address.
+fn parse_reverse(qname: &str) -> Option<Ipv4Addr> {
+    let octets = qname.strip_suffix(".in-addr.arpa")?;
+    let mut parts = octets
+        .split('.')
+        .map(|o| o.parse::<u8>().ok())
+        .collect::<Option<Vec<_>>>()?;
+    if parts.len() != 4 {
+        return None;
+    }
+    parts.reverse();
+    Some(Ipv4Addr::new(parts[0], parts[1], parts[2], parts[3]))
+}
+
+/// Answers a question authoritatively from the lease names.
+///
+/// `suffix` is the route's suffix that matched the query, and `domain` is the domain hosts are
+/// registered under.
+pub fn answer(
+    names: &LeaseNames,
+    question: &dnspkt::Question,
+    suffix: &dnspkt::Domain,
+    domain: &dnspkt::Domain,
+    now: u32,
+) -> Result<dnspkt::DNSPkt, Error> {
+    if question.qclass != dnspkt::CLASS_IN {
+        return Err(Error::NotAuthoritative);
+    }
+    /* Domain names are case insensitive, and clients may randomise the case of their queries. */
+    let qname = question.qdomain.to_string().to_ascii_lowercase();
+    let suffix = suffix.to_string().to_ascii_lowercase();
+    if qname == suffix {
+        /* The zone itself exists, it just has no records we serve. */
+        return Ok(authoritative_reply(question, dnspkt::NOERROR, vec![]));
+    }
+
+    if suffix.ends_with("in-addr.arpa") {
+        let found = parse_reverse(&qname).and_then(|ip| names.lookup_address(ip));
+        return Ok(match found {
+            None => authoritative_reply(question, dnspkt::NXDOMAIN, vec![]),
+            Some(name) if question.qtype == dnspkt::RR_PTR || question.qtype == dnspkt::RR_ANY => {
+                let target = format!("{}.{}", name, domain)
+                    .parse()
+                    .map_err(|e: &str| Error::Denied(e.into()))?;
+                authoritative_reply(
+                    question,
+                    dnspkt::NOERROR,
+                    vec![dnspkt::RR {
+                        domain: question.qdomain.clone(),
+                        class: dnspkt::CLASS_IN,
+                        rrtype: dnspkt::RR_PTR,
+                        ttl: names.ttl(name, now),
+                        rdata: dnspkt::RData::Ptr(target),
+                    }],
+                )
+            }
+            Some(_) => authoritative_reply(question, dnspkt::NOERROR, vec![]),
+        });
+    }
+
+    let host = qname
+        .strip_suffix(&suffix)
+        .and_then(|h| h.strip_suffix('.'))
+        .filter(|h| !h.contains('.'));
+    Ok(
+        match host.and_then(|h| names.lookup_name(h).map(|ip| (h, ip))) {
+            None => authoritative_reply(question, dnspkt::NXDOMAIN, vec![]),
+            Some((host, ip))
+                if question.qtype == dnspkt::RR_A || question.qtype == dnspkt::RR_ANY =>
+            {
+                authoritative_reply(
+                    question,
+                    dnspkt::NOERROR,
+                    vec![dnspkt::RR {
+                        domain: question.qdomain.clone(),
+                        class: dnspkt::CLASS_IN,
+                        rrtype: dnspkt::RR_A,
+                        ttl: names.ttl(host, now),
+                        rdata: dnspkt::RData::Other(ip.octets().to_vec()),
+                    }],
+                )
+            }
+            Some(_) => authoritative_reply(question, dnspkt::NOERROR, vec![]),
+        },
+    )
+}
+
+fn now() -> u32 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as u32
+}
+
+/// Rereads the lease database, opening it first if need be.
+fn rebuild(
+    pool: &mut Option<pool::Pool>,
+    state_directory: &std::path::Path,
+    lease_store: crate::config::LeaseStoreType,
+    previous: &LeaseNames,
+) -> Result<LeaseNames, pool::Error> {
+    let pool = match pool {
+        Some(pool) => pool,
+        None => pool.insert(pool::Pool::new_with_store(crate::dhcp::store::open(
+            state_directory,
+            lease_store,
+            true,
+        )?)),
+    };
+    Ok(LeaseNames::new(&pool.get_leases()?, now(), previous))
+}
+
+/// Keeps a copy of the names from the lease database.
+///
+/// The names are rebuilt in the background periodically, so queries never wait on the database.
+pub struct LeaseNameHandler {
+    names: Arc<RwLock<Arc<LeaseNames>>>,
+}
+
+impl LeaseNameHandler {
+    pub fn new(conf: crate::config::SharedConfig) -> Self {
+        let names = Arc::new(RwLock::new(Arc::new(LeaseNames::default())));
+        let names_copy = names.clone();
+        tokio::spawn(async move {
+            Self::refresh_thread(names_copy, conf).await;
+        });
+        LeaseNameHandler { names }
+    }
+
+    async fn refresh_thread(
+        names: Arc<RwLock<Arc<LeaseNames>>>,
+        conf: crate::config::SharedConfig,
+    ) {
+        let mut pool = None;
+        loop {
+            let store = {
+                let conf = conf.read().await;
+                /* Don't go opening the lease database unless something wants the names */
+                conf.dns_routes
+                    .iter()
+                    .any(|route| matches!(route.dest, super::config::Handler::DhcpLeases(_)))
+                    .then(|| (conf.state_directory.clone(), conf.lease_store))
+            };
+            if let Some((state_directory, lease_store)) = store {
+                let previous = names.read().await.clone();
+                let (p, result) = tokio::task::spawn_blocking(move || {
+                    let result = rebuild(&mut pool, &state_directory, lease_store, &previous);
+                    (pool, result)
+                })
+                .await
+                .expect("Lease name rebuild panicked");
+                pool = p;
+                match result {
+                    Ok(new) => *names.write().await = Arc::new(new),
+                    Err(e) => {
+                        log::warn!("Failed to read leases for DNS: {}", e);
+                        /* Try opening the database again next time */
+                        pool = None;
+                    }
+                }
+            }
+            tokio::time::sleep(REFRESH_INTERVAL).await;
+        }
+    }
+
+    pub async fn handle_query(
+        &self,
+        msg: &super::DnsMessage,
+        suffix: &dnspkt::Domain,
+        domain: &dnspkt::Domain,
+    ) -> Result<dnspkt::DNSPkt, Error> {
+        let names = self.names.read().await.clone();
+        answer(&names, &msg.in_query.question, suffix, domain, now())
+    }
+}
+
+#[cfg(test)]
+fn mk_lease(ip: &str, client_id: &[u8], start: u32, hostname: &str) -> pool::LeaseInfo {
+    let mut options = vec![12, hostname.len() as u8];
+    options.extend(hostname.as_bytes());
+    options.push(255);
+    pool::LeaseInfo {
+        ip: ip.parse().unwrap(),
+        client_id: client_id.to_vec(),
+        chaddr: vec![],
+        start,
+        expire: start + 3600,
+        options,
+    }
+}
+
+#[cfg(test)]
+fn mk_question(qdomain: &str, qtype: dnspkt::Type) -> dnspkt::Question {
+    dnspkt::Question {
+        qdomain: qdomain.parse().unwrap(),
+        qclass: dnspkt::CLASS_IN,
+        qtype,
+    }
+}
+
+#[test]
+fn test_sanitise_hostname() {
+    assert_eq!(sanitise_hostname("Laptop"), Some("laptop".into()));
+    assert_eq!(
+        sanitise_hostname("laptop.example.com"),
+        Some("laptop".into())
+    );
+    assert_eq!(
+        sanitise_hostname("Bob's iPhone"),
+        Some("bob-s-iphone".into())
+    );
+    assert_eq!(sanitise_hostname("-_-"), None);
+    assert_eq!(sanitise_hostname(""), None);
+    assert_eq!(sanitise_hostname(&"a".repeat(100)).unwrap().len(), 63);
+}
+
+#[test]
+fn test_name_conflicts() {
+    let leases = vec![
+        mk_lease("192.0.2.2", &[2], 200, "laptop"),
+        mk_lease("192.0.2.1", &[1], 100, "Laptop"),
+        mk_lease("192.0.2.3", &[3], 100, "desktop"),
+    ];
+    let names = LeaseNames::new(&leases, 150, &LeaseNames::default());
+    /* The oldest lease wins */
+    assert_eq!(
+        names.lookup_name("laptop"),
+        Some("192.0.2.1".parse().unwrap())
+    );
+    assert_eq!(names.lookup_address("192.0.2.2".parse().unwrap()), None);
+    assert_eq!(
+        names.lookup_address("192.0.2.3".parse().unwrap()),
+        Some("desktop")
+    );
+
+    /* When the first client renews its lease, it keeps its name */
+    let leases = vec![
+        mk_lease("192.0.2.2", &[2], 200, "laptop"),
+        mk_lease("192.0.2.1", &[1], 300, "laptop"),
+    ];
+    let names = LeaseNames::new(&leases, 350, &names);
+    assert_eq!(
+        names.lookup_name("laptop"),
+        Some("192.0.2.1".parse().unwrap())
+    );
+
+    /* Once its lease expires, the other client gets the name */
+    let leases = vec![
+        mk_lease("192.0.2.2", &[2], 3000, "laptop"),
+        mk_lease("192.0.2.1", &[1], 300, "laptop"),
+    ];
+    let names = LeaseNames::new(&leases, 4000, &names);
+    assert_eq!(
+        names.lookup_name("laptop"),
+        Some("192.0.2.2".parse().unwrap())
+    );
+}
+
+#[test]
+fn test_answer() {
+    let now = 150;
+    let names = LeaseNames::new(
+        &[mk_lease("192.168.1.10", &[1], 100, "laptop")],
+        now,
+        &LeaseNames::default(),
+    );
+    let domain: dnspkt::Domain = "home.arpa".parse().unwrap();
+    let reverse: dnspkt::Domain = "168.192.in-addr.arpa".parse().unwrap();
+
+    let reply = answer(
+        &names,
+        &mk_question("LAPTOP.home.arpa", dnspkt::RR_A),
+        &domain,
+        &domain,
+        now,
+    )
+    .unwrap();
+    assert!(reply.aa);
+    assert_eq!(reply.rcode, dnspkt::NOERROR);
+    assert_eq!(reply.answer.len(), 1);
+    assert_eq!(reply.answer[0].ttl, MAX_TTL);
+    assert_eq!(
+        reply.answer[0].rdata,
+        dnspkt::RData::Other(vec![192, 168, 1, 10])
+    );
+
+    /* The name exists, but has no AAAA records */
+    let reply = answer(
+        &names,
+        &mk_question("laptop.home.arpa", dnspkt::Type(28)),
+        &domain,
+        &domain,
+        now,
+    )
+    .unwrap();
+    assert_eq!(reply.rcode, dnspkt::NOERROR);
+    assert!(reply.answer.is_empty());
+
+    let reply = answer(
+        &names,
+        &mk_question("desktop.home.arpa", dnspkt::RR_A),
+        &domain,
+        &domain,
+        now,
+    )
+    .unwrap();
+    assert_eq!(reply.rcode, dnspkt::NXDOMAIN);
+
+    let reply = answer(
+        &names,
+        &mk_question("10.1.168.192.in-addr.arpa", dnspkt::RR_PTR),
+        &reverse,
+        &domain,
+        now,
+    )
+    .unwrap();
+    assert_eq!(
+        reply.answer[0].rdata,
+        dnspkt::RData::Ptr("laptop.home.arpa".parse().unwrap())
+    );
+
+    let reply = answer(
+        &names,
+        &mk_question("11.1.168.192.in-addr.arpa", dnspkt::RR_PTR),
+        &reverse,
+        &domain,
+        now,
+    )
+    .unwrap();
+    assert_eq!(reply.rcode, dnspkt::NXDOMAIN);
+}
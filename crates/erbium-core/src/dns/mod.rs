@@ -22,10 +22,11 @@ use erbium_net::udp;
 type UdpSocket = udp::UdpSocket;
 
 mod acl;
-mod bucket;
 mod cache;
 pub(crate) mod config;
 pub mod dnspkt;
+#[cfg(feature = "dhcp")]
+pub(crate) mod leases;
 mod outquery;
 #[cfg(fuzzing)]
 pub mod parse;
@@ -33,6 +34,7 @@ pub mod parse;
 mod parse;
 mod router;
 
+use crate::bucket;
 use bytes::BytesMut;
 use tokio_util::codec::Decoder;
 
@@ -164,6 +166,10 @@ impl std::fmt::Display for Error {
 // has sufficient tokens available, then we fail.  This means for small amounts of fixed memory
 // we can have a pretty low false positive rate.
 type Bucket = tokio::sync::RwLock<bucket::GenericTokenBucket>;
+const BUCKET_RATE: bucket::Rate = bucket::Rate {
+    tokens_per_second: 2,
+    max_tokens: 100,
+};
 struct IpRateLimiter([Bucket; 256]);
 
 impl IpRateLimiter {
@@ -457,12 +463,12 @@ impl IpRateLimiter {
         if self.0[bucket1]
             .read()
             .await
-            .check::<bucket::RealTimeClock>(bytes as u32)
+            .check::<bucket::RealTimeClock>(&BUCKET_RATE, bytes as u32)
         {
             self.0[bucket1]
                 .write()
                 .await
-                .deplete::<bucket::RealTimeClock>(bytes as u32);
+                .deplete::<bucket::RealTimeClock>(&BUCKET_RATE, bytes as u32);
             true
         } else {
             let mut bucket2 = hash2 % (self.0.len() - 1);
@@ -473,12 +479,12 @@ impl IpRateLimiter {
             if self.0[bucket2]
                 .read()
                 .await
-                .check::<bucket::RealTimeClock>(bytes as u32)
+                .check::<bucket::RealTimeClock>(&BUCKET_RATE, bytes as u32)
             {
                 self.0[bucket2]
                     .write()
                     .await
-                    .deplete::<bucket::RealTimeClock>(bytes as u32);
+                    .deplete::<bucket::RealTimeClock>(&BUCKET_RATE, bytes as u32);
                 true
             } else {
                 false
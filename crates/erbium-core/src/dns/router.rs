@@ -25,11 +25,15 @@ use super::Error;
 pub struct DnsRouteHandler {
     conf: crate::config::SharedConfig,
     next: super::cache::CacheHandler,
+    #[cfg(feature = "dhcp")]
+    leases: super::leases::LeaseNameHandler,
 }
 
 impl DnsRouteHandler {
     pub async fn new(conf: crate::config::SharedConfig) -> Self {
         DnsRouteHandler {
+            #[cfg(feature = "dhcp")]
+            leases: super::leases::LeaseNameHandler::new(conf.clone()),
             conf,
             next: super::cache::CacheHandler::new().await,
         }
@@ -78,6 +82,14 @@ impl DnsRouteHandler {
                     }
                 }
                 Handler::ForgeNxDomain => Err(Error::Blocked),
+                #[cfg(feature = "dhcp")]
+                Handler::DhcpLeases(ref domain) => {
+                    self.leases
+                        .handle_query(msg, best_suffix.unwrap(), domain)
+                        .await
+                }
+                #[cfg(not(feature = "dhcp"))]
+                Handler::DhcpLeases(_) => Err(Error::NoRouteConfigured),
             }
         } else {
             Err(Error::NoRouteConfigured)
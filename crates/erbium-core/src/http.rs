@@ -25,6 +25,9 @@ use std::convert::Infallible;
 // TODO: the code here that depends on nix should move into erbium-net
 use erbium_net::nix;
 
+/// How many clients to list in /api/v1/ratelimit.json.
+const MAX_RATE_LIMIT_OFFENDERS: usize = 20;
+
 #[derive(Debug)]
 pub enum Error {
     InvalidName(String),
@@ -144,6 +147,78 @@ async fn serve_leases(
         .unwrap())
 }
 
+async fn serve_ratelimit(
+    _req: Request<Body>,
+    dhcp: &std::sync::Arc<crate::dhcp::DhcpService>,
+) -> Result<Response<Body>, Infallible> {
+    let offenders = dhcp.get_rate_limit_offenders(MAX_RATE_LIMIT_OFFENDERS);
+    let buffer = format!(
+        "{{ \"offenders\" : [\n{}\n]}}\n",
+        offenders
+            .iter()
+            .map(|o| format!(
+                " {{ \"chaddr\": \"{}\", \"dropped\": {}, \"last-dropped\": {} }}",
+                o.chaddr
+                    .iter()
+                    .map(|b| format!("{:0>2x}", b))
+                    .collect::<Vec<_>>()
+                    .join(":"),
+                o.dropped,
+                o.last_dropped.as_secs(),
+            ))
+            .collect::<Vec<_>>()
+            .join(",\n")
+    );
+
+    Ok(Response::builder()
+        .status(200)
+        .header("Content-type", "application/json")
+        .body(buffer.into())
+        .unwrap())
+}
+
+async fn serve_forcerenew(
+    req: Request<Body>,
+    dhcp: &std::sync::Arc<crate::dhcp::DhcpService>,
+) -> Result<Response<Body>, Infallible> {
+    use hyper::StatusCode;
+    let ip = req
+        .uri()
+        .query()
+        .unwrap_or("")
+        .split('&')
+        .find_map(|param| param.strip_prefix("ip="))
+        .and_then(|ip| ip.parse::<std::net::Ipv4Addr>().ok());
+    let (status, body) = match ip {
+        None => (
+            StatusCode::BAD_REQUEST,
+            "Expected ?ip=<ipv4 address>".to_string(),
+        ),
+        Some(ip) => match dhcp.force_renew(ip).await {
+            Ok(()) => (StatusCode::OK, format!("Sent DHCPFORCERENEW to {}", ip)),
+            Err(e @ crate::dhcp::ForceRenewError::NoLease(_)) => {
+                (StatusCode::NOT_FOUND, e.to_string())
+            }
+            Err(e @ crate::dhcp::ForceRenewError::NotConfigured) => {
+                (StatusCode::NOT_IMPLEMENTED, e.to_string())
+            }
+            Err(
+                e @ (crate::dhcp::ForceRenewError::NotCapable(_)
+                | crate::dhcp::ForceRenewError::NoHardwareAddress(_)),
+            ) => (StatusCode::BAD_REQUEST, e.to_string()),
+            Err(e) => {
+                log::warn!("{}", e);
+                (StatusCode::INTERNAL_SERVER_ERROR, e.to_string())
+            }
+        },
+    };
+    Ok(Response::builder()
+        .status(status)
+        .header("Content-type", "text/plain")
+        .body(format!("{}\n", body).into())
+        .unwrap())
+}
+
 fn permission_denied() -> Response<Body> {
     use hyper::StatusCode;
     Response::builder()
@@ -212,6 +287,28 @@ async fn serve_request(
             }
         }
         (&Method::GET, "/api/v1/leases.json") => serve_leases(req, &dhcp).await,
+        (&Method::GET, "/api/v1/ratelimit.json") => {
+            if let Some(ret) = require_http_permission(
+                &conf.read().await.acls,
+                &client,
+                acl::PermissionType::HttpLeases,
+            ) {
+                Ok(ret)
+            } else {
+                serve_ratelimit(req, &dhcp).await
+            }
+        }
+        (&Method::POST, "/api/v1/forcerenew") => {
+            if let Some(ret) = require_http_permission(
+                &conf.read().await.acls,
+                &client,
+                acl::PermissionType::HttpForceRenew,
+            ) {
+                Ok(ret)
+            } else {
+                serve_forcerenew(req, &dhcp).await
+            }
+        }
         _ => {
             if let Some(ret) = require_http_permission(
                 &conf.read().await.acls,
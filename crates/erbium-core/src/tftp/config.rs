@@ -0,0 +1,119 @@
+/*   Copyright 2023 Perry Lorier
+ *
+ *  Licensed under the Apache License, Version 2.0 (the "License");
+ *  you may not use this file except in compliance with the License.
+ *  You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ *  Unless required by applicable law or agreed to in writing, software
+ *  distributed under the License is distributed on an "AS IS" BASIS,
+ *  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ *  See the License for the specific language governing permissions and
+ *  limitations under the License.
+ *
+ *  SPDX-License-Identifier: Apache-2.0
+ *
+ *  TFTP Configuration parsing.
+ */
+pub use crate::config::*;
+use yaml_rust::yaml;
+
+/// How many transfers may be in progress at once by default.
+pub const DEFAULT_MAX_TRANSFERS: usize = 64;
+
+#[derive(Debug)]
+pub struct Config {
+    /// The directory files are served from.
+    pub root: std::path::PathBuf,
+    /// Where to listen.  If not specified, this follows default-listen-style.
+    pub listeners: Option<AddressType>,
+    /// How many transfers may be in progress at once, further requests are refused.
+    pub max_transfers: usize,
+}
+
+pub fn parse(name: &str, fragment: &yaml::Yaml) -> Result<Option<Config>, Error> {
+    match fragment {
+        yaml::Yaml::Null => Ok(None),
+        yaml::Yaml::Hash(h) => {
+            let mut root = None;
+            let mut listeners = None;
+            let mut max_transfers = None;
+            for (k, v) in h {
+                match (k.as_str(), v) {
+                    (Some("root"), r) => {
+                        root = parse_string("root", r)?.map(std::path::PathBuf::from);
+                    }
+                    (Some("listeners"), l) => {
+                        listeners = parse_array("listeners", l, parse_string_sockaddr)?
+                            .map(AddressType::Addresses);
+                    }
+                    (Some("max-transfers"), m) => {
+                        max_transfers = parse_num("max-transfers", m)?;
+                        if max_transfers == Some(0) {
+                            return Err(Error::InvalidConfig(
+                                "max-transfers must be at least 1".into(),
+                            ));
+                        }
+                    }
+                    (Some(m), _) => {
+                        return Err(Error::InvalidConfig(format!("Unknown {} key {}", name, m)))
+                    }
+                    (None, _) => {
+                        return Err(Error::InvalidConfig(format!(
+                            "{} keys are expected to be strings",
+                            name
+                        )))
+                    }
+                }
+            }
+            Ok(Some(Config {
+                root: root.ok_or_else(|| {
+                    Error::InvalidConfig(format!("{} requires a root directory", name))
+                })?,
+                listeners,
+                max_transfers: max_transfers.unwrap_or(DEFAULT_MAX_TRANSFERS),
+            }))
+        }
+        e => Err(Error::InvalidConfig(format!(
+            "Expected hash for {}, got {}",
+            name,
+            type_to_name(e)
+        ))),
+    }
+}
+
+#[tokio::test]
+async fn test_parse() {
+    let conf = load_config_from_string_for_test(
+        "---
+tftp:
+  root: /srv/tftp
+  listeners: ['[::]:6969']
+  max-transfers: 8
+",
+    )
+    .expect("Failed to parse tftp config");
+    let conf = conf.read().await;
+    let tftp = conf.tftp.as_ref().expect("Missing tftp config");
+    assert_eq!(tftp.root, std::path::PathBuf::from("/srv/tftp"));
+    assert!(matches!(&tftp.listeners, Some(AddressType::Addresses(a)) if a.len() == 1));
+    assert_eq!(tftp.max_transfers, 8);
+
+    assert!(load_config_from_string_for_test(
+        "---
+tftp:
+  root: /srv/tftp
+  max-transfers: 0
+",
+    )
+    .is_err());
+
+    assert!(load_config_from_string_for_test(
+        "---
+tftp:
+  listeners: ['[::]:69']
+",
+    )
+    .is_err());
+}
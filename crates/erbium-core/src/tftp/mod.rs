@@ -0,0 +1,781 @@
+/*   Copyright 2023 Perry Lorier
+ *
+ *  Licensed under the Apache License, Version 2.0 (the "License");
+ *  you may not use this file except in compliance with the License.
+ *  You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ *  Unless required by applicable law or agreed to in writing, software
+ *  distributed under the License is distributed on an "AS IS" BASIS,
+ *  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ *  See the License for the specific language governing permissions and
+ *  limitations under the License.
+ *
+ *  SPDX-License-Identifier: Apache-2.0
+ *
+ *  Read only TFTP server (RFC1350), so network booting clients can fetch their boot files.
+ *
+ *  Supports the blksize (RFC2348), and timeout and tsize (RFC2349) options.
+ */
+use erbium_net::addr::{NetAddr, NetAddrExt as _, WithPort as _, UNSPECIFIED6};
+use erbium_net::udp;
+use tokio::io::AsyncReadExt as _;
+
+pub mod config;
+pub mod tftppkt;
+
+use tftppkt::Packet;
+
+const TFTP_PORT: u16 = 69;
+const DEFAULT_BLKSIZE: usize = 512;
+/* RFC2348: Valid values range between 8 and 65464 octets */
+const MIN_BLKSIZE: usize = 8;
+const MAX_BLKSIZE: usize = 65464;
+const DEFAULT_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(1);
+const MAX_RETRIES: u32 = 5;
+
+lazy_static::lazy_static! {
+    static ref TFTP_REQUESTS: prometheus::IntCounterVec = prometheus::register_int_counter_vec!(
+        "tftp_requests",
+        "Counts of TFTP requests, by result",
+        &["result"]
+    )
+    .unwrap();
+    static ref TFTP_SENT_BYTES: prometheus::IntCounter = prometheus::register_int_counter!(
+        "tftp_sent_bytes",
+        "Number of bytes of file data sent over TFTP"
+    )
+    .unwrap();
+    static ref TFTP_ACTIVE_TRANSFERS: prometheus::IntGauge = prometheus::register_int_gauge!(
+        "tftp_active_transfers",
+        "Number of TFTP transfers currently in progress"
+    )
+    .unwrap();
+}
+
+#[cfg_attr(test, derive(Debug))]
+pub enum Error {
+    ListenError(std::io::Error, Box<NetAddr>),
+    RecvError(std::io::Error),
+    Io(std::io::Error),
+    ParseError(tftppkt::ParseError),
+    RefusedByAcl(crate::acl::AclError),
+    ReadOnly,
+    UnsupportedMode(String),
+    AccessViolation(String),
+    FileNotFound(String),
+    UnexpectedPacket,
+    ClientError(tftppkt::ErrorCode, String),
+    Timeout,
+    TooManyTransfers,
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        use Error::*;
+        match self {
+            ListenError(e, addr) => write!(f, "Failed to listen for TFTP on {}: {}", addr, e),
+            RecvError(e) => write!(f, "Failed to receive TFTP packet: {}", e),
+            Io(e) => write!(f, "I/O error: {}", e),
+            ParseError(e) => write!(f, "Failed to parse TFTP packet: {}", e),
+            RefusedByAcl(e) => write!(f, "Refused by ACL: {}", e),
+            ReadOnly => write!(f, "Attempt to write to read only server"),
+            UnsupportedMode(m) => write!(f, "Unsupported transfer mode {}", m),
+            AccessViolation(p) => write!(f, "Access violation for {}", p),
+            FileNotFound(p) => write!(f, "File not found: {}", p),
+            UnexpectedPacket => write!(f, "Unexpected packet"),
+            ClientError(code, msg) => write!(f, "Client sent error {}: {}", code.0, msg),
+            Timeout => write!(f, "Timed out waiting for client"),
+            TooManyTransfers => write!(f, "Too many transfers in progress"),
+        }
+    }
+}
+
+impl Error {
+    const fn get_variant_name(&self) -> &'static str {
+        use Error::*;
+        match self {
+            ListenError(_, _) => "LISTEN_ERROR",
+            RecvError(_) => "RECV_ERROR",
+            Io(_) => "IO_ERROR",
+            ParseError(e) => e.get_variant_name(),
+            RefusedByAcl(_) => "REFUSED_BY_ACL",
+            ReadOnly => "READ_ONLY",
+            UnsupportedMode(_) => "UNSUPPORTED_MODE",
+            AccessViolation(_) => "ACCESS_VIOLATION",
+            FileNotFound(_) => "FILE_NOT_FOUND",
+            UnexpectedPacket => "UNEXPECTED_PACKET",
+            ClientError(_, _) => "CLIENT_ERROR",
+            Timeout => "TIMEOUT",
+            TooManyTransfers => "TOO_MANY_TRANSFERS",
+        }
+    }
+
+    /// The error to send back to the client, if any.
+    fn to_packet(&self) -> Option<Packet> {
+        use Error::*;
+        let (code, message) = match self {
+            RefusedByAcl(_) => (tftppkt::ERR_ACCESS_VIOLATION, "Access denied".into()),
+            ReadOnly => (tftppkt::ERR_ACCESS_VIOLATION, "Server is read only".into()),
+            AccessViolation(_) => (tftppkt::ERR_ACCESS_VIOLATION, "Access denied".into()),
+            FileNotFound(_) => (tftppkt::ERR_FILE_NOT_FOUND, "File not found".into()),
+            UnsupportedMode(m) => (
+                tftppkt::ERR_ILLEGAL_OPERATION,
+                format!("Unsupported mode {}", m),
+            ),
+            UnexpectedPacket => (tftppkt::ERR_ILLEGAL_OPERATION, "Illegal operation".into()),
+            Io(_) => (tftppkt::ERR_NOT_DEFINED, "Read error".into()),
+            TooManyTransfers => (tftppkt::ERR_NOT_DEFINED, "Server busy".into()),
+            /* Don't reply to errors with errors, and there's no point replying to a timeout */
+            ClientError(_, _) | Timeout => return None,
+            ListenError(_, _) | RecvError(_) | ParseError(_) => return None,
+        };
+        Some(Packet::Error { code, message })
+    }
+}
+
+/// The parameters of a transfer, after option negotiation.
+#[derive(Debug, PartialEq, Eq)]
+struct Transfer {
+    blksize: usize,
+    timeout: std::time::Duration,
+    netascii: bool,
+}
+
+/// RFC2347: Works out which of the options the client requested we'll honour, and what to send
+/// back in the OACK.  Options we don't understand or don't like are silently ignored.
+fn negotiate(
+    req: &tftppkt::Request,
+    file_size: u64,
+) -> Result<(Transfer, Vec<(String, String)>), Error> {
+    let netascii = match req.mode.as_str() {
+        "octet" => false,
+        "netascii" => true,
+        m => return Err(Error::UnsupportedMode(m.into())),
+    };
+    let mut transfer = Transfer {
+        blksize: DEFAULT_BLKSIZE,
+        timeout: DEFAULT_TIMEOUT,
+        netascii,
+    };
+    let mut oack = vec![];
+    for (name, value) in &req.options {
+        match (name.as_str(), value.parse::<u64>()) {
+            /* RFC2348: The server may reply with a smaller block size than was asked for */
+            ("blksize", Ok(v)) if v >= MIN_BLKSIZE as u64 => {
+                transfer.blksize = std::cmp::min(v, MAX_BLKSIZE as u64) as usize;
+                oack.push((name.clone(), transfer.blksize.to_string()));
+            }
+            ("timeout", Ok(v)) if (1..=255).contains(&v) => {
+                transfer.timeout = std::time::Duration::from_secs(v);
+                oack.push((name.clone(), value.clone()));
+            }
+            /* RFC2349: The size isn't known in advance for netascii, so only octet gets a tsize */
+            ("tsize", Ok(_)) if !netascii => {
+                oack.push((name.clone(), file_size.to_string()));
+            }
+            _ => (),
+        }
+    }
+    Ok((transfer, oack))
+}
+
+/// Maps a requested file name to a path under `root`, refusing anything that would escape it.
+///
+/// Some clients use \ as a path separator, so that is treated as one too.
+fn resolve_path(root: &std::path::Path, filename: &str) -> Result<std::path::PathBuf, Error> {
+    let mut path = root.to_path_buf();
+    for component in filename.split(['/', '\\']) {
+        match component {
+            "" | "." => (),
+            ".." => return Err(Error::AccessViolation(filename.into())),
+            c => path.push(c),
+        }
+    }
+    Ok(path)
+}
+
+async fn open_file(
+    root: &std::path::Path,
+    filename: &str,
+) -> Result<(tokio::fs::File, u64), Error> {
+    let not_found = |e: std::io::Error| match e.kind() {
+        std::io::ErrorKind::NotFound => Error::FileNotFound(filename.into()),
+        std::io::ErrorKind::PermissionDenied => Error::AccessViolation(filename.into()),
+        _ => Error::Io(e),
+    };
+    let path = resolve_path(root, filename)?;
+    /* Symlinks could still point outside of the root, so check where we really end up */
+    let root = tokio::fs::canonicalize(root).await.map_err(Error::Io)?;
+    let path = tokio::fs::canonicalize(path).await.map_err(not_found)?;
+    if !path.starts_with(&root) {
+        return Err(Error::AccessViolation(filename.into()));
+    }
+    let file = tokio::fs::File::open(&path).await.map_err(not_found)?;
+    let metadata = file.metadata().await.map_err(Error::Io)?;
+    if !metadata.is_file() {
+        return Err(Error::FileNotFound(filename.into()));
+    }
+    Ok((file, metadata.len()))
+}
+
+/// Reads a file in block sized chunks, converting it to netascii if required.
+struct BlockReader<R> {
+    file: R,
+    netascii: bool,
+    pending: Vec<u8>,
+    eof: bool,
+}
+
+impl<R: tokio::io::AsyncRead + Unpin> BlockReader<R> {
+    fn new(file: R, netascii: bool) -> Self {
+        Self {
+            file,
+            netascii,
+            pending: vec![],
+            eof: false,
+        }
+    }
+
+    async fn next_block(&mut self, size: usize) -> Result<Vec<u8>, std::io::Error> {
+        let mut buf = vec![0; size];
+        while self.pending.len() < size && !self.eof {
+            let n = self.file.read(&mut buf).await?;
+            if n == 0 {
+                self.eof = true;
+            } else if self.netascii {
+                /* RFC1350 via RFC764: Line endings are CR LF, and a bare CR is CR NUL */
+                for b in &buf[..n] {
+                    match b {
+                        b'\n' => self.pending.extend_from_slice(b"\r\n"),
+                        b'\r' => self.pending.extend_from_slice(b"\r\0"),
+                        b => self.pending.push(*b),
+                    }
+                }
+            } else {
+                self.pending.extend_from_slice(&buf[..n]);
+            }
+        }
+        let n = std::cmp::min(size, self.pending.len());
+        Ok(self.pending.drain(..n).collect())
+    }
+}
+
+/// Sends `pkt` and waits for the client to acknowledge `block`, retransmitting as needed.
+async fn send_and_wait_for_ack(
+    sock: &tokio::net::UdpSocket,
+    pkt: &[u8],
+    block: u16,
+    timeout: std::time::Duration,
+) -> Result<(), Error> {
+    let mut buf = vec![0; 1500];
+    for _ in 0..MAX_RETRIES {
+        sock.send(pkt).await.map_err(Error::Io)?;
+        let deadline = tokio::time::Instant::now() + timeout;
+        loop {
+            let n = match tokio::time::timeout_at(deadline, sock.recv(&mut buf)).await {
+                Err(_) => break, /* Timed out, retransmit */
+                Ok(r) => r.map_err(Error::Io)?,
+            };
+            match tftppkt::parse(&buf[..n]) {
+                Ok(Packet::Ack { block: b }) if b == block => return Ok(()),
+                /* Duplicate ACKs for earlier blocks are ignored, rather than retransmitting in
+                 * response to them, to avoid the Sorcerer's Apprentice bug (RFC1123 4.2.3.1).
+                 */
+                Ok(Packet::Ack { .. }) => (),
+                Ok(Packet::Error { code, message }) => {
+                    return Err(Error::ClientError(code, message))
+                }
+                Ok(_) => return Err(Error::UnexpectedPacket),
+                Err(e) => return Err(Error::ParseError(e)),
+            }
+        }
+    }
+    Err(Error::Timeout)
+}
+
+async fn send_file(
+    sock: &tokio::net::UdpSocket,
+    req: &tftppkt::Request,
+    root: &std::path::Path,
+) -> Result<u64, Error> {
+    let (file, size) = open_file(root, &req.filename).await?;
+    let (transfer, oack) = negotiate(req, size)?;
+
+    /* RFC2347: If we've accepted any options, the client acknowledges them with an ACK of block
+     * 0 before we start sending data.
+     */
+    if !oack.is_empty() {
+        let pkt = Packet::OptionAck { options: oack }.serialise();
+        send_and_wait_for_ack(sock, &pkt, 0, transfer.timeout).await?;
+    }
+
+    let mut reader = BlockReader::new(file, transfer.netascii);
+    let mut block: u16 = 1;
+    let mut sent = 0;
+    loop {
+        let data = reader
+            .next_block(transfer.blksize)
+            .await
+            .map_err(Error::Io)?;
+        let len = data.len();
+        let pkt = Packet::Data { block, data }.serialise();
+        send_and_wait_for_ack(sock, &pkt, block, transfer.timeout).await?;
+        TFTP_SENT_BYTES.inc_by(len as u64);
+        sent += len as u64;
+        /* A short block marks the end of the file */
+        if len < transfer.blksize {
+            return Ok(sent);
+        }
+        /* Large files wrap the block number around */
+        block = block.wrapping_add(1);
+    }
+}
+
+/// Creates the socket for a transfer with `peer`.
+///
+/// RFC1350: Each transfer gets its own port (TID) on our side.
+async fn transfer_socket(
+    local_ip: std::net::IpAddr,
+    peer: std::net::SocketAddr,
+) -> Result<tokio::net::UdpSocket, std::io::Error> {
+    let local = match (local_ip, peer) {
+        /* Link local addresses need a scope */
+        (std::net::IpAddr::V6(ip), std::net::SocketAddr::V6(p)) => {
+            std::net::SocketAddrV6::new(ip, 0, 0, p.scope_id()).into()
+        }
+        (ip, _) => std::net::SocketAddr::new(ip, 0),
+    };
+    let sock = tokio::net::UdpSocket::bind(local).await?;
+    sock.connect(peer).await?;
+    Ok(sock)
+}
+
+/// Refuses a request because too many transfers are already in progress.
+async fn refuse_request(rm: &erbium_net::socket::RecvMsg) {
+    TFTP_REQUESTS
+        .with_label_values(&[Error::TooManyTransfers.get_variant_name()])
+        .inc();
+    let (peer, local_ip) = match (
+        rm.address.as_ref().and_then(|a| a.to_std_socket_addr()),
+        rm.local_ip(),
+    ) {
+        (Some(peer), Some(local_ip)) => (peer, local_ip),
+        _ => return,
+    };
+    log::warn!(
+        "{}: Refusing TFTP request: {}",
+        peer,
+        Error::TooManyTransfers
+    );
+    if let (Ok(sock), Some(reply)) = (
+        transfer_socket(local_ip, peer).await,
+        Error::TooManyTransfers.to_packet(),
+    ) {
+        /* Best effort, the client will retry or time out if this goes missing */
+        let _ = sock.send(&reply.serialise()).await;
+    }
+}
+
+async fn handle_request(
+    conf: crate::config::SharedConfig,
+    rm: erbium_net::socket::RecvMsg,
+    _permit: tokio::sync::OwnedSemaphorePermit,
+) {
+    let (peer, local_ip) = match (
+        rm.address.as_ref().and_then(|a| a.to_std_socket_addr()),
+        rm.local_ip(),
+    ) {
+        (Some(peer), Some(local_ip)) => (peer, local_ip),
+        _ => {
+            log::warn!("Received TFTP request without addresses");
+            return;
+        }
+    };
+
+    let pkt = match tftppkt::parse(&rm.buffer) {
+        Ok(pkt) => pkt,
+        Err(e) => {
+            log::warn!("{}: Failed to parse TFTP request: {}", peer, e);
+            TFTP_REQUESTS
+                .with_label_values(&[e.get_variant_name()])
+                .inc();
+            return;
+        }
+    };
+
+    let sock = match transfer_socket(local_ip, peer).await {
+        Ok(sock) => sock,
+        Err(e) => {
+            log::warn!("{}: Failed to create TFTP transfer socket: {}", peer, e);
+            TFTP_REQUESTS.with_label_values(&["SOCKET_ERROR"]).inc();
+            return;
+        }
+    };
+
+    TFTP_ACTIVE_TRANSFERS.inc();
+    let result = async {
+        let acl = crate::acl::require_permission(
+            &conf.read().await.acls,
+            &crate::acl::Attributes {
+                addr: rm.address.unwrap(),
+            },
+            crate::acl::PermissionType::Tftp,
+        );
+        acl.map_err(Error::RefusedByAcl)?;
+        match &pkt {
+            Packet::ReadRequest(req) => {
+                let root = match &conf.read().await.tftp {
+                    Some(tftp) => tftp.root.clone(),
+                    None => return Err(Error::FileNotFound(req.filename.clone())),
+                };
+                log::info!("{}: TFTP read request for {}", peer, req.filename);
+                send_file(&sock, req, &root).await
+            }
+            Packet::WriteRequest(_) => Err(Error::ReadOnly),
+            _ => Err(Error::UnexpectedPacket),
+        }
+    }
+    .await;
+    TFTP_ACTIVE_TRANSFERS.dec();
+
+    match result {
+        Ok(size) => {
+            log::info!("{}: TFTP transfer complete, {} bytes", peer, size);
+            TFTP_REQUESTS.with_label_values(&["OK"]).inc();
+        }
+        Err(e) => {
+            log::warn!("{}: TFTP request failed: {}", peer, e);
+            TFTP_REQUESTS
+                .with_label_values(&[e.get_variant_name()])
+                .inc();
+            if let Some(reply) = e.to_packet() {
+                /* Best effort, the client will time out if this goes missing */
+                let _ = sock.send(&reply.serialise()).await;
+            }
+        }
+    }
+}
+
+pub struct TftpService {
+    conf: crate::config::SharedConfig,
+    listeners: Vec<udp::UdpSocket>,
+    /// Limits how many transfers can be in progress at once, across all listeners.
+    transfers: std::sync::Arc<tokio::sync::Semaphore>,
+}
+
+impl TftpService {
+    async fn listen(addr: &NetAddr) -> Result<udp::UdpSocket, Error> {
+        let sock = udp::UdpSocket::bind(&[*addr])
+            .await
+            .map_err(|e| Error::ListenError(e, Box::new(*addr)))?;
+        if addr.as_sockaddr_in6().is_some() {
+            sock.set_opt_ipv6_packet_info(true)
+        } else {
+            sock.set_opt_ipv4_packet_info(true)
+        }
+        .map_err(|e| Error::ListenError(e, Box::new(*addr)))?;
+        log::info!(
+            "Listening for TFTP on {}",
+            sock.local_addr()
+                .map(|name| format!("{}", name))
+                .unwrap_or_else(|_| "Unknown".into())
+        );
+        Ok(sock)
+    }
+
+    /// Creates the TFTP service, or returns None if TFTP isn't configured.
+    pub async fn new(
+        conf: crate::config::SharedConfig,
+        netinfo: &erbium_net::netinfo::SharedNetInfo,
+    ) -> Result<Option<Self>, Error> {
+        let (addrs, max_transfers) = {
+            let roconf = conf.read().await;
+            match &roconf.tftp {
+                None => return Ok(None),
+                Some(config::Config {
+                    listeners: Some(listeners),
+                    max_transfers,
+                    ..
+                }) => (
+                    listeners
+                        .as_sockaddrs(&roconf.addresses, netinfo, TFTP_PORT)
+                        .await,
+                    *max_transfers,
+                ),
+                Some(tftp) => (vec![UNSPECIFIED6.with_port(TFTP_PORT)], tftp.max_transfers),
+            }
+        };
+        let mut listeners = vec![];
+        for addr in &addrs {
+            listeners.push(Self::listen(addr).await?);
+        }
+        Ok(Some(Self {
+            conf,
+            listeners,
+            transfers: std::sync::Arc::new(tokio::sync::Semaphore::new(max_transfers)),
+        }))
+    }
+
+    async fn run_listener(
+        conf: crate::config::SharedConfig,
+        listener: udp::UdpSocket,
+        transfers: std::sync::Arc<tokio::sync::Semaphore>,
+    ) -> Error {
+        loop {
+            match listener.recv_msg(65536, udp::MsgFlags::empty()).await {
+                Ok(rm) => match transfers.clone().try_acquire_owned() {
+                    Ok(permit) => {
+                        tokio::spawn(handle_request(conf.clone(), rm, permit));
+                    }
+                    Err(_) => refuse_request(&rm).await,
+                },
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => (),
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => (),
+                Err(e) => return Error::RecvError(e),
+            }
+        }
+    }
+
+    pub async fn run(self) -> Result<(), Error> {
+        use futures::StreamExt as _;
+        let mut services = self
+            .listeners
+            .into_iter()
+            .map(|listener| {
+                tokio::spawn(Self::run_listener(
+                    self.conf.clone(),
+                    listener,
+                    self.transfers.clone(),
+                ))
+            })
+            .collect::<futures::stream::FuturesUnordered<_>>();
+        match services.next().await {
+            Some(Ok(e)) => Err(e),
+            Some(Err(e)) => Err(Error::Io(e.into())),
+            None => Ok(()),
+        }
+    }
+}
+
+#[test]
+fn test_resolve_path() {
+    let root = std::path::Path::new("/srv/tftp");
+    assert_eq!(
+        resolve_path(root, "pxelinux.0").unwrap(),
+        root.join("pxelinux.0")
+    );
+    assert_eq!(
+        resolve_path(root, "/boot//./grub/grubx64.efi").unwrap(),
+        root.join("boot/grub/grubx64.efi")
+    );
+    assert_eq!(
+        resolve_path(root, "boot\\x64\\wdsnbp.com").unwrap(),
+        root.join("boot/x64/wdsnbp.com")
+    );
+    assert!(resolve_path(root, "../etc/passwd").is_err());
+    assert!(resolve_path(root, "boot/../../etc/passwd").is_err());
+    assert!(resolve_path(root, "..\\etc\\passwd").is_err());
+}
+
+#[tokio::test]
+async fn test_open_file() {
+    let dir = std::env::temp_dir().join(format!("erbium-tftp-test-{}", std::process::id()));
+    let root = dir.join("root");
+    std::fs::create_dir_all(root.join("sub")).unwrap();
+    std::fs::write(root.join("sub/file"), b"hello").unwrap();
+    std::fs::write(dir.join("secret"), b"secret").unwrap();
+    std::os::unix::fs::symlink(dir.join("secret"), root.join("escape")).unwrap();
+
+    let (_, size) = open_file(&root, "/sub/file").await.unwrap();
+    assert_eq!(size, 5);
+    assert!(matches!(
+        open_file(&root, "missing").await,
+        Err(Error::FileNotFound(_))
+    ));
+    assert!(matches!(
+        open_file(&root, "sub").await,
+        Err(Error::FileNotFound(_))
+    ));
+    assert!(matches!(
+        open_file(&root, "escape").await,
+        Err(Error::AccessViolation(_))
+    ));
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_negotiate() {
+    let req = |mode: &str, options: &[(&str, &str)]| tftppkt::Request {
+        filename: "file".into(),
+        mode: mode.into(),
+        options: options
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect(),
+    };
+    let (transfer, oack) = negotiate(&req("octet", &[]), 1234).unwrap();
+    assert_eq!(transfer.blksize, DEFAULT_BLKSIZE);
+    assert!(oack.is_empty());
+
+    let (transfer, oack) = negotiate(
+        &req(
+            "octet",
+            &[
+                ("blksize", "100000"),
+                ("tsize", "0"),
+                ("timeout", "3"),
+                ("multicast", ""),
+            ],
+        ),
+        1234,
+    )
+    .unwrap();
+    assert_eq!(
+        transfer,
+        Transfer {
+            blksize: MAX_BLKSIZE,
+            timeout: std::time::Duration::from_secs(3),
+            netascii: false,
+        }
+    );
+    assert_eq!(
+        oack,
+        vec![
+            ("blksize".to_string(), MAX_BLKSIZE.to_string()),
+            ("tsize".to_string(), "1234".to_string()),
+            ("timeout".to_string(), "3".to_string()),
+        ]
+    );
+
+    /* Invalid values are ignored, and netascii doesn't get a tsize */
+    let (transfer, oack) = negotiate(
+        &req(
+            "netascii",
+            &[("blksize", "4"), ("timeout", "0"), ("tsize", "0")],
+        ),
+        1234,
+    )
+    .unwrap();
+    assert_eq!(transfer.blksize, DEFAULT_BLKSIZE);
+    assert!(transfer.netascii);
+    assert!(oack.is_empty());
+
+    assert!(matches!(
+        negotiate(&req("mail", &[]), 0),
+        Err(Error::UnsupportedMode(_))
+    ));
+}
+
+#[tokio::test]
+async fn test_block_reader() {
+    let mut reader = BlockReader::new(&b"line1\nline2\rx"[..], true);
+    assert_eq!(reader.next_block(8).await.unwrap(), b"line1\r\nl");
+    assert_eq!(reader.next_block(8).await.unwrap(), b"ine2\r\0x");
+
+    /* An exact multiple of the block size ends with an empty block */
+    let mut reader = BlockReader::new(&[1, 2, 3, 4][..], false);
+    assert_eq!(reader.next_block(2).await.unwrap(), vec![1, 2]);
+    assert_eq!(reader.next_block(2).await.unwrap(), vec![3, 4]);
+    assert_eq!(reader.next_block(2).await.unwrap(), Vec::<u8>::new());
+}
+
+#[tokio::test]
+async fn test_transfer() {
+    let dir = std::env::temp_dir().join(format!("erbium-tftp-xfer-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let contents = (0..1000).map(|i| i as u8).collect::<Vec<u8>>();
+    std::fs::write(dir.join("boot.img"), &contents).unwrap();
+
+    let server = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let client = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    server.connect(client.local_addr().unwrap()).await.unwrap();
+    client.connect(server.local_addr().unwrap()).await.unwrap();
+
+    let req = tftppkt::Request {
+        filename: "boot.img".into(),
+        mode: "octet".into(),
+        options: vec![("blksize".into(), "600".into())],
+    };
+    let root = dir.clone();
+    let server_task = tokio::spawn(async move { send_file(&server, &req, &root).await });
+
+    let mut buf = vec![0; 1500];
+    let mut received = vec![];
+    let n = client.recv(&mut buf).await.unwrap();
+    assert_eq!(
+        tftppkt::parse(&buf[..n]),
+        Ok(Packet::OptionAck {
+            options: vec![("blksize".into(), "600".into())]
+        })
+    );
+    client
+        .send(&Packet::Ack { block: 0 }.serialise())
+        .await
+        .unwrap();
+    for expected_block in 1..=2 {
+        let n = client.recv(&mut buf).await.unwrap();
+        match tftppkt::parse(&buf[..n]) {
+            Ok(Packet::Data { block, data }) => {
+                assert_eq!(block, expected_block);
+                received.extend(data);
+            }
+            p => panic!("Unexpected packet {:?}", p),
+        }
+        client
+            .send(
+                &Packet::Ack {
+                    block: expected_block,
+                }
+                .serialise(),
+            )
+            .await
+            .unwrap();
+    }
+    assert_eq!(server_task.await.unwrap().unwrap(), 1000);
+    assert_eq!(received, contents);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn test_refuse_request() {
+    let listener = TftpService::listen(
+        &"127.0.0.1:0"
+            .parse::<std::net::SocketAddr>()
+            .unwrap()
+            .into(),
+    )
+    .await
+    .unwrap();
+    let client = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let req = Packet::ReadRequest(tftppkt::Request {
+        filename: "boot.img".into(),
+        mode: "octet".into(),
+        options: vec![],
+    });
+    client
+        .send_to(
+            &req.serialise(),
+            listener.local_addr().unwrap().to_std_socket_addr().unwrap(),
+        )
+        .await
+        .unwrap();
+    let rm = listener
+        .recv_msg(65536, udp::MsgFlags::empty())
+        .await
+        .unwrap();
+    refuse_request(&rm).await;
+
+    let mut buf = vec![0; 1500];
+    let n = client.recv(&mut buf).await.unwrap();
+    assert_eq!(
+        tftppkt::parse(&buf[..n]),
+        Ok(Packet::Error {
+            code: tftppkt::ERR_NOT_DEFINED,
+            message: "Server busy".into()
+        })
+    );
+}
@@ -0,0 +1,233 @@
+/*   Copyright 2023 Perry Lorier
+ *
+ *  Licensed under the Apache License, Version 2.0 (the "License");
+ *  you may not use this file except in compliance with the License.
+ *  You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ *  Unless required by applicable law or agreed to in writing, software
+ *  distributed under the License is distributed on an "AS IS" BASIS,
+ *  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ *  See the License for the specific language governing permissions and
+ *  limitations under the License.
+ *
+ *  SPDX-License-Identifier: Apache-2.0
+ *
+ *  TFTP packet parsing and serialisation (RFC1350, with options from RFC2347).
+ */
+use crate::pktparser;
+
+pub const OP_RRQ: u16 = 1;
+pub const OP_WRQ: u16 = 2;
+pub const OP_DATA: u16 = 3;
+pub const OP_ACK: u16 = 4;
+pub const OP_ERROR: u16 = 5;
+pub const OP_OACK: u16 = 6; /* RFC2347 */
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ErrorCode(pub u16);
+
+pub const ERR_NOT_DEFINED: ErrorCode = ErrorCode(0);
+pub const ERR_FILE_NOT_FOUND: ErrorCode = ErrorCode(1);
+pub const ERR_ACCESS_VIOLATION: ErrorCode = ErrorCode(2);
+pub const ERR_ILLEGAL_OPERATION: ErrorCode = ErrorCode(4);
+pub const ERR_UNKNOWN_TID: ErrorCode = ErrorCode(5);
+pub const ERR_OPTION_REFUSED: ErrorCode = ErrorCode(8); /* RFC2347 */
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ParseError {
+    UnexpectedEndOfInput,
+    UnknownOpcode(u16),
+    InvalidString,
+}
+
+impl std::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        use ParseError::*;
+        match self {
+            UnexpectedEndOfInput => write!(f, "Unexpected end of input"),
+            UnknownOpcode(op) => write!(f, "Unknown opcode {}", op),
+            InvalidString => write!(f, "Invalid string"),
+        }
+    }
+}
+
+impl ParseError {
+    pub const fn get_variant_name(&self) -> &'static str {
+        use ParseError::*;
+        match self {
+            UnexpectedEndOfInput => "UNEXPECTED_END_OF_INPUT",
+            UnknownOpcode(_) => "UNKNOWN_OPCODE",
+            InvalidString => "INVALID_STRING",
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct Request {
+    pub filename: String,
+    pub mode: String,
+    /// Options (RFC2347) in the order the client sent them.  Names are lowercased.
+    pub options: Vec<(String, String)>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Packet {
+    ReadRequest(Request),
+    WriteRequest(Request),
+    Data { block: u16, data: Vec<u8> },
+    Ack { block: u16 },
+    Error { code: ErrorCode, message: String },
+    OptionAck { options: Vec<(String, String)> },
+}
+
+fn get_string(buf: &mut pktparser::Buffer) -> Result<String, ParseError> {
+    let mut s = vec![];
+    loop {
+        match buf.get_u8().ok_or(ParseError::UnexpectedEndOfInput)? {
+            0 => break,
+            c => s.push(c),
+        }
+    }
+    String::from_utf8(s).map_err(|_| ParseError::InvalidString)
+}
+
+fn get_options(buf: &mut pktparser::Buffer) -> Result<Vec<(String, String)>, ParseError> {
+    let mut options = vec![];
+    while !buf.empty() {
+        let name = get_string(buf)?.to_ascii_lowercase();
+        let value = get_string(buf)?;
+        options.push((name, value));
+    }
+    Ok(options)
+}
+
+fn get_request(buf: &mut pktparser::Buffer) -> Result<Request, ParseError> {
+    Ok(Request {
+        filename: get_string(buf)?,
+        mode: get_string(buf)?.to_ascii_lowercase(),
+        options: get_options(buf)?,
+    })
+}
+
+pub fn parse(pkt: &[u8]) -> Result<Packet, ParseError> {
+    let mut buf = pktparser::Buffer::new(pkt);
+    let opcode = buf.get_be16().ok_or(ParseError::UnexpectedEndOfInput)?;
+    match opcode {
+        OP_RRQ => Ok(Packet::ReadRequest(get_request(&mut buf)?)),
+        OP_WRQ => Ok(Packet::WriteRequest(get_request(&mut buf)?)),
+        OP_DATA => Ok(Packet::Data {
+            block: buf.get_be16().ok_or(ParseError::UnexpectedEndOfInput)?,
+            data: buf.get_vec(buf.remaining()).unwrap_or_default(),
+        }),
+        OP_ACK => Ok(Packet::Ack {
+            block: buf.get_be16().ok_or(ParseError::UnexpectedEndOfInput)?,
+        }),
+        OP_ERROR => Ok(Packet::Error {
+            code: ErrorCode(buf.get_be16().ok_or(ParseError::UnexpectedEndOfInput)?),
+            message: get_string(&mut buf)?,
+        }),
+        OP_OACK => Ok(Packet::OptionAck {
+            options: get_options(&mut buf)?,
+        }),
+        op => Err(ParseError::UnknownOpcode(op)),
+    }
+}
+
+fn push_string(v: &mut Vec<u8>, s: &str) {
+    v.extend_from_slice(s.as_bytes());
+    v.push(0);
+}
+
+fn push_request(v: &mut Vec<u8>, opcode: u16, req: &Request) {
+    v.extend_from_slice(&opcode.to_be_bytes());
+    push_string(v, &req.filename);
+    push_string(v, &req.mode);
+    push_options(v, &req.options);
+}
+
+fn push_options(v: &mut Vec<u8>, options: &[(String, String)]) {
+    for (name, value) in options {
+        push_string(v, name);
+        push_string(v, value);
+    }
+}
+
+impl Packet {
+    pub fn serialise(&self) -> Vec<u8> {
+        let mut v = vec![];
+        match self {
+            Packet::ReadRequest(req) => push_request(&mut v, OP_RRQ, req),
+            Packet::WriteRequest(req) => push_request(&mut v, OP_WRQ, req),
+            Packet::Data { block, data } => {
+                v.extend_from_slice(&OP_DATA.to_be_bytes());
+                v.extend_from_slice(&block.to_be_bytes());
+                v.extend_from_slice(data);
+            }
+            Packet::Ack { block } => {
+                v.extend_from_slice(&OP_ACK.to_be_bytes());
+                v.extend_from_slice(&block.to_be_bytes());
+            }
+            Packet::Error { code, message } => {
+                v.extend_from_slice(&OP_ERROR.to_be_bytes());
+                v.extend_from_slice(&code.0.to_be_bytes());
+                push_string(&mut v, message);
+            }
+            Packet::OptionAck { options } => {
+                v.extend_from_slice(&OP_OACK.to_be_bytes());
+                push_options(&mut v, options);
+            }
+        }
+        v
+    }
+}
+
+#[test]
+fn test_parse_rrq() {
+    let pkt = b"\x00\x01pxelinux.0\x00OCTET\x00blksize\x001428\x00TSize\x000\x00";
+    let expected = Packet::ReadRequest(Request {
+        filename: "pxelinux.0".into(),
+        mode: "octet".into(),
+        options: vec![
+            ("blksize".into(), "1428".into()),
+            ("tsize".into(), "0".into()),
+        ],
+    });
+    assert_eq!(parse(pkt), Ok(expected));
+    /* Missing the NUL on the mode */
+    assert_eq!(
+        parse(b"\x00\x01pxelinux.0\x00octet"),
+        Err(ParseError::UnexpectedEndOfInput)
+    );
+    assert_eq!(parse(b"\x00\x09"), Err(ParseError::UnknownOpcode(9)));
+}
+
+#[test]
+fn test_round_trip() {
+    for pkt in [
+        Packet::WriteRequest(Request {
+            filename: "file".into(),
+            mode: "netascii".into(),
+            options: vec![],
+        }),
+        Packet::Data {
+            block: 1,
+            data: vec![1, 2, 3],
+        },
+        Packet::Data {
+            block: 2,
+            data: vec![],
+        },
+        Packet::Ack { block: 65535 },
+        Packet::Error {
+            code: ERR_FILE_NOT_FOUND,
+            message: "File not found".into(),
+        },
+        Packet::OptionAck {
+            options: vec![("blksize".into(), "1428".into())],
+        },
+    ] {
+        assert_eq!(parse(&pkt.serialise()), Ok(pkt));
+    }
+}
@@ -34,6 +34,9 @@ pub const ALL_NODES: Ipv6Addr = Ipv6Addr::new(
 pub const ALL_ROUTERS: Ipv6Addr = Ipv6Addr::new(
     0xff02, 0x0000, 0x0000, 0x0000, 0x0000, 0x0000, 0x0000, 0x0002,
 );
+pub const ALL_DHCP_RELAY_AGENTS_AND_SERVERS: Ipv6Addr = Ipv6Addr::new(
+    0xff02, 0x0000, 0x0000, 0x0000, 0x0000, 0x0000, 0x0001, 0x0002,
+);
 
 /// Converts the socket address to a NetAddr.
 pub trait ToNetAddr {
@@ -18,6 +18,7 @@ pub mod addr;
 pub mod netinfo;
 pub mod packet;
 pub mod raw;
+pub mod route;
 pub mod socket;
 pub mod udp;
 
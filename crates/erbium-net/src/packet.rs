@@ -157,7 +157,7 @@ impl<'a> Fragment<'a> {
         let netsum = finish_netsum(partial_netsum(0, &f.buffer));
         f.buffer[10] = (netsum >> 8) as u8;
         f.buffer[11] = (netsum & 0xFF) as u8;
-        Self::new_ethernet(dstmac, srcmac, 0x0800_u16, Tail::Fragment(Box::new(f)))
+        Self::new_ethernet(dstmac, srcmac, ETHERTYPE_IP4, Tail::Fragment(Box::new(f)))
     }
 
     pub fn new_udp4<'l>(
@@ -193,6 +193,66 @@ impl<'a> Fragment<'a> {
             t,
         )
     }
+
+    /// Builds an RFC5227 ARP Probe asking who has `target`, broadcast from `srcmac`.
+    ///
+    /// The sender protocol address is left as 0.0.0.0, so that the probe doesn't pollute the ARP
+    /// caches of other hosts on the link.
+    pub fn new_arp_probe(srcmac: &[u8; 6], target: net::Ipv4Addr) -> Fragment<'static> {
+        let mut f = Fragment::from_tail(Tail::None);
+        f.push_be16(ARP_HTYPE_ETHERNET);
+        f.push_be16(ETHERTYPE_IP4);
+        f.push_u8(6); /* Hardware address length */
+        f.push_u8(4); /* Protocol address length */
+        f.push_be16(ARP_REQUEST);
+        f.push_bytes(srcmac);
+        f.push_bytes(&net::Ipv4Addr::UNSPECIFIED.octets());
+        f.push_bytes(&[0; 6]);
+        f.push_bytes(&target.octets());
+        Self::new_ethernet(
+            &[0xff; 6],
+            srcmac,
+            ETHERTYPE_ARP,
+            Tail::Fragment(Box::new(f)),
+        )
+    }
+}
+
+const ETHERTYPE_IP4: u16 = 0x0800;
+const ETHERTYPE_ARP: u16 = 0x0806;
+const ARP_HTYPE_ETHERNET: u16 = 1;
+pub const ARP_REQUEST: u16 = 1;
+pub const ARP_REPLY: u16 = 2;
+
+/// An Ethernet/IPv4 ARP packet.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Arp {
+    pub op: u16,
+    pub sender_mac: [u8; 6],
+    pub sender_ip: net::Ipv4Addr,
+    pub target_mac: [u8; 6],
+    pub target_ip: net::Ipv4Addr,
+}
+
+/// Parses an ethernet frame containing an ARP packet, returning None if it isn't one.
+pub fn parse_arp(frame: &[u8]) -> Option<Arp> {
+    let ethertype = u16::from_be_bytes(frame.get(12..14)?.try_into().ok()?);
+    let arp = frame.get(14..42)?;
+    if ethertype != ETHERTYPE_ARP
+        || arp[0..2] != ARP_HTYPE_ETHERNET.to_be_bytes()
+        || arp[2..4] != ETHERTYPE_IP4.to_be_bytes()
+        || arp[4] != 6
+        || arp[5] != 4
+    {
+        return None;
+    }
+    Some(Arp {
+        op: u16::from_be_bytes([arp[6], arp[7]]),
+        sender_mac: arp[8..14].try_into().ok()?,
+        sender_ip: <[u8; 4]>::try_from(&arp[14..18]).ok()?.into(),
+        target_mac: arp[18..24].try_into().ok()?,
+        target_ip: <[u8; 4]>::try_from(&arp[24..28]).ok()?.into(),
+    })
 }
 
 #[test]
@@ -213,3 +273,27 @@ fn test_checksum() {
 
     assert_eq!(finish_netsum(partial_netsum(0, &data)), 0xE5CA);
 }
+
+#[test]
+fn test_arp_probe() {
+    let probe =
+        Fragment::new_arp_probe(&[2, 0, 0, 0, 0, 1], "192.0.2.5".parse().unwrap()).flatten();
+    assert_eq!(probe.len(), 42);
+    assert_eq!(probe[0..6], [0xff; 6]);
+    assert_eq!(
+        parse_arp(&probe),
+        Some(Arp {
+            op: ARP_REQUEST,
+            sender_mac: [2, 0, 0, 0, 0, 1],
+            sender_ip: net::Ipv4Addr::UNSPECIFIED,
+            target_mac: [0; 6],
+            target_ip: "192.0.2.5".parse().unwrap(),
+        })
+    );
+    /* Not ARP */
+    let mut ip = probe.clone();
+    ip[12..14].copy_from_slice(&ETHERTYPE_IP4.to_be_bytes());
+    assert_eq!(parse_arp(&ip), None);
+    /* Truncated */
+    assert_eq!(parse_arp(&probe[..30]), None);
+}
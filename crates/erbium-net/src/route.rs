@@ -0,0 +1,115 @@
+/*   Copyright 2023 Perry Lorier
+ *
+ *  Licensed under the Apache License, Version 2.0 (the "License");
+ *  you may not use this file except in compliance with the License.
+ *  You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ *  Unless required by applicable law or agreed to in writing, software
+ *  distributed under the License is distributed on an "AS IS" BASIS,
+ *  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ *  See the License for the specific language governing permissions and
+ *  limitations under the License.
+ *
+ *  SPDX-License-Identifier: Apache-2.0
+ *
+ *  Adding and removing routes in the kernel routing table.
+ *
+ *  Currently uses netlink, like netinfo.
+ */
+use netlink_packet_route::rtnl::nlas::route::Nla;
+use netlink_packet_route::{
+    constants::*, NetlinkHeader, NetlinkMessage, NetlinkPayload, RouteHeader, RouteMessage,
+    RtnlMessage,
+};
+use netlink_sys::TokioSocket as Socket;
+use netlink_sys::{protocols, SocketAddr};
+
+/// A route to a prefix via a nexthop.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Route {
+    pub destination: std::net::IpAddr,
+    pub prefixlen: u8,
+    pub nexthop: std::net::IpAddr,
+    /// The interface the nexthop is on.  Required if the nexthop is link local.
+    pub oifidx: Option<u32>,
+}
+
+fn ip_bytes(ip: &std::net::IpAddr) -> Vec<u8> {
+    match ip {
+        std::net::IpAddr::V4(ip4) => ip4.octets().to_vec(),
+        std::net::IpAddr::V6(ip6) => ip6.octets().to_vec(),
+    }
+}
+
+impl Route {
+    fn to_message(&self) -> RouteMessage {
+        let mut nlas = vec![
+            Nla::Destination(ip_bytes(&self.destination)),
+            Nla::Gateway(ip_bytes(&self.nexthop)),
+        ];
+        if let Some(oif) = self.oifidx {
+            nlas.push(Nla::Oif(oif));
+        }
+        RouteMessage {
+            header: RouteHeader {
+                address_family: match self.destination {
+                    std::net::IpAddr::V4(_) => AF_INET as u8,
+                    std::net::IpAddr::V6(_) => AF_INET6 as u8,
+                },
+                destination_prefix_length: self.prefixlen,
+                table: RT_TABLE_MAIN,
+                protocol: RTPROT_DHCP,
+                scope: RT_SCOPE_UNIVERSE,
+                kind: RTN_UNICAST,
+                ..Default::default()
+            },
+            nlas,
+        }
+    }
+}
+
+async fn send_request(flags: u16, message: RtnlMessage) -> Result<(), std::io::Error> {
+    let mut socket = Socket::new(protocols::NETLINK_ROUTE)?;
+    socket.bind_auto()?;
+    socket.connect(&SocketAddr::new(0, 0))?;
+
+    let mut packet = NetlinkMessage {
+        header: NetlinkHeader {
+            flags: NLM_F_REQUEST | NLM_F_ACK | flags,
+            sequence_number: 1,
+            ..Default::default()
+        },
+        payload: NetlinkPayload::from(message),
+    };
+    packet.finalize();
+
+    let mut buf = vec![0; packet.header.length as usize];
+    packet.serialize(&mut buf[..]);
+    socket.send(&buf[..]).await?;
+
+    /* Wait for the kernel to acknowledge (or reject) the request */
+    let mut receive_buffer = vec![0; 4096];
+    let size = socket.recv(&mut receive_buffer[..]).await?;
+    let reply = <NetlinkMessage<RtnlMessage>>::deserialize(&receive_buffer[..size])
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e.to_string()))?;
+    match reply.payload {
+        NetlinkPayload::Error(e) if e.code != 0 => Err(std::io::Error::from_raw_os_error(-e.code)),
+        _ => Ok(()),
+    }
+}
+
+/// Installs (or replaces) a route in the main routing table.
+pub async fn add_route(route: &Route) -> Result<(), std::io::Error> {
+    send_request(
+        NLM_F_CREATE | NLM_F_REPLACE,
+        RtnlMessage::NewRoute(route.to_message()),
+    )
+    .await
+}
+
+/// Removes a route from the main routing table.
+pub async fn del_route(route: &Route) -> Result<(), std::io::Error> {
+    send_request(0, RtnlMessage::DelRoute(route.to_message())).await
+}
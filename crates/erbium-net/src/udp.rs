@@ -120,6 +120,19 @@ impl UdpSocket {
         .map_err(|e| e.into())
     }
 
+    pub fn join_multicast_v6(&self, group: &std::net::Ipv6Addr, ifindex: u32) -> io::Result<()> {
+        self.fd.get_ref().join_multicast_v6(group, ifindex)
+    }
+
+    pub fn set_opt_broadcast(&self, b: bool) -> Result<(), io::Error> {
+        nix::sys::socket::setsockopt(
+            self.fd.get_ref().as_raw_fd(),
+            nix::sys::socket::sockopt::Broadcast,
+            &b,
+        )
+        .map_err(|e| e.into())
+    }
+
     pub fn set_opt_reuse_port(&self, b: bool) -> Result<(), io::Error> {
         nix::sys::socket::setsockopt(
             self.fd.get_ref().as_raw_fd(),
@@ -91,6 +91,15 @@ async fn go() -> Result<(), Error> {
         let dhcp_copy = dhcp.clone();
         services.push(tokio::spawn(async move { dhcp_copy.run().await }));
     }
+    #[cfg(feature = "dhcp6")]
+    {
+        let dhcp6 = std::sync::Arc::new(
+            dhcp6::Dhcp6Service::new(netinfo.clone(), conf.clone())
+                .await
+                .map_err(Error::Service)?,
+        );
+        services.push(tokio::spawn(async move { dhcp6.run().await }));
+    }
     #[cfg(feature = "radv")]
     {
         let radv = std::sync::Arc::new(
@@ -100,6 +109,17 @@ async fn go() -> Result<(), Error> {
 
         services.push(tokio::spawn(async move { radv.run().await }));
     }
+    #[cfg(feature = "tftp")]
+    {
+        if let Some(tftp) = tftp::TftpService::new(conf.clone(), &netinfo)
+            .await
+            .map_err(|err| Error::Service(err.to_string()))?
+        {
+            services.push(tokio::spawn(async move {
+                tftp.run().await.map_err(|err| err.to_string())
+            }));
+        }
+    }
     #[cfg(feature = "http")]
     http::run(dhcp, conf.clone())
         .await